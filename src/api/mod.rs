@@ -1,8 +1,176 @@
-use warp::Filter;
-use tracing::info;
+use crate::broker::handler::BrokerHandler;
+use crate::broker::storage::{BrokerStorage, JobPatch, PatchOutcome, RequeueOutcome};
+use crate::broker::types::{BookingJob, JobState, NotificationState};
+use crate::config::Config;
+use crate::metrics::SharedMetrics;
+use crate::p2p::command::{SwarmCommand, GATEWAY_SERVICE_KEY};
+use crate::p2p::swarm::identify_protocol_version;
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use std::sync::Arc;
+use std::time::Duration;
+use warp::{Filter, Reply};
+use tracing::{debug, info, warn};
 
+mod rate_limit;
+mod readiness;
 mod state;
-pub use state::{SharedNetworkState, new_shared_network_state};
+pub use readiness::{Readiness, SharedReadiness};
+pub use state::{SharedNetworkState, new_shared_network_state, persist_network_snapshot};
+use rate_limit::{rate_limit, RateLimiter};
+use state::PeerQuery;
+
+/// Margen sobre `kad_query_timeout_secs` al esperar la respuesta de `/kad/closest`
+const CLOSEST_REPLY_MARGIN: Duration = Duration::from_secs(5);
+
+/// Umbral por defecto de `/broker/reconcile` para trabajos atascados
+const DEFAULT_STUCK_AFTER_SECS: u64 = 3600;
+
+/// Estado compartido que necesitan los endpoints de la API
+#[derive(Clone)]
+pub struct ApiContext {
+    pub config: Arc<Config>,
+    pub network_state: SharedNetworkState,
+    pub readiness: SharedReadiness,
+    pub metrics: SharedMetrics,
+    /// Canal hacia el bucle del swarm para lanzar consultas (p. ej. al DHT)
+    pub swarm_commands: mpsc::Sender<SwarmCommand>,
+    /// Almacenamiento del broker (solo en gateways con broker activo)
+    pub broker_storage: Option<Arc<BrokerStorage>>,
+    /// Handler de reservas del broker, para el modo drain
+    pub broker_handler: Option<Arc<BrokerHandler>>,
+}
+
+/// Respuesta de `POST /drain`
+#[derive(Debug, serde::Serialize)]
+pub struct DrainReport {
+    pub draining: bool,
+    /// Si ya estaba en drain antes de esta petición
+    pub already_draining: bool,
+    /// Trabajos queued/sending que faltan por terminar (solo con broker)
+    pub unfinished_jobs: Option<u64>,
+}
+
+impl ApiContext {
+    /// Pasa el nodo a modo drain (`POST /drain` o SIGUSR1): `/ready` responde
+    /// 503 y el broker rechaza reservas y ops nuevas con "draining", mientras el
+    /// forwarder termina las que ya tiene. Se puede repetir para ver cuántos
+    /// trabajos quedan antes de apagarlo.
+    pub fn start_drain(&self) -> DrainReport {
+        let already_draining = self.readiness.set_draining();
+        if let Some(handler) = &self.broker_handler {
+            handler.start_draining();
+        }
+        let unfinished_jobs = self.broker_handler.as_ref().and_then(|handler| match handler.unfinished_jobs() {
+            Ok(count) => Some(count),
+            Err(e) => {
+                warn!("Error al contar los trabajos pendientes: {:?}", e);
+                None
+            }
+        });
+        if !already_draining {
+            info!("Modo drain activado: no se aceptan reservas ni ops nuevas");
+        }
+        DrainReport { draining: true, already_draining, unfinished_jobs }
+    }
+}
+
+/// Respuesta de `GET /version`
+#[derive(Debug, serde::Serialize)]
+struct VersionInfo {
+    crate_version: &'static str,
+    /// Versión anunciada vía identify (incluye el `network_id` si hay uno)
+    protocol_version: String,
+    agent_version: String,
+    /// `GIT_SHA` / `BUILD_TIME` del entorno de compilación ("unknown" si no estaban)
+    git_sha: &'static str,
+    build_time: &'static str,
+}
+
+impl VersionInfo {
+    fn new(config: &Config) -> Self {
+        VersionInfo {
+            crate_version: env!("CARGO_PKG_VERSION"),
+            protocol_version: identify_protocol_version(config.network_id.as_deref()),
+            agent_version: config.agent_version.clone(),
+            git_sha: option_env!("GIT_SHA").unwrap_or("unknown"),
+            build_time: option_env!("BUILD_TIME").unwrap_or("unknown"),
+        }
+    }
+}
+
+/// Respuesta de `GET /booking/{correlation_id}/status`: contrato mínimo y
+/// estable para clientes, sin los datos de la reserva ni detalles del broker
+#[derive(Debug, serde::Serialize)]
+struct BookingStatusInfo {
+    correlation_id: String,
+    /// queued, sending, confirmed o failed
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+impl From<BookingJob> for BookingStatusInfo {
+    fn from(job: BookingJob) -> Self {
+        BookingStatusInfo {
+            correlation_id: job.correlation_id,
+            status: job.state.as_str(),
+            http_status: job.http_status,
+            last_error: job.last_error,
+        }
+    }
+}
+
+/// Parámetros de `POST /network/providers`
+#[derive(Debug, Default, Deserialize)]
+struct ProvidersQuery {
+    key: Option<String>,
+}
+
+/// Cuerpo de `POST /kad/closest`
+#[derive(Debug, Deserialize)]
+struct ClosestQuery {
+    key: String,
+}
+
+/// Parámetros de `GET /broker/reconcile`
+#[derive(Debug, Default, Deserialize)]
+struct ReconcileQuery {
+    /// Antigüedad a partir de la cual un trabajo queued/sending se considera atascado
+    stuck_after_secs: Option<u64>,
+}
+
+/// Parámetros de `POST /notifications/replay`
+#[derive(Debug, Default, Deserialize)]
+struct ReplayQuery {
+    /// Solo las fallidas hace al menos estos segundos (por defecto, todas)
+    older_than: Option<u64>,
+}
+
+/// Cuerpo de `PATCH /jobs/{correlation_id}`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobPatchBody {
+    /// queued o failed (solo failed ↔ queued)
+    state: Option<String>,
+    /// epoch ms; solo para trabajos que quedan en queued
+    next_attempt_at: Option<i64>,
+    /// Los trabajos no tienen prioridad: se rechaza con 400 en lugar de ignorarlo
+    priority: Option<serde_json::Value>,
+}
+
+/// Parámetros de `GET /notifications`
+#[derive(Debug, Default, Deserialize)]
+struct NotificationQuery {
+    state: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests;
 
 /// Inicia el servidor HTTP local para comunicación entre nodos
 /// 
@@ -10,15 +178,107 @@ pub use state::{SharedNetworkState, new_shared_network_state};
 /// Levanta un servidor HTTP en 127.0.0.1:8080 con los siguientes endpoints:
 /// - GET /: Devuelve la página HTML de la UI
 /// - GET /status: Devuelve {"estado": "activo"}
+/// - GET /version: Versión del crate, del protocolo (identify), `agent_version`,
+///   y el commit y la fecha de compilación (`GIT_SHA`/`BUILD_TIME` al compilar)
 /// - GET /network: Devuelve un snapshot de red (peers, bootstrap peers, etc.)
+///   Acepta `?connected=`, `?discovered_via=`, `?offset=` y `?limit=` para
+///   filtrar y paginar los peers; `peers_total` indica cuántos coinciden.
+///   Cada peer indica `direction` (inbound, outbound o both) mientras está conectado,
+///   y `relayed: true` si todas sus conexiones pasan por un relay (`/p2p-circuit`).
+///   Con `snapshot_path`, los peers y el feed de eventos se recuperan tras reiniciar.
+/// - GET /peers: Solo los PeerId, como array JSON de strings (p. ej.
+///   `?connected=true` para los conectados); mismos filtros que `/network`
+/// - GET /network/summary: Devuelve solo totales agregados (conectados, descubiertos, RTT medio, uptime)
+/// - GET /config: Configuración efectiva (CLI > entorno > config.toml > valores
+///   por defecto) con los secretos (`api_token`, clave de identidad, clave de
+///   cifrado de la base de datos) sustituidos por `"***"`. Requiere
+///   `Authorization: Bearer <api_token>` (401 sin él; 403 si no hay `api_token`).
+/// - GET /health: Liveness, siempre 200 mientras el proceso esté vivo
+/// - GET /ready: Readiness, 503 hasta que el swarm escuche en alguna dirección
+///   (en gateways con Kademlia, también hasta el primer bootstrap exitoso o
+///   hasta que pase discovery_timeout_secs)
+///   y, en gateways con broker, el almacenamiento y los workers estén activos.
+///   En modo drain responde siempre 503 (`draining: true`)
+/// - POST /network/providers: Lanza una búsqueda de proveedores en el DHT
+///   (`?key=`, por defecto la clave de servicio de los gateways); los
+///   resultados aparecen en `providers` del snapshot de `/network`
+/// - POST /kad/closest: Busca en el DHT los peers más cercanos a `{"key": ...}`
+///   y devuelve `{key, peers: [{peer_id, addrs}]}` al terminar la consulta.
+///   503 si el swarm no corre o Kademlia está desactivado; 504 si no responde
+///   a tiempo (`kad_query_timeout_secs`).
+/// - GET /metrics: Métricas en formato Prometheus/OpenMetrics (bytes por
+///   dirección y pila de protocolos de transporte; en gateways, trabajos y
+///   notificaciones por estado y tamaño de la base de datos)
+/// - GET /metrics.json: Las mismas métricas en JSON para quien no usa
+///   Prometheus: `{p2p, broker, notifier}`, cada grupo con las muestras por
+///   nombre (sin el prefijo `hch_`); las que tienen etiquetas son listas de
+///   `{labels, value}`
+/// - GET /notifications: Lista las notificaciones del broker (solo gateways con
+///   broker). Acepta `?state=` (pending, simulated_sent, failed), `?offset=` y
+///   `?limit=`; `notifications_total` indica cuántas coinciden. Incluye el
+///   destinatario (`email_to`), así que requiere `Authorization: Bearer <api_token>`.
+/// - POST /notifications/replay: Vuelve a poner en pending, con los intentos a
+///   cero y para ya, las notificaciones `failed` (p. ej. tras corregir la
+///   configuración del envío). `?older_than=<segundos>` limita a las que
+///   fallaron hace al menos ese tiempo. Devuelve `{requeued}`. Requiere
+///   `Authorization: Bearer <api_token>`.
+/// - GET /booking/{correlation_id}/status: Estado de una reserva enviada por
+///   P2P, pensado para que los clientes lo consulten:
+///   `{correlation_id, status, http_status?, last_error?}` con `status` en
+///   queued, sending, confirmed o failed. 404 si no existe.
+/// - GET /broker/reconcile: Auditoría de consistencia del broker (solo
+///   lectura): trabajos confirmados sin notificación, notificaciones sin
+///   trabajo y trabajos queued/sending sin cambios desde hace
+///   `?stuck_after_secs=` (3600 por defecto). Requiere
+///   `Authorization: Bearer <api_token>` (401 sin él; 403 si no hay `api_token`).
+/// - POST /jobs/{correlation_id}/retry: Vuelve a encolar un trabajo `Failed`
+///   (intentos a 0, reintento inmediato). 404 si no existe, 409 si no está fallido.
+///   Requiere `Authorization: Bearer <api_token>`.
+/// - GET /jobs/events: Stream SSE (`event: job_state`) con cada transición de
+///   estado de los trabajos del broker: `{correlation_id, from, to, attempts, at_ms}`.
+///   Un cliente lento pierde eventos en vez de frenar al broker.
+/// - DELETE /jobs/{correlation_id}: Borra el trabajo, su notificación y sus
+///   índices. Requiere `Authorization: Bearer <api_token>` (401 sin él; 403 si
+///   no hay `api_token` configurado). 404 si no existe.
+/// - PATCH /jobs/{correlation_id}: Corrige un trabajo sin reencolarlo entero.
+///   Acepta `{"state": "queued"|"failed", "next_attempt_at": <epoch ms>}`;
+///   solo se permiten failed → queued (con los intentos a cero), queued →
+///   failed y cambiar `next_attempt_at` de un trabajo que queda en queued.
+///   409 para cualquier otra transición, 400 para `priority` (los trabajos no
+///   tienen prioridad). Devuelve el trabajo actualizado y deja constancia en el
+///   log de auditoría (`hybrid_connection_health::api::audit`). Requiere
+///   `Authorization: Bearer <api_token>`.
+/// - POST /drain: Modo drain antes de un apagado planificado (también con
+///   SIGUSR1): `/ready` pasa a 503, las reservas nuevas reciben
+///   `BookingAck { status: "draining" }` y las ops `OpAck { ok: false, msg:
+///   "draining" }`, mientras el forwarder sigue con los
+///   trabajos queued/sending. Devuelve `{draining, already_draining,
+///   unfinished_jobs}`; cuando `unfinished_jobs` llega a 0 se puede apagar.
+///   Requiere `Authorization: Bearer <api_token>`.
 /// 
+/// Los endpoints que modifican estado (POST/PATCH/DELETE) están limitados a
+/// `api_rate_limit_per_min` peticiones por minuto (por IP o globales según
+/// `api_rate_limit_scope`); al superarlo responden 429 con `Retry-After`.
+///
+/// Cada petición queda registrada (método, ruta sin query, estado, IP remota y
+/// latencia): en `info` las que modifican estado o fallan, en `debug` el resto.
+/// Nunca se registran cabeceras, así que `Authorization` no aparece en los logs.
+///
+/// Si `api_cors_origins` está configurado, todas las rutas incluyen cabeceras
+/// CORS para esos orígenes (y responden a los preflight `OPTIONS`).
+///
 /// # Ejemplo
 /// ```bash
 /// curl http://127.0.0.1:8080/status
 /// # Respuesta: {"estado":"activo"}
 /// ```
-pub async fn iniciar_api_local(network_state: SharedNetworkState) {
+pub async fn iniciar_api_local(ctx: ApiContext) {
     info!("Iniciando API local en 127.0.0.1:8080");
+    let cors_origins = ctx.config.api_cors_origins.clone();
+    let limiter = (ctx.config.api_rate_limit_per_min > 0).then(|| {
+        Arc::new(RateLimiter::new(ctx.config.api_rate_limit_per_min, ctx.config.api_rate_limit_scope))
+    });
+    let with_ctx = warp::any().map(move || ctx.clone());
 
     // Definir el endpoint para la UI (GET /)
     let ui_route = warp::path::end()
@@ -36,26 +296,604 @@ pub async fn iniciar_api_local(network_state: SharedNetworkState) {
             }))
         });
 
+    // Definir el endpoint /version
+    let version_route = warp::path("version")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .map(|ctx: ApiContext| warp::reply::json(&VersionInfo::new(&ctx.config)));
+
+    // Definir el endpoint /config (configuración efectiva, requiere api_token)
+    let config_route = warp::path("config")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|ctx: ApiContext, authorization: Option<String>| {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return reply;
+            }
+            warp::reply::with_status(warp::reply::json(&ctx.config.redacted()), warp::http::StatusCode::OK)
+        });
+
+    // Definir el endpoint /health (liveness)
+    let health_route = warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| {
+            warp::reply::json(&serde_json::json!({
+                "status": "ok"
+            }))
+        });
+
+    // Definir el endpoint /ready (readiness)
+    let ready_route = warp::path("ready")
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .map(|ctx: ApiContext| {
+            let report = ctx.readiness.report();
+            let status = if report.ready {
+                warp::http::StatusCode::OK
+            } else {
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply::json(&report), status)
+        });
+
     // Definir el endpoint /network (snapshot)
-    let with_state = warp::any().map(move || network_state.clone());
     let network_route = warp::path("network")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .and(warp::query::<PeerQuery>())
+        .and_then(|ctx: ApiContext, query: PeerQuery| async move {
+            let page = ctx.network_state.read().await.page(&query);
+            Ok::<_, std::convert::Infallible>(warp::reply::json(&page))
+        });
+
+    // Definir el endpoint /peers (solo los PeerId, para integraciones ligeras)
+    let peers_route = warp::path("peers")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .and(warp::query::<PeerQuery>())
+        .and_then(|ctx: ApiContext, query: PeerQuery| async move {
+            let peer_ids = ctx.network_state.read().await.peer_ids(&query);
+            Ok::<_, std::convert::Infallible>(warp::reply::json(&peer_ids))
+        });
+
+    // Definir el endpoint /network/summary (totales agregados, cacheable)
+    let summary_route = warp::path!("network" / "summary")
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .and_then(|ctx: ApiContext| async move {
+            let summary = ctx.network_state.read().await.summary();
+            Ok::<_, std::convert::Infallible>(warp::reply::with_header(
+                warp::reply::json(&summary),
+                "Cache-Control",
+                "max-age=5",
+            ))
+        });
+
+    // Definir el endpoint /network/providers (búsqueda de proveedores en el DHT)
+    let providers_route = warp::path!("network" / "providers")
+        .and(warp::post())
+        .and(rate_limit(limiter.clone()))
+        .and(with_ctx.clone())
+        .and(warp::query::<ProvidersQuery>())
+        .and_then(|ctx: ApiContext, query: ProvidersQuery| async move {
+            let key = query.key.unwrap_or_else(|| GATEWAY_SERVICE_KEY.to_string());
+            let reply = match ctx.swarm_commands.send(SwarmCommand::FindProviders(key.clone())).await {
+                Ok(()) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "key": key, "status": "lookup_started" })),
+                    warp::http::StatusCode::ACCEPTED,
+                ),
+                Err(_) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "error": "swarm not running" })),
+                    warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                ),
+            };
+            Ok::<_, std::convert::Infallible>(reply)
+        });
+
+    // Definir el endpoint /kad/closest (peers más cercanos a una clave en el DHT)
+    let closest_route = warp::path!("kad" / "closest")
+        .and(warp::post())
+        .and(rate_limit(limiter.clone()))
+        .and(with_ctx.clone())
+        .and(warp::body::json::<ClosestQuery>())
+        .and_then(|ctx: ApiContext, query: ClosestQuery| async move {
+            let (reply, result) = oneshot::channel();
+            let command = SwarmCommand::FindClosest { key: query.key.clone(), reply };
+            if ctx.swarm_commands.send(command).await.is_err() {
+                return Ok::<_, std::convert::Infallible>(error_reply(
+                    warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                    "swarm not running".to_string(),
+                ));
+            }
+            // Kademlia gives up on its own after kad_query_timeout_secs; the margin covers the swarm loop
+            let wait = Duration::from_secs(ctx.config.kad_query_timeout_secs) + CLOSEST_REPLY_MARGIN;
+            let reply = match tokio::time::timeout(wait, result).await {
+                Ok(Ok(Ok(peers))) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "key": query.key, "peers": peers })),
+                    warp::http::StatusCode::OK,
+                ),
+                Ok(Ok(Err(e))) => error_reply(warp::http::StatusCode::SERVICE_UNAVAILABLE, e),
+                Ok(Err(_)) => error_reply(warp::http::StatusCode::SERVICE_UNAVAILABLE, "swarm not running".to_string()),
+                Err(_) => error_reply(warp::http::StatusCode::GATEWAY_TIMEOUT, "closest peers lookup timed out".to_string()),
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint /metrics (formato OpenMetrics para Prometheus)
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .map(|ctx: ApiContext| {
+            warp::reply::with_header(
+                ctx.metrics.encode(),
+                "Content-Type",
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )
+        });
+
+    // Definir el endpoint /metrics.json (mismas métricas, agrupadas en JSON)
+    let metrics_json_route = warp::path("metrics.json")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .map(|ctx: ApiContext| warp::reply::json(&ctx.metrics.json()));
+
+    // Definir el endpoint /notifications (listado del outbox del broker, requiere api_token)
+    let notifications_route = warp::path("notifications")
+        .and(warp::path::end())
         .and(warp::get())
-        .and(with_state)
-        .and_then(|state: SharedNetworkState| async move {
-            let snapshot = state.read().await.clone();
-            Ok::<_, std::convert::Infallible>(warp::reply::json(&snapshot))
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<NotificationQuery>())
+        .and_then(|ctx: ApiContext, authorization: Option<String>, query: NotificationQuery| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            let Some(storage) = ctx.broker_storage else {
+                return Ok(broker_disabled_reply());
+            };
+            let state = match query.state.as_deref().map(|s| (s, NotificationState::parse(s))) {
+                None => None,
+                Some((_, Some(state))) => Some(state),
+                Some((s, None)) => {
+                    return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, format!("unknown notification state '{}'", s)));
+                }
+            };
+            let (offset, limit) = (query.offset.unwrap_or(0), query.limit.unwrap_or(usize::MAX));
+            let reply = match blocking(move || storage.list_notifications(state, offset, limit)).await {
+                Ok((notifications, total)) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({
+                        "notifications": notifications,
+                        "notifications_total": total,
+                    })),
+                    warp::http::StatusCode::OK,
+                ),
+                Err(e) => {
+                    warn!("Error al listar notificaciones: {:?}", e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to read notifications".to_string())
+                }
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint /booking/{correlation_id}/status (estado de una reserva para clientes)
+    let booking_status_route = warp::path!("booking" / String / "status")
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .and_then(|correlation_id: String, ctx: ApiContext| async move {
+            let Some(storage) = ctx.broker_storage else {
+                return Ok::<_, std::convert::Infallible>(broker_disabled_reply());
+            };
+            let id = correlation_id.clone();
+            let reply = match blocking(move || storage.get_booking_job(&id)).await {
+                Ok(Some(job)) => warp::reply::with_status(
+                    warp::reply::json(&BookingStatusInfo::from(job)),
+                    warp::http::StatusCode::OK,
+                ),
+                Ok(None) => {
+                    error_reply(warp::http::StatusCode::NOT_FOUND, format!("booking {} not found", correlation_id))
+                }
+                Err(e) => {
+                    warn!("Error al leer la reserva {}: {:?}", correlation_id, e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to read booking".to_string())
+                }
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint /broker/reconcile (auditoría de consistencia, requiere api_token)
+    let reconcile_route = warp::path!("broker" / "reconcile")
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<ReconcileQuery>())
+        .and_then(|ctx: ApiContext, authorization: Option<String>, query: ReconcileQuery| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            let Some(storage) = ctx.broker_storage else {
+                return Ok(broker_disabled_reply());
+            };
+            let stuck_after_secs = query.stuck_after_secs.unwrap_or(DEFAULT_STUCK_AFTER_SECS);
+            let stuck_after_ms = i64::try_from(stuck_after_secs.saturating_mul(1000)).unwrap_or(i64::MAX);
+            let cutoff_ms = chrono::Utc::now().timestamp_millis().saturating_sub(stuck_after_ms);
+            let reply = match blocking(move || storage.reconcile(cutoff_ms)).await {
+                Ok(report) => warp::reply::with_status(warp::reply::json(&report), warp::http::StatusCode::OK),
+                Err(e) => {
+                    warn!("Error al reconciliar el broker: {:?}", e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to reconcile broker".to_string())
+                }
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint /jobs/{correlation_id}/retry (reencolar un trabajo fallido, requiere api_token)
+    let job_retry_route = warp::path!("jobs" / String / "retry")
+        .and(warp::post())
+        .and(rate_limit(limiter.clone()))
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(|correlation_id: String, ctx: ApiContext, authorization: Option<String>| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            let Some(storage) = ctx.broker_storage else {
+                return Ok(broker_disabled_reply());
+            };
+            let id = correlation_id.clone();
+            let reply = match blocking(move || storage.requeue_job(&id)).await {
+                Ok(RequeueOutcome::Requeued) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({
+                        "correlation_id": correlation_id,
+                        "state": "queued",
+                    })),
+                    warp::http::StatusCode::OK,
+                ),
+                Ok(RequeueOutcome::NotFound) => {
+                    error_reply(warp::http::StatusCode::NOT_FOUND, format!("job {} not found", correlation_id))
+                }
+                Ok(RequeueOutcome::NotFailed(state)) => error_reply(
+                    warp::http::StatusCode::CONFLICT,
+                    format!("job {} is {}, only failed jobs can be retried", correlation_id, state.as_str()),
+                ),
+                Err(e) => {
+                    warn!("Error al reencolar el trabajo {}: {:?}", correlation_id, e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to requeue job".to_string())
+                }
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint /jobs/events (SSE con las transiciones de trabajos)
+    let job_events_route = warp::path!("jobs" / "events")
+        .and(warp::get())
+        .and(with_ctx.clone())
+        .map(|ctx: ApiContext| match ctx.broker_storage {
+            Some(storage) => {
+                let events = futures::stream::unfold(storage.subscribe_job_events(), |mut rx| async move {
+                    loop {
+                        match rx.recv().await {
+                            Ok(event) => {
+                                let sse = warp::sse::Event::default()
+                                    .event("job_state")
+                                    .json_data(&event)
+                                    .unwrap_or_else(|_| warp::sse::Event::default().comment("unserializable event"));
+                                return Some((Ok::<_, std::convert::Infallible>(sse), rx));
+                            }
+                            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                warn!("Cliente SSE lento: {} eventos descartados", missed);
+                            }
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                });
+                warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+            }
+            None => broker_disabled_reply().into_response(),
+        });
+
+    // Definir el endpoint DELETE /jobs/{correlation_id} (requiere api_token)
+    let job_delete_route = warp::path!("jobs" / String)
+        .and(warp::delete())
+        .and(rate_limit(limiter.clone()))
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(|correlation_id: String, ctx: ApiContext, authorization: Option<String>| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            let Some(storage) = ctx.broker_storage else {
+                return Ok(broker_disabled_reply());
+            };
+            let id = correlation_id.clone();
+            let reply = match blocking(move || storage.delete_job(&id)).await {
+                Ok(true) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "deleted": correlation_id })),
+                    warp::http::StatusCode::OK,
+                ),
+                Ok(false) => error_reply(warp::http::StatusCode::NOT_FOUND, format!("job {} not found", correlation_id)),
+                Err(e) => {
+                    warn!("Error al borrar el trabajo {}: {:?}", correlation_id, e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to delete job".to_string())
+                }
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint POST /notifications/replay (reintentar notificaciones fallidas, requiere api_token)
+    let notifications_replay_route = warp::path!("notifications" / "replay")
+        .and(warp::post())
+        .and(rate_limit(limiter.clone()))
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<ReplayQuery>())
+        .and_then(|ctx: ApiContext, authorization: Option<String>, query: ReplayQuery| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            let Some(storage) = ctx.broker_storage else {
+                return Ok(broker_disabled_reply());
+            };
+            let older_than_ms = i64::try_from(query.older_than.unwrap_or(0).saturating_mul(1000)).unwrap_or(i64::MAX);
+            let updated_before_ms = storage.now_ms().saturating_sub(older_than_ms).saturating_add(1);
+            let reply = match blocking(move || storage.requeue_failed_notifications(updated_before_ms)).await {
+                Ok(requeued) => {
+                    info!("{} notificaciones fallidas reencoladas", requeued);
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "requeued": requeued })),
+                        warp::http::StatusCode::OK,
+                    )
+                }
+                Err(e) => {
+                    warn!("Error al reencolar las notificaciones fallidas: {:?}", e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to requeue notifications".to_string())
+                }
+            };
+            Ok(reply)
+        });
+
+    // Definir el endpoint POST /drain (dejar de aceptar reservas antes de apagar)
+    let drain_route = warp::path("drain")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(rate_limit(limiter.clone()))
+        .and(with_ctx.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(|ctx: ApiContext, authorization: Option<String>| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            // start_drain cuenta los trabajos pendientes en sled
+            let reply = match blocking(move || Ok(ctx.start_drain())).await {
+                Ok(report) => warp::reply::with_status(warp::reply::json(&report), warp::http::StatusCode::OK),
+                Err(e) => {
+                    warn!("Error al activar el modo drain: {:?}", e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to start drain".to_string())
+                }
+            };
+            Ok(reply)
         });
 
     // Combinar todas las rutas
-    let routes = ui_route.or(status_route).or(network_route);
+    let routes = ui_route
+        .or(status_route)
+        .or(version_route)
+        .or(config_route)
+        .or(health_route)
+        .or(ready_route)
+        .or(network_route)
+        .or(peers_route)
+        .or(summary_route)
+        .or(providers_route)
+        .or(closest_route)
+        .or(metrics_route)
+        .or(metrics_json_route)
+        .or(notifications_route)
+        .or(booking_status_route)
+        .or(reconcile_route)
+        .or(job_retry_route)
+        .or(job_events_route)
+        .or(job_delete_route)
+        .or(job_patch_route(with_ctx.clone(), limiter.clone()))
+        .or(notifications_replay_route)
+        .or(drain_route)
+        .recover(rate_limit::handle_rejection)
+        .with(warp::log::custom(access_log))
+        .boxed();
 
     info!("API local lista. Endpoints disponibles:");
     info!("  GET http://127.0.0.1:8080/");
     info!("  GET http://127.0.0.1:8080/status");
-    info!("  GET http://127.0.0.1:8080/network");
+    info!("  GET http://127.0.0.1:8080/version");
+    info!("  GET http://127.0.0.1:8080/config (Authorization: Bearer <api_token>)");
+    info!("  GET http://127.0.0.1:8080/health");
+    info!("  GET http://127.0.0.1:8080/ready");
+    info!("  GET http://127.0.0.1:8080/network[?connected=&discovered_via=&offset=&limit=]");
+    info!("  GET http://127.0.0.1:8080/peers[?connected=&discovered_via=&offset=&limit=]");
+    info!("  GET http://127.0.0.1:8080/network/summary");
+    info!("  POST http://127.0.0.1:8080/network/providers[?key=]");
+    info!("  POST http://127.0.0.1:8080/kad/closest {{\"key\": ...}}");
+    info!("  GET http://127.0.0.1:8080/metrics");
+    info!("  GET http://127.0.0.1:8080/metrics.json");
+    info!("  GET http://127.0.0.1:8080/notifications[?state=&offset=&limit=] (Authorization: Bearer <api_token>)");
+    info!("  GET http://127.0.0.1:8080/booking/{{correlation_id}}/status");
+    info!("  GET http://127.0.0.1:8080/broker/reconcile[?stuck_after_secs=] (Authorization: Bearer <api_token>)");
+    info!("  POST http://127.0.0.1:8080/jobs/{{correlation_id}}/retry (Authorization: Bearer <api_token>)");
+    info!("  GET http://127.0.0.1:8080/jobs/events (SSE)");
+    info!("  DELETE http://127.0.0.1:8080/jobs/{{correlation_id}} (Authorization: Bearer <api_token>)");
+    info!("  PATCH http://127.0.0.1:8080/jobs/{{correlation_id}} (Authorization: Bearer <api_token>)");
+    info!("  POST http://127.0.0.1:8080/notifications/replay (Authorization: Bearer <api_token>)");
+    info!("  POST http://127.0.0.1:8080/drain (Authorization: Bearer <api_token>)");
+
+    // Iniciar el servidor (con CORS solo si hay orígenes configurados)
+    if cors_origins.is_empty() {
+        warp::serve(routes)
+            .run(([127, 0, 0, 1], 8080))
+            .await;
+    } else {
+        info!("  CORS habilitado para: {:?}", cors_origins);
+        warp::serve(routes.with(cors_filter(&cors_origins)))
+            .run(([127, 0, 0, 1], 8080))
+            .await;
+    }
+}
+
+/// Endpoint PATCH /jobs/{correlation_id} (correcciones de un operador, requiere api_token).
+/// Separado de `iniciar_api_local` para poder probarlo con `warp::test`
+fn job_patch_route(
+    with_ctx: impl Filter<Extract = (ApiContext,), Error = std::convert::Infallible> + Clone + Send + Sync + 'static,
+    limiter: Option<Arc<RateLimiter>>,
+) -> impl Filter<Extract = (warp::reply::WithStatus<warp::reply::Json>,), Error = warp::Rejection> + Clone {
+    warp::path!("jobs" / String)
+        .and(warp::patch())
+        .and(rate_limit(limiter))
+        .and(with_ctx)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::addr::remote())
+        .and(warp::body::json::<JobPatchBody>())
+        .and_then(|correlation_id: String, ctx: ApiContext, authorization: Option<String>, remote: Option<std::net::SocketAddr>, body: JobPatchBody| async move {
+            if let Err(reply) = authorize(&ctx.config, authorization.as_deref()) {
+                return Ok::<_, std::convert::Infallible>(reply);
+            }
+            let Some(storage) = ctx.broker_storage else {
+                return Ok(broker_disabled_reply());
+            };
+            if body.priority.is_some() {
+                return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, "jobs have no priority".to_string()));
+            }
+            let state = match body.state.as_deref().map(|s| JobState::parse(s).ok_or(s)).transpose() {
+                Ok(state) => state,
+                Err(s) => return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, format!("unknown job state '{}'", s))),
+            };
+            let patch = JobPatch { state, next_attempt_at: body.next_attempt_at };
+            let id = correlation_id.clone();
+            let reply = match blocking(move || storage.patch_job(&id, &patch)).await {
+                Ok(PatchOutcome::Patched { before, after }) => {
+                    info!(
+                        target: "hybrid_connection_health::api::audit",
+                        correlation_id = %correlation_id,
+                        remote = %remote.map(|a| a.to_string()).unwrap_or_else(|| "-".to_string()),
+                        from_state = before.state.as_str(),
+                        to_state = after.state.as_str(),
+                        from_next_attempt_at = before.next_attempt_at,
+                        to_next_attempt_at = after.next_attempt_at,
+                        "Trabajo {} modificado vía PATCH", correlation_id
+                    );
+                    warp::reply::with_status(warp::reply::json(&after), warp::http::StatusCode::OK)
+                }
+                Ok(PatchOutcome::NotFound) => {
+                    error_reply(warp::http::StatusCode::NOT_FOUND, format!("job {} not found", correlation_id))
+                }
+                Ok(PatchOutcome::InvalidTransition { from, to }) => error_reply(
+                    warp::http::StatusCode::CONFLICT,
+                    format!("job {} cannot go from {} to {}", correlation_id, from.as_str(), to.as_str()),
+                ),
+                Ok(PatchOutcome::NotQueued(state)) => error_reply(
+                    warp::http::StatusCode::CONFLICT,
+                    format!("job {} is {}, next_attempt_at only applies to queued jobs", correlation_id, state.as_str()),
+                ),
+                Err(e) => {
+                    warn!("Error al modificar el trabajo {}: {:?}", correlation_id, e);
+                    error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to patch job".to_string())
+                }
+            };
+            Ok(reply)
+        })
+}
+
+/// Registro de acceso por petición. Solo método, ruta (sin query string),
+/// estado, IP y latencia: ni cabeceras ni cuerpo, para no filtrar secretos.
+fn access_log(req: warp::log::Info) {
+    let latency_ms = req.elapsed().as_secs_f64() * 1000.0;
+    let remote = req.remote_addr().map(|a| a.to_string()).unwrap_or_else(|| "-".to_string());
+    if is_notable_request(req.method(), req.status()) {
+        info!(
+            target: "hybrid_connection_health::api::access",
+            method = %req.method(), path = %req.path(), status = req.status().as_u16(), remote = %remote, latency_ms,
+            "{} {} {}", req.method(), req.path(), req.status().as_u16()
+        );
+    } else {
+        debug!(
+            target: "hybrid_connection_health::api::access",
+            method = %req.method(), path = %req.path(), status = req.status().as_u16(), remote = %remote, latency_ms,
+            "{} {} {}", req.method(), req.path(), req.status().as_u16()
+        );
+    }
+}
+
+/// Las peticiones que modifican estado o fallan se registran en `info`; las
+/// lecturas correctas (la UI sondea cada pocos segundos) en `debug`
+fn is_notable_request(method: &warp::http::Method, status: warp::http::StatusCode) -> bool {
+    !matches!(*method, warp::http::Method::GET | warp::http::Method::HEAD | warp::http::Method::OPTIONS)
+        || status.is_client_error()
+        || status.is_server_error()
+}
+
+/// Exige `Authorization: Bearer <api_token>` en los endpoints protegidos:
+/// 403 si no hay `api_token` configurado, 401 si el token falta o no coincide
+fn authorize(
+    config: &Config,
+    authorization: Option<&str>,
+) -> Result<(), warp::reply::WithStatus<warp::reply::Json>> {
+    let Some(token) = config.api_token.as_deref() else {
+        return Err(error_reply(
+            warp::http::StatusCode::FORBIDDEN,
+            "api_token is not configured; protected endpoints are disabled".to_string(),
+        ));
+    };
+    if !bearer_token_matches(token, authorization) {
+        return Err(error_reply(warp::http::StatusCode::UNAUTHORIZED, "missing or invalid bearer token".to_string()));
+    }
+    Ok(())
+}
+
+/// Comprueba la cabecera `Authorization: Bearer <token>` sin cortocircuitar
+/// en el primer byte distinto
+fn bearer_token_matches(expected: &str, authorization: Option<&str>) -> bool {
+    let Some(given) = authorization.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Ejecuta fuera de los hilos del runtime una operación del broker que
+/// recorre (y descifra) un árbol entero de sled
+async fn blocking<T: Send + 'static>(op: impl FnOnce() -> anyhow::Result<T> + Send + 'static) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(op).await?
+}
+
+/// Respuesta JSON `{"error": ...}` con el código indicado
+fn error_reply(status: warp::http::StatusCode, message: String) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status)
+}
+
+/// Respuesta de los endpoints del broker en nodos sin broker
+fn broker_disabled_reply() -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply(warp::http::StatusCode::NOT_FOUND, "broker not enabled on this node".to_string())
+}
+
+/// Construye el filtro CORS; `*` en la lista permite cualquier origen
+fn cors_filter(origins: &[String]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(vec!["GET", "POST", "PATCH", "DELETE", "OPTIONS"])
+        .allow_headers(vec!["content-type", "authorization"]);
 
-    // Iniciar el servidor
-    warp::serve(routes)
-        .run(([127, 0, 0, 1], 8080))
-        .await;
+    if origins.iter().any(|o| o == "*") {
+        warn!("CORS permite cualquier origen (*): cualquier página web puede leer esta API");
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+    }
 }
@@ -0,0 +1,110 @@
+use crate::config::RateLimitScope;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+
+/// Buckets kept before full (idle) ones are pruned
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+/// Token bucket limiter for the mutating API endpoints. Each bucket holds up
+/// to a minute's worth of requests and refills continuously.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    scope: RateLimitScope,
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Rejection carrying how long the client should wait
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+impl RateLimiter {
+    pub fn new(per_min: u32, scope: RateLimitScope) -> Self {
+        RateLimiter {
+            capacity: f64::from(per_min),
+            refill_per_sec: f64::from(per_min) / 60.0,
+            scope,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available
+    pub fn check(&self, client: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let key = match self.scope {
+            RateLimitScope::Global => None,
+            RateLimitScope::PerIp => client,
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&key) {
+            let (capacity, refill) = (self.capacity, self.refill_per_sec);
+            buckets.retain(|_, b| b.refilled(now, refill, capacity) < capacity);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: self.capacity, updated: now });
+        bucket.tokens = bucket.refilled(now, self.refill_per_sec, self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, refill_per_sec: f64, capacity: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * refill_per_sec).min(capacity)
+    }
+}
+
+/// Filter for mutating routes: passes when `limiter` is `None` (disabled) or
+/// has a token for the caller, rejects with `RateLimited` otherwise
+pub fn rate_limit(
+    limiter: Option<Arc<RateLimiter>>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and_then(move |remote: Option<SocketAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                match limiter.map(|l| l.check(remote.map(|a| a.ip()), Instant::now())) {
+                    None | Some(Ok(())) => Ok(()),
+                    Some(Err(retry_after)) => Err(warp::reject::custom(RateLimited { retry_after })),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Turns a `RateLimited` rejection into 429 with `Retry-After` (whole seconds)
+pub async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    match rejection.find::<RateLimited>() {
+        Some(limited) => {
+            let secs = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "error": "rate limit exceeded" })),
+                    warp::http::StatusCode::TOO_MANY_REQUESTS,
+                ),
+                "Retry-After",
+                secs.to_string(),
+            ))
+        }
+        None => Err(rejection),
+    }
+}
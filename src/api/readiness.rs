@@ -0,0 +1,91 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub type SharedReadiness = Arc<Readiness>;
+
+/// Readiness signals reported by `GET /ready`. Components flip their own flag
+/// once they're able to serve traffic.
+#[derive(Debug)]
+pub struct Readiness {
+    listening: AtomicBool,
+    broker_required: bool,
+    broker_ready: AtomicBool,
+    dht_required: bool,
+    dht_ready: AtomicBool,
+    /// Set by `POST /drain` / SIGUSR1; a draining node is never ready again
+    draining: AtomicBool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub listening: bool,
+    /// `None` when this node doesn't run the broker
+    pub broker: Option<bool>,
+    /// `None` unless this node waits for a Kademlia bootstrap before serving
+    pub dht: Option<bool>,
+    /// True once the node was asked to drain before shutdown
+    pub draining: bool,
+}
+
+impl Readiness {
+    /// `broker_required` is true for gateways that run the broker subsystem,
+    /// `dht_required` for gateways that advertise themselves via Kademlia
+    pub fn new(broker_required: bool, dht_required: bool) -> Self {
+        Self {
+            listening: AtomicBool::new(false),
+            broker_required,
+            broker_ready: AtomicBool::new(false),
+            dht_required,
+            dht_ready: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the swarm has at least one active listen address
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    /// Whether broker storage is open and its workers are running
+    pub fn set_broker_ready(&self, ready: bool) {
+        self.broker_ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Whether the node is discoverable: a Kademlia bootstrap succeeded, or
+    /// the swarm stopped waiting for one
+    pub fn set_dht_ready(&self, ready: bool) {
+        self.dht_ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Reports the node unready for good, so load balancers stop sending it
+    /// traffic while in-flight work finishes. Returns whether it already was.
+    pub fn set_draining(&self) -> bool {
+        self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    /// True while a required DHT bootstrap hasn't been signalled yet
+    pub fn dht_pending(&self) -> bool {
+        self.dht_required && !self.dht_ready.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) -> ReadinessReport {
+        let listening = self.listening.load(Ordering::Relaxed);
+        let broker = self
+            .broker_required
+            .then(|| self.broker_ready.load(Ordering::Relaxed));
+        let dht = self
+            .dht_required
+            .then(|| self.dht_ready.load(Ordering::Relaxed));
+        let draining = self.draining.load(Ordering::Relaxed);
+
+        ReadinessReport {
+            ready: listening && broker.unwrap_or(true) && dht.unwrap_or(true) && !draining,
+            listening,
+            broker,
+            dht,
+            draining,
+        }
+    }
+}
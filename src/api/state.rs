@@ -1,11 +1,15 @@
 use crate::config::Config;
+use crate::metrics::BandwidthStats;
+use anyhow::{Context, Result};
 use libp2p::multiaddr::Protocol;
 use libp2p::Multiaddr;
-use serde::Serialize;
-use std::collections::{BTreeMap, BTreeSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, RwLock};
+use tracing::{info, warn};
 
 pub type SharedNetworkState = Arc<RwLock<NetworkSnapshot>>;
 
@@ -13,29 +17,274 @@ pub type SharedNetworkState = Arc<RwLock<NetworkSnapshot>>;
 pub struct NetworkSnapshot {
     pub local_peer_id: String,
     pub role: String,
-    pub listen: String,
+    /// Addresses the swarm is actually bound to, one per `NewListenAddr`
+    /// (e.g. the OS-assigned port of `tcp/0`); empty until it binds
+    pub listen_addrs: Vec<String>,
+    /// The `listen` setting, for reference
+    pub configured_listen: Vec<String>,
     pub bootstrap_peers: Vec<BootstrapPeerRow>,
     pub peers: BTreeMap<String, PeerRow>,
+    /// Transport bytes in/out, refreshed on every health tick
+    pub bandwidth: BandwidthStats,
+    /// Peers found advertising a DHT service key (key -> peer ids)
+    pub providers: BTreeMap<String, BTreeSet<String>>,
+    /// Our confirmed external addresses (from several peers' identify reports
+    /// or a UPnP mapping), dropped again when the swarm expires them
+    pub external_addrs: BTreeSet<String>,
+    /// External addresses currently mapped on the router via UPnP
+    pub upnp_mapped_addrs: BTreeSet<String>,
+    /// Recent activity feed, oldest first, capped at `event_log_capacity`
+    pub events: VecDeque<NetworkEvent>,
+    #[serde(skip)]
+    event_capacity: usize,
+    pub started_at_ms: u64,
     pub updated_at_ms: u64,
 }
 
+/// One entry of the activity feed. Repeats of the latest event for the same
+/// peer are folded into it (`count` goes up, `at_ms` moves forward).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkEvent {
+    pub at_ms: u64,
+    pub peer_id: String,
+    #[serde(flatten)]
+    pub kind: NetworkEventKind,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NetworkEventKind {
+    Connected,
+    Disconnected,
+    Discovered { via: String },
+    PingTimeout,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct BootstrapPeerRow {
     pub multiaddr: String,
+    /// From the configured `/p2p/` component, or learned from the first
+    /// connection dialed to this address when it has none
     pub peer_id: Option<String>,
     pub connected: bool,
+    /// Why the most recent dial to this entry failed (kept after a later success)
+    pub last_error: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Who opened the connections currently open to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionDirection {
+    /// The peer dialed us
+    Inbound,
+    /// We dialed the peer
+    Outbound,
+    /// At least one connection each way (e.g. a simultaneous dial)
+    Both,
+}
+
+/// Missing fields load with their defaults, so snapshots saved before a
+/// field existed still restore
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PeerRow {
     pub peer_id: String,
+    /// True while at least one connection to the peer is open
     pub connected: bool,
+    /// Number of currently open connections (e.g. TCP + QUIC)
+    pub connections: u32,
+    /// Who dialed the open connections; `None` while disconnected
+    pub direction: Option<ConnectionDirection>,
+    #[serde(skip)]
+    inbound_connections: u32,
+    #[serde(skip)]
+    outbound_connections: u32,
+    /// True while every open connection goes through a relay circuit (the
+    /// slow path a client takes when it can't reach a gateway directly)
+    pub relayed: bool,
+    #[serde(skip)]
+    relayed_connections: u32,
     pub discovered_via: BTreeSet<String>,
     pub last_rtt_ms: Option<u64>,
+    pub min_rtt_ms: Option<u64>,
+    pub max_rtt_ms: Option<u64>,
+    /// Exponentially-weighted moving average of the ping RTT
+    pub avg_rtt_ms: Option<f64>,
+    /// When the peer was last seen disconnecting (cleared on reconnect)
+    pub disconnected_at_ms: Option<u64>,
+    /// Agent version the peer advertised via identify (spot version skew)
+    pub agent_version: Option<String>,
+    /// Request/response protocol we negotiate with the peer (e.g.
+    /// "/node-agent/rr/2"); `None` if it speaks none of ours
+    pub rr_protocol: Option<String>,
+    /// Our requests to the peer that failed (timeouts, refused streams, ...)
+    pub outbound_failures: u64,
+    /// The peer's requests to us that failed before we could respond
+    pub inbound_failures: u64,
+    /// Failed pings since the last successful one (reset on reconnect)
+    pub ping_failures: u32,
+    /// Failed pings over the peer's lifetime in the map
+    pub ping_failures_total: u64,
 }
 
+/// Weight given to the newest sample in the RTT moving average
+const RTT_EWMA_ALPHA: f64 = 0.2;
+
+impl PeerRow {
+    fn new(peer_id: String) -> Self {
+        Self { peer_id, ..Default::default() }
+    }
+
+    /// Counts a connection opened (`delta` 1) or closed (-1) in the given
+    /// direction and recomputes `direction` from what is left open
+    fn track_direction(&mut self, dialer: bool, delta: i32) {
+        let count = if dialer { &mut self.outbound_connections } else { &mut self.inbound_connections };
+        *count = count.saturating_add_signed(delta);
+        self.direction = match (self.inbound_connections > 0, self.outbound_connections > 0) {
+            (true, true) => Some(ConnectionDirection::Both),
+            (true, false) => Some(ConnectionDirection::Inbound),
+            (false, true) => Some(ConnectionDirection::Outbound),
+            (false, false) => None,
+        };
+    }
+
+    /// Drops the live connection state, keeping provenance and history
+    fn mark_disconnected(&mut self, at_ms: u64) {
+        self.connected = false;
+        self.connections = 0;
+        self.direction = None;
+        self.inbound_connections = 0;
+        self.outbound_connections = 0;
+        self.relayed = false;
+        self.relayed_connections = 0;
+        self.last_rtt_ms = None;
+        self.avg_rtt_ms = None;
+        self.disconnected_at_ms = Some(at_ms);
+    }
+
+    fn refresh_relayed(&mut self) {
+        self.relayed = self.connections > 0 && self.relayed_connections >= self.connections;
+    }
+
+    fn record_rtt(&mut self, rtt_ms: u64) {
+        self.last_rtt_ms = Some(rtt_ms);
+        self.min_rtt_ms = Some(self.min_rtt_ms.map_or(rtt_ms, |min| min.min(rtt_ms)));
+        self.max_rtt_ms = Some(self.max_rtt_ms.map_or(rtt_ms, |max| max.max(rtt_ms)));
+        let sample = rtt_ms as f64;
+        self.avg_rtt_ms = Some(match self.avg_rtt_ms {
+            Some(avg) => RTT_EWMA_ALPHA * sample + (1.0 - RTT_EWMA_ALPHA) * avg,
+            None => sample,
+        });
+    }
+}
+
+/// Aggregate counts for lightweight health widgets (`GET /network/summary`)
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkSummary {
+    pub connected: usize,
+    pub total_known: usize,
+    pub discovered_via_mdns: usize,
+    pub discovered_via_kad: usize,
+    pub bootstrap_connected: usize,
+    /// Mean of the connected peers' moving-average RTT
+    pub avg_rtt_ms: Option<f64>,
+    pub uptime_secs: u64,
+}
+
+/// Query parameters accepted by `GET /network` and `GET /peers` to filter
+/// and page the peer map
+#[derive(Debug, Default, Deserialize)]
+pub struct PeerQuery {
+    pub connected: Option<bool>,
+    pub discovered_via: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+impl PeerQuery {
+    fn matches(&self, row: &PeerRow) -> bool {
+        self.connected.is_none_or(|c| row.connected == c)
+            && self
+                .discovered_via
+                .as_ref()
+                .is_none_or(|via| row.discovered_via.contains(via))
+    }
+}
+
+/// Snapshot with the peer map filtered/paged, plus the total number of peers
+/// matching the filter so clients can paginate
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkPage {
+    #[serde(flatten)]
+    pub snapshot: NetworkSnapshot,
+    pub peers_total: usize,
+}
+
+/// How often `persist_network_snapshot` saves the snapshot
+const SNAPSHOT_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The parts of a saved snapshot worth restoring: everything else describes
+/// the previous process (listen and external addresses, bandwidth, ...)
+#[derive(Debug, Deserialize)]
+struct SavedSnapshot {
+    #[serde(default)]
+    peers: BTreeMap<String, PeerRow>,
+    #[serde(default)]
+    events: VecDeque<NetworkEvent>,
+    updated_at_ms: u64,
+}
+
+/// A fresh snapshot, carrying over the peer history saved at `snapshot_path`
+/// when there is one
 pub fn new_shared_network_state(config: &Config, local_peer_id: String) -> SharedNetworkState {
-    Arc::new(RwLock::new(NetworkSnapshot::new(config, local_peer_id)))
+    let mut snapshot = NetworkSnapshot::new(config, local_peer_id);
+    if let Some(path) = &config.snapshot_path {
+        match snapshot.restore(Path::new(path)) {
+            Ok(true) => info!("📂 Restored {} peers from network snapshot {}", snapshot.peers.len(), path),
+            Ok(false) => {}
+            Err(e) => warn!("Ignoring network snapshot {}: {:#}", path, e),
+        }
+    }
+    Arc::new(RwLock::new(snapshot))
+}
+
+/// Saves the snapshot to `path` every `SNAPSHOT_SAVE_INTERVAL`, and once
+/// more when `shutdown` fires
+pub async fn persist_network_snapshot(state: SharedNetworkState, path: PathBuf, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(SNAPSHOT_SAVE_INTERVAL);
+    interval.tick().await;
+    loop {
+        let stop = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown.changed() => true,
+        };
+        if let Err(e) = save_snapshot(&state, &path).await {
+            warn!("Failed to save network snapshot to {}: {:#}", path.display(), e);
+        }
+        if stop {
+            return;
+        }
+    }
+}
+
+/// Serializes the snapshot under the read lock, then writes it from a
+/// blocking thread so the swarm loop isn't held up by disk I/O
+async fn save_snapshot(state: &SharedNetworkState, path: &Path) -> Result<()> {
+    let json = state.read().await.to_json()?;
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_snapshot(&path, &json)).await?
+}
+
+/// Writes a serialized snapshot to `path`, through a temporary file so a
+/// crash mid-write leaves the previous save intact
+fn write_snapshot(path: &Path, json: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, json).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
 }
 
 impl NetworkSnapshot {
@@ -47,53 +296,351 @@ impl NetworkSnapshot {
                 multiaddr: ma.clone(),
                 peer_id: peer_id_from_multiaddr_str(ma),
                 connected: false,
+                last_error: None,
             })
             .collect();
 
         Self {
             local_peer_id,
             role: config.role.to_string(),
-            listen: config.listen.clone(),
+            listen_addrs: Vec::new(),
+            configured_listen: config.listen.clone(),
             bootstrap_peers,
             peers: BTreeMap::new(),
+            bandwidth: BandwidthStats::default(),
+            providers: BTreeMap::new(),
+            external_addrs: BTreeSet::new(),
+            upnp_mapped_addrs: BTreeSet::new(),
+            events: VecDeque::new(),
+            event_capacity: config.event_log_capacity,
+            started_at_ms: now_ms(),
             updated_at_ms: now_ms(),
         }
     }
 
-    pub fn set_connected(&mut self, peer_id: String, connected: bool) {
-        let entry = self.peers.entry(peer_id.clone()).or_insert_with(|| PeerRow {
-            peer_id: peer_id.clone(),
-            connected,
-            discovered_via: BTreeSet::new(),
-            last_rtt_ms: None,
-        });
-        entry.connected = connected;
+    /// Writes the snapshot to `path` right away; the node itself saves
+    /// through `persist_network_snapshot`
+    #[cfg(test)]
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        write_snapshot(path, &self.to_json()?)
+    }
+
+    fn to_json(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).context("Failed to serialize network snapshot")
+    }
+
+    /// Loads the peers and activity feed saved at `path`, every peer marked
+    /// disconnected until it connects again. False when there's no file yet.
+    pub fn restore(&mut self, path: &Path) -> Result<bool> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let saved: SavedSnapshot = serde_json::from_slice(&json).context("Malformed network snapshot")?;
+
+        self.peers = saved.peers;
+        for row in self.peers.values_mut() {
+            if row.connected {
+                // Still connected when last saved: gone by the restart
+                row.mark_disconnected(saved.updated_at_ms);
+            }
+        }
+        self.events = saved.events;
+        let excess = self.events.len().saturating_sub(self.event_capacity);
+        self.events.drain(..excess);
+        self.touch();
+        Ok(true)
+    }
+
+    pub fn summary(&self) -> NetworkSummary {
+        let count_via = |via: &str| {
+            self.peers
+                .values()
+                .filter(|row| row.discovered_via.contains(via))
+                .count()
+        };
+        let connected_rtts: Vec<f64> = self
+            .peers
+            .values()
+            .filter(|row| row.connected)
+            .filter_map(|row| row.avg_rtt_ms)
+            .collect();
+        let avg_rtt_ms = if connected_rtts.is_empty() {
+            None
+        } else {
+            Some(connected_rtts.iter().sum::<f64>() / connected_rtts.len() as f64)
+        };
+
+        NetworkSummary {
+            connected: self.peers.values().filter(|row| row.connected).count(),
+            total_known: self.peers.len(),
+            discovered_via_mdns: count_via("mdns"),
+            discovered_via_kad: count_via("kad"),
+            bootstrap_connected: self.bootstrap_peers.iter().filter(|bp| bp.connected).count(),
+            avg_rtt_ms,
+            uptime_secs: now_ms().saturating_sub(self.started_at_ms) / 1000,
+        }
+    }
+
+    /// Records a newly established connection. `num_established` is the
+    /// swarm's count of open connections to the peer, including this one;
+    /// `dialer` is whether we dialed it (`endpoint.is_dialer()`).
+    pub fn connection_established(&mut self, peer_id: String, num_established: u32, dialer: bool) {
+        if num_established == 1 {
+            self.push_event(peer_id.clone(), NetworkEventKind::Connected);
+        }
+        let entry = self.peer_entry(peer_id);
+        entry.connections = num_established;
+        entry.connected = true;
+        entry.disconnected_at_ms = None;
+        if num_established == 1 {
+            entry.ping_failures = 0;
+            entry.inbound_connections = 0;
+            entry.outbound_connections = 0;
+            entry.relayed_connections = 0;
+        }
+        entry.track_direction(dialer, 1);
+        entry.refresh_relayed();
         self.refresh_bootstrap_connected_flags();
         self.touch();
     }
 
-    pub fn mark_discovered(&mut self, peer_id: String, via: &'static str) {
-        let entry = self.peers.entry(peer_id.clone()).or_insert_with(|| PeerRow {
-            peer_id: peer_id.clone(),
-            connected: false,
-            discovered_via: BTreeSet::new(),
-            last_rtt_ms: None,
-        });
-        entry.discovered_via.insert(via.to_string());
+    /// Records a closed connection. `remaining` is the number of connections
+    /// still open to the peer; the peer only counts as disconnected once it
+    /// drops to zero, so closing one of several transports doesn't flap.
+    /// `dialer` is whether we had dialed the closed connection.
+    pub fn connection_closed(&mut self, peer_id: String, remaining: u32, dialer: bool) {
+        if remaining == 0 {
+            self.on_disconnect(peer_id);
+        } else {
+            let entry = self.peer_entry(peer_id);
+            entry.connections = remaining;
+            entry.track_direction(dialer, -1);
+            entry.refresh_relayed();
+            self.touch();
+        }
+    }
+
+    /// Counts a relayed (`/p2p-circuit`) connection to the peer opened
+    /// (`delta` 1) or closed (-1). Called after `connection_established` and
+    /// before `connection_closed` for connections over a circuit.
+    pub fn track_relayed(&mut self, peer_id: String, delta: i32) {
+        let entry = self.peer_entry(peer_id);
+        entry.relayed_connections = entry.relayed_connections.saturating_add_signed(delta);
+        entry.refresh_relayed();
+        self.touch();
+    }
+
+    /// Marks a peer as disconnected and drops its live latency readings so the
+    /// UI doesn't show stale RTT for a gone peer. Discovery provenance and the
+    /// historical min/max RTT are preserved.
+    pub fn on_disconnect(&mut self, peer_id: String) {
+        self.push_event(peer_id.clone(), NetworkEventKind::Disconnected);
+        self.peer_entry(peer_id).mark_disconnected(now_ms());
+        self.refresh_bootstrap_connected_flags();
         self.touch();
     }
 
-    pub fn set_rtt_ms(&mut self, peer_id: String, rtt_ms: u64) {
-        let entry = self.peers.entry(peer_id.clone()).or_insert_with(|| PeerRow {
-            peer_id: peer_id.clone(),
-            connected: false,
-            discovered_via: BTreeSet::new(),
-            last_rtt_ms: None,
+    /// Handles an mDNS expiry. Peers that are disconnected and were only ever
+    /// known through mDNS are removed from the map entirely: they left the LAN
+    /// and will be re-added if they come back. Peers also known via Kademlia
+    /// or a live connection are kept.
+    pub fn on_mdns_expired(&mut self, peer_id: &str) {
+        let remove = self.peers.get(peer_id).is_some_and(|row| {
+            !row.connected && row.discovered_via.iter().all(|via| via == "mdns")
         });
-        entry.last_rtt_ms = Some(rtt_ms);
+        if remove {
+            self.peers.remove(peer_id);
+            self.touch();
+        }
+    }
+
+    pub fn mark_discovered(&mut self, peer_id: String, via: &'static str) {
+        // Only a new discovery source is worth an activity entry
+        if self.peer_entry(peer_id.clone()).discovered_via.insert(via.to_string()) {
+            self.push_event(peer_id, NetworkEventKind::Discovered { via: via.to_string() });
+        }
+        self.touch();
+    }
+
+    pub fn on_ping_timeout(&mut self, peer_id: String) {
+        self.push_event(peer_id, NetworkEventKind::PingTimeout);
+        self.touch();
+    }
+
+    /// Counts a failed ping and returns the peer's failures in a row
+    pub fn record_ping_failure(&mut self, peer_id: String) -> u32 {
+        let entry = self.peer_entry(peer_id);
+        entry.ping_failures += 1;
+        entry.ping_failures_total += 1;
+        let failures = entry.ping_failures;
+        self.touch();
+        failures
+    }
+
+    /// Records a ping sample and returns the peer's updated RTT moving average
+    pub fn set_rtt_ms(&mut self, peer_id: String, rtt_ms: u64) -> f64 {
+        let entry = self.peer_entry(peer_id);
+        entry.record_rtt(rtt_ms);
+        entry.ping_failures = 0;
+        let avg = entry.avg_rtt_ms.unwrap_or(rtt_ms as f64);
+        self.touch();
+        avg
+    }
+
+    /// Records peers found providing `key` and marks them as discovered via the DHT
+    pub fn add_providers(&mut self, key: String, providers: impl IntoIterator<Item = String>) {
+        let known = self.providers.entry(key).or_default();
+        let providers: Vec<String> = providers.into_iter().collect();
+        known.extend(providers.iter().cloned());
+        for peer_id in providers {
+            self.peer_entry(peer_id).discovered_via.insert("kad".to_string());
+        }
+        self.touch();
+    }
+
+    pub fn set_agent_version(&mut self, peer_id: String, agent_version: String) {
+        self.peer_entry(peer_id).agent_version = Some(agent_version);
+        self.touch();
+    }
+
+    pub fn set_rr_protocol(&mut self, peer_id: String, rr_protocol: Option<String>) {
+        self.peer_entry(peer_id).rr_protocol = rr_protocol;
+        self.touch();
+    }
+
+    pub fn record_outbound_failure(&mut self, peer_id: String) {
+        self.peer_entry(peer_id).outbound_failures += 1;
+        self.touch();
+    }
+
+    pub fn record_inbound_failure(&mut self, peer_id: String) {
+        self.peer_entry(peer_id).inbound_failures += 1;
+        self.touch();
+    }
+
+    pub fn add_external_addr(&mut self, addr: String) {
+        self.external_addrs.insert(addr);
+        self.touch();
+    }
+
+    pub fn remove_external_addr(&mut self, addr: &str) {
+        self.external_addrs.remove(addr);
+        self.touch();
+    }
+
+    /// Replaces `listen_addrs` with the addresses the swarm currently listens on
+    pub fn set_listen_addrs(&mut self, addrs: impl IntoIterator<Item = String>) {
+        let mut listen_addrs: Vec<String> = addrs.into_iter().collect();
+        listen_addrs.sort();
+        self.listen_addrs = listen_addrs;
+        self.touch();
+    }
+
+    /// Adds a UPnP-mapped external address, or removes it once the mapping expires
+    pub fn set_upnp_mapping(&mut self, addr: String, mapped: bool) {
+        if mapped {
+            self.upnp_mapped_addrs.insert(addr);
+        } else {
+            self.upnp_mapped_addrs.remove(&addr);
+        }
         self.touch();
     }
 
+    pub fn set_bandwidth(&mut self, bandwidth: BandwidthStats) {
+        self.bandwidth = bandwidth;
+        self.touch();
+    }
+
+    /// Returns a copy of the snapshot whose `peers` only holds the rows matching
+    /// `query`, sliced by `offset`/`limit`. Without parameters all peers are kept.
+    pub fn page(&self, query: &PeerQuery) -> NetworkPage {
+        let mut snapshot = self.clone();
+        let matching: Vec<(String, PeerRow)> = std::mem::take(&mut snapshot.peers)
+            .into_iter()
+            .filter(|(_, row)| query.matches(row))
+            .collect();
+        let peers_total = matching.len();
+
+        snapshot.peers = matching
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+
+        NetworkPage {
+            snapshot,
+            peers_total,
+        }
+    }
+
+    /// Just the ids of the peers matching `query`, in the same order as
+    /// `page`, without cloning the rest of the snapshot
+    pub fn peer_ids(&self, query: &PeerQuery) -> Vec<String> {
+        self.peers
+            .iter()
+            .filter(|(_, row)| query.matches(row))
+            .map(|(peer_id, _)| peer_id.clone())
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Appends to the activity feed, folding a repeat of the latest event and
+    /// dropping the oldest entries beyond the configured capacity
+    fn push_event(&mut self, peer_id: String, kind: NetworkEventKind) {
+        if self.event_capacity == 0 {
+            return;
+        }
+        let at_ms = now_ms();
+        if let Some(last) = self.events.back_mut() {
+            if last.peer_id == peer_id && last.kind == kind {
+                last.count += 1;
+                last.at_ms = at_ms;
+                return;
+            }
+        }
+        self.events.push_back(NetworkEvent { at_ms, peer_id, kind, count: 1 });
+        while self.events.len() > self.event_capacity {
+            self.events.pop_front();
+        }
+    }
+
+    /// Learns the peer id of bootstrap entries configured without `/p2p/`
+    /// from a connection we dialed to `dialed`. Call before
+    /// `connection_established` so the entry shows as connected.
+    pub fn resolve_bootstrap_peer(&mut self, dialed: &Multiaddr, peer_id: &str) {
+        for bp in &mut self.bootstrap_peers {
+            if bp.peer_id.is_none() && same_transport_addr(&bp.multiaddr, dialed) {
+                bp.peer_id = Some(peer_id.to_string());
+            }
+        }
+    }
+
+    /// Records a failed dial on the bootstrap entries it was for, matched by
+    /// peer id or by one of the addresses that were tried
+    pub fn bootstrap_dial_failed(&mut self, peer_id: Option<&str>, tried: &[Multiaddr], error: &str) {
+        let mut matched = false;
+        for bp in &mut self.bootstrap_peers {
+            let by_peer = peer_id.is_some() && bp.peer_id.as_deref() == peer_id;
+            let by_addr = tried.iter().any(|addr| same_transport_addr(&bp.multiaddr, addr));
+            if by_peer || by_addr {
+                bp.last_error = Some(error.to_string());
+                matched = true;
+            }
+        }
+        if matched {
+            self.touch();
+        }
+    }
+
+    fn peer_entry(&mut self, peer_id: String) -> &mut PeerRow {
+        self.peers
+            .entry(peer_id.clone())
+            .or_insert_with(|| PeerRow::new(peer_id))
+    }
+
     fn refresh_bootstrap_connected_flags(&mut self) {
         for bp in &mut self.bootstrap_peers {
             bp.connected = bp
@@ -120,6 +667,21 @@ fn peer_id_from_multiaddr_str(multiaddr: &str) -> Option<String> {
     None
 }
 
+/// Whether `configured` and `addr` are the same address once any `/p2p/`
+/// component is ignored
+fn same_transport_addr(configured: &str, addr: &Multiaddr) -> bool {
+    let Ok(configured) = configured.parse::<Multiaddr>() else {
+        return false;
+    };
+    let strip = |ma: &Multiaddr| -> Vec<Protocol<'static>> {
+        ma.iter()
+            .filter(|p| !matches!(p, Protocol::P2p(_)))
+            .map(|p| p.acquire())
+            .collect()
+    };
+    strip(&configured) == strip(addr)
+}
+
 fn now_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
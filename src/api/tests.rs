@@ -0,0 +1,655 @@
+use super::state::{new_shared_network_state, ConnectionDirection, NetworkEventKind, NetworkSnapshot, PeerQuery};
+use crate::config::Config;
+
+// Helper to create the config of a client node
+fn create_test_config() -> Config {
+    Config {
+        listen: vec!["/ip4/0.0.0.0/tcp/0".to_string()],
+        enable_mdns: true,
+        ..Config::for_tests()
+    }
+}
+
+// Helper to create a snapshot for a client node
+fn create_test_snapshot() -> NetworkSnapshot {
+    NetworkSnapshot::new(&create_test_config(), "local".to_string())
+}
+
+#[test]
+fn test_bootstrap_row_without_p2p_matched_by_dialed_address() {
+    let mut config = create_test_config();
+    config.bootstrap_peers = vec!["/ip4/10.0.0.1/tcp/4001".to_string()];
+    let mut snap = NetworkSnapshot::new(&config, "local".to_string());
+    let peer = libp2p::PeerId::random().to_string();
+
+    let tried: libp2p::Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+    snap.bootstrap_dial_failed(None, std::slice::from_ref(&tried), "connection refused");
+    assert_eq!(snap.bootstrap_peers[0].last_error.as_deref(), Some("connection refused"));
+    assert!(!snap.bootstrap_peers[0].connected);
+
+    let dialed = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer).parse().unwrap();
+    snap.resolve_bootstrap_peer(&dialed, &peer);
+    snap.connection_established(peer.clone(), 1, true);
+    assert_eq!(snap.bootstrap_peers[0].peer_id.as_deref(), Some(peer.as_str()));
+    assert!(snap.bootstrap_peers[0].connected);
+
+    // Later failures are matched by the learned peer id
+    snap.bootstrap_dial_failed(Some(&peer), &[], "timed out");
+    assert_eq!(snap.bootstrap_peers[0].last_error.as_deref(), Some("timed out"));
+}
+
+#[test]
+fn test_rtt_min_max_and_average() {
+    let mut snap = create_test_snapshot();
+
+    let avg = snap.set_rtt_ms("peer-a".to_string(), 100);
+    assert_eq!(avg, 100.0); // First sample seeds the average
+
+    snap.set_rtt_ms("peer-a".to_string(), 600);
+    let avg = snap.set_rtt_ms("peer-a".to_string(), 50);
+
+    let row = &snap.peers["peer-a"];
+    assert_eq!(row.last_rtt_ms, Some(50));
+    assert_eq!(row.min_rtt_ms, Some(50));
+    assert_eq!(row.max_rtt_ms, Some(600));
+    // 100 -> 0.2*600 + 0.8*100 = 200 -> 0.2*50 + 0.8*200 = 170
+    assert!((avg - 170.0).abs() < 1e-9);
+    assert_eq!(row.avg_rtt_ms, Some(avg));
+}
+
+#[test]
+fn test_single_spike_does_not_dominate_average() {
+    let mut snap = create_test_snapshot();
+
+    for _ in 0..10 {
+        snap.set_rtt_ms("peer-a".to_string(), 20);
+    }
+    let avg = snap.set_rtt_ms("peer-a".to_string(), 2000);
+
+    // The spike is visible in max/last but the average stays well below it
+    assert!(avg < 500.0);
+    assert_eq!(snap.peers["peer-a"].max_rtt_ms, Some(2000));
+}
+
+#[test]
+fn test_disconnect_clears_live_rtt_but_keeps_history() {
+    let mut snap = create_test_snapshot();
+    snap.mark_discovered("peer-a".to_string(), "kad");
+    snap.connection_established("peer-a".to_string(), 1, true);
+    snap.set_rtt_ms("peer-a".to_string(), 40);
+
+    snap.connection_closed("peer-a".to_string(), 0, true);
+
+    let row = &snap.peers["peer-a"];
+    assert!(!row.connected);
+    assert_eq!(row.last_rtt_ms, None);
+    assert_eq!(row.avg_rtt_ms, None);
+    assert_eq!(row.max_rtt_ms, Some(40));
+    assert!(row.discovered_via.contains("kad"));
+    assert!(row.disconnected_at_ms.is_some());
+
+    snap.connection_established("peer-a".to_string(), 1, true);
+    assert_eq!(snap.peers["peer-a"].disconnected_at_ms, None);
+}
+
+#[test]
+fn test_ping_failures_in_a_row_reset_by_a_pong_or_a_reconnect() {
+    let mut snap = create_test_snapshot();
+    snap.connection_established("peer-a".to_string(), 1, true);
+    assert_eq!(snap.record_ping_failure("peer-a".to_string()), 1);
+    assert_eq!(snap.record_ping_failure("peer-a".to_string()), 2);
+
+    snap.set_rtt_ms("peer-a".to_string(), 40);
+    assert_eq!(snap.peers["peer-a"].ping_failures, 0);
+    assert_eq!(snap.record_ping_failure("peer-a".to_string()), 1);
+
+    snap.connection_closed("peer-a".to_string(), 0, true);
+    snap.connection_established("peer-a".to_string(), 1, true);
+    let row = &snap.peers["peer-a"];
+    assert_eq!(row.ping_failures, 0);
+    assert_eq!(row.ping_failures_total, 3);
+}
+
+#[test]
+fn test_mdns_expiry_removes_only_mdns_only_disconnected_peers() {
+    let mut snap = create_test_snapshot();
+    snap.mark_discovered("lan-only".to_string(), "mdns");
+    snap.mark_discovered("lan-and-dht".to_string(), "mdns");
+    snap.mark_discovered("lan-and-dht".to_string(), "kad");
+    snap.mark_discovered("lan-connected".to_string(), "mdns");
+    snap.connection_established("lan-connected".to_string(), 1, true);
+
+    snap.on_mdns_expired("lan-only");
+    snap.on_mdns_expired("lan-and-dht");
+    snap.on_mdns_expired("lan-connected");
+
+    assert!(!snap.peers.contains_key("lan-only"));
+    assert!(snap.peers.contains_key("lan-and-dht"));
+    assert!(snap.peers.contains_key("lan-connected"));
+}
+
+#[test]
+fn test_closing_one_of_several_connections_keeps_peer_connected() {
+    let mut snap = create_test_snapshot();
+    snap.connection_established("peer-a".to_string(), 1, true);
+    snap.connection_established("peer-a".to_string(), 2, true);
+    assert_eq!(snap.peers["peer-a"].connections, 2);
+
+    snap.connection_closed("peer-a".to_string(), 1, true);
+    assert!(snap.peers["peer-a"].connected);
+    assert_eq!(snap.peers["peer-a"].connections, 1);
+
+    snap.connection_closed("peer-a".to_string(), 0, true);
+    assert!(!snap.peers["peer-a"].connected);
+    assert_eq!(snap.peers["peer-a"].connections, 0);
+}
+
+#[test]
+fn test_connection_direction_follows_the_open_connections() {
+    let mut snap = create_test_snapshot();
+    snap.connection_established("peer-a".to_string(), 1, false);
+    assert_eq!(snap.peers["peer-a"].direction, Some(ConnectionDirection::Inbound));
+
+    // We dial it too (e.g. a simultaneous dial): one connection each way
+    snap.connection_established("peer-a".to_string(), 2, true);
+    assert_eq!(snap.peers["peer-a"].direction, Some(ConnectionDirection::Both));
+
+    snap.connection_closed("peer-a".to_string(), 1, false);
+    assert_eq!(snap.peers["peer-a"].direction, Some(ConnectionDirection::Outbound));
+
+    snap.connection_closed("peer-a".to_string(), 0, true);
+    assert_eq!(snap.peers["peer-a"].direction, None);
+    let json = serde_json::to_value(&snap).unwrap();
+    assert!(json["peers"]["peer-a"]["direction"].is_null());
+
+    snap.connection_established("peer-a".to_string(), 1, true);
+    let json = serde_json::to_value(&snap).unwrap();
+    assert_eq!(json["peers"]["peer-a"]["direction"], "outbound");
+}
+
+#[test]
+fn test_peer_is_relayed_only_while_every_connection_is() {
+    let mut snap = create_test_snapshot();
+
+    snap.connection_established("gateway".to_string(), 1, true);
+    snap.track_relayed("gateway".to_string(), 1);
+    assert!(snap.peers["gateway"].relayed);
+
+    // A direct connection next to the circuit: no longer on the slow path
+    snap.connection_established("gateway".to_string(), 2, true);
+    assert!(!snap.peers["gateway"].relayed);
+
+    // The direct one closes, the circuit remains
+    snap.connection_closed("gateway".to_string(), 1, true);
+    assert!(snap.peers["gateway"].relayed);
+    let json = serde_json::to_value(&snap).unwrap();
+    assert_eq!(json["peers"]["gateway"]["relayed"], true);
+
+    snap.track_relayed("gateway".to_string(), -1);
+    snap.connection_closed("gateway".to_string(), 0, true);
+    assert!(!snap.peers["gateway"].relayed);
+}
+
+#[tokio::test]
+async fn test_network_snapshot_survives_a_restart_with_peers_disconnected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state").join("network.json");
+    let mut config = create_test_config();
+    config.snapshot_path = Some(path.to_string_lossy().into_owned());
+
+    // Nothing saved yet: starts empty
+    let state = new_shared_network_state(&config, "local".to_string());
+    assert!(state.read().await.peers.is_empty());
+
+    let mut snap = create_test_snapshot();
+    snap.mark_discovered("peer-a".to_string(), "kad");
+    snap.connection_established("peer-a".to_string(), 1, true);
+    snap.set_rtt_ms("peer-a".to_string(), 40);
+    snap.mark_discovered("peer-b".to_string(), "mdns");
+    snap.save(&path).unwrap();
+
+    let state = new_shared_network_state(&config, "local".to_string());
+    let restored = state.read().await;
+    let peer_a = &restored.peers["peer-a"];
+    assert!(!peer_a.connected);
+    assert_eq!(peer_a.connections, 0);
+    assert_eq!(peer_a.direction, None);
+    assert_eq!(peer_a.last_rtt_ms, None);
+    assert_eq!(peer_a.min_rtt_ms, Some(40));
+    assert!(peer_a.disconnected_at_ms.is_some());
+    assert!(peer_a.discovered_via.contains("kad"));
+    assert!(restored.peers["peer-b"].discovered_via.contains("mdns"));
+    assert_eq!(restored.events.len(), snap.events.len());
+    drop(restored);
+
+    // Rows saved by an older version, before some fields existed, still load
+    std::fs::write(
+        &path,
+        r#"{"peers": {"peer-old": {"peer_id": "peer-old", "connected": false, "discovered_via": ["mdns"]}}, "updated_at_ms": 1}"#,
+    )
+    .unwrap();
+    let state = new_shared_network_state(&config, "local".to_string());
+    let peer_old = &state.read().await.peers["peer-old"];
+    assert!(peer_old.discovered_via.contains("mdns"));
+    assert_eq!(peer_old.outbound_failures, 0);
+    assert_eq!(peer_old.ping_failures_total, 0);
+
+    // A corrupt file is ignored rather than stopping the node
+    std::fs::write(&path, "{").unwrap();
+    let state = new_shared_network_state(&config, "local".to_string());
+    assert!(state.read().await.peers.is_empty());
+}
+
+#[test]
+fn test_peer_page_filters_and_paginates() {
+    let mut snap = create_test_snapshot();
+    for i in 0..5 {
+        let id = format!("peer-{}", i);
+        snap.mark_discovered(id.clone(), if i % 2 == 0 { "kad" } else { "mdns" });
+        if i < 3 {
+            snap.connection_established(id, 1, true);
+        }
+    }
+
+    // No parameters: full peer map
+    let page = snap.page(&PeerQuery::default());
+    assert_eq!(page.peers_total, 5);
+    assert_eq!(page.snapshot.peers.len(), 5);
+
+    let page = snap.page(&PeerQuery {
+        connected: Some(true),
+        ..Default::default()
+    });
+    assert_eq!(page.peers_total, 3);
+
+    let page = snap.page(&PeerQuery {
+        discovered_via: Some("kad".to_string()),
+        offset: Some(1),
+        limit: Some(1),
+        ..Default::default()
+    });
+    assert_eq!(page.peers_total, 3); // peer-0, peer-2, peer-4
+    assert_eq!(page.snapshot.peers.keys().collect::<Vec<_>>(), vec!["peer-2"]);
+}
+
+#[test]
+fn test_peer_ids_lists_only_matching_ids() {
+    let mut snap = create_test_snapshot();
+    for i in 0..4 {
+        let id = format!("peer-{}", i);
+        snap.mark_discovered(id.clone(), "mdns");
+        if i % 2 == 1 {
+            snap.connection_established(id, 1, true);
+        }
+    }
+
+    let connected = snap.peer_ids(&PeerQuery {
+        connected: Some(true),
+        ..Default::default()
+    });
+    assert_eq!(connected, vec!["peer-1", "peer-3"]);
+    assert_eq!(
+        serde_json::to_string(&connected).unwrap(),
+        r#"["peer-1","peer-3"]"#
+    );
+    assert_eq!(snap.peer_ids(&PeerQuery::default()).len(), 4);
+}
+
+#[test]
+fn test_summary_counts() {
+    let mut snap = create_test_snapshot();
+    snap.mark_discovered("peer-a".to_string(), "mdns");
+    snap.mark_discovered("peer-b".to_string(), "kad");
+    snap.mark_discovered("peer-b".to_string(), "mdns");
+    snap.mark_discovered("peer-c".to_string(), "kad");
+    snap.connection_established("peer-a".to_string(), 1, true);
+    snap.connection_established("peer-b".to_string(), 1, true);
+    snap.set_rtt_ms("peer-a".to_string(), 10);
+    snap.set_rtt_ms("peer-b".to_string(), 30);
+    snap.set_rtt_ms("peer-c".to_string(), 1000); // not connected, excluded from avg
+
+    let summary = snap.summary();
+    assert_eq!(summary.connected, 2);
+    assert_eq!(summary.total_known, 3);
+    assert_eq!(summary.discovered_via_mdns, 2);
+    assert_eq!(summary.discovered_via_kad, 2);
+    assert_eq!(summary.bootstrap_connected, 0);
+    assert_eq!(summary.avg_rtt_ms, Some(20.0));
+}
+
+#[test]
+fn test_readiness_requires_listen_and_broker() {
+    use super::Readiness;
+
+    let client = Readiness::new(false, false);
+    assert!(!client.report().ready);
+    client.set_listening(true);
+    assert!(client.report().ready);
+    assert_eq!(client.report().broker, None);
+    assert_eq!(client.report().dht, None);
+
+    let gateway = Readiness::new(true, false);
+    gateway.set_listening(true);
+    assert!(!gateway.report().ready);
+    gateway.set_broker_ready(true);
+    assert!(gateway.report().ready);
+}
+
+#[test]
+fn test_draining_is_for_good_and_never_ready() {
+    use super::Readiness;
+
+    let gateway = Readiness::new(true, false);
+    gateway.set_listening(true);
+    gateway.set_broker_ready(true);
+    assert!(!gateway.report().draining);
+
+    assert!(!gateway.set_draining());
+    assert!(!gateway.report().ready);
+    assert!(gateway.report().draining);
+    // Repeating it reports it was already draining; nothing turns it off
+    assert!(gateway.set_draining());
+    gateway.set_listening(true);
+    gateway.set_broker_ready(true);
+    assert!(!gateway.report().ready);
+}
+
+#[test]
+fn test_readiness_waits_for_dht_on_kad_gateways() {
+    use super::Readiness;
+
+    let gateway = Readiness::new(false, true);
+    gateway.set_listening(true);
+    assert!(gateway.dht_pending());
+    assert!(!gateway.report().ready);
+    assert_eq!(gateway.report().dht, Some(false));
+
+    gateway.set_dht_ready(true);
+    assert!(!gateway.dht_pending());
+    assert!(gateway.report().ready);
+}
+
+#[test]
+fn test_providers_recorded_and_marked_as_kad() {
+    let mut snap = create_test_snapshot();
+
+    snap.add_providers("svc".to_string(), vec!["gw-1".to_string(), "gw-2".to_string()]);
+    snap.add_providers("svc".to_string(), vec!["gw-1".to_string()]);
+
+    assert_eq!(snap.providers["svc"].len(), 2);
+    assert!(snap.peers["gw-1"].discovered_via.contains("kad"));
+    assert!(!snap.peers["gw-2"].connected);
+}
+
+#[test]
+fn test_listen_and_external_addrs_follow_the_swarm() {
+    let mut snap = create_test_snapshot();
+    assert!(snap.listen_addrs.is_empty());
+    assert_eq!(snap.configured_listen, vec!["/ip4/0.0.0.0/tcp/0".to_string()]);
+
+    snap.set_listen_addrs(vec!["/ip4/192.168.1.5/tcp/4001".to_string(), "/ip4/127.0.0.1/tcp/4001".to_string()]);
+    assert_eq!(snap.listen_addrs, vec!["/ip4/127.0.0.1/tcp/4001", "/ip4/192.168.1.5/tcp/4001"]);
+    snap.set_listen_addrs(vec!["/ip4/127.0.0.1/tcp/4001".to_string()]);
+    assert_eq!(snap.listen_addrs, vec!["/ip4/127.0.0.1/tcp/4001"]);
+    assert_eq!(snap.configured_listen, vec!["/ip4/0.0.0.0/tcp/0".to_string()]);
+
+    snap.add_external_addr("/ip4/203.0.113.7/tcp/4001".to_string());
+    snap.remove_external_addr("/ip4/203.0.113.7/tcp/4001");
+    assert!(snap.external_addrs.is_empty());
+}
+
+#[test]
+fn test_agent_version_recorded_per_peer() {
+    let mut snap = create_test_snapshot();
+
+    snap.set_agent_version("peer-a".to_string(), "hch/0.1.0".to_string());
+    snap.connection_closed("peer-a".to_string(), 0, true);
+
+    // Kept across disconnects so operators can still see which build a peer ran
+    assert_eq!(snap.peers["peer-a"].agent_version.as_deref(), Some("hch/0.1.0"));
+}
+
+#[test]
+fn test_events_fold_repeats_and_respect_capacity() {
+    let mut config = create_test_config();
+    config.event_log_capacity = 3;
+    let mut snap = NetworkSnapshot::new(&config, "local".to_string());
+
+    snap.on_ping_timeout("peer-a".to_string());
+    snap.on_ping_timeout("peer-a".to_string());
+    assert_eq!(snap.events.len(), 1);
+    assert_eq!(snap.events[0].count, 2);
+
+    // Re-discovery through a known source adds nothing
+    snap.mark_discovered("peer-b".to_string(), "mdns");
+    snap.mark_discovered("peer-b".to_string(), "mdns");
+    assert_eq!(snap.events.len(), 2);
+
+    snap.connection_established("peer-b".to_string(), 1, true);
+    snap.connection_established("peer-b".to_string(), 2, true); // second transport, not a new event
+    snap.connection_closed("peer-b".to_string(), 0, true);
+
+    assert_eq!(snap.events.len(), 3);
+    assert_eq!(snap.events[0].kind, NetworkEventKind::Discovered { via: "mdns".to_string() });
+    assert_eq!(snap.events[1].kind, NetworkEventKind::Connected);
+    assert_eq!(snap.events[2].kind, NetworkEventKind::Disconnected);
+}
+
+#[test]
+fn test_bearer_token_must_match_exactly() {
+    use super::bearer_token_matches;
+    assert!(bearer_token_matches("s3cret", Some("Bearer s3cret")));
+    assert!(!bearer_token_matches("s3cret", Some("Bearer s3cre")));
+    assert!(!bearer_token_matches("s3cret", Some("Bearer s3creT")));
+    assert!(!bearer_token_matches("s3cret", Some("s3cret")));
+    assert!(!bearer_token_matches("s3cret", None));
+}
+
+#[test]
+fn test_version_reports_crate_and_protocol() {
+    let info = serde_json::to_value(super::VersionInfo::new(&create_test_config())).unwrap();
+    assert_eq!(info["crate_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["protocol_version"], crate::p2p::swarm::IDENTIFY_PROTOCOL_VERSION);
+    assert!(info["git_sha"].is_string());
+    assert!(info["build_time"].is_string());
+}
+
+#[test]
+fn test_booking_status_exposes_only_the_outcome() {
+    use crate::broker::types::{BookingJob, JobState};
+
+    let job = BookingJob {
+        correlation_id: "c1".to_string(),
+        booking_json: r#"{"name":"Test User"}"#.to_string(),
+        notify_json: r#"{"email":"test@example.com"}"#.to_string(),
+        state: JobState::Queued,
+        attempts: 0,
+        next_attempt_at: 0,
+        last_error: None,
+        http_status: None,
+        central_response_json: None,
+        created_at: 0,
+        updated_at: 0,
+    };
+    let status = serde_json::to_value(super::BookingStatusInfo::from(job.clone())).unwrap();
+    assert_eq!(status, serde_json::json!({ "correlation_id": "c1", "status": "queued" }));
+
+    let failed = BookingJob {
+        state: JobState::Failed,
+        http_status: Some(422),
+        last_error: Some("slot taken".to_string()),
+        ..job
+    };
+    let status = serde_json::to_value(super::BookingStatusInfo::from(failed)).unwrap();
+    assert_eq!(status["status"], "failed");
+    assert_eq!(status["http_status"], 422);
+    assert_eq!(status["last_error"], "slot taken");
+    assert!(!status.to_string().contains("test@example.com"));
+}
+
+#[test]
+fn test_config_view_redacts_secrets() {
+    let mut config = create_test_config();
+    config.api_token = Some("s3cret".to_string());
+    let view = serde_json::to_value(config.redacted()).unwrap();
+    assert_eq!(view["api_token"], "***");
+    assert_eq!(view["identity_keypair"], "***");
+    assert!(view["db_encryption_key"].is_null());
+    assert_eq!(view["role"], "client");
+    assert_eq!(view["api_rate_limit_scope"], "per_ip");
+    assert!(!view.to_string().contains("s3cret"));
+}
+
+#[test]
+fn test_rate_limiter_refills_and_reports_retry_after() {
+    use super::rate_limit::RateLimiter;
+    use crate::config::RateLimitScope;
+    use std::time::{Duration, Instant};
+
+    let limiter = RateLimiter::new(2, RateLimitScope::PerIp);
+    let a = Some("10.0.0.1".parse().unwrap());
+    let b = Some("10.0.0.2".parse().unwrap());
+    let start = Instant::now();
+    assert!(limiter.check(a, start).is_ok());
+    assert!(limiter.check(a, start).is_ok());
+    let retry_after = limiter.check(a, start).unwrap_err();
+    assert_eq!(retry_after, Duration::from_secs(30)); // 2/min refills one every 30s
+    // Other clients have their own bucket
+    assert!(limiter.check(b, start).is_ok());
+    assert!(limiter.check(a, start + Duration::from_secs(30)).is_ok());
+
+    let global = RateLimiter::new(1, RateLimitScope::Global);
+    assert!(global.check(a, start).is_ok());
+    assert!(global.check(b, start).is_err());
+}
+
+#[test]
+fn test_access_log_level_by_method_and_status() {
+    use super::is_notable_request;
+    use warp::http::{Method, StatusCode};
+    assert!(!is_notable_request(&Method::GET, StatusCode::OK));
+    assert!(is_notable_request(&Method::GET, StatusCode::NOT_FOUND));
+    assert!(is_notable_request(&Method::POST, StatusCode::ACCEPTED));
+    assert!(is_notable_request(&Method::DELETE, StatusCode::OK));
+}
+
+#[tokio::test]
+async fn test_cors_preflight_allows_only_configured_origins() {
+    use super::cors_filter;
+    use warp::Filter;
+
+    let route = warp::path("status")
+        .map(|| "ok")
+        .with(cors_filter(&["http://localhost:3000".to_string()]));
+    let preflight = |origin: &str| {
+        warp::test::request()
+            .method("OPTIONS")
+            .path("/status")
+            .header("origin", origin)
+            .header("access-control-request-method", "GET")
+    };
+
+    let allowed = preflight("http://localhost:3000").reply(&route).await;
+    assert_eq!(allowed.status(), 200);
+    assert_eq!(allowed.headers()["access-control-allow-origin"], "http://localhost:3000");
+
+    let refused = preflight("http://evil.example").reply(&route).await;
+    assert_eq!(refused.status(), 403);
+    assert!(refused.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn test_job_patch_route_answers_each_outcome_and_audits_changes() {
+    use super::{job_patch_route, ApiContext, Readiness};
+    use crate::broker::storage::BrokerStorage;
+    use crate::broker::types::{BookingJob, JobState};
+    use crate::config::DurabilityMode;
+    use crate::metrics::Metrics;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let logs = Captured::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("broker.db");
+    let storage = Arc::new(BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+    let job = BookingJob {
+        correlation_id: "c1".to_string(),
+        booking_json: r#"{"name":"Test User"}"#.to_string(),
+        notify_json: r#"{"email":"test@example.com"}"#.to_string(),
+        state: JobState::Queued,
+        attempts: 0,
+        next_attempt_at: 0,
+        last_error: None,
+        http_status: None,
+        central_response_json: None,
+        created_at: 0,
+        updated_at: 0,
+    };
+    storage.persist_booking_job(&job).unwrap();
+    storage
+        .persist_booking_job(&BookingJob { correlation_id: "c2".to_string(), state: JobState::Confirmed, ..job })
+        .unwrap();
+
+    let mut config = create_test_config();
+    config.api_token = Some("secret".to_string());
+    let (swarm_commands, _swarm_rx) = tokio::sync::mpsc::channel(1);
+    let ctx = ApiContext {
+        network_state: new_shared_network_state(&config, "local".to_string()),
+        config: Arc::new(config),
+        readiness: Arc::new(Readiness::new(true, false)),
+        metrics: Arc::new(Metrics::new(prometheus_client::registry::Registry::default())),
+        swarm_commands,
+        broker_storage: Some(storage.clone()),
+        broker_handler: None,
+    };
+    let route = job_patch_route(warp::any().map(move || ctx.clone()), None);
+    let patch = |id: &str, token: &str, body: &str| {
+        warp::test::request()
+            .method("PATCH")
+            .path(&format!("/jobs/{}", id))
+            .remote_addr("127.0.0.1:4000".parse().unwrap())
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(body)
+    };
+
+    assert_eq!(patch("c1", "wrong", r#"{"state":"failed"}"#).reply(&route).await.status(), 401);
+    assert_eq!(patch("c1", "secret", r#"{"state":"bogus"}"#).reply(&route).await.status(), 400);
+    assert_eq!(patch("c1", "secret", r#"{"priority":1}"#).reply(&route).await.status(), 400);
+    assert_eq!(patch("missing", "secret", r#"{"state":"failed"}"#).reply(&route).await.status(), 404);
+    assert_eq!(patch("c2", "secret", r#"{"state":"queued"}"#).reply(&route).await.status(), 409);
+    assert_eq!(patch("c2", "secret", r#"{"next_attempt_at":5}"#).reply(&route).await.status(), 409);
+    // Nothing changed so far, so nothing was audited
+    assert!(!String::from_utf8(logs.0.lock().unwrap().clone()).unwrap().contains("api::audit"));
+
+    let patched = patch("c1", "secret", r#"{"state":"failed"}"#).reply(&route).await;
+    assert_eq!(patched.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(patched.body()).unwrap();
+    assert_eq!(body["correlation_id"], "c1");
+    assert_eq!(storage.get_booking_job("c1").unwrap().unwrap().state, JobState::Failed);
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let audit: Vec<&str> = logs.lines().filter(|line| line.contains("hybrid_connection_health::api::audit")).collect();
+    assert_eq!(audit.len(), 1, "{}", logs);
+    for field in ["correlation_id=c1", "remote=127.0.0.1:4000", "from_state=\"queued\"", "to_state=\"failed\""] {
+        assert!(audit[0].contains(field), "{} missing from {}", field, audit[0]);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::broker::storage::BrokerStorage;
+use crate::broker::types::{BookingJob, InboundOp, NotificationRecord};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// One line of a broker export (newline-delimited JSON)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Job(BookingJob),
+    Notification(NotificationRecord),
+    Op(InboundOp),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ExportStats {
+    pub jobs: usize,
+    pub notifications: usize,
+    pub ops: usize,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportStats {
+    pub imported: usize,
+    /// Records whose correlation_id (op_id for ops) already existed in the target DB
+    pub skipped: usize,
+}
+
+/// Writes every job, notification and inbound op as one JSON object per line.
+/// Jobs come first so an import never sees a notification before its job.
+pub fn export_ndjson<W: Write>(storage: &BrokerStorage, mut out: W) -> Result<ExportStats> {
+    let mut stats = ExportStats::default();
+    for job in storage.all_booking_jobs()? {
+        write_record(&mut out, &ExportRecord::Job(job))?;
+        stats.jobs += 1;
+    }
+    for notif in storage.all_notifications()? {
+        write_record(&mut out, &ExportRecord::Notification(notif))?;
+        stats.notifications += 1;
+    }
+    for op in storage.all_inbound_ops()? {
+        write_record(&mut out, &ExportRecord::Op(op))?;
+        stats.ops += 1;
+    }
+    out.flush().context("Failed to flush export")?;
+    Ok(stats)
+}
+
+/// Replays an export into `storage`. Records that already exist are left
+/// untouched, so importing the same file twice is harmless.
+pub fn import_ndjson<R: BufRead>(storage: &BrokerStorage, input: R) -> Result<ImportStats> {
+    let mut stats = ImportStats::default();
+    for (index, line) in input.lines().enumerate() {
+        let line = line.context("Failed to read import file")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ExportRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid record on line {}", index + 1))?;
+        let exists = match &record {
+            ExportRecord::Job(job) => storage.get_booking_job(&job.correlation_id)?.is_some(),
+            ExportRecord::Notification(notif) => storage.get_notification(&notif.correlation_id)?.is_some(),
+            ExportRecord::Op(op) => storage.get_inbound_op(&op.op_id)?.is_some(),
+        };
+        if exists {
+            stats.skipped += 1;
+            continue;
+        }
+        match &record {
+            ExportRecord::Job(job) => {
+                storage.persist_booking_job(job)?;
+            }
+            ExportRecord::Notification(notif) => {
+                storage.persist_notification(notif)?;
+            }
+            ExportRecord::Op(op) => {
+                storage.persist_inbound_op(op)?;
+            }
+        }
+        stats.imported += 1;
+    }
+    Ok(stats)
+}
+
+fn write_record<W: Write>(out: &mut W, record: &ExportRecord) -> Result<()> {
+    serde_json::to_writer(&mut *out, record).context("Failed to serialize export record")?;
+    out.write_all(b"\n").context("Failed to write export")?;
+    Ok(())
+}
@@ -0,0 +1,50 @@
+//! Where the broker gets "now" from. Production reads the system clock;
+//! tests swap in a `MockClock` and move time forward explicitly, so backoff
+//! scheduling, due-job selection and retention don't depend on sleeping.
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+#[cfg(test)]
+use std::time::Duration;
+
+pub trait Clock: Send + Sync {
+    /// Current time as epoch milliseconds
+    fn now_ms(&self) -> i64;
+}
+
+/// The wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now_ms: AtomicI64,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now_ms: i64) -> Self {
+        MockClock { now_ms: AtomicI64::new(now_ms) }
+    }
+
+    /// Moves time forward by `by`
+    pub fn advance(&self, by: Duration) {
+        let by_ms = i64::try_from(by.as_millis()).unwrap_or(i64::MAX);
+        self.now_ms.fetch_add(by_ms, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now_ms(&self) -> i64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+const NONCE_LEN: usize = 12;
+
+/// 256-bit key for encrypting broker records at rest
+#[derive(Clone)]
+pub struct DbEncryptionKey([u8; 32]);
+
+impl DbEncryptionKey {
+    /// Parses a key given as 64 hex characters
+    pub fn from_hex(hex_key: &str) -> Result<Self> {
+        let bytes = hex::decode(hex_key.trim()).context("key is not valid hex")?;
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|b: Vec<u8>| anyhow::anyhow!("key must be 32 bytes (64 hex chars), got {}", b.len()))?;
+        Ok(DbEncryptionKey(key))
+    }
+
+    /// Derives a key from arbitrary key material (e.g. the contents of a key file)
+    pub fn derive(material: &[u8]) -> Self {
+        DbEncryptionKey(Sha256::digest(material).into())
+    }
+}
+
+// Never print key material, not even in debug logs of the config
+impl fmt::Debug for DbEncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DbEncryptionKey(<redacted>)")
+    }
+}
+
+/// Seals individual sled values with ChaCha20-Poly1305. Each value is stored
+/// as `nonce || ciphertext`, with a fresh random nonce per write.
+pub struct RecordCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl RecordCipher {
+    pub fn new(key: &DbEncryptionKey) -> Self {
+        RecordCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key.0)),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt record"))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            bail!("Encrypted record is truncated");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt record (wrong key or corrupted data)"))
+    }
+}
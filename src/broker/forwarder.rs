@@ -1,56 +1,106 @@
 use crate::broker::storage::{BrokerStorage, JobStateUpdate};
 use crate::broker::types::{BookingJob, JobState, NotificationRecord, NotificationState};
-use crate::config::Config;
+use crate::config::{Config, UnreadableBodyAction};
+use crate::metrics::{HistogramFamily, Metrics};
+use crate::p2p::protocol::NotifyData;
 use anyhow::{Context, Result};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::histogram::Histogram;
 use reqwest::Client;
 use serde_json::json;
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{error, info, warn};
-
-const MAX_BACKOFF_MS: u64 = 300_000; // 5 minutes max
-const JITTER_MS: u64 = 1000; // 1 second jitter
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// `traceparent`/`tracestate` headers for `span`; empty unless OTLP export
+/// installed a propagator
+fn trace_context_headers(span: &tracing::Span) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut headers)
+    });
+    headers
+}
 
 pub struct ForwarderWorker {
     storage: Arc<BrokerStorage>,
     http_client: Client,
     central_api_url: String,
+    /// `central_api_url` + `central_api_health_path`, when a probe is configured
+    health_url: Option<String>,
+    probe_timeout: Duration,
     max_retry_attempts: u32,
+    retry_warn_threshold: u32,
+    job_attempts: Histogram,
+    job_retry_warnings: Counter,
+    duplicate_notifications: Counter,
+    central_api_latency: HistogramFamily,
+    unreadable_success_body: UnreadableBodyAction,
+    send_attempt_header: bool,
     initial_backoff_ms: u64,
+    max_backoff_ms: u64,
+    backoff_jitter_ms: u64,
 }
 
 impl ForwarderWorker {
-    pub fn new(storage: Arc<BrokerStorage>, config: Config) -> Result<Self> {
+    pub fn new(storage: Arc<BrokerStorage>, config: Config, metrics: &Metrics) -> Result<Self> {
         let central_api_url = config
             .central_api_url
             .ok_or_else(|| anyhow::anyhow!("central_api_url not configured"))?;
 
         // Create HTTP client with timeouts
         let http_client = Client::builder()
-            .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(30))
+            .connect_timeout(Duration::from_millis(config.central_api_connect_timeout_ms))
+            .timeout(Duration::from_millis(config.central_api_request_timeout_ms))
             .build()
             .context("Failed to create HTTP client")?;
 
+        let health_url = config
+            .central_api_health_path
+            .map(|path| format!("{}{}", central_api_url, path));
+
         Ok(ForwarderWorker {
             storage,
             http_client,
             central_api_url,
+            health_url,
+            probe_timeout: Duration::from_millis(config.central_api_connect_timeout_ms),
             max_retry_attempts: config.max_retry_attempts,
+            retry_warn_threshold: config.retry_warn_threshold,
+            job_attempts: metrics.job_attempts.clone(),
+            job_retry_warnings: metrics.job_retry_warnings.clone(),
+            duplicate_notifications: metrics.duplicate_notifications.clone(),
+            central_api_latency: metrics.central_api_latency.clone(),
+            unreadable_success_body: config.unreadable_success_body,
+            send_attempt_header: config.send_attempt_header,
             initial_backoff_ms: config.initial_backoff_ms,
+            max_backoff_ms: config.max_backoff_ms,
+            backoff_jitter_ms: config.backoff_jitter_ms,
         })
     }
 
-    /// Run the forwarder worker loop
-    pub async fn run(&self) -> Result<()> {
+    /// Run the forwarder worker loop until `shutdown` flips to true.
+    /// A job already being forwarded is always finished before returning.
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
         info!("Forwarder worker started");
 
         let mut interval = tokio::time::interval(Duration::from_secs(1));
 
         loop {
-            interval.tick().await;
+            // A dropped sender also means the node is going away
+            let stop = tokio::select! {
+                _ = interval.tick() => *shutdown.borrow(),
+                res = shutdown.changed() => res.is_err() || *shutdown.borrow(),
+            };
+            if stop {
+                info!("Forwarder worker stopped");
+                return Ok(());
+            }
 
-            match self.process_due_jobs().await {
+            match self.process_due_jobs(&shutdown).await {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Error in forwarder worker: {:?}", e);
@@ -59,11 +109,18 @@ impl ForwarderWorker {
         }
     }
 
-    /// Process due jobs
-    async fn process_due_jobs(&self) -> Result<()> {
+    /// Process due jobs, stopping early between jobs if shutdown was requested.
+    /// If the health probe fails, the whole tick is skipped and jobs stay queued.
+    pub(crate) async fn process_due_jobs(&self, shutdown: &watch::Receiver<bool>) -> Result<()> {
         let jobs = self.storage.get_due_jobs(10)?;
+        if jobs.is_empty() || !self.central_api_healthy().await {
+            return Ok(());
+        }
 
         for job in jobs {
+            if *shutdown.borrow() {
+                break;
+            }
             if let Err(e) = self.process_job(job).await {
                 error!("Failed to process job: {:?}", e);
             }
@@ -72,12 +129,40 @@ impl ForwarderWorker {
         Ok(())
     }
 
+    /// GET the configured health path; true when it answers 2xx or no probe
+    /// is configured. Bounded by the connect timeout so a hung API can't
+    /// stall the loop for a full request timeout.
+    async fn central_api_healthy(&self) -> bool {
+        let Some(url) = &self.health_url else {
+            return true;
+        };
+        let probe = self.http_client.get(url).timeout(self.probe_timeout).send().await;
+        match probe {
+            Ok(response) if response.status().is_success() => {
+                debug!(url = %url, http_status = response.status().as_u16(), "Central API health probe succeeded");
+                true
+            }
+            Ok(response) => {
+                warn!(
+                    url = %url,
+                    http_status = response.status().as_u16(),
+                    "Central API health probe failed, leaving due jobs queued"
+                );
+                false
+            }
+            Err(e) => {
+                warn!(url = %url, error = %e, "Central API health probe failed, leaving due jobs queued");
+                false
+            }
+        }
+    }
+
     /// Process a single job
+    #[tracing::instrument(name = "booking", skip_all, fields(correlation_id = %job.correlation_id))]
     async fn process_job(&self, job: BookingJob) -> Result<()> {
         let correlation_id = job.correlation_id.clone();
 
         info!(
-            correlation_id = %correlation_id,
             attempts = job.attempts,
             "Processing booking job"
         );
@@ -110,55 +195,66 @@ impl ForwarderWorker {
             "name": booking["name"],
         });
 
+        // 1 for the first try, like the job_attempts histogram
+        let attempt = job.attempts + 1;
         info!(
-            correlation_id = %correlation_id,
             url = %url,
+            request_id = %correlation_id,
+            attempt,
             "Sending request to Central API"
         );
 
-        // Make HTTP request
-        match self
+        // Make HTTP request, in its own span carrying the W3C trace context
+        let request_span = info_span!(
+            "central_api_request",
+            otel.kind = "client",
+            correlation_id = %correlation_id,
+            http.request.method = "POST",
+            url.full = %url,
+        );
+        let mut request = self
             .http_client
             .post(&url)
             .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await
-        {
+            // Lets the central API recognise a booking sent again after a lost response
+            .header("Idempotency-Key", &correlation_id)
+            // Matches the central API's logs for this request with ours
+            .header("X-Request-Id", &correlation_id)
+            .json(&request_body);
+        if self.send_attempt_header {
+            request = request.header("X-Attempt", attempt.to_string());
+        }
+        for (name, value) in trace_context_headers(&request_span) {
+            request = request.header(name, value);
+        }
+        let started = Instant::now();
+        match request.send().instrument(request_span).await {
             Ok(response) => {
                 let status = response.status();
                 let status_code = status.as_u16();
 
                 match response.text().await {
                     Ok(response_body) => {
+                        let outcome = if status.is_success() {
+                            "success"
+                        } else if status.is_client_error() {
+                            "4xx"
+                        } else if status.is_server_error() {
+                            "5xx"
+                        } else {
+                            "other"
+                        };
+                        self.observe_central_api_latency(outcome, started);
                         if status.is_success() {
                             // Success - update job to Confirmed
                             info!(
-                                correlation_id = %correlation_id,
                                 http_status = status_code,
                                 "Job forwarded successfully to Central API"
                             );
-
-                            self.storage
-                                .update_job_state(
-                                    &correlation_id,
-                                    JobStateUpdate {
-                                        state: JobState::Confirmed,
-                                        attempts: None,
-                                        next_attempt_at: None,
-                                        last_error: None,
-                                        http_status: Some(status_code),
-                                        central_response_json: Some(&response_body),
-                                    },
-                                )
-                                .context("Failed to update job to Confirmed")?;
-
-                            // Create notification record
-                            self.create_notification(&correlation_id, &job.notify_json)?;
+                            self.confirm_job(&job, status_code, Some(&response_body))?;
                         } else {
                             // HTTP error (4xx/5xx) - mark as Failed (non-retryable)
                             warn!(
-                                correlation_id = %correlation_id,
                                 http_status = status_code,
                                 "HTTP error from Central API, marking job as failed"
                             );
@@ -176,12 +272,26 @@ impl ForwarderWorker {
                                     },
                                 )
                                 .context("Failed to update job to Failed")?;
+                            self.job_attempts.observe(f64::from(job.attempts + 1));
                         }
                     }
+                    Err(e)
+                        if status.is_success()
+                            && self.unreadable_success_body == UnreadableBodyAction::Confirm =>
+                    {
+                        // The central API accepted it; sending it again could book it twice
+                        self.observe_central_api_latency("success", started);
+                        warn!(
+                            http_status = status_code,
+                            error = %e,
+                            "Central API accepted the job but its response body couldn't be read, confirming without it"
+                        );
+                        self.confirm_job(&job, status_code, None)?;
+                    }
                     Err(e) => {
                         // Failed to read response body
+                        self.observe_central_api_latency("network_error", started);
                         warn!(
-                            correlation_id = %correlation_id,
                             error = %e,
                             "Failed to read response body"
                         );
@@ -191,8 +301,8 @@ impl ForwarderWorker {
             }
             Err(e) => {
                 // Network error or timeout - retry
+                self.observe_central_api_latency("network_error", started);
                 warn!(
-                    correlation_id = %correlation_id,
                     error = %e,
                     "Network error forwarding job, will retry"
                 );
@@ -203,6 +313,33 @@ impl ForwarderWorker {
         Ok(())
     }
 
+    /// Marks `job` Confirmed with the central API's answer and queues its notification
+    fn confirm_job(&self, job: &BookingJob, status_code: u16, response_body: Option<&str>) -> Result<()> {
+        self.storage
+            .update_job_state(
+                &job.correlation_id,
+                JobStateUpdate {
+                    state: JobState::Confirmed,
+                    attempts: None,
+                    next_attempt_at: None,
+                    last_error: None,
+                    http_status: Some(status_code),
+                    central_response_json: response_body,
+                },
+            )
+            .context("Failed to update job to Confirmed")?;
+        self.job_attempts.observe(f64::from(job.attempts + 1));
+
+        // Create notification record
+        self.create_notification(&job.correlation_id, &job.notify_json)
+    }
+
+    fn observe_central_api_latency(&self, outcome: &'static str, started: Instant) {
+        self.central_api_latency
+            .get_or_create(&vec![("outcome".to_string(), outcome.to_string())])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
     /// Handle retry with exponential backoff
     fn handle_retry(
         &self,
@@ -215,7 +352,6 @@ impl ForwarderWorker {
         if new_attempts > self.max_retry_attempts {
             // Max retries exceeded - mark as Failed
             error!(
-                correlation_id = %correlation_id,
                 attempts = new_attempts,
                 "Max retry attempts exceeded, marking job as failed"
             );
@@ -233,16 +369,27 @@ impl ForwarderWorker {
                     },
                 )
                 .context("Failed to update job to Failed")?;
+            self.job_attempts.observe(f64::from(new_attempts));
 
             return Ok(());
         }
 
+        // Early warning, once per job, that the central API keeps failing
+        if new_attempts == self.retry_warn_threshold {
+            warn!(
+                attempts = new_attempts,
+                max_retry_attempts = self.max_retry_attempts,
+                error = %error,
+                "Job reached the retry warning threshold; Central API may be degraded"
+            );
+            self.job_retry_warnings.inc();
+        }
+
         // Calculate exponential backoff with jitter
         let backoff_delay = self.calculate_backoff(new_attempts);
-        let next_attempt_at = chrono::Utc::now().timestamp_millis() + backoff_delay as i64;
+        let next_attempt_at = self.storage.now_ms() + backoff_delay as i64;
 
         warn!(
-            correlation_id = %correlation_id,
             attempts = new_attempts,
             next_attempt_at = next_attempt_at,
             "Scheduling retry with exponential backoff"
@@ -271,35 +418,33 @@ impl ForwarderWorker {
         use rand::Rng;
         let mut rng = rand::thread_rng();
 
-        // Exponential backoff: initial_backoff_ms * 2^attempts
-        let base_delay = self.initial_backoff_ms.saturating_mul(1 << attempts.min(20)); // Cap at 2^20 to avoid overflow
+        // Exponential backoff: initial_backoff_ms * 2^(attempts - 1), so the first retry waits initial_backoff_ms
+        let exponent = attempts.saturating_sub(1).min(20); // Cap at 2^20 to avoid overflow
+        let base_delay = self.initial_backoff_ms.saturating_mul(1 << exponent);
 
         // Cap at max backoff
-        let delay = base_delay.min(MAX_BACKOFF_MS);
+        let delay = base_delay.min(self.max_backoff_ms);
 
-        // Add jitter: random(0, JITTER_MS)
-        let jitter = rng.gen_range(0..=JITTER_MS);
+        // Subtract jitter: random(0, min(delay, backoff_jitter_ms)). With the
+        // default jitter this is full jitter, random(0, delay), which spreads
+        // many clients' retries over the whole window instead of one second
+        let jitter = rng.gen_range(0..=delay.min(self.backoff_jitter_ms));
 
-        delay + jitter
+        delay - jitter
     }
 
     /// Create notification record in outbox
     fn create_notification(&self, correlation_id: &str, notify_json: &str) -> Result<()> {
-        // Parse notify data
-        let notify: serde_json::Value = serde_json::from_str(notify_json)
+        // Validated by the handler before the job was persisted
+        let notify: NotifyData = serde_json::from_str(notify_json)
             .context("Failed to parse notify_json")?;
 
-        let email = notify["email"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing email in notify data"))?
-            .to_string();
-
-        let now = chrono::Utc::now().timestamp_millis();
+        let now = self.storage.now_ms();
 
         // Create notification record (will be populated by notifier worker)
         let notif = NotificationRecord {
             correlation_id: correlation_id.to_string(),
-            email_to: email,
+            email_to: notify.email,
             state: NotificationState::Pending,
             attempts: 0,
             next_attempt_at: now, // Process immediately
@@ -311,14 +456,26 @@ impl ForwarderWorker {
             updated_at: now,
         };
 
-        self.storage
+        let created = self
+            .storage
             .persist_notification(&notif)
             .context("Failed to persist notification")?;
 
-        info!(
-            correlation_id = %correlation_id,
-            "Notification record created in outbox"
-        );
+        if created {
+            info!("Notification record created in outbox");
+        } else {
+            // Idempotency kept the first one; a job confirmed twice (e.g. after
+            // a manual requeue) ends up here, which operators may want to know
+            let existing_state = self
+                .storage
+                .get_notification(correlation_id)?
+                .map(|existing| existing.state.as_str());
+            warn!(
+                existing_state = existing_state.unwrap_or("unknown"),
+                "Notification already exists for this booking, skipped duplicate"
+            );
+            self.duplicate_notifications.inc();
+        }
 
         Ok(())
     }
@@ -1,56 +1,141 @@
 use crate::broker::storage::BrokerStorage;
-use crate::broker::types::{BookingJob, JobState};
-use crate::p2p::protocol::{BookingData, Msg, NotifyData};
+use crate::broker::types::{BookingJob, InboundOp, InboundOpState, JobState};
+use crate::p2p::protocol::{BookingData, BookingStatus, Msg, NotifyData, Op};
 use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tracing::info;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tracing::{error, info, warn};
+
+/// Loose shape check for a notification address: one `@`, something before
+/// it, a dotted domain after it and no whitespace. Deliverability is the
+/// notifier's problem; this only catches bookings that could never be notified.
+pub(crate) fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| !label.is_empty())
+        && !email.chars().any(char::is_whitespace)
+}
+
+/// Ack for a booking whose correlation_id is already stored as `existing`
+fn existing_status(existing: &BookingJob, submitted: &BookingJob) -> BookingStatus {
+    // Same id, different booking: a client reusing ids. Acking the
+    // other booking's status would silently drop this one.
+    if existing.booking_json != submitted.booking_json || existing.notify_json != submitted.notify_json {
+        warn!("Rejecting booking: correlation_id already used for a different booking");
+        return BookingStatus::Rejected;
+    }
+
+    let status = match existing.state {
+        JobState::Confirmed => BookingStatus::Confirmed,
+        JobState::Failed => BookingStatus::Failed,
+        _ => BookingStatus::Queued,
+    };
+
+    info!(
+        status = %status,
+        "Booking already exists, returning existing status"
+    );
+
+    status
+}
+
+/// A `SubmitBooking` queued by the swarm loop; its `BookingAck` goes back on `reply`
+pub struct BookingRequest {
+    pub correlation_id: String,
+    pub booking: BookingData,
+    pub notify: NotifyData,
+    pub reply: oneshot::Sender<Msg>,
+}
 
 pub struct BrokerHandler {
     storage: Arc<BrokerStorage>,
+    /// New bookings are throttled once this many jobs are unfinished (0 = no limit)
+    max_queued_jobs: u64,
+    /// Set before a planned shutdown: new bookings and ops are answered
+    /// "draining" while the workers finish what is already persisted
+    draining: AtomicBool,
+    /// One permit per received op being persisted; ops arriving with none
+    /// left are answered "busy"
+    op_slots: Arc<Semaphore>,
 }
 
 impl BrokerHandler {
-    pub fn new(storage: Arc<BrokerStorage>) -> Self {
-        BrokerHandler { storage }
+    pub fn new(storage: Arc<BrokerStorage>, max_queued_jobs: u64) -> Self {
+        BrokerHandler {
+            storage,
+            max_queued_jobs,
+            draining: AtomicBool::new(false),
+            op_slots: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+        }
+    }
+
+    /// Bounds how many received ops are persisted at once (`max_concurrent_broker_ops`)
+    pub fn with_max_concurrent_ops(mut self, max: usize) -> Self {
+        self.op_slots = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// A slot for persisting one received op, held until its ack is sent.
+    /// None when `max_concurrent_broker_ops` are already in flight.
+    pub fn try_reserve_op(&self) -> Option<OwnedSemaphorePermit> {
+        self.op_slots.clone().try_acquire_owned().ok()
+    }
+
+    /// Stops accepting new bookings and ops. Returns whether it already was draining.
+    pub fn start_draining(&self) -> bool {
+        self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    /// Jobs still queued or being sent, i.e. what a drain is waiting for
+    pub fn unfinished_jobs(&self) -> Result<u64> {
+        self.storage.count_unfinished_jobs()
+    }
+
+    /// Handles queued submissions one at a time, off the swarm loop, until
+    /// every sender is dropped. A failed submission is acked with "error".
+    pub async fn run_queue(self: Arc<Self>, mut requests: mpsc::Receiver<BookingRequest>) {
+        while let Some(request) = requests.recv().await {
+            let correlation_id = request.correlation_id.clone();
+            let status = match self
+                .handle_submit_booking(request.correlation_id, request.booking, request.notify)
+                .await
+            {
+                Ok(status) => status,
+                Err(e) => {
+                    error!("Failed to handle booking submission: {:?}", e);
+                    BookingStatus::Error
+                }
+            };
+            // The swarm loop may have shut down meanwhile; nobody to answer then
+            let _ = request.reply.send(status.ack(correlation_id));
+        }
     }
 
     /// Handle booking submission with idempotency
-    /// Returns BookingAck message
+    /// Returns the status to ack it with
+    #[tracing::instrument(name = "booking", skip_all, fields(correlation_id = %correlation_id))]
     pub async fn handle_submit_booking(
         &self,
         correlation_id: String,
         booking: BookingData,
         notify: NotifyData,
-    ) -> Result<Msg> {
-        info!(
-            correlation_id = %correlation_id,
-            "Received booking submission request"
-        );
-
-        // Check if correlation_id already exists (idempotency)
-        match self.storage.get_booking_job(&correlation_id)? {
-            Some(existing_job) => {
-                // Job already exists - return appropriate status
-                let status = match existing_job.state {
-                    JobState::Confirmed => "confirmed",
-                    JobState::Failed => "failed",
-                    _ => "queued",
-                };
-
-                info!(
-                    correlation_id = %correlation_id,
-                    status = status,
-                    "Booking already exists, returning existing status"
-                );
-
-                return Ok(Msg::BookingAck {
-                    correlation_id,
-                    status: status.to_string(),
-                });
-            }
-            None => {
-                // New job - create and persist
-            }
+    ) -> Result<BookingStatus> {
+        info!("Received booking submission request");
+
+        if self.draining.load(Ordering::Relaxed) {
+            info!("Refusing booking: gateway is draining");
+            return Ok(BookingStatus::Draining);
+        }
+
+        // Reject up front what would only fail after the booking was confirmed
+        if !is_valid_email(&notify.email) {
+            warn!(email = %notify.email, "Rejecting booking: invalid notification email");
+            return Ok(BookingStatus::Rejected);
         }
 
         // Serialize booking and notify data
@@ -60,7 +145,7 @@ impl BrokerHandler {
             .context("Failed to serialize notify data")?;
 
         // Create new booking job
-        let now = chrono::Utc::now().timestamp_millis();
+        let now = self.storage.now_ms();
         let job = BookingJob {
             correlation_id: correlation_id.clone(),
             booking_json,
@@ -75,19 +160,67 @@ impl BrokerHandler {
             updated_at: now,
         };
 
-        // Persist atomically - ACK only after successful persist
-        self.storage
+        // Shed load before writing anything so a flood can't fill the disk.
+        // Only new jobs are throttled: a retry of a job we already hold still
+        // gets that job's status.
+        if self.max_queued_jobs > 0 {
+            let unfinished = self
+                .storage
+                .count_unfinished_jobs()
+                .context("Failed to read queued job count")?;
+            if unfinished >= self.max_queued_jobs {
+                let existing = self
+                    .storage
+                    .get_booking_job(&correlation_id)
+                    .context("Failed to look up booking job")?;
+                if let Some(existing_job) = existing {
+                    return Ok(existing_status(&existing_job, &job));
+                }
+                warn!(unfinished, max_queued_jobs = self.max_queued_jobs, "Throttling booking: too many queued jobs");
+                return Ok(BookingStatus::Throttled);
+            }
+        }
+
+        // Persist atomically - ACK only after successful persist. If a job with
+        // this correlation_id already exists (a retry, or a concurrent duplicate
+        // that won the insert), report its status instead (idempotency)
+        let existing = self
+            .storage
             .persist_booking_job(&job)
             .context("Failed to persist booking job")?;
 
-        info!(
-            correlation_id = %correlation_id,
-            "Booking job persisted successfully, sending ACK"
-        );
+        if let Some(existing_job) = existing {
+            return Ok(existing_status(&existing_job, &job));
+        }
 
-        Ok(Msg::BookingAck {
-            correlation_id,
-            status: "queued".to_string(),
-        })
+        info!("Booking job persisted successfully, sending ACK");
+
+        Ok(BookingStatus::Queued)
+    }
+
+    /// Persists an op received from `from_peer` so it survives a restart
+    /// before the op worker gets to it; the `OpAck` is only sent after this.
+    /// A resend of a stored op is acked without storing it again. False,
+    /// with nothing stored, while draining.
+    #[tracing::instrument(name = "op", skip_all, fields(op_id = %op.op_id))]
+    pub async fn handle_op_submit(&self, op: Op, from_peer: String) -> Result<bool> {
+        if self.draining.load(Ordering::Relaxed) {
+            info!("Refusing op: gateway is draining");
+            return Ok(false);
+        }
+        let inbound = InboundOp {
+            op_id: op.op_id.clone(),
+            op_json: serde_json::to_string(&op).context("Failed to serialize op")?,
+            from_peer,
+            state: InboundOpState::Received,
+            received_at: self.storage.now_ms(),
+            processed_at: None,
+        };
+        if self.storage.persist_inbound_op(&inbound).context("Failed to persist inbound op")? {
+            info!(kind = %op.kind, entity = %op.entity, "Inbound op persisted");
+        } else {
+            info!("Inbound op already received, acking again");
+        }
+        Ok(true)
     }
 }
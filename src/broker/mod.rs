@@ -1,8 +1,14 @@
 pub mod types;
+pub mod clock;
+pub mod crypto;
 pub mod storage;
 pub mod handler;
 pub mod forwarder;
 pub mod notifier;
+pub mod ops;
+pub mod supervisor;
+pub mod retention;
+pub mod backup;
 
 #[cfg(test)]
 mod tests;
@@ -1,30 +1,58 @@
 use crate::broker::storage::BrokerStorage;
 use crate::broker::types::{BookingJob, NotificationRecord, NotificationState};
+use crate::config::Config;
 use anyhow::{Context, Result};
 use serde_json::Value;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
 use tracing::{error, info, warn};
 
+/// A notification ready to send
+#[derive(Debug)]
+pub(crate) struct Email {
+    /// `"Name" <address>`, or just the address without `email_from_name`
+    pub from: String,
+    pub reply_to: Option<String>,
+    pub subject: String,
+    pub body: String,
+}
+
 pub struct NotifierWorker {
     storage: Arc<BrokerStorage>,
+    /// From header, built once from `email_from`/`email_from_name`
+    from: String,
+    reply_to: Option<String>,
 }
 
 impl NotifierWorker {
-    pub fn new(storage: Arc<BrokerStorage>) -> Self {
-        NotifierWorker { storage }
+    pub fn new(storage: Arc<BrokerStorage>, config: &Config) -> Self {
+        let from = if config.email_from_name.is_empty() {
+            config.email_from.clone()
+        } else {
+            format!("\"{}\" <{}>", config.email_from_name, config.email_from)
+        };
+        NotifierWorker { storage, from, reply_to: config.email_reply_to.clone() }
     }
 
-    /// Run the notifier worker loop
-    pub async fn run(&self) -> Result<()> {
+    /// Run the notifier worker loop until `shutdown` flips to true
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
         info!("Notifier worker started");
 
         let mut interval = tokio::time::interval(Duration::from_secs(2));
 
         loop {
-            interval.tick().await;
+            // A dropped sender also means the node is going away
+            let stop = tokio::select! {
+                _ = interval.tick() => *shutdown.borrow(),
+                res = shutdown.changed() => res.is_err() || *shutdown.borrow(),
+            };
+            if stop {
+                info!("Notifier worker stopped");
+                return Ok(());
+            }
 
-            match self.process_due_notifications().await {
+            match self.process_due_notifications(&shutdown).await {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Error in notifier worker: {:?}", e);
@@ -33,11 +61,14 @@ impl NotifierWorker {
         }
     }
 
-    /// Process due notifications
-    async fn process_due_notifications(&self) -> Result<()> {
+    /// Process due notifications, stopping early if shutdown was requested
+    async fn process_due_notifications(&self, shutdown: &watch::Receiver<bool>) -> Result<()> {
         let notifications = self.storage.get_due_notifications(10)?;
 
         for notif in notifications {
+            if *shutdown.borrow() {
+                break;
+            }
             if let Err(e) = self.process_notification(notif).await {
                 error!("Failed to process notification: {:?}", e);
             }
@@ -47,11 +78,11 @@ impl NotifierWorker {
     }
 
     /// Process a single notification
+    #[tracing::instrument(name = "booking", skip_all, fields(correlation_id = %notif.correlation_id))]
     async fn process_notification(&self, notif: NotificationRecord) -> Result<()> {
         let correlation_id = notif.correlation_id.clone();
 
         info!(
-            correlation_id = %correlation_id,
             email = %notif.email_to,
             "Processing notification"
         );
@@ -65,7 +96,6 @@ impl NotifierWorker {
         // Skip if job is not Confirmed
         if job.state != crate::broker::types::JobState::Confirmed {
             warn!(
-                correlation_id = %correlation_id,
                 state = %job.state.as_str(),
                 "Skipping notification - booking job not confirmed"
             );
@@ -73,7 +103,7 @@ impl NotifierWorker {
         }
 
         // Build email subject and body
-        let (subject, body) = self.build_email(&job)?;
+        let Email { from, reply_to, subject, body } = self.build_email(&job)?;
 
         // Log simulated email
         let body_preview = if body.len() > 100 {
@@ -83,18 +113,20 @@ impl NotifierWorker {
         };
 
         info!(
-            correlation_id = %correlation_id,
             to = %notif.email_to,
+            from = %from,
             subject = %subject,
-            "SIMULATED_EMAIL correlation_id={} to={} subject=\"{}\" body_preview=\"{}\"",
+            "SIMULATED_EMAIL correlation_id={} from={} reply_to={} to={} subject=\"{}\" body_preview=\"{}\"",
             correlation_id,
+            from,
+            reply_to.as_deref().unwrap_or("-"),
             notif.email_to,
             subject,
             body_preview
         );
 
         // Update notification state to SimulatedSent
-        let sent_at = chrono::Utc::now().timestamp_millis();
+        let sent_at = self.storage.now_ms();
         self.storage
             .update_notification_state(
                 &correlation_id,
@@ -105,16 +137,13 @@ impl NotifierWorker {
             )
             .context("Failed to update notification state")?;
 
-        info!(
-            correlation_id = %correlation_id,
-            "Notification processed and simulated email sent"
-        );
+        info!("Notification processed and simulated email sent");
 
         Ok(())
     }
 
-    /// Build email subject and body from booking job
-    fn build_email(&self, job: &BookingJob) -> Result<(String, String)> {
+    /// Build the email for a booking job, sent from the configured sender
+    pub(crate) fn build_email(&self, job: &BookingJob) -> Result<Email> {
         // Parse booking data
         let booking: Value = serde_json::from_str(&job.booking_json)
             .context("Failed to parse booking_json")?;
@@ -151,6 +180,6 @@ impl NotifierWorker {
             name, date, start_time, end_time, name, response_info
         );
 
-        Ok((subject, body))
+        Ok(Email { from: self.from.clone(), reply_to: self.reply_to.clone(), subject, body })
     }
 }
@@ -0,0 +1,74 @@
+use crate::broker::storage::BrokerStorage;
+use crate::broker::types::InboundOp;
+use crate::p2p::protocol::Op;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info};
+
+/// Works through the ops gateways persisted on `OpSubmit` (see
+/// `BrokerHandler::handle_op_submit`), marking each processed once applied
+pub struct OpWorker {
+    storage: Arc<BrokerStorage>,
+}
+
+impl OpWorker {
+    pub fn new(storage: Arc<BrokerStorage>) -> Self {
+        OpWorker { storage }
+    }
+
+    /// Run the op worker loop until `shutdown` flips to true
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        info!("Op worker started");
+
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+
+        loop {
+            // A dropped sender also means the node is going away
+            let stop = tokio::select! {
+                _ = interval.tick() => *shutdown.borrow(),
+                res = shutdown.changed() => res.is_err() || *shutdown.borrow(),
+            };
+            if stop {
+                info!("Op worker stopped");
+                return Ok(());
+            }
+
+            if let Err(e) = self.process_received_ops(&shutdown) {
+                error!("Error in op worker: {:?}", e);
+            }
+        }
+    }
+
+    /// Process received ops, stopping early if shutdown was requested.
+    /// Returns how many were processed.
+    pub fn process_received_ops(&self, shutdown: &watch::Receiver<bool>) -> Result<usize> {
+        let mut processed = 0;
+        for inbound in self.storage.get_received_ops(10)? {
+            if *shutdown.borrow() {
+                break;
+            }
+            match self.process_op(&inbound) {
+                Ok(()) => processed += 1,
+                Err(e) => error!(op_id = %inbound.op_id, "Failed to process inbound op: {:?}", e),
+            }
+        }
+        Ok(processed)
+    }
+
+    /// Process a single op. Ops carry no gateway-side effect yet beyond
+    /// being recorded, so applying one means logging it and marking it done.
+    #[tracing::instrument(name = "op", skip_all, fields(op_id = %inbound.op_id))]
+    fn process_op(&self, inbound: &InboundOp) -> Result<()> {
+        let op: Op = serde_json::from_str(&inbound.op_json).context("Failed to parse stored op")?;
+        info!(
+            kind = %op.kind,
+            entity = %op.entity,
+            actor_id = %op.actor_id,
+            from_peer = %inbound.from_peer,
+            "Applying inbound op"
+        );
+        self.storage.mark_op_processed(&inbound.op_id)
+    }
+}
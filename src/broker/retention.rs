@@ -0,0 +1,127 @@
+use crate::broker::storage::BrokerStorage;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// How often finished jobs are checked against the retention window
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often the size monitor logs the DB size on disk
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Periodically deletes confirmed/failed jobs (and their notifications) and
+/// processed inbound ops older than `job_retention_days`, so the sled DB
+/// doesn't grow without bound
+pub struct RetentionWorker {
+    storage: Arc<BrokerStorage>,
+    retention_days: u32,
+}
+
+impl RetentionWorker {
+    pub fn new(storage: Arc<BrokerStorage>, retention_days: u32) -> Self {
+        RetentionWorker { storage, retention_days }
+    }
+
+    /// Run the retention sweep loop until `shutdown` flips to true
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        info!(retention_days = self.retention_days, "Retention worker started");
+
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+        loop {
+            // A dropped sender also means the node is going away
+            let stop = tokio::select! {
+                _ = interval.tick() => *shutdown.borrow(),
+                res = shutdown.changed() => res.is_err() || *shutdown.borrow(),
+            };
+            if stop {
+                info!("Retention worker stopped");
+                return Ok(());
+            }
+
+            if let Err(e) = self.sweep() {
+                error!("Error in retention worker: {:?}", e);
+            }
+        }
+    }
+
+    /// Returns how many jobs were purged
+    pub(crate) fn sweep(&self) -> Result<usize> {
+        let now = self.storage.now_ms();
+        let cutoff = now - i64::from(self.retention_days) * MS_PER_DAY;
+        let purged = self.storage.purge_finished_jobs(cutoff)?;
+        if purged > 0 {
+            info!(purged, "Purged finished jobs past retention");
+        }
+        // Any peer can submit ops, so the processed ones have to go too
+        let purged_ops = self.storage.purge_processed_ops(cutoff)?;
+        if purged_ops > 0 {
+            info!(purged_ops, "Purged processed ops past retention");
+        }
+        Ok(purged)
+    }
+}
+
+/// Safety net for a DB that bloats anyway: logs its size on disk every few
+/// minutes and, past `threshold_bytes`, runs the retention sweep early
+pub struct SizeMonitor {
+    storage: Arc<BrokerStorage>,
+    threshold_bytes: u64,
+    /// None when finished jobs are kept forever; nothing can be swept then
+    retention: Option<RetentionWorker>,
+}
+
+impl SizeMonitor {
+    pub fn new(storage: Arc<BrokerStorage>, threshold_bytes: u64, retention_days: u32) -> Self {
+        let retention = (retention_days > 0).then(|| RetentionWorker::new(storage.clone(), retention_days));
+        SizeMonitor { storage, threshold_bytes, retention }
+    }
+
+    /// Run the size check loop until `shutdown` flips to true
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        info!(threshold_bytes = self.threshold_bytes, "DB size monitor started");
+
+        let mut interval = tokio::time::interval(SIZE_CHECK_INTERVAL);
+
+        loop {
+            let stop = tokio::select! {
+                _ = interval.tick() => *shutdown.borrow(),
+                res = shutdown.changed() => res.is_err() || *shutdown.borrow(),
+            };
+            if stop {
+                info!("DB size monitor stopped");
+                return Ok(());
+            }
+
+            if let Err(e) = self.check() {
+                error!("Error in DB size monitor: {:?}", e);
+            }
+        }
+    }
+
+    /// Logs the current size and sweeps if it's over the threshold. Returns
+    /// how many jobs the sweep purged, or None if no sweep ran.
+    pub fn check(&self) -> Result<Option<usize>> {
+        let size_bytes = self.storage.storage_size_bytes()?;
+        info!(size_bytes, "Broker DB size on disk");
+        if self.threshold_bytes == 0 || size_bytes <= self.threshold_bytes {
+            return Ok(None);
+        }
+
+        let Some(retention) = self.retention.as_ref() else {
+            warn!(size_bytes, threshold_bytes = self.threshold_bytes, "Broker DB over size threshold, but job_retention_days = 0 leaves nothing to sweep");
+            return Ok(None);
+        };
+        warn!(size_bytes, threshold_bytes = self.threshold_bytes, "Broker DB over size threshold, running retention sweep");
+        let purged = retention.sweep()?;
+        // Deletes only show up on disk once sled has written them out
+        self.storage.flush()?;
+        let after_bytes = self.storage.storage_size_bytes()?;
+        info!(before_bytes = size_bytes, after_bytes, purged, "Retention sweep for DB size done");
+        Ok(Some(purged))
+    }
+}
@@ -1,12 +1,108 @@
-use crate::broker::types::{BookingJob, JobState, NotificationRecord, NotificationState};
-use anyhow::{Context, Result};
+use crate::broker::clock::{Clock, SystemClock};
+use crate::broker::crypto::{DbEncryptionKey, RecordCipher};
+use crate::broker::types::{BookingJob, InboundOp, InboundOpState, JobEvent, JobState, NotificationRecord, NotificationState};
+use crate::config::DurabilityMode;
+use anyhow::{bail, Context, Result};
 use bincode;
-use tracing::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree};
+use sled::Transactional;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::{debug, info};
+
+/// Key in the `meta` tree recording how record values are stored
+const FORMAT_KEY: &str = "format";
+const FORMAT_PLAIN: &[u8] = b"plain";
+const FORMAT_ENCRYPTED: &[u8] = b"chacha20poly1305";
+/// Known value sealed with the key, to reject a wrong key at open time
+const KEY_CHECK_KEY: &str = "key_check";
+const KEY_CHECK_PLAINTEXT: &[u8] = b"hybrid-connection-health/broker";
+/// Job events buffered per subscriber; slower subscribers lose the oldest
+const JOB_EVENTS_CAPACITY: usize = 256;
+
+/// Background flush interval of sled in `DurabilityMode::Batched`
+pub const BATCHED_FLUSH_EVERY_MS: u64 = 500;
+
+/// Finished jobs removed per transaction by `purge_finished_jobs`
+const PURGE_BATCH_SIZE: usize = 256;
 
 pub struct BrokerStorage {
     db: sled::Db,
     booking_jobs: sled::Tree,
     notification_outbox: sled::Tree,
+    /// `OpSubmit`s received from peers, by op_id
+    inbound_ops: sled::Tree,
+    /// Scheduling indexes: `next_attempt_at` (u64 BE) ++ correlation_id -> (),
+    /// for queued jobs and pending notifications, range-scanned up to "now"
+    queued_index: sled::Tree,
+    pending_index: sled::Tree,
+    /// `received_at` (u64 BE) ++ op_id -> (), for ops the op worker hasn't
+    /// processed yet, so its poll doesn't walk the processed ones
+    received_index: sled::Tree,
+    /// Per-state record counts ("jobs:{state}", "notifications:{state}" -> u64 BE),
+    /// kept up to date on every transition and rebuilt from the data on open
+    counters: sled::Tree,
+    /// Set when records are encrypted at rest (see `db_encryption_key`)
+    cipher: Option<RecordCipher>,
+    /// Job state transitions for live subscribers (e.g. `GET /jobs/events`)
+    job_events: broadcast::Sender<JobEvent>,
+    durability: DurabilityMode,
+    /// "Now" for timestamps and due-record scans; the whole broker reads it
+    /// through `now_ms` so tests can drive time with a `MockClock`
+    clock: Arc<dyn Clock>,
+}
+
+/// Result of `BrokerStorage::requeue_job`
+#[derive(Debug, PartialEq)]
+pub enum RequeueOutcome {
+    Requeued,
+    NotFound,
+    /// Only failed jobs can be retried; carries the job's current state
+    NotFailed(JobState),
+}
+
+/// Fields an operator may change on a job through `BrokerStorage::patch_job`
+#[derive(Debug, Default)]
+pub struct JobPatch {
+    pub state: Option<JobState>,
+    pub next_attempt_at: Option<i64>,
+}
+
+/// Result of `BrokerStorage::patch_job`
+#[derive(Debug)]
+pub enum PatchOutcome {
+    /// Carries the job as it was before the patch and as it is now
+    Patched { before: Box<BookingJob>, after: Box<BookingJob> },
+    NotFound,
+    /// Only failed ↔ queued is allowed; anything else races the forwarder or
+    /// rewrites a settled outcome
+    InvalidTransition { from: JobState, to: JobState },
+    /// `next_attempt_at` only means something to a job that stays queued
+    NotQueued(JobState),
+}
+
+/// Consistency problems found by `BrokerStorage::reconcile`
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    pub jobs_scanned: usize,
+    pub notifications_scanned: usize,
+    /// Confirmed jobs with no notification record
+    pub confirmed_without_notification: Vec<String>,
+    /// Notifications whose booking job no longer exists
+    pub orphaned_notifications: Vec<String>,
+    /// Queued or sending jobs not updated since the cutoff
+    pub stuck_jobs: Vec<StuckJob>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StuckJob {
+    pub correlation_id: String,
+    pub state: &'static str,
+    pub attempts: u32,
+    pub updated_at: i64,
 }
 
 /// Parameters for updating job state
@@ -20,14 +116,20 @@ pub struct JobStateUpdate<'a> {
 }
 
 impl BrokerStorage {
-    pub fn new(db_path: &str) -> Result<Self> {
+    /// Opens the database, encrypting record values when a key is given.
+    /// An existing plaintext database is encrypted in place the first time a
+    /// key is configured; an encrypted one can't be opened without its key.
+    pub fn new(db_path: &str, encryption_key: Option<&DbEncryptionKey>, durability: DurabilityMode) -> Result<Self> {
         // Ensure parent directory exists
         if let Some(parent) = std::path::Path::new(db_path).parent() {
             std::fs::create_dir_all(parent)
                 .with_context(|| format!("Failed to create database directory: {}", parent.display()))?;
         }
 
-        let db = sled::open(db_path)
+        let db = sled::Config::new()
+            .path(db_path)
+            .flush_every_ms(Some(BATCHED_FLUSH_EVERY_MS))
+            .open()
             .with_context(|| format!("Failed to open sled database at: {}", db_path))?;
 
         let booking_jobs = db
@@ -38,49 +140,282 @@ impl BrokerStorage {
             .open_tree("notification_outbox")
             .context("Failed to open notification_outbox tree")?;
 
-        Ok(BrokerStorage {
+        let inbound_ops = db
+            .open_tree("inbound_ops")
+            .context("Failed to open inbound_ops tree")?;
+
+        let queued_index = db
+            .open_tree("queued_index")
+            .context("Failed to open queued_index tree")?;
+
+        let pending_index = db
+            .open_tree("pending_index")
+            .context("Failed to open pending_index tree")?;
+
+        let received_index = db
+            .open_tree("received_index")
+            .context("Failed to open received_index tree")?;
+
+        let counters = db
+            .open_tree("counters")
+            .context("Failed to open counters tree")?;
+
+        let meta = db
+            .open_tree("meta")
+            .context("Failed to open meta tree")?;
+
+        let storage = BrokerStorage {
             db,
             booking_jobs,
             notification_outbox,
-        })
+            inbound_ops,
+            queued_index,
+            pending_index,
+            received_index,
+            counters,
+            cipher: encryption_key.map(RecordCipher::new),
+            job_events: broadcast::channel(JOB_EVENTS_CAPACITY).0,
+            durability,
+            clock: Arc::new(SystemClock),
+        };
+        storage.drop_legacy_index_rows()?;
+        storage.check_format(&meta)?;
+        // Records, indexes and counters are written in one transaction, but
+        // DBs written by older versions may be out of step; rebuilding once
+        // at startup heals that
+        storage.rebuild_counters_and_indexes()?;
+        Ok(storage)
+    }
+
+    /// Replaces the system clock with a `MockClock`
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Current time (epoch ms) according to the broker's clock
+    pub fn now_ms(&self) -> i64 {
+        self.clock.now_ms()
+    }
+
+    /// Number of booking jobs in each state (states with no jobs count 0)
+    pub fn count_jobs_by_state(&self) -> Result<HashMap<JobState, u64>> {
+        JobState::ALL
+            .into_iter()
+            .map(|state| Ok((state, self.read_counter(&job_counter_key(state))?)))
+            .collect()
+    }
+
+    /// Jobs still queued or being sent; two counter reads, cheap enough to
+    /// check on every submission
+    pub fn count_unfinished_jobs(&self) -> Result<u64> {
+        Ok(self.read_counter(&job_counter_key(JobState::Queued))?
+            + self.read_counter(&job_counter_key(JobState::Sending))?)
+    }
+
+    /// Number of notifications in each state (states with none count 0)
+    pub fn count_notifications_by_state(&self) -> Result<HashMap<NotificationState, u64>> {
+        NotificationState::ALL
+            .into_iter()
+            .map(|state| Ok((state, self.read_counter(&notification_counter_key(state))?)))
+            .collect()
     }
 
-    /// Persist a booking job with idempotency check
-    pub fn persist_booking_job(&self, job: &BookingJob) -> Result<()> {
-        let key = job.correlation_id.as_str();
-        
-        // Check if already exists (idempotency)
-        if self.booking_jobs.contains_key(key)? {
+    /// Receives every job state transition from now on. A subscriber that
+    /// falls behind gets `RecvError::Lagged` and misses events; writers never wait.
+    pub fn subscribe_job_events(&self) -> broadcast::Receiver<JobEvent> {
+        self.job_events.subscribe()
+    }
+
+    fn publish_job_event(&self, from: Option<JobState>, job: &BookingJob) {
+        // Err only means nobody is listening
+        let _ = self.job_events.send(JobEvent {
+            correlation_id: job.correlation_id.clone(),
+            from: from.map(|s| s.as_str()),
+            to: job.state.as_str(),
+            attempts: job.attempts,
+            at_ms: job.updated_at,
+        });
+    }
+
+    /// Size of the database files on disk
+    pub fn storage_size_bytes(&self) -> Result<u64> {
+        self.db.size_on_disk().context("Failed to read sled DB size")
+    }
+
+    /// Flush all pending writes to disk (used on shutdown)
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush().context("Failed to flush sled DB")?;
+        Ok(())
+    }
+
+    /// Flushes after a single write in `Strict` mode; `Batched` leaves it to
+    /// sled's background flush
+    fn flush_write(&self, what: &str) -> Result<()> {
+        if self.durability == DurabilityMode::Strict {
+            self.db
+                .flush()
+                .with_context(|| format!("Failed to flush sled DB after {}", what))?;
+        }
+        Ok(())
+    }
+
+    /// Persist a booking job unless one with the same correlation_id exists.
+    /// The check and the insert are a single transaction, so concurrent
+    /// submissions can't both insert; the loser gets the stored job back.
+    pub fn persist_booking_job(&self, job: &BookingJob) -> Result<Option<BookingJob>> {
+        // Store job, index entry and counter only if the key is still absent
+        let insert = IndexedWrite {
+            key: job.correlation_id.clone(),
+            expected: None,
+            value: self.encode(job).context("Failed to serialize booking job")?,
+            old_index_key: None,
+            new_index_key: job_index_key(job),
+            counter_moves: vec![(job_counter_key(job.state), 1)],
+        };
+        if !apply_indexed_write(&self.booking_jobs, &self.queued_index, &self.counters, &insert)
+            .context("Failed to insert booking job")?
+        {
             debug!(correlation_id = %job.correlation_id, "Booking job already exists, skipping insert");
-            return Ok(());
+            let existing = self
+                .get_booking_job(&job.correlation_id)?
+                .context("Booking job vanished during insert")?;
+            return Ok(Some(existing));
         }
 
-        // Serialize job
-        let value = bincode::serialize(job)
-            .context("Failed to serialize booking job")?;
+        // In strict mode, durable persist before ACK is sent
+        self.flush_write("booking insert")?;
 
-        // Store job
-        self.booking_jobs
-            .insert(key, value)
-            .context("Failed to insert booking job")?;
+        self.publish_job_event(None, job);
+        debug!(correlation_id = %job.correlation_id, "Booking job persisted");
+        Ok(None)
+    }
 
-        // Update index for scheduling queries
-        self.update_job_index(job)?;
+    /// Persist an op received from a peer unless one with the same op_id
+    /// exists (a resend). Returns whether it was newly stored.
+    pub fn persist_inbound_op(&self, op: &InboundOp) -> Result<bool> {
+        // The op and its received_index entry land together, only if the key is still absent
+        let insert = IndexedWrite {
+            key: op.op_id.clone(),
+            expected: None,
+            value: self.encode(op).context("Failed to serialize inbound op")?,
+            old_index_key: None,
+            new_index_key: op_index_key(op),
+            counter_moves: Vec::new(),
+        };
+        let inserted = apply_indexed_write(&self.inbound_ops, &self.received_index, &self.counters, &insert)
+            .context("Failed to insert inbound op")?;
+        if !inserted {
+            debug!(op_id = %op.op_id, "Inbound op already exists, skipping insert");
+            return Ok(false);
+        }
 
-        // Ensure durable persist before ACK is sent
-        self.db.flush().context("Failed to flush sled DB after booking insert")?;
+        // In strict mode, durable persist before the OpAck is sent
+        self.flush_write("op insert")?;
+        debug!(op_id = %op.op_id, "Inbound op persisted");
+        Ok(true)
+    }
 
-        debug!(correlation_id = %job.correlation_id, "Booking job persisted");
+    /// Get an inbound op by op_id
+    pub fn get_inbound_op(&self, op_id: &str) -> Result<Option<InboundOp>> {
+        Ok(self.get_stored_op(op_id)?.map(|stored| stored.record))
+    }
+
+    fn get_stored_op(&self, op_id: &str) -> Result<Option<Stored<InboundOp>>> {
+        match self.inbound_ops.get(op_id)? {
+            Some(raw) => {
+                let record = self.decode(&raw).context("Failed to deserialize inbound op")?;
+                Ok(Some(Stored { record, raw }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Up to `limit` ops not processed yet, oldest first
+    pub fn get_received_ops(&self, limit: usize) -> Result<Vec<InboundOp>> {
+        let mut ops = Vec::new();
+        for key in self.received_index.iter().keys().take(limit) {
+            let key = key.context("Failed to read received_index")?;
+            let op_id = String::from_utf8_lossy(&key[8..]);
+            // Index and op are written together, so a miss means it was purged in between
+            if let Some(op) = self.get_inbound_op(&op_id)? {
+                ops.push(op);
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Marks an inbound op processed
+    pub fn mark_op_processed(&self, op_id: &str) -> Result<()> {
+        loop {
+            let old = self
+                .get_stored_op(op_id)?
+                .ok_or_else(|| anyhow::anyhow!("Inbound op not found: {}", op_id))?;
+            let op = InboundOp {
+                state: InboundOpState::Processed,
+                processed_at: Some(self.clock.now_ms()),
+                ..old.record.clone()
+            };
+            let transition = IndexedWrite {
+                key: op.op_id.clone(),
+                expected: Some(old.raw.clone()),
+                value: self.encode(&op).context("Failed to serialize inbound op")?,
+                old_index_key: op_index_key(&old.record),
+                new_index_key: None,
+                counter_moves: Vec::new(),
+            };
+            if apply_indexed_write(&self.inbound_ops, &self.received_index, &self.counters, &transition)
+                .context("Failed to update inbound op")?
+            {
+                break;
+            }
+        }
+        self.flush_write("op update")?;
         Ok(())
     }
 
+    /// Every inbound op, in op_id order
+    pub fn all_inbound_ops(&self) -> Result<Vec<InboundOp>> {
+        let mut ops = Vec::new();
+        for item in self.inbound_ops.iter() {
+            let (_, value) = item.context("Failed to read from inbound_ops tree")?;
+            ops.push(self.decode(&value).context("Failed to deserialize inbound op")?);
+        }
+        Ok(ops)
+    }
+
+    /// Deletes ops processed before `cutoff_ms`; ops still waiting for the op
+    /// worker are kept regardless of age. Returns how many were deleted.
+    pub fn purge_processed_ops(&self, cutoff_ms: i64) -> Result<usize> {
+        let mut batch = sled::Batch::default();
+        let mut purged = 0;
+        for op in self.all_inbound_ops()? {
+            if op.processed_at.is_some_and(|at| at < cutoff_ms) {
+                batch.remove(op.op_id.as_str());
+                purged += 1;
+            }
+        }
+        if purged > 0 {
+            // Processed ops have no received_index entry left to remove
+            self.inbound_ops.apply_batch(batch).context("Failed to purge inbound ops")?;
+            self.db.flush().context("Failed to flush sled DB after op purge")?;
+            debug!(count = purged, "Purged processed inbound ops");
+        }
+        Ok(purged)
+    }
+
     /// Get a booking job by correlation_id
     pub fn get_booking_job(&self, correlation_id: &str) -> Result<Option<BookingJob>> {
+        Ok(self.get_stored_job(correlation_id)?.map(|stored| stored.record))
+    }
+
+    /// Get a booking job together with its stored bytes, for a conditional write
+    pub(crate) fn get_stored_job(&self, correlation_id: &str) -> Result<Option<Stored<BookingJob>>> {
         match self.booking_jobs.get(correlation_id)? {
-            Some(value) => {
-                let job: BookingJob = bincode::deserialize(&value)
-                    .context("Failed to deserialize booking job")?;
-                Ok(Some(job))
+            Some(raw) => {
+                let record = self.decode(&raw).context("Failed to deserialize booking job")?;
+                Ok(Some(Stored { record, raw }))
             }
             None => Ok(None),
         }
@@ -92,11 +427,22 @@ impl BrokerStorage {
         correlation_id: &str,
         update: JobStateUpdate,
     ) -> Result<()> {
-        let mut job = self
-            .get_booking_job(correlation_id)?
-            .ok_or_else(|| anyhow::anyhow!("Job not found: {}", correlation_id))?;
+        loop {
+            let old_job = self
+                .get_stored_job(correlation_id)?
+                .ok_or_else(|| anyhow::anyhow!("Job not found: {}", correlation_id))?;
+            let job = self.updated_job(&old_job.record, &update);
+            // A job changed since it was read is read again, so the counters
+            // always move from the state actually replaced
+            if self.write_job_transition(&old_job, &job)? {
+                return Ok(());
+            }
+        }
+    }
 
-        // Update fields
+    /// `job` with `update` applied and `updated_at` set to now
+    fn updated_job(&self, job: &BookingJob, update: &JobStateUpdate) -> BookingJob {
+        let mut job = job.clone();
         job.state = update.state;
         if let Some(att) = update.attempts {
             job.attempts = att;
@@ -113,116 +459,277 @@ impl BrokerStorage {
         if let Some(resp) = update.central_response_json {
             job.central_response_json = Some(resp.to_string());
         }
-        job.updated_at = chrono::Utc::now().timestamp_millis();
+        job.updated_at = self.clock.now_ms();
+        job
+    }
 
-        // Remove old index entry
-        self.remove_job_index(&job)?;
+    /// Writes `new` over `old` (see `job_transition`), then flushes and
+    /// publishes the transition. Returns false, writing nothing, if the job
+    /// changed since `old` was read.
+    fn write_job_transition(&self, old: &Stored<BookingJob>, new: &BookingJob) -> Result<bool> {
+        let transition = self.job_transition(old, new)?;
+        if !self.apply_job_transition(&transition)? {
+            debug!(correlation_id = %new.correlation_id, "Job changed while being updated");
+            return Ok(false);
+        }
 
-        // Update job
-        let value = bincode::serialize(&job)
-            .context("Failed to serialize updated booking job")?;
-        self.booking_jobs
-            .insert(correlation_id, value)
-            .context("Failed to update booking job")?;
+        // Ensure durability of state transition
+        self.flush_write("job update")?;
 
-        // Update index
-        self.update_job_index(&job)?;
+        if old.record.state != new.state {
+            self.publish_job_event(Some(old.record.state), new);
+        }
+        debug!(correlation_id = %new.correlation_id, state = %new.state.as_str(), "Job state updated");
+        Ok(true)
+    }
 
-        // Ensure durability of state transition
-        self.db.flush().context("Failed to flush sled DB after job update")?;
+    /// Puts a failed job back in the queue with a fresh attempt budget, due now
+    pub fn requeue_job(&self, correlation_id: &str) -> Result<RequeueOutcome> {
+        loop {
+            let Some(job) = self.get_stored_job(correlation_id)? else {
+                return Ok(RequeueOutcome::NotFound);
+            };
+            if job.record.state != JobState::Failed {
+                return Ok(RequeueOutcome::NotFailed(job.record.state));
+            }
+            let requeued = self.updated_job(&job.record, &self.requeue_update());
+            // Only written while the job is still the failed one checked above
+            if self.write_job_transition(&job, &requeued)? {
+                info!(correlation_id = %correlation_id, "Failed job requeued");
+                return Ok(RequeueOutcome::Requeued);
+            }
+        }
+    }
 
-        debug!(correlation_id = %correlation_id, state = %job.state.as_str(), "Job state updated");
-        Ok(())
+    /// Back to queued with a fresh attempt budget, due now
+    fn requeue_update(&self) -> JobStateUpdate<'static> {
+        JobStateUpdate {
+            state: JobState::Queued,
+            attempts: Some(0),
+            next_attempt_at: Some(self.clock.now_ms()),
+            last_error: None,
+            http_status: None,
+            central_response_json: None,
+        }
     }
 
-    /// Get due jobs (state=queued and next_attempt_at <= now)
-    pub fn get_due_jobs(&self, limit: usize) -> Result<Vec<BookingJob>> {
-        let now = chrono::Utc::now().timestamp_millis();
-        let mut jobs = Vec::new();
+    /// Applies an operator's correction: failed → queued (with a fresh attempt
+    /// budget, like `requeue_job`), queued → failed, and/or a new
+    /// `next_attempt_at` for a queued job. The check and the write are one
+    /// conditional write, so a job the forwarder moved on in the meantime is
+    /// checked again in its new state instead of being overwritten.
+    pub fn patch_job(&self, correlation_id: &str, patch: &JobPatch) -> Result<PatchOutcome> {
+        loop {
+            let Some(before) = self.get_stored_job(correlation_id)? else {
+                return Ok(PatchOutcome::NotFound);
+            };
+            let to = patch.state.unwrap_or(before.record.state);
+            let update = match (before.record.state, to) {
+                (from, to) if from == to => JobStateUpdate {
+                    state: to,
+                    attempts: None,
+                    next_attempt_at: None,
+                    last_error: None,
+                    http_status: None,
+                    central_response_json: None,
+                },
+                (JobState::Failed, JobState::Queued) => self.requeue_update(),
+                (JobState::Queued, JobState::Failed) => JobStateUpdate {
+                    state: JobState::Failed,
+                    attempts: None,
+                    next_attempt_at: None,
+                    last_error: Some("Marked failed by an operator"),
+                    http_status: None,
+                    central_response_json: None,
+                },
+                (from, to) => return Ok(PatchOutcome::InvalidTransition { from, to }),
+            };
+            if patch.next_attempt_at.is_some() && to != JobState::Queued {
+                return Ok(PatchOutcome::NotQueued(to));
+            }
+            let update = JobStateUpdate { next_attempt_at: patch.next_attempt_at.or(update.next_attempt_at), ..update };
+
+            let after = self.updated_job(&before.record, &update);
+            if self.write_job_transition(&before, &after)? {
+                return Ok(PatchOutcome::Patched { before: Box::new(before.record), after: Box::new(after) });
+            }
+        }
+    }
 
-        // Scan jobs with composite key prefix: "queued:{next_attempt_at}"
-        // We iterate over all jobs since sled doesn't support range queries easily
-        // In production, consider using a secondary index tree
+    /// Deletes a job, its notification and their index entries in one
+    /// transaction. Returns false if there was no such job.
+    pub fn delete_job(&self, correlation_id: &str) -> Result<bool> {
+        loop {
+            let Some(job) = self.get_stored_job(correlation_id)? else {
+                return Ok(false);
+            };
+            let notif = self.get_stored_notification(correlation_id)?;
+            // A job or notification changed since it was read is read again,
+            // so the counters and index keys match the records actually removed
+            if self.remove_job_records(&[(job, notif)])? == 1 {
+                break;
+            }
+            debug!(correlation_id = %correlation_id, "Job changed while being deleted");
+        }
+        self.flush_write("job delete")?;
+
+        info!(correlation_id = %correlation_id, "Job deleted");
+        Ok(true)
+    }
+
+    /// Removes each job and its notification, with their index entries and
+    /// counters, in one transaction. Pairs no longer stored exactly as read
+    /// are skipped. Returns how many jobs were removed.
+    fn remove_job_records(&self, records: &[(Stored<BookingJob>, Option<Stored<NotificationRecord>>)]) -> Result<usize> {
+        if records.is_empty() {
+            return Ok(0);
+        }
+        (&self.booking_jobs, &self.queued_index, &self.notification_outbox, &self.pending_index, &self.counters)
+            .transaction(|(jobs_tx, queued_tx, notifications_tx, pending_tx, counters_tx)| {
+                let mut removed = 0;
+                for (job, notif) in records {
+                    let correlation_id = job.record.correlation_id.as_str();
+                    if jobs_tx.get(correlation_id)?.as_ref() != Some(&job.raw)
+                        || notifications_tx.get(correlation_id)?.as_ref() != notif.as_ref().map(|n| &n.raw)
+                    {
+                        continue;
+                    }
+                    jobs_tx.remove(correlation_id)?;
+                    if let Some(key) = job_index_key(&job.record) {
+                        queued_tx.remove(key)?;
+                    }
+                    add_to_counter(counters_tx, &job_counter_key(job.record.state), -1)?;
+                    if let Some(notif) = notif {
+                        notifications_tx.remove(correlation_id)?;
+                        if let Some(key) = notification_index_key(&notif.record) {
+                            pending_tx.remove(key)?;
+                        }
+                        add_to_counter(counters_tx, &notification_counter_key(notif.record.state), -1)?;
+                    }
+                    removed += 1;
+                }
+                Ok(removed)
+            })
+            .map_err(|e: TransactionError<()>| anyhow::anyhow!("Failed to delete jobs: {:?}", e))
+    }
+
+    /// Deletes confirmed/failed jobs last updated before `cutoff_ms`, together
+    /// with their notifications and any index entries, `PURGE_BATCH_SIZE` jobs
+    /// per transaction. Queued and sending jobs are kept regardless of age,
+    /// and so is a job that changed (e.g. was retried) after it was scanned.
+    /// Returns how many jobs were deleted.
+    pub fn purge_finished_jobs(&self, cutoff_ms: i64) -> Result<usize> {
+        let mut purged = 0;
+        let mut batch = Vec::new();
         for item in self.booking_jobs.iter() {
-            let (key, value) = item.context("Failed to read from booking_jobs tree")?;
-            
-            // Skip index entries
-            if key.len() > 64 {
+            let (_, raw) = item.context("Failed to read from booking_jobs tree")?;
+            let record: BookingJob = self.decode(&raw).context("Failed to deserialize booking job")?;
+            if !matches!(record.state, JobState::Confirmed | JobState::Failed) || record.updated_at >= cutoff_ms {
                 continue;
             }
+            let notif = self.get_stored_notification(&record.correlation_id)?;
+            batch.push((Stored { record, raw }, notif));
+            if batch.len() == PURGE_BATCH_SIZE {
+                purged += self.remove_job_records(&batch)?;
+                batch.clear();
+            }
+        }
+        purged += self.remove_job_records(&batch)?;
+        if purged > 0 {
+            self.flush_write("purge")?;
+            debug!(count = purged, "Purged finished jobs");
+        }
+        Ok(purged)
+    }
 
-            let job: BookingJob = bincode::deserialize(&value)
-                .context("Failed to deserialize booking job")?;
+    /// Scans jobs and notifications for records that are out of step: confirmed
+    /// jobs missing their notification, notifications without a job, and
+    /// queued/sending jobs last updated before `stuck_cutoff_ms`. Read-only.
+    pub fn reconcile(&self, stuck_cutoff_ms: i64) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        for item in self.booking_jobs.iter() {
+            let (_, value) = item.context("Failed to read from booking_jobs tree")?;
+            let job: BookingJob = self.decode(&value).context("Failed to deserialize booking job")?;
+            report.jobs_scanned += 1;
+            match job.state {
+                JobState::Confirmed if !self.notification_outbox.contains_key(job.correlation_id.as_str())? => {
+                    report.confirmed_without_notification.push(job.correlation_id);
+                }
+                JobState::Queued | JobState::Sending if job.updated_at < stuck_cutoff_ms => {
+                    report.stuck_jobs.push(StuckJob {
+                        state: job.state.as_str(),
+                        attempts: job.attempts,
+                        updated_at: job.updated_at,
+                        correlation_id: job.correlation_id,
+                    });
+                }
+                _ => {}
+            }
+        }
+        for item in self.notification_outbox.iter() {
+            let (key, _) = item.context("Failed to read from notification_outbox tree")?;
+            report.notifications_scanned += 1;
+            if !self.booking_jobs.contains_key(&key)? {
+                report.orphaned_notifications.push(String::from_utf8_lossy(&key).into_owned());
+            }
+        }
+        Ok(report)
+    }
 
-            // Filter due jobs
-            if job.state == JobState::Queued
-                && job.next_attempt_at <= now
-                && jobs.len() < limit
-            {
+    /// Get due jobs (state=queued and next_attempt_at <= now), earliest first
+    pub fn get_due_jobs(&self, limit: usize) -> Result<Vec<BookingJob>> {
+        let now = self.clock.now_ms();
+        let mut jobs = Vec::new();
+
+        for correlation_id in scan_due(&self.queued_index, now, limit)? {
+            // The index is only written alongside the job, so a miss means the
+            // job was purged in between; skip it
+            if let Some(job) = self.get_booking_job(&correlation_id)? {
                 jobs.push(job);
             }
         }
 
-        // Sort by next_attempt_at
-        jobs.sort_by_key(|j| j.next_attempt_at);
-
         debug!(count = jobs.len(), "Retrieved due jobs");
         Ok(jobs)
     }
 
-    /// Persist a notification record (idempotent)
-    pub fn persist_notification(&self, notif: &NotificationRecord) -> Result<()> {
-        let key = notif.correlation_id.as_str();
-
-        // Check if already exists (idempotency)
-        if self.notification_outbox.contains_key(key)? {
+    /// Persist a notification record (idempotent). Returns false if one for
+    /// the same correlation_id already existed and nothing was written.
+    pub fn persist_notification(&self, notif: &NotificationRecord) -> Result<bool> {
+        // Record, index entry and counter land only if the key is still absent (idempotency)
+        let insert = IndexedWrite {
+            key: notif.correlation_id.clone(),
+            expected: None,
+            value: self.encode(notif).context("Failed to serialize notification")?,
+            old_index_key: None,
+            new_index_key: notification_index_key(notif),
+            counter_moves: vec![(notification_counter_key(notif.state), 1)],
+        };
+        if !apply_indexed_write(&self.notification_outbox, &self.pending_index, &self.counters, &insert)
+            .context("Failed to insert notification")?
+        {
             debug!(correlation_id = %notif.correlation_id, "Notification already exists, skipping insert");
-            return Ok(());
+            return Ok(false);
         }
 
-        let value = bincode::serialize(notif)
-            .context("Failed to serialize notification")?;
-
-        self.notification_outbox
-            .insert(key, value)
-            .context("Failed to insert notification")?;
-
-        // Update index
-        self.update_notification_index(notif)?;
-
         // Durable persist
-        self.db.flush().context("Failed to flush sled DB after notification insert")?;
+        self.flush_write("notification insert")?;
 
         debug!(correlation_id = %notif.correlation_id, "Notification persisted");
-        Ok(())
+        Ok(true)
     }
 
-    /// Get due notifications (state=pending and next_attempt_at <= now)
+    /// Get due notifications (state=pending and next_attempt_at <= now), earliest first
     pub fn get_due_notifications(&self, limit: usize) -> Result<Vec<NotificationRecord>> {
-        let now = chrono::Utc::now().timestamp_millis();
+        let now = self.clock.now_ms();
         let mut notifications = Vec::new();
 
-        for item in self.notification_outbox.iter() {
-            let (key, value) = item.context("Failed to read from notification_outbox tree")?;
-            
-            // Skip index entries
-            if key.len() > 64 {
-                continue;
-            }
-
-            let notif: NotificationRecord = bincode::deserialize(&value)
-                .context("Failed to deserialize notification")?;
-
-            if notif.state == NotificationState::Pending
-                && notif.next_attempt_at <= now
-                && notifications.len() < limit
-            {
+        for correlation_id in scan_due(&self.pending_index, now, limit)? {
+            if let Some(notif) = self.get_notification(&correlation_id)? {
                 notifications.push(notif);
             }
         }
 
-        notifications.sort_by_key(|n| n.next_attempt_at);
-
         debug!(count = notifications.len(), "Retrieved due notifications");
         Ok(notifications)
     }
@@ -236,83 +743,473 @@ impl BrokerStorage {
         subject: Option<&str>,
         body: Option<&str>,
     ) -> Result<()> {
-        let mut notif = self
-            .get_notification(correlation_id)?
-            .ok_or_else(|| anyhow::anyhow!("Notification not found: {}", correlation_id))?;
+        loop {
+            let old_notif = self
+                .get_stored_notification(correlation_id)?
+                .ok_or_else(|| anyhow::anyhow!("Notification not found: {}", correlation_id))?;
+            let mut notif = old_notif.record.clone();
+
+            notif.state = state;
+            if let Some(sent_at) = simulated_sent_at {
+                notif.simulated_sent_at = Some(sent_at);
+            }
+            if let Some(subject) = subject {
+                notif.subject = subject.to_string();
+            }
+            if let Some(body) = body {
+                notif.body = body.to_string();
+            }
+            notif.updated_at = self.clock.now_ms();
 
-        notif.state = state;
-        if let Some(sent_at) = simulated_sent_at {
-            notif.simulated_sent_at = Some(sent_at);
+            // Changed since it was read: read it again
+            if self.write_notification_transition(&old_notif, &notif)? {
+                debug!(correlation_id = %correlation_id, state = %notif.state.as_str(), "Notification state updated");
+                return Ok(());
+            }
         }
-        if let Some(subject) = subject {
-            notif.subject = subject.to_string();
+    }
+
+    /// Puts a failed notification back to pending with a fresh attempt
+    /// budget, due now. Returns false if there's no failed notification
+    /// with this correlation_id.
+    pub fn requeue_notification(&self, correlation_id: &str) -> Result<bool> {
+        loop {
+            let Some(old_notif) = self.get_stored_notification(correlation_id)? else {
+                return Ok(false);
+            };
+            if old_notif.record.state != NotificationState::Failed {
+                return Ok(false);
+            }
+            let now = self.clock.now_ms();
+            let notif = NotificationRecord {
+                state: NotificationState::Pending,
+                attempts: 0,
+                next_attempt_at: now,
+                updated_at: now,
+                ..old_notif.record.clone()
+            };
+
+            // Only lands while it's still the failed record read above, so a
+            // concurrent requeue can't move the counters twice
+            if self.write_notification_transition(&old_notif, &notif)? {
+                info!(correlation_id = %correlation_id, "Failed notification requeued");
+                return Ok(true);
+            }
         }
-        if let Some(body) = body {
-            notif.body = body.to_string();
+    }
+
+    /// Requeues every failed notification last updated before
+    /// `updated_before_ms` (see `requeue_notification`). Returns how many.
+    pub fn requeue_failed_notifications(&self, updated_before_ms: i64) -> Result<usize> {
+        let mut requeued = 0;
+        for notif in self.all_notifications()? {
+            if notif.state == NotificationState::Failed
+                && notif.updated_at < updated_before_ms
+                && self.requeue_notification(&notif.correlation_id)?
+            {
+                requeued += 1;
+            }
         }
-        notif.updated_at = chrono::Utc::now().timestamp_millis();
+        Ok(requeued)
+    }
 
-        // Remove old index
-        self.remove_notification_index(&notif)?;
+    /// Writes `new` over `old` together with its pending-index and counter
+    /// changes, then flushes. Returns false, writing nothing, if the
+    /// notification changed since `old` was read.
+    fn write_notification_transition(&self, old: &Stored<NotificationRecord>, new: &NotificationRecord) -> Result<bool> {
+        // Same as jobs: the stale index entry goes away with the record write
+        let transition = IndexedWrite {
+            key: new.correlation_id.clone(),
+            expected: Some(old.raw.clone()),
+            value: self.encode(new).context("Failed to serialize updated notification")?,
+            old_index_key: notification_index_key(&old.record),
+            new_index_key: notification_index_key(new),
+            counter_moves: state_counter_moves(old.record.state, new.state, notification_counter_key),
+        };
+        if !apply_indexed_write(&self.notification_outbox, &self.pending_index, &self.counters, &transition)
+            .context("Failed to update notification")?
+        {
+            return Ok(false);
+        }
 
-        let value = bincode::serialize(&notif)
-            .context("Failed to serialize updated notification")?;
-        self.notification_outbox
-            .insert(correlation_id, value)
-            .context("Failed to update notification")?;
+        // Durable persist
+        self.flush_write("notification update")?;
+        Ok(true)
+    }
 
-        // Update index
-        self.update_notification_index(&notif)?;
+    /// Every booking job, in key order
+    pub fn all_booking_jobs(&self) -> Result<Vec<BookingJob>> {
+        let mut jobs = Vec::new();
+        for item in self.booking_jobs.iter() {
+            let (_, value) = item.context("Failed to read from booking_jobs tree")?;
+            jobs.push(self.decode(&value).context("Failed to deserialize booking job")?);
+        }
+        Ok(jobs)
+    }
 
-        // Durable persist
-        self.db.flush().context("Failed to flush sled DB after notification update")?;
+    /// Every notification record, in key order
+    pub fn all_notifications(&self) -> Result<Vec<NotificationRecord>> {
+        let mut notifications = Vec::new();
+        for item in self.notification_outbox.iter() {
+            let (_, value) = item.context("Failed to read from notification_outbox tree")?;
+            notifications.push(self.decode(&value).context("Failed to deserialize notification")?);
+        }
+        Ok(notifications)
+    }
 
-        debug!(correlation_id = %correlation_id, state = %notif.state.as_str(), "Notification state updated");
-        Ok(())
+    /// Notifications matching `state_filter` (all when `None`), paged with
+    /// `offset`/`limit`, plus the total number matching the filter
+    pub fn list_notifications(
+        &self,
+        state_filter: Option<NotificationState>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<NotificationRecord>, usize)> {
+        let mut page = Vec::new();
+        let mut total = 0;
+        for item in self.notification_outbox.iter() {
+            let (_, value) = item.context("Failed to read from notification_outbox tree")?;
+            let notif: NotificationRecord = self.decode(&value).context("Failed to deserialize notification")?;
+            if state_filter.is_some_and(|state| notif.state != state) {
+                continue;
+            }
+            if total >= offset && page.len() < limit {
+                page.push(notif);
+            }
+            total += 1;
+        }
+        Ok((page, total))
     }
 
     /// Get a notification by correlation_id
     pub fn get_notification(&self, correlation_id: &str) -> Result<Option<NotificationRecord>> {
+        Ok(self.get_stored_notification(correlation_id)?.map(|stored| stored.record))
+    }
+
+    /// Get a notification together with its stored bytes, for a conditional write
+    fn get_stored_notification(&self, correlation_id: &str) -> Result<Option<Stored<NotificationRecord>>> {
         match self.notification_outbox.get(correlation_id)? {
-            Some(value) => {
-                let notif: NotificationRecord = bincode::deserialize(&value)
-                    .context("Failed to deserialize notification")?;
-                Ok(Some(notif))
+            Some(raw) => {
+                let record = self.decode(&raw).context("Failed to deserialize notification")?;
+                Ok(Some(Stored { record, raw }))
             }
             None => Ok(None),
         }
     }
 
-    /// Update index for job scheduling queries
-    fn update_job_index(&self, job: &BookingJob) -> Result<()> {
-        if job.state == JobState::Queued {
-            // Create composite key: "queued:{next_attempt_at}:{correlation_id}"
-            let index_key = format!("queued:{}:{}", job.next_attempt_at, job.correlation_id);
-            self.booking_jobs.insert(index_key.as_str(), &[])?;
+    fn read_counter(&self, key: &str) -> Result<u64> {
+        Ok(self
+            .counters
+            .get(key)?
+            .map(|v| decode_counter(&v))
+            .unwrap_or(0))
+    }
+
+    /// Recounts every record by state and rebuilds the scheduling and
+    /// received-op indexes
+    fn rebuild_counters_and_indexes(&self) -> Result<()> {
+        let mut jobs: HashMap<JobState, u64> = HashMap::new();
+        let mut queued = sled::Batch::default();
+        for item in self.booking_jobs.iter() {
+            let (_, value) = item.context("Failed to read from booking_jobs tree")?;
+            let job: BookingJob = self.decode(&value).context("Failed to deserialize booking job")?;
+            *jobs.entry(job.state).or_default() += 1;
+            if let Some(index_key) = job_index_key(&job) {
+                queued.insert(index_key, &[] as &[u8]);
+            }
+        }
+
+        let mut notifications: HashMap<NotificationState, u64> = HashMap::new();
+        let mut pending = sled::Batch::default();
+        for item in self.notification_outbox.iter() {
+            let (_, value) = item.context("Failed to read from notification_outbox tree")?;
+            let notif: NotificationRecord = self.decode(&value).context("Failed to deserialize notification")?;
+            *notifications.entry(notif.state).or_default() += 1;
+            if let Some(index_key) = notification_index_key(&notif) {
+                pending.insert(index_key, &[] as &[u8]);
+            }
+        }
+
+        let mut batch = sled::Batch::default();
+        for state in JobState::ALL {
+            let count = jobs.get(&state).copied().unwrap_or(0);
+            batch.insert(job_counter_key(state).as_str(), &count.to_be_bytes());
+        }
+        for state in NotificationState::ALL {
+            let count = notifications.get(&state).copied().unwrap_or(0);
+            batch.insert(notification_counter_key(state).as_str(), &count.to_be_bytes());
+        }
+        self.counters.apply_batch(batch).context("Failed to rebuild counters")?;
+
+        self.queued_index.clear().context("Failed to clear queued_index")?;
+        self.queued_index.apply_batch(queued).context("Failed to rebuild queued_index")?;
+        self.pending_index.clear().context("Failed to clear pending_index")?;
+        self.pending_index.apply_batch(pending).context("Failed to rebuild pending_index")?;
+
+        let mut received = sled::Batch::default();
+        for op in self.all_inbound_ops()? {
+            if let Some(index_key) = op_index_key(&op) {
+                received.insert(index_key, &[] as &[u8]);
+            }
+        }
+        self.received_index.clear().context("Failed to clear received_index")?;
+        self.received_index.apply_batch(received).context("Failed to rebuild received_index")?;
+        Ok(())
+    }
+
+    /// Older versions kept "queued:{ts}:{id}" / "pending:{ts}:{id}" index rows
+    /// next to the records; the dedicated index trees replace them
+    fn drop_legacy_index_rows(&self) -> Result<()> {
+        for (tree, prefix) in [(&self.booking_jobs, "queued:"), (&self.notification_outbox, "pending:")] {
+            let mut batch = sled::Batch::default();
+            let mut dropped = 0;
+            for key in tree.scan_prefix(prefix).keys() {
+                batch.remove(key?);
+                dropped += 1;
+            }
+            if dropped > 0 {
+                tree.apply_batch(batch).context("Failed to drop legacy index rows")?;
+                info!(dropped, prefix, "Dropped legacy scheduling index rows");
+            }
         }
         Ok(())
     }
 
-    /// Remove old index entry
-    fn remove_job_index(&self, job: &BookingJob) -> Result<()> {
-        // Remove old index by scanning (sled limitation)
-        // In production, track old state
-        let _ = job;
+    /// Scheduling index entries currently stored for booking jobs
+    #[cfg(test)]
+    pub(crate) fn job_index_entries(&self) -> Result<Vec<(i64, String)>> {
+        self.queued_index
+            .iter()
+            .keys()
+            .map(|k| Ok(decode_index_key(&k?)))
+            .collect()
+    }
+
+    /// Builds the writes for moving a job from `old` to `new`: drop the old index
+    /// entry, store the job, add the new index entry and move the state
+    /// counters. Applied in one transaction so a crash can't leave the index
+    /// or the counts pointing at a stale state.
+    pub(crate) fn job_transition(&self, old: &Stored<BookingJob>, new: &BookingJob) -> Result<IndexedWrite> {
+        Ok(IndexedWrite {
+            key: new.correlation_id.clone(),
+            expected: Some(old.raw.clone()),
+            value: self.encode(new).context("Failed to serialize updated booking job")?,
+            old_index_key: job_index_key(&old.record),
+            new_index_key: job_index_key(new),
+            counter_moves: state_counter_moves(old.record.state, new.state, job_counter_key),
+        })
+    }
+
+    /// Applies a `job_transition`; false if the job changed since it was read
+    pub(crate) fn apply_job_transition(&self, transition: &IndexedWrite) -> Result<bool> {
+        apply_indexed_write(&self.booking_jobs, &self.queued_index, &self.counters, transition)
+            .context("Failed to apply booking job transition")
+    }
+
+    /// Serializes a record, sealing it when encryption at rest is on
+    fn encode<T: Serialize>(&self, record: &T) -> Result<Vec<u8>> {
+        let bytes = bincode::serialize(record)?;
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&bytes),
+            None => Ok(bytes),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T> {
+        match &self.cipher {
+            Some(cipher) => Ok(bincode::deserialize(&cipher.decrypt(value)?)?),
+            None => Ok(bincode::deserialize(value)?),
+        }
+    }
+
+    /// Reconciles the stored format marker with the configured key. Databases
+    /// from before the marker existed are plaintext.
+    fn check_format(&self, meta: &sled::Tree) -> Result<()> {
+        let format = meta.get(FORMAT_KEY)?;
+        let encrypted = match format.as_deref() {
+            None | Some(FORMAT_PLAIN) => false,
+            Some(FORMAT_ENCRYPTED) => true,
+            Some(other) => bail!("Unknown broker DB format '{}'", String::from_utf8_lossy(other)),
+        };
+
+        match (&self.cipher, encrypted) {
+            (None, false) => {
+                if format.is_none() {
+                    meta.insert(FORMAT_KEY, FORMAT_PLAIN)?;
+                }
+            }
+            (None, true) => bail!("Broker DB is encrypted; set db_encryption_key or db_encryption_key_file"),
+            (Some(cipher), true) => {
+                let check = meta
+                    .get(KEY_CHECK_KEY)?
+                    .context("Encrypted broker DB is missing its key check")?;
+                if cipher.decrypt(&check).ok().as_deref() != Some(KEY_CHECK_PLAINTEXT) {
+                    bail!("Wrong db_encryption_key for the broker DB");
+                }
+            }
+            (Some(cipher), false) => self.encrypt_in_place(cipher, meta)?,
+        }
         Ok(())
     }
 
-    /// Update index for notification scheduling
-    fn update_notification_index(&self, notif: &NotificationRecord) -> Result<()> {
-        if notif.state == NotificationState::Pending {
-            let index_key = format!("pending:{}:{}", notif.next_attempt_at, notif.correlation_id);
-            self.notification_outbox.insert(index_key.as_str(), &[])?;
+    /// Seals every plaintext record and flips the format marker, all in one
+    /// transaction so an interrupted migration leaves the DB readable as before
+    fn encrypt_in_place(&self, cipher: &RecordCipher, meta: &sled::Tree) -> Result<()> {
+        let mut jobs = Vec::new();
+        for item in self.booking_jobs.iter() {
+            let (key, value) = item.context("Failed to read from booking_jobs tree")?;
+            jobs.push((key, cipher.encrypt(&value)?));
+        }
+        let mut notifications = Vec::new();
+        for item in self.notification_outbox.iter() {
+            let (key, value) = item.context("Failed to read from notification_outbox tree")?;
+            notifications.push((key, cipher.encrypt(&value)?));
+        }
+        let mut ops = Vec::new();
+        for item in self.inbound_ops.iter() {
+            let (key, value) = item.context("Failed to read from inbound_ops tree")?;
+            ops.push((key, cipher.encrypt(&value)?));
         }
+        let key_check = cipher.encrypt(KEY_CHECK_PLAINTEXT)?;
+
+        (&self.booking_jobs, &self.notification_outbox, &self.inbound_ops, meta)
+            .transaction(|(jobs_tx, notifications_tx, ops_tx, meta_tx)| {
+                for (key, value) in &jobs {
+                    jobs_tx.insert(key, value.as_slice())?;
+                }
+                for (key, value) in &notifications {
+                    notifications_tx.insert(key, value.as_slice())?;
+                }
+                for (key, value) in &ops {
+                    ops_tx.insert(key, value.as_slice())?;
+                }
+                meta_tx.insert(KEY_CHECK_KEY, key_check.as_slice())?;
+                meta_tx.insert(FORMAT_KEY, FORMAT_ENCRYPTED)?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e: TransactionError<()>| anyhow::anyhow!("Failed to encrypt broker DB: {:?}", e))?;
+        self.db.flush().context("Failed to flush sled DB after encryption")?;
+
+        info!(jobs = jobs.len(), notifications = notifications.len(), "Encrypted existing broker DB records");
         Ok(())
     }
+}
 
-    /// Remove old notification index
-    fn remove_notification_index(&self, _notif: &NotificationRecord) -> Result<()> {
-        // Remove old index by scanning (sled limitation)
+fn job_counter_key(state: JobState) -> String {
+    format!("jobs:{}", state.as_str())
+}
+
+fn notification_counter_key(state: NotificationState) -> String {
+    format!("notifications:{}", state.as_str())
+}
+
+fn decode_counter(bytes: &[u8]) -> u64 {
+    bytes.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
+
+/// Adjusts a counter inside a transaction (never below zero)
+fn add_to_counter(counters: &TransactionalTree, key: &str, delta: i64) -> ConflictableTransactionResult<(), ()> {
+    let current = counters.get(key)?.map(|v| decode_counter(&v)).unwrap_or(0);
+    counters.insert(key, &current.saturating_add_signed(delta).to_be_bytes())?;
+    Ok(())
+}
+
+/// A record as read from its tree, with the stored bytes it was decoded from
+pub(crate) struct Stored<T> {
+    pub record: T,
+    raw: sled::IVec,
+}
+
+/// A record write that also moves its scheduling index entry and the state
+/// counters. It only lands while the stored record is still `expected` (the
+/// bytes the new value was computed from, or absent for an insert), so a
+/// decision made on a stale read never overwrites a concurrent change.
+pub(crate) struct IndexedWrite {
+    key: String,
+    expected: Option<sled::IVec>,
+    value: Vec<u8>,
+    old_index_key: Option<Vec<u8>>,
+    new_index_key: Option<Vec<u8>>,
+    /// (counter key, delta) pairs
+    counter_moves: Vec<(String, i64)>,
+}
+
+/// Applies an `IndexedWrite` to a record tree, its index and the counters in
+/// one transaction. Returns false, writing nothing, if the stored record is
+/// no longer the expected one.
+fn apply_indexed_write(records: &sled::Tree, index: &sled::Tree, counters: &sled::Tree, write: &IndexedWrite) -> Result<bool> {
+    let applied = (records, index, counters).transaction(|(records_tx, index_tx, counters_tx)| {
+        if records_tx.get(write.key.as_str())? != write.expected {
+            return Err(ConflictableTransactionError::Abort(()));
+        }
+        if let Some(old_key) = &write.old_index_key {
+            index_tx.remove(old_key.as_slice())?;
+        }
+        records_tx.insert(write.key.as_str(), write.value.as_slice())?;
+        if let Some(new_key) = &write.new_index_key {
+            index_tx.insert(new_key.as_slice(), &[] as &[u8])?;
+        }
+        for (key, delta) in &write.counter_moves {
+            add_to_counter(counters_tx, key, *delta)?;
+        }
         Ok(())
+    });
+    match applied {
+        Ok(()) => Ok(true),
+        Err(TransactionError::Abort(())) => Ok(false),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
     }
 }
+
+/// Counter moves for a record going from `old` to `new` state (none if equal)
+fn state_counter_moves<S: PartialEq>(old: S, new: S, key: impl Fn(S) -> String) -> Vec<(String, i64)> {
+    if old == new {
+        Vec::new()
+    } else {
+        vec![(key(old), -1), (key(new), 1)]
+    }
+}
+
+/// Index key: `next_attempt_at` as big-endian u64 (so byte order is time
+/// order) followed by the correlation_id
+fn index_key(next_attempt_at: i64, correlation_id: &str) -> Vec<u8> {
+    let mut key = (next_attempt_at.max(0) as u64).to_be_bytes().to_vec();
+    key.extend_from_slice(correlation_id.as_bytes());
+    key
+}
+
+#[cfg(test)]
+fn decode_index_key(key: &[u8]) -> (i64, String) {
+    let (ts, id) = key.split_at(8);
+    let ts = u64::from_be_bytes(ts.try_into().unwrap_or_default());
+    (ts as i64, String::from_utf8_lossy(id).into_owned())
+}
+
+/// Correlation ids in `index` with `next_attempt_at <= now`, earliest first
+fn scan_due(index: &sled::Tree, now: i64, limit: usize) -> Result<Vec<String>> {
+    let upper = (now.max(0) as u64).saturating_add(1).to_be_bytes();
+    index
+        .range(..upper)
+        .keys()
+        .take(limit)
+        .map(|key| {
+            let key = key.context("Failed to read scheduling index")?;
+            Ok(String::from_utf8_lossy(&key[8..]).into_owned())
+        })
+        .collect()
+}
+
+/// Index key for a job, only while it's queued
+fn job_index_key(job: &BookingJob) -> Option<Vec<u8>> {
+    (job.state == JobState::Queued).then(|| index_key(job.next_attempt_at, &job.correlation_id))
+}
+
+/// Index key for an inbound op, only until it's processed
+fn op_index_key(op: &InboundOp) -> Option<Vec<u8>> {
+    (op.state == InboundOpState::Received).then(|| index_key(op.received_at, &op.op_id))
+}
+
+/// Index key for a notification, only while it's pending
+fn notification_index_key(notif: &NotificationRecord) -> Option<Vec<u8>> {
+    (notif.state == NotificationState::Pending).then(|| index_key(notif.next_attempt_at, &notif.correlation_id))
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+const INITIAL_RESTART_DELAY: Duration = Duration::from_millis(500);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// A worker that ran at least this long before dying gets the initial delay again
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// Run a broker worker in its own task, restarting it with exponential backoff
+/// if it panics or returns an error, until `shutdown` flips to true.
+///
+/// `start` is called once per (re)start and must return the worker's run future.
+pub async fn supervise<F, Fut>(name: &'static str, mut shutdown: watch::Receiver<bool>, mut start: F)
+where
+    F: FnMut(watch::Receiver<bool>) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut restart_delay = INITIAL_RESTART_DELAY;
+
+    loop {
+        let started_at = Instant::now();
+        let outcome = tokio::spawn(start(shutdown.clone())).await;
+
+        if *shutdown.borrow() {
+            break;
+        }
+
+        match outcome {
+            Ok(Ok(())) => {
+                info!(worker = name, "Worker exited");
+                break;
+            }
+            Ok(Err(e)) => {
+                error!(worker = name, "Worker failed: {:?}", e);
+            }
+            Err(e) if e.is_panic() => {
+                error!(worker = name, "Worker panicked: {:?}", e);
+            }
+            Err(e) => {
+                warn!(worker = name, "Worker task cancelled: {:?}", e);
+                break;
+            }
+        }
+
+        if started_at.elapsed() >= HEALTHY_RUN {
+            restart_delay = INITIAL_RESTART_DELAY;
+        }
+        warn!(worker = name, "Restarting worker in {:?}", restart_delay);
+
+        tokio::select! {
+            _ = tokio::time::sleep(restart_delay) => {}
+            _ = shutdown.changed() => break,
+        }
+        restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
@@ -1,156 +1,1080 @@
-#[cfg(test)]
-mod tests {
-    use super::super::*;
-    use crate::broker::types::*;
-    use crate::config::Config;
-    use crate::config::Role;
-    use crate::p2p::protocol;
-    use std::sync::Arc;
-    use tempfile::TempDir;
-    use uuid::Uuid;
-
-    // Helper to create test storage
-    fn create_test_storage() -> (TempDir, Arc<storage::BrokerStorage>) {
-        let temp_dir = TempDir::new().unwrap();
-        let db_path = temp_dir.path().join("test.db");
-        let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap()).unwrap());
-        (temp_dir, storage)
-    }
-
-    // Helper to create test booking data
-    fn create_test_booking() -> (protocol::BookingData, protocol::NotifyData) {
-        let booking = protocol::BookingData {
-            date: "2026-01-15".to_string(),
-            start_time: "10:00".to_string(),
-            end_time: "11:00".to_string(),
-            name: "Test User".to_string(),
-        };
-        let notify = protocol::NotifyData {
-            email: "test@example.com".to_string(),
-            locale: Some("en".to_string()),
-            timezone: Some("UTC".to_string()),
-        };
-        (booking, notify)
-    }
+use super::*;
+use crate::broker::types::*;
+use crate::config::Config;
+use crate::config::{DurabilityMode, Role, UnreadableBodyAction};
+use crate::metrics::Metrics;
+use crate::p2p::protocol;
+use prometheus_client::registry::Registry;
+use std::sync::Arc;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+// Helper to create test storage
+fn create_test_storage() -> (TempDir, Arc<storage::BrokerStorage>) {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+    (temp_dir, storage)
+}
+
+// Helper to create test storage whose time only moves when the test says so
+fn create_test_storage_with_clock() -> (TempDir, Arc<storage::BrokerStorage>, Arc<clock::MockClock>) {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let clock = Arc::new(clock::MockClock::new(chrono::Utc::now().timestamp_millis()));
+    let storage = storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict)
+        .unwrap()
+        .with_clock(clock.clone());
+    (temp_dir, Arc::new(storage), clock)
+}
+
+// Helper to create test booking data
+fn create_test_booking() -> (protocol::BookingData, protocol::NotifyData) {
+    let booking = protocol::BookingData {
+        date: "2026-01-15".to_string(),
+        start_time: "10:00".to_string(),
+        end_time: "11:00".to_string(),
+        name: "Test User".to_string(),
+    };
+    let notify = protocol::NotifyData {
+        email: "test@example.com".to_string(),
+        locale: Some("en".to_string()),
+        timezone: Some("UTC".to_string()),
+    };
+    (booking, notify)
+}
 
-    #[tokio::test]
-    async fn test_idempotency() {
-        let (_temp_dir, storage) = create_test_storage();
-        let handler = handler::BrokerHandler::new(storage.clone());
+#[tokio::test]
+async fn test_booking_with_missing_email_is_rejected() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 0);
+    let (booking, mut notify) = create_test_booking();
 
+    for email in ["", "not-an-email", "user@localhost", "a b@example.com"] {
+        notify.email = email.to_string();
         let correlation_id = Uuid::new_v4().to_string();
-        let (booking, notify) = create_test_booking();
-
-        // First submission
-        let ack1 = handler
-            .handle_submit_booking(
-                correlation_id.clone(),
-                booking.clone(),
-                notify.clone(),
-            )
+        let ack = handler
+            .handle_submit_booking(correlation_id.clone(), booking.clone(), notify.clone())
             .await
             .unwrap();
 
-        assert!(matches!(ack1, protocol::Msg::BookingAck { status, .. } if status == "queued"));
+        assert_eq!(ack, protocol::BookingStatus::Rejected, "{:?}", email);
+        assert!(storage.get_booking_job(&correlation_id).unwrap().is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_bookings_throttled_above_max_queued_jobs() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 1);
+    let (booking, notify) = create_test_booking();
+
+    let first = Uuid::new_v4().to_string();
+    let ack = handler.handle_submit_booking(first.clone(), booking.clone(), notify.clone()).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Queued);
+
+    let second = Uuid::new_v4().to_string();
+    let ack = handler.handle_submit_booking(second.clone(), booking.clone(), notify.clone()).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Throttled);
+    assert!(storage.get_booking_job(&second).unwrap().is_none());
+
+    // A retry of the job already held creates nothing, so it gets that job's status
+    let ack = handler.handle_submit_booking(first.clone(), booking.clone(), notify).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Queued);
+    // Reusing its id for another booking is still rejected, not throttled
+    let other_notify = protocol::NotifyData { email: "other@example.com".to_string(), locale: None, timezone: None };
+    let ack = handler.handle_submit_booking(first, booking, other_notify).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Rejected);
+}
+
+#[tokio::test]
+async fn test_draining_gateway_refuses_new_bookings_but_keeps_queued_ones() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 0);
+    let (booking, notify) = create_test_booking();
+
+    let queued = Uuid::new_v4().to_string();
+    let ack = handler.handle_submit_booking(queued.clone(), booking.clone(), notify.clone()).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Queued);
+
+    assert!(!handler.start_draining());
+    let refused = Uuid::new_v4().to_string();
+    let ack = handler.handle_submit_booking(refused.clone(), booking, notify).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Draining);
+    assert!(storage.get_booking_job(&refused).unwrap().is_none());
+
+    // What was accepted before is still left for the forwarder
+    assert_eq!(storage.get_booking_job(&queued).unwrap().unwrap().state, JobState::Queued);
+    assert_eq!(handler.unfinished_jobs().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_draining_gateway_refuses_new_ops() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 0);
+    let op = |op_id: &str| protocol::Op {
+        op_id: op_id.to_string(),
+        actor_id: "client".to_string(),
+        kind: "UpsertNote".to_string(),
+        entity: "note:1".to_string(),
+        payload_json: "{}".to_string(),
+        created_at_ms: 0,
+    };
+
+    assert!(handler.handle_op_submit(op("before"), "peer-a".to_string()).await.unwrap());
+    handler.start_draining();
+    assert!(!handler.handle_op_submit(op("after"), "peer-a".to_string()).await.unwrap());
+
+    assert!(storage.get_inbound_op("after").unwrap().is_none());
+    // Ops received before are still left for the op worker
+    let received = storage.get_received_ops(10).unwrap();
+    assert_eq!(received.iter().map(|op| op.op_id.as_str()).collect::<Vec<_>>(), ["before"]);
+}
+
+#[tokio::test]
+async fn test_idempotency() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 0);
+
+    let correlation_id = Uuid::new_v4().to_string();
+    let (booking, notify) = create_test_booking();
+
+    // First submission
+    let ack1 = handler
+        .handle_submit_booking(
+            correlation_id.clone(),
+            booking.clone(),
+            notify.clone(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(ack1, protocol::BookingStatus::Queued);
+
+    // Second submission with same correlation_id (idempotency)
+    let ack2 = handler
+        .handle_submit_booking(
+            correlation_id.clone(),
+            booking.clone(),
+            notify.clone(),
+        )
+        .await
+        .unwrap();
+
+    // Should return queued status (already exists)
+    assert_eq!(ack2, protocol::BookingStatus::Queued);
+
+    // Verify only one job was created
+    let job = storage.get_booking_job(&correlation_id).unwrap().unwrap();
+    assert_eq!(job.correlation_id, correlation_id);
+}
+
+#[tokio::test]
+async fn test_reused_correlation_id_for_another_booking_is_rejected() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 0);
+    let correlation_id = Uuid::new_v4().to_string();
+    let (booking, notify) = create_test_booking();
+
+    let ack = handler.handle_submit_booking(correlation_id.clone(), booking.clone(), notify.clone()).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Queued);
+
+    let mut other = booking.clone();
+    other.date = "2026-02-01".to_string();
+    let ack = handler.handle_submit_booking(correlation_id.clone(), other, notify).await.unwrap();
+    assert_eq!(ack, protocol::BookingStatus::Rejected);
+
+    // The first booking is left as it was
+    let job = storage.get_booking_job(&correlation_id).unwrap().unwrap();
+    assert_eq!(job.booking_json, serde_json::to_string(&booking).unwrap());
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Queued], 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_duplicate_submissions_insert_once() {
+    let (_temp_dir, storage) = create_test_storage();
+    let mut events = storage.subscribe_job_events();
+    let (booking, notify) = create_test_booking();
+
+    for _ in 0..20 {
+        let correlation_id = Uuid::new_v4().to_string();
+        let submissions: Vec<_> = (0..2)
+            .map(|_| {
+                let handler = handler::BrokerHandler::new(storage.clone(), 0);
+                let (id, booking, notify) = (correlation_id.clone(), booking.clone(), notify.clone());
+                tokio::spawn(async move { handler.handle_submit_booking(id, booking, notify).await })
+            })
+            .collect();
+
+        for submission in submissions {
+            let ack = submission.await.unwrap().unwrap();
+            assert_eq!(ack, protocol::BookingStatus::Queued);
+        }
+    }
+
+    // Each id was inserted (counted and announced) exactly once
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Queued], 20);
+    let mut inserted = 0;
+    while events.try_recv().is_ok() {
+        inserted += 1;
+    }
+    assert_eq!(inserted, 20);
+}
+
+#[tokio::test]
+async fn test_booking_queue_acks_each_request() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = Arc::new(handler::BrokerHandler::new(storage.clone(), 0));
+    let (queue, requests) = tokio::sync::mpsc::channel(Config::for_tests().max_concurrent_broker_ops);
+    let worker = tokio::spawn(handler.run_queue(requests));
+
+    let (booking, notify) = create_test_booking();
+    let mut acks = Vec::new();
+    for correlation_id in ["a", "b", "a"] {
+        let (reply, ack) = tokio::sync::oneshot::channel();
+        let request = handler::BookingRequest {
+            correlation_id: correlation_id.to_string(),
+            booking: booking.clone(),
+            notify: notify.clone(),
+            reply,
+        };
+        queue.try_send(request).unwrap();
+        acks.push((correlation_id, ack));
+    }
+    for (expected_id, ack) in acks {
+        let ack = ack.await.unwrap();
+        assert!(matches!(ack, protocol::Msg::BookingAck { correlation_id, status }
+            if correlation_id == expected_id && protocol::BookingStatus::parse(&status) == Some(protocol::BookingStatus::Queued)));
+    }
+
+    // The worker stops once the swarm side drops its sender
+    drop(queue);
+    worker.await.unwrap();
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Queued], 2);
+}
+
+// Covers the default `DurabilityMode::Strict`: the job is flushed before the
+// ACK. In `Batched` mode it's only in sled's page cache when the ACK goes out.
+#[tokio::test]
+async fn test_ack_after_persist() {
+    let (_temp_dir, storage) = create_test_storage();
+    let handler = handler::BrokerHandler::new(storage.clone(), 0);
+
+    let correlation_id = Uuid::new_v4().to_string();
+    let (booking, notify) = create_test_booking();
+
+    // Submit booking
+    let ack = handler
+        .handle_submit_booking(correlation_id.clone(), booking, notify)
+        .await
+        .unwrap();
+
+    // ACK should be returned
+    assert_eq!(ack, protocol::BookingStatus::Queued);
+
+    // Verify job was persisted
+    let job = storage.get_booking_job(&correlation_id).unwrap();
+    assert!(job.is_some());
+    let job = job.unwrap();
+    assert_eq!(job.correlation_id, correlation_id);
+    assert_eq!(job.state, JobState::Queued);
+    assert_eq!(job.attempts, 0);
+}
+
+#[tokio::test]
+async fn test_offline_retry_keeps_job_queued() {
+    let (_temp_dir, storage) = create_test_storage();
+    
+    // Create a job manually
+    let correlation_id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().timestamp_millis();
+    let job = BookingJob {
+        correlation_id: correlation_id.clone(),
+        booking_json: r#"{"date":"2026-01-15","start_time":"10:00","end_time":"11:00","name":"Test"}"#.to_string(),
+        notify_json: r#"{"email":"test@example.com"}"#.to_string(),
+        state: JobState::Queued,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        http_status: None,
+        central_response_json: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    storage.persist_booking_job(&job).unwrap();
+
+    // Verify job is queued
+    let retrieved = storage.get_booking_job(&correlation_id).unwrap().unwrap();
+    assert_eq!(retrieved.state, JobState::Queued);
+}
+
+// Helper to create a queued job that is due now
+fn create_due_job() -> BookingJob {
+    let now = chrono::Utc::now().timestamp_millis();
+    BookingJob {
+        correlation_id: Uuid::new_v4().to_string(),
+        booking_json: r#"{"date":"2026-01-15","start_time":"10:00","end_time":"11:00","name":"Test"}"#.to_string(),
+        notify_json: r#"{"email":"test@example.com"}"#.to_string(),
+        state: JobState::Queued,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        http_status: None,
+        central_response_json: None,
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+#[tokio::test]
+async fn test_job_transition_moves_index_atomically() {
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+    assert_eq!(storage.get_due_jobs(10).unwrap().len(), 1);
+
+    // Retry later: the old index entry is replaced, not duplicated
+    let later = job.next_attempt_at + 60_000;
+    storage
+        .update_job_state(
+            &job.correlation_id,
+            storage::JobStateUpdate {
+                state: JobState::Queued,
+                attempts: Some(1),
+                next_attempt_at: Some(later),
+                last_error: Some("offline"),
+                http_status: None,
+                central_response_json: None,
+            },
+        )
+        .unwrap();
+    let entries = storage.job_index_entries().unwrap();
+    assert_eq!(entries, vec![(later, job.correlation_id.clone())]);
+    assert!(storage.get_due_jobs(10).unwrap().is_empty());
+
+    // Leaving the queue drops the index entry entirely
+    storage
+        .update_job_state(
+            &job.correlation_id,
+            storage::JobStateUpdate {
+                state: JobState::Confirmed,
+                attempts: None,
+                next_attempt_at: None,
+                last_error: None,
+                http_status: Some(200),
+                central_response_json: None,
+            },
+        )
+        .unwrap();
+    assert!(storage.job_index_entries().unwrap().is_empty());
+}
 
-        // Second submission with same correlation_id (idempotency)
-        let ack2 = handler
-            .handle_submit_booking(
-                correlation_id.clone(),
-                booking.clone(),
-                notify.clone(),
+#[tokio::test]
+async fn test_conflicting_job_transition_is_rolled_back() {
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+
+    // A transition computed from this read...
+    let stale = storage.get_stored_job(&job.correlation_id).unwrap().unwrap();
+    let mut confirmed = job.clone();
+    confirmed.state = JobState::Confirmed;
+    let transition = storage.job_transition(&stale, &confirmed).unwrap();
+
+    // ...conflicts with a write that lands first
+    storage
+        .update_job_state(
+            &job.correlation_id,
+            storage::JobStateUpdate {
+                state: JobState::Sending,
+                attempts: None,
+                next_attempt_at: None,
+                last_error: None,
+                http_status: None,
+                central_response_json: None,
+            },
+        )
+        .unwrap();
+    assert!(!storage.apply_job_transition(&transition).unwrap());
+
+    // None of its writes (record, index removal, counters) are left behind
+    let stored = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Sending);
+    assert!(storage.job_index_entries().unwrap().is_empty());
+    let counts = storage.count_jobs_by_state().unwrap();
+    assert_eq!(counts[&JobState::Sending], 1);
+    assert_eq!(counts[&JobState::Queued], 0);
+    assert_eq!(counts[&JobState::Confirmed], 0);
+
+    // Recomputed from a fresh read, the same transition applies in full
+    let fresh = storage.get_stored_job(&job.correlation_id).unwrap().unwrap();
+    assert!(storage.apply_job_transition(&storage.job_transition(&fresh, &confirmed).unwrap()).unwrap());
+    let counts = storage.count_jobs_by_state().unwrap();
+    assert_eq!(counts[&JobState::Sending], 0);
+    assert_eq!(counts[&JobState::Confirmed], 1);
+}
+
+#[tokio::test]
+async fn test_state_counters_follow_transitions_and_survive_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let job = create_due_job();
+    {
+        let storage = storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap();
+        storage.persist_booking_job(&job).unwrap();
+        storage.persist_booking_job(&job).unwrap(); // idempotent, not double counted
+        storage.persist_booking_job(&create_due_job()).unwrap();
+        storage
+            .update_job_state(
+                &job.correlation_id,
+                storage::JobStateUpdate {
+                    state: JobState::Confirmed,
+                    attempts: None,
+                    next_attempt_at: None,
+                    last_error: None,
+                    http_status: Some(200),
+                    central_response_json: None,
+                },
             )
-            .await
             .unwrap();
 
-        // Should return queued status (already exists)
-        assert!(matches!(ack2, protocol::Msg::BookingAck { status, .. } if status == "queued"));
+        let counts = storage.count_jobs_by_state().unwrap();
+        assert_eq!(counts[&JobState::Queued], 1);
+        assert_eq!(counts[&JobState::Confirmed], 1);
+        assert_eq!(counts[&JobState::Failed], 0);
+        assert!(storage.storage_size_bytes().unwrap() > 0);
+        storage.flush().unwrap();
+    }
+
+    // Counters are rebuilt from the records on open
+    let storage = reopen_storage(&db_path, None).await.unwrap();
+    let counts = storage.count_jobs_by_state().unwrap();
+    assert_eq!(counts[&JobState::Queued], 1);
+    assert_eq!(counts[&JobState::Confirmed], 1);
+    assert_eq!(storage.count_notifications_by_state().unwrap()[&NotificationState::Pending], 0);
+}
+
+#[test]
+fn test_state_counters_stay_exact_under_concurrent_transitions() {
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
 
-        // Verify only one job was created
-        let job = storage.get_booking_job(&correlation_id).unwrap().unwrap();
-        assert_eq!(job.correlation_id, correlation_id);
+    // Writers racing on one job: each counter move happens in the same
+    // transaction as the write it belongs to, so no reopen is needed to fix them up
+    let writers: Vec<_> = [JobState::Sending, JobState::Queued, JobState::Failed, JobState::Confirmed]
+        .into_iter()
+        .map(|state| {
+            let storage = storage.clone();
+            let correlation_id = job.correlation_id.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    storage
+                        .update_job_state(
+                            &correlation_id,
+                            storage::JobStateUpdate {
+                                state,
+                                attempts: None,
+                                next_attempt_at: None,
+                                last_error: None,
+                                http_status: None,
+                                central_response_json: None,
+                            },
+                        )
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
     }
 
-    #[tokio::test]
-    async fn test_ack_after_persist() {
-        let (_temp_dir, storage) = create_test_storage();
-        let handler = handler::BrokerHandler::new(storage.clone());
+    let final_state = storage.get_booking_job(&job.correlation_id).unwrap().unwrap().state;
+    let counts = storage.count_jobs_by_state().unwrap();
+    for state in JobState::ALL {
+        assert_eq!(counts[&state], u64::from(state == final_state), "count of {:?}", state);
+    }
+    let indexed = storage.job_index_entries().unwrap().len();
+    assert_eq!(indexed, usize::from(final_state == JobState::Queued));
+}
 
-        let correlation_id = Uuid::new_v4().to_string();
-        let (booking, notify) = create_test_booking();
+#[tokio::test]
+async fn test_batched_durability_persists_on_close() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let job = create_due_job();
+    {
+        let storage = storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Batched).unwrap();
+        storage.persist_booking_job(&job).unwrap();
+        // Written without a per-write flush, but visible right away
+        assert!(storage.get_booking_job(&job.correlation_id).unwrap().is_some());
+        // No explicit flush: closing the handle (or the background flush) writes it out
+    }
 
-        // Submit booking
-        let ack = handler
-            .handle_submit_booking(correlation_id.clone(), booking, notify)
-            .await
-            .unwrap();
+    let storage = reopen_storage(&db_path, None).await.unwrap();
+    assert_eq!(storage.get_booking_job(&job.correlation_id).unwrap().unwrap().state, JobState::Queued);
+}
 
-        // ACK should be returned
-        assert!(matches!(ack, protocol::Msg::BookingAck { status, .. } if status == "queued"));
+// sled's background flusher may briefly hold the file lock after the
+// previous handle is dropped, so retry while the DB is still locked
+async fn reopen_storage(
+    db_path: &std::path::Path,
+    key: Option<&crypto::DbEncryptionKey>,
+) -> anyhow::Result<storage::BrokerStorage> {
+    for _ in 0..20 {
+        match storage::BrokerStorage::new(db_path.to_str().unwrap(), key, DurabilityMode::Strict) {
+            Err(e) if format!("{:?}", e).contains("could not acquire lock") => {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            result => return result,
+        }
+    }
+    storage::BrokerStorage::new(db_path.to_str().unwrap(), key, DurabilityMode::Strict)
+}
 
-        // Verify job was persisted
-        let job = storage.get_booking_job(&correlation_id).unwrap();
-        assert!(job.is_some());
-        let job = job.unwrap();
-        assert_eq!(job.correlation_id, correlation_id);
-        assert_eq!(job.state, JobState::Queued);
-        assert_eq!(job.attempts, 0);
+// Helper to create an op as received from a peer at `received_at`
+fn create_inbound_op(op_id: &str, received_at: i64) -> InboundOp {
+    InboundOp {
+        op_id: op_id.to_string(),
+        op_json: r#"{"op_id":"op","actor_id":"client","kind":"TestOp","entity":"test","payload_json":"{}","created_at_ms":0}"#.to_string(),
+        from_peer: "peer-a".to_string(),
+        state: InboundOpState::Received,
+        received_at,
+        processed_at: None,
     }
+}
 
-    #[tokio::test]
-    async fn test_offline_retry_keeps_job_queued() {
-        let (_temp_dir, storage) = create_test_storage();
-        
-        // Create a job manually
-        let correlation_id = Uuid::new_v4().to_string();
-        let now = chrono::Utc::now().timestamp_millis();
-        let job = BookingJob {
-            correlation_id: correlation_id.clone(),
-            booking_json: r#"{"date":"2026-01-15","start_time":"10:00","end_time":"11:00","name":"Test"}"#.to_string(),
-            notify_json: r#"{"email":"test@example.com"}"#.to_string(),
-            state: JobState::Queued,
+#[tokio::test]
+async fn test_received_op_survives_reopen_until_processed() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let op = protocol::Op {
+        op_id: "op-1".to_string(),
+        actor_id: "client".to_string(),
+        kind: "UpsertNote".to_string(),
+        entity: "note:1".to_string(),
+        payload_json: r#"{"text":"hi"}"#.to_string(),
+        created_at_ms: 0,
+    };
+    {
+        let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+        let handler = handler::BrokerHandler::new(storage.clone(), 0);
+        assert!(handler.handle_op_submit(op.clone(), "peer-a".to_string()).await.unwrap());
+        // A resend is acked again but stored once
+        assert!(handler.handle_op_submit(op, "peer-a".to_string()).await.unwrap());
+        assert_eq!(storage.get_received_ops(10).unwrap().len(), 1);
+    }
+
+    // Acked before the gateway went down: still there after a reopen
+    let storage = Arc::new(reopen_storage(&db_path, None).await.unwrap());
+    let received = storage.get_received_ops(10).unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!((received[0].op_id.as_str(), received[0].from_peer.as_str()), ("op-1", "peer-a"));
+    assert!(received[0].op_json.contains("note:1"));
+
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let worker = ops::OpWorker::new(storage.clone());
+    assert_eq!(worker.process_received_ops(&shutdown_rx).unwrap(), 1);
+    assert_eq!(worker.process_received_ops(&shutdown_rx).unwrap(), 0);
+    let op = storage.get_inbound_op("op-1").unwrap().unwrap();
+    assert_eq!(op.state, InboundOpState::Processed);
+    assert!(op.processed_at.is_some());
+}
+
+#[test]
+fn test_received_ops_poll_skips_processed_ops() {
+    let (_temp_dir, storage) = create_test_storage();
+    // Oldest first, whatever the op_ids
+    storage.persist_inbound_op(&create_inbound_op("op-b", 1_000)).unwrap();
+    storage.persist_inbound_op(&create_inbound_op("op-a", 2_000)).unwrap();
+    storage.persist_inbound_op(&create_inbound_op("op-c", 3_000)).unwrap();
+    let ids = |ops: Vec<InboundOp>| ops.into_iter().map(|op| op.op_id).collect::<Vec<_>>();
+    assert_eq!(ids(storage.get_received_ops(10).unwrap()), ["op-b", "op-a", "op-c"]);
+    assert_eq!(ids(storage.get_received_ops(2).unwrap()), ["op-b", "op-a"]);
+
+    // Processed ops leave the index, so the limit is spent on unprocessed ones only
+    storage.mark_op_processed("op-b").unwrap();
+    storage.mark_op_processed("op-a").unwrap();
+    assert_eq!(ids(storage.get_received_ops(1).unwrap()), ["op-c"]);
+    assert_eq!(storage.all_inbound_ops().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_plaintext_db_is_encrypted_when_key_configured() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let key = crypto::DbEncryptionKey::derive(b"test key material");
+    let job = create_due_job();
+    {
+        let storage = storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap();
+        storage.persist_booking_job(&job).unwrap();
+    }
+
+    // Existing plaintext records are migrated and stay readable with the key
+    {
+        let storage = reopen_storage(&db_path, Some(&key)).await.unwrap();
+        let stored = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+        assert_eq!(stored.notify_json, job.notify_json);
+        assert_eq!(storage.get_due_jobs(10).unwrap().len(), 1);
+        storage.persist_booking_job(&create_due_job()).unwrap();
+        assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Queued], 2);
+    }
+
+    let err = reopen_storage(&db_path, None).await.err().unwrap();
+    assert!(err.to_string().contains("encrypted"), "{:?}", err);
+    let wrong = crypto::DbEncryptionKey::derive(b"other key material");
+    let err = reopen_storage(&db_path, Some(&wrong)).await.err().unwrap();
+    assert!(err.to_string().contains("Wrong db_encryption_key"), "{:?}", err);
+
+    let storage = reopen_storage(&db_path, Some(&key)).await.unwrap();
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Queued], 2);
+}
+
+#[test]
+fn test_record_cipher_roundtrip() {
+    let key = crypto::DbEncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+    let cipher = crypto::RecordCipher::new(&key);
+    let plaintext = br#"{"email":"test@example.com"}"#;
+    let sealed = cipher.encrypt(plaintext).unwrap();
+    assert!(!sealed.windows(plaintext.len()).any(|w| w == plaintext));
+    assert_ne!(sealed, cipher.encrypt(plaintext).unwrap()); // fresh nonce per write
+    assert_eq!(cipher.decrypt(&sealed).unwrap(), plaintext);
+
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(cipher.decrypt(&tampered).is_err());
+    assert!(crypto::DbEncryptionKey::from_hex("abcd").is_err());
+    assert_eq!(format!("{:?}", key), "DbEncryptionKey(<redacted>)");
+}
+
+#[tokio::test]
+async fn test_reconcile_reports_missing_orphaned_and_stuck_records() {
+    let (_temp_dir, storage) = create_test_storage();
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut confirmed = create_due_job();
+    confirmed.state = JobState::Confirmed;
+    let mut stuck = create_due_job();
+    stuck.updated_at = now - 2 * 3_600_000;
+    let fresh = create_due_job();
+    for job in [&confirmed, &stuck, &fresh] {
+        storage.persist_booking_job(job).unwrap();
+    }
+    let orphan = NotificationRecord {
+        correlation_id: "gone".to_string(),
+        email_to: "test@example.com".to_string(),
+        state: NotificationState::Pending,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        subject: String::new(),
+        body: String::new(),
+        simulated_sent_at: None,
+        created_at: now,
+        updated_at: now,
+    };
+    storage.persist_notification(&orphan).unwrap();
+
+    let report = storage.reconcile(now - 3_600_000).unwrap();
+    assert_eq!(report.jobs_scanned, 3);
+    assert_eq!(report.notifications_scanned, 1);
+    assert_eq!(report.confirmed_without_notification, vec![confirmed.correlation_id.clone()]);
+    assert_eq!(report.orphaned_notifications, vec!["gone".to_string()]);
+    assert_eq!(report.stuck_jobs.len(), 1);
+    assert_eq!(report.stuck_jobs[0].correlation_id, stuck.correlation_id);
+
+    // Giving the confirmed job its notification clears that finding
+    storage
+        .persist_notification(&NotificationRecord { correlation_id: confirmed.correlation_id.clone(), ..orphan })
+        .unwrap();
+    assert!(storage.reconcile(now - 3_600_000).unwrap().confirmed_without_notification.is_empty());
+}
+
+#[tokio::test]
+async fn test_export_import_roundtrip_is_idempotent() {
+    let (_source_dir, source) = create_test_storage();
+    let mut confirmed = create_due_job();
+    confirmed.state = JobState::Confirmed;
+    let queued = create_due_job();
+    source.persist_booking_job(&confirmed).unwrap();
+    source.persist_booking_job(&queued).unwrap();
+    let now = chrono::Utc::now().timestamp_millis();
+    source
+        .persist_notification(&NotificationRecord {
+            correlation_id: confirmed.correlation_id.clone(),
+            email_to: "test@example.com".to_string(),
+            state: NotificationState::Pending,
             attempts: 0,
             next_attempt_at: now,
             last_error: None,
-            http_status: None,
-            central_response_json: None,
+            subject: String::new(),
+            body: String::new(),
+            simulated_sent_at: None,
             created_at: now,
             updated_at: now,
-        };
+        })
+        .unwrap();
+    source.persist_inbound_op(&create_inbound_op("op-1", now)).unwrap();
 
-        storage.persist_booking_job(&job).unwrap();
+    let mut export = Vec::new();
+    let stats = backup::export_ndjson(&source, &mut export).unwrap();
+    assert_eq!(stats, backup::ExportStats { jobs: 2, notifications: 1, ops: 1 });
+    assert_eq!(String::from_utf8_lossy(&export).lines().count(), 4);
 
-        // Verify job is queued
-        let retrieved = storage.get_booking_job(&correlation_id).unwrap().unwrap();
-        assert_eq!(retrieved.state, JobState::Queued);
-    }
+    let (_target_dir, target) = create_test_storage();
+    let stats = backup::import_ndjson(&target, export.as_slice()).unwrap();
+    assert_eq!(stats, backup::ImportStats { imported: 4, skipped: 0 });
+    let imported = target.get_booking_job(&confirmed.correlation_id).unwrap().unwrap();
+    assert_eq!(imported.state, JobState::Confirmed);
+    assert_eq!(imported.booking_json, confirmed.booking_json);
+    // Indexes are rebuilt on import, so the queued job is due again
+    assert_eq!(target.get_due_jobs(10).unwrap().len(), 1);
+    assert_eq!(target.get_due_notifications(10).unwrap().len(), 1);
+    assert_eq!(target.get_received_ops(10).unwrap().len(), 1);
 
-    #[tokio::test]
-    async fn test_notification_only_after_confirmation() {
-        let (_temp_dir, storage) = create_test_storage();
+    let stats = backup::import_ndjson(&target, export.as_slice()).unwrap();
+    assert_eq!(stats, backup::ImportStats { imported: 0, skipped: 4 });
+    assert_eq!(target.count_jobs_by_state().unwrap()[&JobState::Queued], 1);
+}
 
-        let correlation_id = Uuid::new_v4().to_string();
-        let now = chrono::Utc::now().timestamp_millis();
-
-        // Create a confirmed job
-        let job = BookingJob {
-            correlation_id: correlation_id.clone(),
-            booking_json: r#"{"date":"2026-01-15","start_time":"10:00","end_time":"11:00","name":"Test"}"#.to_string(),
-            notify_json: r#"{"email":"test@example.com"}"#.to_string(),
-            state: JobState::Confirmed,
+#[tokio::test]
+async fn test_list_notifications_filters_and_pages() {
+    let (_temp_dir, storage) = create_test_storage();
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut sent = Vec::new();
+    for i in 0..5 {
+        let notif = NotificationRecord {
+            correlation_id: Uuid::new_v4().to_string(),
+            email_to: format!("user{}@example.com", i),
+            state: NotificationState::Pending,
             attempts: 0,
             next_attempt_at: now,
             last_error: None,
-            http_status: Some(200),
-            central_response_json: Some(r#"{"id":"123"}"#.to_string()),
+            subject: String::new(),
+            body: String::new(),
+            simulated_sent_at: None,
             created_at: now,
             updated_at: now,
         };
+        storage.persist_notification(&notif).unwrap();
+        if i % 2 == 0 {
+            storage
+                .update_notification_state(&notif.correlation_id, NotificationState::SimulatedSent, Some(now), None, None)
+                .unwrap();
+            sent.push(notif.correlation_id);
+        }
+    }
+
+    let (all, total) = storage.list_notifications(None, 0, usize::MAX).unwrap();
+    assert_eq!((all.len(), total), (5, 5));
+
+    let (page, total) = storage
+        .list_notifications(Some(NotificationState::SimulatedSent), 1, 1)
+        .unwrap();
+    assert_eq!(total, 3);
+    assert_eq!(page.len(), 1);
+    assert!(sent.contains(&page[0].correlation_id));
+
+    let (pending, total) = storage.list_notifications(Some(NotificationState::Pending), 0, 10).unwrap();
+    assert_eq!((pending.len(), total), (2, 2));
+    assert!(pending.iter().all(|n| n.state == NotificationState::Pending));
+    assert_eq!(NotificationState::parse("simulated_sent"), Some(NotificationState::SimulatedSent));
+    assert_eq!(NotificationState::parse("sent"), None);
+}
+
+#[tokio::test]
+async fn test_notification_index_tracks_pending_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let now = chrono::Utc::now().timestamp_millis();
+    let notif = |next_attempt_at| NotificationRecord {
+        correlation_id: Uuid::new_v4().to_string(),
+        email_to: "test@example.com".to_string(),
+        state: NotificationState::Pending,
+        attempts: 0,
+        next_attempt_at,
+        last_error: None,
+        subject: String::new(),
+        body: String::new(),
+        simulated_sent_at: None,
+        created_at: now,
+        updated_at: now,
+    };
+    let due = notif(now - 1_000);
+    let later = notif(now + 60_000);
+
+    // A DB from before the index tree: record plus an in-tree "pending:" row
+    {
+        let db = sled::open(&db_path).unwrap();
+        let outbox = db.open_tree("notification_outbox").unwrap();
+        outbox.insert(due.correlation_id.as_str(), bincode::serialize(&due).unwrap()).unwrap();
+        outbox.insert(format!("pending:{}:{}", due.next_attempt_at, due.correlation_id), &[] as &[u8]).unwrap();
+        db.flush().unwrap();
+    }
+
+    // The legacy row is dropped and the index rebuilt from the records
+    let storage = reopen_storage(&db_path, None).await.unwrap();
+    assert_eq!(storage.list_notifications(None, 0, 10).unwrap().1, 1);
+    storage.persist_notification(&later).unwrap();
+    let found = storage.get_due_notifications(10).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].correlation_id, due.correlation_id);
+
+    // Leaving pending removes the index entry instead of leaving it stale
+    storage
+        .update_notification_state(&due.correlation_id, NotificationState::SimulatedSent, Some(now), None, None)
+        .unwrap();
+    assert!(storage.get_due_notifications(10).unwrap().is_empty());
+    assert_eq!(storage.count_notifications_by_state().unwrap()[&NotificationState::Pending], 1);
+}
+
+#[tokio::test]
+async fn test_requeue_only_failed_jobs() {
+    let (_temp_dir, storage) = create_test_storage();
+    let mut failed = create_due_job();
+    failed.state = JobState::Failed;
+    failed.attempts = 10;
+    failed.next_attempt_at += 3_600_000;
+    let queued = create_due_job();
+    storage.persist_booking_job(&failed).unwrap();
+    storage.persist_booking_job(&queued).unwrap();
+    assert_eq!(storage.get_due_jobs(10).unwrap().len(), 1);
+
+    assert_eq!(storage.requeue_job(&failed.correlation_id).unwrap(), storage::RequeueOutcome::Requeued);
+    let requeued = storage.get_booking_job(&failed.correlation_id).unwrap().unwrap();
+    assert_eq!(requeued.state, JobState::Queued);
+    assert_eq!(requeued.attempts, 0);
+    assert_eq!(storage.get_due_jobs(10).unwrap().len(), 2);
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Failed], 0);
+
+    assert_eq!(
+        storage.requeue_job(&queued.correlation_id).unwrap(),
+        storage::RequeueOutcome::NotFailed(JobState::Queued)
+    );
+    assert_eq!(storage.requeue_job("missing").unwrap(), storage::RequeueOutcome::NotFound);
+}
+
+#[tokio::test]
+async fn test_patch_job_allows_only_safe_corrections() {
+    let (_temp_dir, storage) = create_test_storage();
+    let queued = create_due_job();
+    let mut confirmed = create_due_job();
+    confirmed.state = JobState::Confirmed;
+    storage.persist_booking_job(&queued).unwrap();
+    storage.persist_booking_job(&confirmed).unwrap();
+
+    // Deferring a queued job takes it out of the due set
+    let later = queued.next_attempt_at + 3_600_000;
+    let patch = storage::JobPatch { state: None, next_attempt_at: Some(later) };
+    let storage::PatchOutcome::Patched { after, .. } = storage.patch_job(&queued.correlation_id, &patch).unwrap() else {
+        panic!("queued job should be patchable");
+    };
+    assert_eq!(after.next_attempt_at, later);
+    assert!(storage.get_due_jobs(10).unwrap().is_empty());
+
+    let patch = storage::JobPatch { state: Some(JobState::Failed), next_attempt_at: None };
+    assert!(matches!(storage.patch_job(&queued.correlation_id, &patch).unwrap(), storage::PatchOutcome::Patched { .. }));
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Failed], 1);
+
+    let patch = storage::JobPatch { state: Some(JobState::Queued), next_attempt_at: None };
+    assert!(matches!(
+        storage.patch_job(&confirmed.correlation_id, &patch).unwrap(),
+        storage::PatchOutcome::InvalidTransition { from: JobState::Confirmed, to: JobState::Queued }
+    ));
+    let patch = storage::JobPatch { state: None, next_attempt_at: Some(later) };
+    assert!(matches!(
+        storage.patch_job(&confirmed.correlation_id, &patch).unwrap(),
+        storage::PatchOutcome::NotQueued(JobState::Confirmed)
+    ));
+    assert_eq!(storage.get_booking_job(&confirmed.correlation_id).unwrap().unwrap().state, JobState::Confirmed);
+    assert!(matches!(storage.patch_job("missing", &patch).unwrap(), storage::PatchOutcome::NotFound));
+}
+
+#[test]
+fn test_patch_racing_the_forwarder_never_fails_a_confirmed_job() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Batched).unwrap());
+    let confirm = || storage::JobStateUpdate {
+        state: JobState::Confirmed,
+        attempts: None,
+        next_attempt_at: None,
+        last_error: None,
+        http_status: Some(200),
+        central_response_json: None,
+    };
+
+    let rounds = 500;
+    for _ in 0..rounds {
+        let job = create_due_job();
         storage.persist_booking_job(&job).unwrap();
 
-        // Create notification
-        let notif = NotificationRecord {
-            correlation_id: correlation_id.clone(),
+        // The forwarder confirms the job while an operator marks it failed
+        let start = Arc::new(std::sync::Barrier::new(2));
+        let forwarder = {
+            let (storage, start) = (storage.clone(), start.clone());
+            let correlation_id = job.correlation_id.clone();
+            let confirm = confirm();
+            std::thread::spawn(move || {
+                start.wait();
+                storage.update_job_state(&correlation_id, confirm).unwrap();
+            })
+        };
+        let patch = storage::JobPatch { state: Some(JobState::Failed), next_attempt_at: None };
+        start.wait();
+        let outcome = storage.patch_job(&job.correlation_id, &patch).unwrap();
+        forwarder.join().unwrap();
+
+        // Either the patch landed while the job was still queued (and the
+        // confirmation came after it), or it saw the confirmation and refused
+        match outcome {
+            storage::PatchOutcome::Patched { before, .. } => assert_eq!(before.state, JobState::Queued),
+            storage::PatchOutcome::InvalidTransition { from: JobState::Confirmed, to: JobState::Failed } => {}
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(storage.get_booking_job(&job.correlation_id).unwrap().unwrap().state, JobState::Confirmed);
+    }
+    let counts = storage.count_jobs_by_state().unwrap();
+    assert_eq!(counts[&JobState::Confirmed], rounds);
+    assert_eq!(counts[&JobState::Failed], 0);
+}
+
+#[test]
+fn test_delete_racing_the_forwarder_keeps_counters_and_index_exact() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Batched).unwrap());
+    let update = |state: JobState| storage::JobStateUpdate {
+        state,
+        attempts: None,
+        next_attempt_at: None,
+        last_error: None,
+        http_status: None,
+        central_response_json: None,
+    };
+
+    for _ in 0..500 {
+        let job = create_due_job();
+        storage.persist_booking_job(&job).unwrap();
+
+        // The forwarder sends and confirms the job while an operator deletes it
+        let start = Arc::new(std::sync::Barrier::new(2));
+        let forwarder = {
+            let (storage, start) = (storage.clone(), start.clone());
+            let correlation_id = job.correlation_id.clone();
+            let (sending, confirmed) = (update(JobState::Sending), update(JobState::Confirmed));
+            std::thread::spawn(move || {
+                start.wait();
+                // Either step fails once the job is gone
+                let _ = storage
+                    .update_job_state(&correlation_id, sending)
+                    .and_then(|()| storage.update_job_state(&correlation_id, confirmed));
+            })
+        };
+        start.wait();
+        assert!(storage.delete_job(&job.correlation_id).unwrap());
+        forwarder.join().unwrap();
+        assert!(storage.get_booking_job(&job.correlation_id).unwrap().is_none());
+    }
+    // Whatever state each job was deleted in, its own counter went down
+    assert!(storage.count_jobs_by_state().unwrap().values().all(|count| *count == 0));
+    assert!(storage.job_index_entries().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_failed_notifications_are_replayed_only_past_the_cutoff() {
+    let (_temp_dir, storage, clock) = create_test_storage_with_clock();
+    let notification = |state: NotificationState| NotificationRecord {
+        correlation_id: Uuid::new_v4().to_string(),
+        email_to: "test@example.com".to_string(),
+        state,
+        attempts: 5,
+        next_attempt_at: storage.now_ms(),
+        last_error: Some("SMTP misconfigured".to_string()),
+        subject: String::new(),
+        body: String::new(),
+        simulated_sent_at: None,
+        created_at: storage.now_ms(),
+        updated_at: storage.now_ms(),
+    };
+    let old_failed = notification(NotificationState::Failed);
+    let sent = notification(NotificationState::SimulatedSent);
+    storage.persist_notification(&old_failed).unwrap();
+    storage.persist_notification(&sent).unwrap();
+    clock.advance(std::time::Duration::from_secs(3600));
+    let recent_failed = notification(NotificationState::Failed);
+    storage.persist_notification(&recent_failed).unwrap();
+    assert!(storage.get_due_notifications(10).unwrap().is_empty());
+
+    // Only what failed over half an hour ago
+    let cutoff = storage.now_ms() - 1_800_000;
+    assert_eq!(storage.requeue_failed_notifications(cutoff).unwrap(), 1);
+    let replayed = storage.get_notification(&old_failed.correlation_id).unwrap().unwrap();
+    assert_eq!(replayed.state, NotificationState::Pending);
+    assert_eq!(replayed.attempts, 0);
+    assert_eq!(replayed.next_attempt_at, storage.now_ms());
+    let due = storage.get_due_notifications(10).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].correlation_id, old_failed.correlation_id);
+
+    let counts = storage.count_notifications_by_state().unwrap();
+    assert_eq!(counts[&NotificationState::Pending], 1);
+    assert_eq!(counts[&NotificationState::Failed], 1);
+    assert_eq!(counts[&NotificationState::SimulatedSent], 1);
+    assert!(!storage.requeue_notification(&sent.correlation_id).unwrap());
+    assert!(!storage.requeue_notification("missing").unwrap());
+}
+
+#[test]
+fn test_concurrent_replays_requeue_each_notification_once() {
+    let (_temp_dir, storage) = create_test_storage();
+    for _ in 0..20 {
+        storage
+            .persist_notification(&NotificationRecord {
+                correlation_id: Uuid::new_v4().to_string(),
+                email_to: "test@example.com".to_string(),
+                state: NotificationState::Failed,
+                attempts: 5,
+                next_attempt_at: storage.now_ms(),
+                last_error: Some("SMTP misconfigured".to_string()),
+                subject: String::new(),
+                body: String::new(),
+                simulated_sent_at: None,
+                created_at: storage.now_ms(),
+                updated_at: storage.now_ms(),
+            })
+            .unwrap();
+    }
+
+    // Two operators replaying at once: every notification goes back to
+    // pending exactly once, so the counters don't drift
+    let replays: Vec<_> = (0..4)
+        .map(|_| {
+            let storage = storage.clone();
+            std::thread::spawn(move || storage.requeue_failed_notifications(i64::MAX).unwrap())
+        })
+        .collect();
+    let requeued: usize = replays.into_iter().map(|replay| replay.join().unwrap()).sum();
+
+    assert_eq!(requeued, 20);
+    let counts = storage.count_notifications_by_state().unwrap();
+    assert_eq!(counts[&NotificationState::Pending], 20);
+    assert_eq!(counts[&NotificationState::Failed], 0);
+    assert_eq!(storage.get_due_notifications(100).unwrap().len(), 20);
+}
+
+#[tokio::test]
+async fn test_delete_job_removes_notification_and_indexes() {
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    let other = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+    storage.persist_booking_job(&other).unwrap();
+    let now = chrono::Utc::now().timestamp_millis();
+    storage
+        .persist_notification(&NotificationRecord {
+            correlation_id: job.correlation_id.clone(),
             email_to: "test@example.com".to_string(),
             state: NotificationState::Pending,
             attempts: 0,
@@ -161,47 +1085,607 @@ mod tests {
             simulated_sent_at: None,
             created_at: now,
             updated_at: now,
+        })
+        .unwrap();
+
+    assert!(storage.delete_job(&job.correlation_id).unwrap());
+    assert!(storage.get_booking_job(&job.correlation_id).unwrap().is_none());
+    assert!(storage.get_notification(&job.correlation_id).unwrap().is_none());
+    assert_eq!(storage.job_index_entries().unwrap(), vec![(other.next_attempt_at, other.correlation_id.clone())]);
+    assert!(storage.get_due_notifications(10).unwrap().is_empty());
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Queued], 1);
+    assert_eq!(storage.count_notifications_by_state().unwrap()[&NotificationState::Pending], 0);
+
+    assert!(!storage.delete_job(&job.correlation_id).unwrap());
+}
+
+#[tokio::test]
+async fn test_job_transitions_are_published() {
+    let (_temp_dir, storage) = create_test_storage();
+    let mut events = storage.subscribe_job_events();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+    storage
+        .update_job_state(
+            &job.correlation_id,
+            storage::JobStateUpdate {
+                state: JobState::Sending,
+                attempts: Some(1),
+                next_attempt_at: None,
+                last_error: None,
+                http_status: None,
+                central_response_json: None,
+            },
+        )
+        .unwrap();
+
+    let created = events.recv().await.unwrap();
+    assert_eq!((created.from, created.to), (None, "queued"));
+    let sending = events.recv().await.unwrap();
+    assert_eq!(sending.correlation_id, job.correlation_id);
+    assert_eq!((sending.from, sending.to, sending.attempts), (Some("queued"), "sending", 1));
+}
+
+#[tokio::test]
+async fn test_retention_purges_only_old_finished_jobs() {
+    use crate::broker::retention::RetentionWorker;
+
+    let (_temp_dir, storage, clock) = create_test_storage_with_clock();
+    let finish = |job: &BookingJob, state: JobState| {
+        let update = storage::JobStateUpdate {
+            state,
+            attempts: None,
+            next_attempt_at: None,
+            last_error: None,
+            http_status: None,
+            central_response_json: None,
         };
-        storage.persist_notification(&notif).unwrap();
+        storage.update_job_state(&job.correlation_id, update).unwrap();
+    };
 
-        // Verify notification exists and is pending
-        let retrieved = storage.get_notification(&correlation_id).unwrap().unwrap();
-        assert_eq!(retrieved.state, NotificationState::Pending);
-    }
-
-    #[test]
-    fn test_exponential_backoff_calculation() {
-        let temp_dir = TempDir::new().unwrap();
-        let db_path = temp_dir.path().join("test.db");
-        let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap()).unwrap());
-
-        let config = Config {
-            role: Role::Gateway,
-            listen: "/ip4/0.0.0.0/tcp/0".to_string(),
-            dial: None,
-            peers: vec![],
-            identity_keypair: libp2p::identity::Keypair::generate_ed25519(),
-            bootstrap_peers: vec![],
-            enable_mdns: true,
-            enable_kad: true,
-            enable_relay: false,
-            discovery_timeout_secs: 60,
-            central_api_url: Some("https://example.com".to_string()),
-            db_path: "./data/broker.db".to_string(),
-            max_retry_attempts: 10,
-            initial_backoff_ms: 1000,
+    let old_confirmed = create_due_job();
+    let old_failed = create_due_job();
+    // Queued jobs are never purged, however old
+    let old_queued = create_due_job();
+    for job in [&old_confirmed, &old_failed, &old_queued] {
+        storage.persist_booking_job(job).unwrap();
+    }
+    finish(&old_confirmed, JobState::Confirmed);
+    finish(&old_failed, JobState::Failed);
+
+    let notif = NotificationRecord {
+        correlation_id: old_confirmed.correlation_id.clone(),
+        email_to: "test@example.com".to_string(),
+        state: NotificationState::Pending,
+        attempts: 0,
+        next_attempt_at: storage.now_ms(),
+        last_error: None,
+        subject: String::new(),
+        body: String::new(),
+        simulated_sent_at: None,
+        created_at: storage.now_ms(),
+        updated_at: storage.now_ms(),
+    };
+    storage.persist_notification(&notif).unwrap();
+    // Processed ops are purged like finished jobs; unprocessed ones are kept
+    storage.persist_inbound_op(&create_inbound_op("old-processed", storage.now_ms())).unwrap();
+    storage.mark_op_processed("old-processed").unwrap();
+    storage.persist_inbound_op(&create_inbound_op("old-received", storage.now_ms())).unwrap();
+
+    // A month later, one more job finishes
+    clock.advance(std::time::Duration::from_secs(31 * 24 * 60 * 60));
+    let recent_confirmed = create_due_job();
+    storage.persist_booking_job(&recent_confirmed).unwrap();
+    finish(&recent_confirmed, JobState::Confirmed);
+
+    let retention = RetentionWorker::new(storage.clone(), 30);
+    assert_eq!(retention.sweep().unwrap(), 2);
+    assert!(storage.get_booking_job(&old_confirmed.correlation_id).unwrap().is_none());
+    assert!(storage.get_booking_job(&old_failed.correlation_id).unwrap().is_none());
+    assert!(storage.get_booking_job(&recent_confirmed.correlation_id).unwrap().is_some());
+    assert!(storage.get_booking_job(&old_queued.correlation_id).unwrap().is_some());
+    assert!(storage.get_notification(&old_confirmed.correlation_id).unwrap().is_none());
+    assert!(storage.get_due_notifications(10).unwrap().is_empty());
+    assert!(storage.get_inbound_op("old-processed").unwrap().is_none());
+    assert_eq!(storage.get_received_ops(10).unwrap()[0].op_id, "old-received");
+
+    let counts = storage.count_jobs_by_state().unwrap();
+    assert_eq!(counts[&JobState::Confirmed], 1);
+    assert_eq!(counts[&JobState::Failed], 0);
+    assert_eq!(counts[&JobState::Queued], 1);
+    assert_eq!(storage.count_notifications_by_state().unwrap()[&NotificationState::Pending], 0);
+
+    // Nothing left to purge on a second sweep
+    assert_eq!(retention.sweep().unwrap(), 0);
+}
+
+#[test]
+fn test_purge_racing_a_retry_never_deletes_the_requeued_job() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Batched).unwrap());
+    let fail = || storage::JobStateUpdate {
+        state: JobState::Failed,
+        attempts: None,
+        next_attempt_at: None,
+        last_error: None,
+        http_status: None,
+        central_response_json: None,
+    };
+
+    let mut requeued = 0;
+    for _ in 0..300 {
+        let job = create_due_job();
+        storage.persist_booking_job(&job).unwrap();
+        storage.update_job_state(&job.correlation_id, fail()).unwrap();
+
+        // An operator retries the failed job while the retention sweep runs
+        let start = Arc::new(std::sync::Barrier::new(2));
+        let retry = {
+            let (storage, start) = (storage.clone(), start.clone());
+            let correlation_id = job.correlation_id.clone();
+            std::thread::spawn(move || {
+                start.wait();
+                storage.requeue_job(&correlation_id).unwrap()
+            })
         };
+        start.wait();
+        storage.purge_finished_jobs(i64::MAX).unwrap();
+        match retry.join().unwrap() {
+            storage::RequeueOutcome::Requeued => {
+                requeued += 1;
+                assert_eq!(storage.get_booking_job(&job.correlation_id).unwrap().unwrap().state, JobState::Queued);
+            }
+            storage::RequeueOutcome::NotFound => {
+                assert!(storage.get_booking_job(&job.correlation_id).unwrap().is_none());
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+    let counts = storage.count_jobs_by_state().unwrap();
+    assert_eq!(counts[&JobState::Queued], requeued);
+    assert_eq!(counts[&JobState::Failed], 0);
+    assert_eq!(storage.job_index_entries().unwrap().len() as u64, requeued);
+}
+
+#[test]
+fn test_purge_spans_several_batches() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let storage = storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Batched).unwrap();
+    for _ in 0..600 {
+        let mut job = create_due_job();
+        job.state = JobState::Confirmed;
+        storage.persist_booking_job(&job).unwrap();
+    }
+
+    assert_eq!(storage.purge_finished_jobs(i64::MAX).unwrap(), 600);
+    assert_eq!(storage.count_jobs_by_state().unwrap()[&JobState::Confirmed], 0);
+    assert_eq!(storage.purge_finished_jobs(i64::MAX).unwrap(), 0);
+}
 
-        let forwarder = forwarder::ForwarderWorker::new(storage, config).unwrap();
+#[tokio::test]
+async fn test_size_monitor_sweeps_only_past_its_threshold() {
+    use crate::broker::retention::SizeMonitor;
 
-        // Test backoff calculation
-        let backoff1 = forwarder.calculate_backoff(1);
-        assert!(backoff1 >= 1000 && backoff1 <= 1000 + 1000); // base + jitter
+    let (_temp_dir, storage) = create_test_storage();
+    let mut old_confirmed = create_due_job();
+    old_confirmed.state = JobState::Confirmed;
+    old_confirmed.updated_at = chrono::Utc::now().timestamp_millis() - 31 * 24 * 60 * 60 * 1000;
+    storage.persist_booking_job(&old_confirmed).unwrap();
 
-        let backoff2 = forwarder.calculate_backoff(2);
-        assert!(backoff2 >= 2000 && backoff2 <= 2000 + 1000); // 2^2 * 1000 + jitter
+    // Under the threshold (or without one) the size is only logged
+    assert_eq!(SizeMonitor::new(storage.clone(), u64::MAX, 30).check().unwrap(), None);
+    assert_eq!(SizeMonitor::new(storage.clone(), 0, 30).check().unwrap(), None);
+    // Nothing to sweep when finished jobs are kept forever
+    assert_eq!(SizeMonitor::new(storage.clone(), 1, 0).check().unwrap(), None);
+    assert!(storage.get_booking_job(&old_confirmed.correlation_id).unwrap().is_some());
 
-        let backoff3 = forwarder.calculate_backoff(3);
-        assert!(backoff3 >= 4000 && backoff3 <= 4000 + 1000); // 2^3 * 1000 + jitter
+    assert_eq!(SizeMonitor::new(storage.clone(), 1, 30).check().unwrap(), Some(1));
+    assert!(storage.get_booking_job(&old_confirmed.correlation_id).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_email_is_sent_from_the_configured_sender() {
+    let (_temp_dir, storage) = create_test_storage();
+    let mut job = create_due_job();
+    job.booking_json = r#"{"date":"2026-01-15","start_time":"10:00","end_time":"11:00","name":"Test"}"#.to_string();
+
+    let mut config = create_forwarder_config("http://127.0.0.1:1");
+    config.email_reply_to = Some("support@example.com".to_string());
+    let email = notifier::NotifierWorker::new(storage.clone(), &config).build_email(&job).unwrap();
+    assert_eq!(email.from, "\"Bookings\" <bookings@example.com>");
+    assert_eq!(email.reply_to.as_deref(), Some("support@example.com"));
+    assert_eq!(email.subject, "Booking Confirmed - Test");
+
+    config.email_from_name.clear();
+    let email = notifier::NotifierWorker::new(storage, &config).build_email(&job).unwrap();
+    assert_eq!(email.from, "bookings@example.com");
+}
+
+#[tokio::test]
+async fn test_notification_only_after_confirmation() {
+    let (_temp_dir, storage) = create_test_storage();
+
+    let correlation_id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().timestamp_millis();
+
+    // Create a confirmed job
+    let job = BookingJob {
+        correlation_id: correlation_id.clone(),
+        booking_json: r#"{"date":"2026-01-15","start_time":"10:00","end_time":"11:00","name":"Test"}"#.to_string(),
+        notify_json: r#"{"email":"test@example.com"}"#.to_string(),
+        state: JobState::Confirmed,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        http_status: Some(200),
+        central_response_json: Some(r#"{"id":"123"}"#.to_string()),
+        created_at: now,
+        updated_at: now,
+    };
+    storage.persist_booking_job(&job).unwrap();
+
+    // Create notification
+    let notif = NotificationRecord {
+        correlation_id: correlation_id.clone(),
+        email_to: "test@example.com".to_string(),
+        state: NotificationState::Pending,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        subject: String::new(),
+        body: String::new(),
+        simulated_sent_at: None,
+        created_at: now,
+        updated_at: now,
+    };
+    storage.persist_notification(&notif).unwrap();
+
+    // Verify notification exists and is pending
+    let retrieved = storage.get_notification(&correlation_id).unwrap().unwrap();
+    assert_eq!(retrieved.state, NotificationState::Pending);
+}
+
+// Helper to create a gateway config pointing the forwarder at `central_api_url`
+fn create_forwarder_config(central_api_url: &str) -> Config {
+    Config {
+        role: Role::Gateway,
+        listen: vec!["/ip4/0.0.0.0/tcp/0".to_string()],
+        enable_mdns: true,
+        central_api_url: Some(central_api_url.to_string()),
+        ..Config::for_tests()
+    }
+}
+
+#[test]
+fn test_exponential_backoff_calculation() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("test.db");
+    let storage = Arc::new(storage::BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+
+    let config = create_forwarder_config("https://example.com");
+
+    // Without jitter the delay doubles per attempt up to max_backoff_ms
+    let mut exact = config.clone();
+    exact.backoff_jitter_ms = 0;
+    exact.max_backoff_ms = 5000;
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), exact, &Metrics::new(Registry::default())).unwrap();
+    assert_eq!(forwarder.calculate_backoff(1), 1000);
+    assert_eq!(forwarder.calculate_backoff(2), 2000); // 2^1 * 1000
+    assert_eq!(forwarder.calculate_backoff(3), 4000); // 2^2 * 1000
+    assert_eq!(forwarder.calculate_backoff(4), 5000); // capped
+
+    // Full jitter (the default): anywhere in [0, delay]
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config.clone(), &Metrics::new(Registry::default())).unwrap();
+    for _ in 0..50 {
+        assert!(forwarder.calculate_backoff(1) <= 1000);
+        assert!(forwarder.calculate_backoff(3) <= 4000);
+        assert!(forwarder.calculate_backoff(30) <= 300_000);
     }
+
+    // Partial jitter only takes up to backoff_jitter_ms off the delay
+    let mut partial = config;
+    partial.backoff_jitter_ms = 500;
+    let forwarder = forwarder::ForwarderWorker::new(storage, partial, &Metrics::new(Registry::default())).unwrap();
+    for _ in 0..50 {
+        assert!((3500..=4000).contains(&forwarder.calculate_backoff(3)));
+    }
+}
+
+#[tokio::test]
+async fn test_failed_health_probe_leaves_due_jobs_queued() {
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+
+    // Nothing listens on port 1, so the probe fails straight away
+    let mut config = create_forwarder_config("http://127.0.0.1:1");
+    config.central_api_health_path = Some("/health".to_string());
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &Metrics::new(Registry::default())).unwrap();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(300), forwarder.run(shutdown_rx)).await;
+
+    let retrieved = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(retrieved.state, JobState::Queued);
+    assert_eq!(retrieved.attempts, 0);
+    assert!(retrieved.last_error.is_none());
+}
+
+#[tokio::test]
+async fn test_forwarder_stops_on_shutdown_leaving_due_jobs_queued() {
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+
+    let config = create_forwarder_config("http://127.0.0.1:1");
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &Metrics::new(Registry::default())).unwrap();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    shutdown_tx.send(true).unwrap();
+
+    // A tick that starts after shutdown was requested doesn't pick up new jobs
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+    let retrieved = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(retrieved.state, JobState::Queued);
+    assert_eq!(retrieved.attempts, 0);
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), forwarder.run(shutdown_rx))
+        .await
+        .expect("forwarder should exit on shutdown")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_retry_warning_fires_once_threshold_is_reached() {
+    let (_temp_dir, storage, clock) = create_test_storage_with_clock();
+    let mut job = create_due_job();
+    job.next_attempt_at = storage.now_ms();
+    storage.persist_booking_job(&job).unwrap();
+
+    // Nothing listens on port 1, so every attempt fails and is retried
+    let mut config = create_forwarder_config("http://127.0.0.1:1");
+    config.retry_warn_threshold = 1;
+    config.backoff_jitter_ms = 0;
+    let metrics = Metrics::new(Registry::default());
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &metrics).unwrap();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+
+    let retrieved = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(retrieved.state, JobState::Queued);
+    assert_eq!(retrieved.attempts, 1);
+    assert_eq!(retrieved.next_attempt_at, storage.now_ms() + 1000);
+    let encoded = metrics.encode();
+    assert!(encoded.contains("hch_broker_job_retry_warnings_total 1"));
+    // Still retrying, so no attempts were recorded for a finished job yet
+    assert!(encoded.contains("hch_broker_job_attempts_count 0"));
+
+    // Not retried before its backoff (initial_backoff_ms) has elapsed
+    clock.advance(std::time::Duration::from_millis(999));
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+    assert_eq!(storage.get_booking_job(&job.correlation_id).unwrap().unwrap().attempts, 1);
+
+    // Retried right after, waiting twice as long next; the warning doesn't fire again
+    clock.advance(std::time::Duration::from_millis(1));
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+    let retrieved = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(retrieved.attempts, 2);
+    assert_eq!(retrieved.next_attempt_at, storage.now_ms() + 2000);
+    let encoded = metrics.encode();
+    assert!(encoded.contains("hch_broker_job_retry_warnings_total 1"));
+    assert!(encoded.contains("hch_broker_central_api_request_duration_seconds_count{outcome=\"network_error\"} 2"));
+}
+
+#[tokio::test]
+async fn test_confirming_a_job_again_counts_the_skipped_duplicate_notification() {
+    use warp::Filter;
+
+    // A central API that confirms everything
+    let (central_addr, central) = warp::serve(warp::any().map(|| "{}")).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(central);
+
+    let (_temp_dir, storage) = create_test_storage();
+    let job = create_due_job();
+    storage.persist_booking_job(&job).unwrap();
+    // Left over from an earlier confirmation, before the job was requeued
+    let now = chrono::Utc::now().timestamp_millis();
+    let earlier = NotificationRecord {
+        correlation_id: job.correlation_id.clone(),
+        email_to: "test@example.com".to_string(),
+        state: NotificationState::SimulatedSent,
+        attempts: 1,
+        next_attempt_at: now,
+        last_error: None,
+        subject: "Booking confirmed".to_string(),
+        body: String::new(),
+        simulated_sent_at: Some(now),
+        created_at: now,
+        updated_at: now,
+    };
+    assert!(storage.persist_notification(&earlier).unwrap());
+
+    let metrics = Metrics::new(Registry::default());
+    let config = create_forwarder_config(&format!("http://{}", central_addr));
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &metrics).unwrap();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(500), forwarder.run(shutdown_rx)).await;
+
+    assert_eq!(storage.get_booking_job(&job.correlation_id).unwrap().unwrap().state, JobState::Confirmed);
+    // The earlier notification is kept as it was, and the skip is counted
+    let notif = storage.get_notification(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(notif.state, NotificationState::SimulatedSent);
+    let encoded = metrics.encode();
+    assert!(encoded.contains("hch_broker_notifications_duplicate_total 1"));
+    assert!(encoded.contains("hch_broker_central_api_request_duration_seconds_count{outcome=\"success\"} 1"));
+}
+
+#[tokio::test]
+async fn test_central_api_requests_carry_request_id_and_attempt() {
+    use warp::Filter;
+
+    // A central API that confirms everything and records the tracing headers
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = seen.clone();
+    let central = warp::any()
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(warp::header::optional::<String>("x-attempt"))
+        .map(move |request_id: Option<String>, attempt: Option<String>| {
+            recorder.lock().unwrap().push((request_id, attempt));
+            "{}"
+        });
+    let (central_addr, central) = warp::serve(central).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(central);
+
+    let (_temp_dir, storage, _clock) = create_test_storage_with_clock();
+    let mut job = create_due_job();
+    job.attempts = 2;
+    job.next_attempt_at = storage.now_ms();
+    storage.persist_booking_job(&job).unwrap();
+
+    let metrics = Metrics::new(Registry::default());
+    let mut config = create_forwarder_config(&format!("http://{}", central_addr));
+    config.send_attempt_header = true;
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &metrics).unwrap();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+
+    assert_eq!(storage.get_booking_job(&job.correlation_id).unwrap().unwrap().state, JobState::Confirmed);
+    // Third try: two earlier attempts failed
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![(Some(job.correlation_id.clone()), Some("3".to_string()))]
+    );
+}
+
+/// A central API that answers every booking 200 but hangs up partway through
+/// the body, recording the `Idempotency-Key` of each request it got
+async fn spawn_truncated_body_central() -> (std::net::SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = keys.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = vec![0u8; 8192];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            if let Some(key) = request
+                .lines()
+                .find_map(|line| line.strip_prefix("idempotency-key: ").or_else(|| line.strip_prefix("Idempotency-Key: ")))
+            {
+                seen.lock().unwrap().push(key.trim().to_string());
+            }
+            let _ = socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 64\r\n\r\n{\"id\":")
+                .await;
+        }
+    });
+    (addr, keys)
+}
+
+#[tokio::test]
+async fn test_unreadable_success_body_is_retried_by_default() {
+    let (central_addr, keys) = spawn_truncated_body_central().await;
+
+    let (_temp_dir, storage, clock) = create_test_storage_with_clock();
+    let mut job = create_due_job();
+    job.next_attempt_at = storage.now_ms();
+    storage.persist_booking_job(&job).unwrap();
+
+    let metrics = Metrics::new(Registry::default());
+    let mut config = create_forwarder_config(&format!("http://{}", central_addr));
+    config.backoff_jitter_ms = 0;
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &metrics).unwrap();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+
+    let stored = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Queued);
+    assert_eq!(stored.attempts, 1);
+    assert_eq!(stored.next_attempt_at, storage.now_ms() + 1000);
+    assert!(storage.get_notification(&job.correlation_id).unwrap().is_none());
+
+    // Retried once its backoff is over
+    clock.advance(std::time::Duration::from_millis(1000));
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+    let stored = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Queued);
+    assert_eq!(stored.attempts, 2);
+    assert!(storage.get_notification(&job.correlation_id).unwrap().is_none());
+    // Both attempts carried the same key, so the central API can drop the repeat
+    assert_eq!(*keys.lock().unwrap(), vec![job.correlation_id.clone(), job.correlation_id.clone()]);
+}
+
+#[tokio::test]
+async fn test_unreadable_success_body_can_confirm_the_job() {
+    let (central_addr, keys) = spawn_truncated_body_central().await;
+
+    let (_temp_dir, storage, clock) = create_test_storage_with_clock();
+    let mut job = create_due_job();
+    job.next_attempt_at = storage.now_ms();
+    storage.persist_booking_job(&job).unwrap();
+
+    let metrics = Metrics::new(Registry::default());
+    let mut config = create_forwarder_config(&format!("http://{}", central_addr));
+    config.unreadable_success_body = UnreadableBodyAction::Confirm;
+    let forwarder = forwarder::ForwarderWorker::new(storage.clone(), config, &metrics).unwrap();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+
+    let stored = storage.get_booking_job(&job.correlation_id).unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Confirmed);
+    // Confirmed on the first try: no failed attempts recorded
+    assert_eq!(stored.attempts, 0);
+    assert_eq!(stored.http_status, Some(200));
+    assert_eq!(stored.central_response_json, None);
+    assert!(storage.get_notification(&job.correlation_id).unwrap().is_some());
+
+    // Sent once and never again, however long we wait
+    clock.advance(std::time::Duration::from_secs(3600));
+    forwarder.process_due_jobs(&shutdown_rx).await.unwrap();
+    assert_eq!(*keys.lock().unwrap(), vec![job.correlation_id.clone()]);
+    assert!(metrics
+        .encode()
+        .contains("hch_broker_central_api_request_duration_seconds_count{outcome=\"success\"} 1"));
+}
+
+#[tokio::test]
+async fn test_supervisor_restarts_panicking_worker() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let starts = Arc::new(AtomicU32::new(0));
+
+    let counter = starts.clone();
+    supervisor::supervise("test", shutdown_rx, move |_shutdown| {
+        let counter = counter.clone();
+        async move {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("worker crashed");
+            }
+            Ok(())
+        }
+    })
+    .await;
+
+    // First run panicked, second returned cleanly
+    assert_eq!(starts.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_supervisor_stops_on_shutdown() {
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+    let supervised = tokio::spawn(supervisor::supervise("test", shutdown_rx, |mut shutdown| async move {
+        let _ = shutdown.changed().await;
+        Ok(())
+    }));
+    shutdown_tx.send(true).unwrap();
+
+    tokio::time::timeout(std::time::Duration::from_secs(2), supervised)
+        .await
+        .expect("supervisor should exit on shutdown")
+        .unwrap();
 }
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 /// Booking job state
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum JobState {
     Queued,
     Sending,
@@ -10,6 +10,8 @@ pub enum JobState {
 }
 
 impl JobState {
+    pub const ALL: [JobState; 4] = [JobState::Queued, JobState::Sending, JobState::Confirmed, JobState::Failed];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             JobState::Queued => "queued",
@@ -18,6 +20,11 @@ impl JobState {
             JobState::Failed => "failed",
         }
     }
+
+    /// Inverse of `as_str`
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|state| state.as_str() == s)
+    }
 }
 
 /// Booking job stored in database
@@ -37,7 +44,7 @@ pub struct BookingJob {
 }
 
 /// Notification state
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum NotificationState {
     Pending,
     SimulatedSent,
@@ -45,6 +52,9 @@ pub enum NotificationState {
 }
 
 impl NotificationState {
+    pub const ALL: [NotificationState; 3] =
+        [NotificationState::Pending, NotificationState::SimulatedSent, NotificationState::Failed];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             NotificationState::Pending => "pending",
@@ -52,6 +62,11 @@ impl NotificationState {
             NotificationState::Failed => "failed",
         }
     }
+
+    /// Inverse of `as_str`
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|state| state.as_str() == s)
+    }
 }
 
 /// Notification record stored in database
@@ -69,3 +84,34 @@ pub struct NotificationRecord {
     pub created_at: i64,
     pub updated_at: i64,
 }
+
+/// Inbound op state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InboundOpState {
+    /// Persisted and acked, waiting for the op worker
+    Received,
+    Processed,
+}
+
+/// An `OpSubmit` received from a peer, persisted before its `OpAck` is sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboundOp {
+    pub op_id: String,
+    pub op_json: String,
+    /// Peer the op was received from
+    pub from_peer: String,
+    pub state: InboundOpState,
+    pub received_at: i64,
+    pub processed_at: Option<i64>,
+}
+
+/// Published on every job state transition (see `BrokerStorage::subscribe_job_events`)
+#[derive(Debug, Clone, Serialize)]
+pub struct JobEvent {
+    pub correlation_id: String,
+    /// `None` when the job was just created
+    pub from: Option<&'static str>,
+    pub to: &'static str,
+    pub attempts: u32,
+    pub at_ms: i64,
+}
@@ -1,12 +1,13 @@
+use crate::broker::crypto::DbEncryptionKey;
 use clap::{Parser, Subcommand, ValueEnum};
-use libp2p::identity;
-use serde::Deserialize;
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, ValueEnum, Deserialize, PartialEq)]
+#[derive(Debug, Clone, ValueEnum, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Client,
@@ -22,6 +23,87 @@ impl fmt::Display for Role {
     }
 }
 
+/// Log output format: human-readable or one JSON object per line (Loki, ELK)
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+/// Whether the API rate limit is shared by all callers or tracked per client IP
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitScope {
+    Global,
+    PerIp,
+}
+
+/// When broker writes reach the disk. `Strict` flushes every write before it
+/// returns, so a `BookingAck` "queued" means the job survives a crash.
+/// `Batched` leaves flushing to sled's background flush (every
+/// `BATCHED_FLUSH_EVERY_MS`): much higher write throughput, but a crash can
+/// lose jobs acknowledged in the last interval.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DurabilityMode {
+    Strict,
+    Batched,
+}
+
+/// What the swarm does when no peer is connected after `discovery_timeout_secs`.
+/// `Log` only reports it; `Restart` stops the node with a non-zero exit so a
+/// supervisor restarts it; `Rebootstrap` redials the bootstrap peers (again
+/// each timeout while still alone).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryTimeoutAction {
+    Log,
+    Restart,
+    Rebootstrap,
+}
+
+/// Which client-supplied `SubmitBooking` correlation_ids a gateway accepts.
+/// `Uuid` only takes hyphenated UUIDs, so a client that reuses or mangles ids
+/// is refused instead of silently colliding with another booking; `Any`
+/// takes any non-empty string.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CorrelationIdFormat {
+    Any,
+    Uuid,
+}
+
+/// What the forwarder does when the central API answers 2xx but the
+/// response body can't be read. `Retry` sends the booking again (safe as long
+/// as the central API honours the `Idempotency-Key` header carrying the
+/// correlation_id); `Confirm` takes the status as success and confirms the
+/// job without a stored response.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnreadableBodyAction {
+    Retry,
+    Confirm,
+}
+
+impl CorrelationIdFormat {
+    pub fn accepts(&self, correlation_id: &str) -> bool {
+        match self {
+            CorrelationIdFormat::Any => !correlation_id.is_empty(),
+            CorrelationIdFormat::Uuid => correlation_id.len() == 36 && uuid::Uuid::parse_str(correlation_id).is_ok(),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(name = "hybrid-connection-health")]
 #[command(version = "1.0")]
@@ -39,9 +121,9 @@ pub struct CliArgs {
     #[arg(long, value_enum)]
     pub role: Option<Role>,
 
-    /// Multiaddr to listen on
+    /// Multiaddr to listen on (repeat for dual-stack, e.g. IPv4 + IPv6)
     #[arg(long)]
-    pub listen: Option<String>,
+    pub listen: Vec<String>,
 
     /// Optional peer to dial (multiaddr)
     #[arg(long)]
@@ -56,9 +138,9 @@ pub enum Commands {
         #[arg(long, value_enum)]
         role: Option<Role>,
 
-        /// Multiaddr to listen on
+        /// Multiaddr to listen on (repeat for dual-stack, e.g. IPv4 + IPv6)
         #[arg(long)]
-        listen: Option<String>,
+        listen: Vec<String>,
 
         /// Optional peer to dial (multiaddr)
         #[arg(long)]
@@ -66,6 +148,17 @@ pub enum Commands {
     },
     /// Print the Peer ID derived from the identity file and exit
     PeerId,
+    /// Print a booking token for --peer signed with the identity file, for
+    /// gateways whose booking_token_issuer is this node's Peer ID
+    MintBookingToken {
+        /// Peer ID of the client allowed to submit bookings
+        #[arg(long)]
+        peer: PeerId,
+
+        /// How long the token stays valid, in seconds
+        #[arg(long, default_value = "3600")]
+        ttl_secs: u64,
+    },
     /// Run a one-shot P2P test (OpSubmit -> OpAck)
     TestSubmit {
         /// Multiaddr to listen on (e.g., /ip4/0.0.0.0/tcp/0)
@@ -79,27 +172,351 @@ pub enum Commands {
         /// Timeout in seconds waiting for ACK
         #[arg(long, default_value = "10")]
         timeout_secs: u64,
+
+        /// Op kind to submit (e.g. UpsertNote)
+        #[arg(long, default_value = "TestOp")]
+        kind: String,
+
+        /// Entity the Op targets (e.g. note:123)
+        #[arg(long, default_value = "test")]
+        entity: String,
+
+        /// File with the JSON payload to send (defaults to "{}")
+        #[arg(long)]
+        payload_file: Option<PathBuf>,
+
+        /// Print the result as one JSON object on stdout ({passed, op_id, rtt_ms, error});
+        /// logs go to stderr. The exit code is non-zero when the test fails.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run a one-shot booking test against a gateway (SubmitBooking -> BookingAck "queued")
+    TestBooking {
+        /// Gateway to dial (Multiaddr)
+        #[arg(long)]
+        dial: String,
+
+        /// Booking date (YYYY-MM-DD)
+        #[arg(long)]
+        date: String,
+
+        /// Start time (HH:MM)
+        #[arg(long)]
+        start_time: String,
+
+        /// End time (HH:MM)
+        #[arg(long)]
+        end_time: String,
+
+        /// Name the booking is made under
+        #[arg(long)]
+        name: String,
+
+        /// Email to notify once the booking is confirmed
+        #[arg(long)]
+        email: String,
+
+        /// Timeout in seconds waiting for the BookingAck
+        #[arg(long, default_value = "10")]
+        timeout_secs: u64,
+    },
+    /// Submit a booking to a gateway over P2P and print its BookingAck as JSON on stdout.
+    /// Without --dial, a gateway is looked up in the DHT through the configured bootstrap_peers.
+    SubmitBooking {
+        /// Gateway to dial (Multiaddr); found via the DHT when omitted
+        #[arg(long)]
+        dial: Option<String>,
+
+        /// Booking date (YYYY-MM-DD)
+        #[arg(long)]
+        date: String,
+
+        /// Start time (HH:MM)
+        #[arg(long)]
+        start_time: String,
+
+        /// End time (HH:MM)
+        #[arg(long)]
+        end_time: String,
+
+        /// Name the booking is made under
+        #[arg(long)]
+        name: String,
+
+        /// Email to notify once the booking is confirmed
+        #[arg(long)]
+        email: String,
+
+        /// Locale for the confirmation email (e.g. es-CO)
+        #[arg(long)]
+        locale: Option<String>,
+
+        /// Timezone the booking times are in (e.g. America/Bogota)
+        #[arg(long)]
+        timezone: Option<String>,
+
+        /// Capability token from `mint-booking-token`, for gateways that require one
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Timeout in seconds for finding a gateway, and again for its BookingAck
+        #[arg(long, default_value = "30")]
+        timeout_secs: u64,
+    },
+    /// Export all broker jobs, notifications and inbound ops (db_path) to a newline-delimited JSON file
+    BrokerExport {
+        /// File to write the export to
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Import a BrokerExport file into the broker DB (db_path), skipping existing records
+    BrokerImport {
+        /// Export file to read
+        #[arg(long = "in", value_name = "FILE")]
+        input: PathBuf,
     },
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub role: Role,
-    pub listen: String,
+    pub listen: Vec<String>,
     pub dial: Option<String>,
     pub peers: Vec<String>,
     pub identity_keypair: identity::Keypair,
+    /// Refuse to start without `--identity-file` instead of running with an
+    /// ephemeral key (whose PeerId changes on every restart)
+    pub require_persistent_identity: bool,
     // Production peer discovery configuration
     pub bootstrap_peers: Vec<String>,
     pub enable_mdns: bool,
     pub enable_kad: bool,
     pub enable_relay: bool,
+    /// Known relay (`/.../p2p/<relay id>`) a client retries through, over a
+    /// `/p2p-circuit` address, when a direct dial to a gateway fails
+    pub fallback_relay: Option<Multiaddr>,
+    /// Ask the LAN router (UPnP IGD) to forward our listen ports
+    pub enable_upnp: bool,
+    /// How long to wait for peers before warning; also how long a Kademlia
+    /// gateway stays unready waiting for its first successful bootstrap
     pub discovery_timeout_secs: u64,
+    /// What to do when still no peer is connected after the discovery timeout
+    pub discovery_timeout_action: DiscoveryTimeoutAction,
+    /// How often the swarm checks discovery health (and refreshes bandwidth);
+    /// the summary line is only logged at info when the counts changed
+    pub health_check_interval_secs: u64,
+    /// How often connected peers are pinged
+    pub ping_interval_secs: u64,
+    /// How long a ping may take before it counts as failed
+    pub ping_timeout_secs: u64,
+    /// Failed pings in a row after which a peer is disconnected, reclaiming
+    /// connections that are dead but not closed (0 = never)
+    pub max_ping_failures: u32,
+    pub kad_query_timeout_secs: u64,
+    /// How often the swarm does a random DHT walk to refresh the routing table
+    /// (clients also re-query gateway providers then); skipped while no peer
+    /// is connected. 0 disables maintenance.
+    pub kad_maintenance_secs: u64,
+    /// Number of peers a DHT record is replicated to (1..=20)
+    pub kad_replication_factor: usize,
+    /// How long an outbound request/response exchange may take before it
+    /// fails with `OutboundFailure::Timeout`
+    pub rr_request_timeout_secs: u64,
+    /// Times a timed-out `OpSubmit` is re-sent before it's given up on
+    pub rr_max_retries: u32,
+    /// Consecutive failed request/response exchanges with a peer (either
+    /// direction) after which it is disconnected (0 = never)
+    pub rr_failure_disconnect_threshold: u32,
+    /// Agent version advertised via identify (e.g. "hch/0.1.0")
+    pub agent_version: String,
+    /// Max entries kept in the network activity feed (0 disables it)
+    pub event_log_capacity: usize,
+    /// JSON file the `/network` snapshot is saved to periodically and
+    /// reloaded from on startup, so peer history survives restarts
+    pub snapshot_path: Option<String>,
+    /// If non-empty, only these peers may stay connected
+    pub allowed_peers: Vec<PeerId>,
+    /// Peers that are always disconnected and never auto-dialed
+    pub denied_peers: Vec<PeerId>,
+    /// Disconnect peers that don't speak our request/response protocol once
+    /// their grace period ends (bootstrap and allowlisted peers are kept)
+    pub disconnect_unrelated_peers: bool,
+    /// Scopes discovery to one cluster: advertised via identify, and nodes
+    /// reporting a different network id are disconnected and not re-dialed
+    pub network_id: Option<String>,
+    /// Auto-dials (mDNS, Kademlia, DHT providers) in flight at once; further
+    /// ones wait in a small queue until a dial completes or fails
+    pub max_concurrent_dials: usize,
+    /// Bound on a dial including its noise/yamux handshake, so a peer that
+    /// accepts TCP but stalls doesn't hold up failing over to another one
+    pub dial_timeout_secs: u64,
     // Broker configuration
     pub central_api_url: Option<String>,
+    /// Path under `central_api_url` probed with a GET before each batch of
+    /// due jobs; while it fails, jobs stay queued instead of being attempted
+    pub central_api_health_path: Option<String>,
+    pub db_path: String,
+    /// Flush per write (ack-after-persist) or in the background (see [`DurabilityMode`])
+    pub durability_mode: DurabilityMode,
+    pub max_retry_attempts: u32,
+    /// A job reaching this many attempts is logged and counted once as an
+    /// early sign of a degraded central API (0 = never)
+    pub retry_warn_threshold: u32,
+    pub initial_backoff_ms: u64,
+    /// Upper bound of the exponential retry delay
+    pub max_backoff_ms: u64,
+    /// How much of the retry delay is randomized: the wait is the delay minus
+    /// random(0, min(delay, backoff_jitter_ms)). The default (= max_backoff_ms)
+    /// is full jitter, random(0, delay); 0 disables jitter.
+    pub backoff_jitter_ms: u64,
+    pub central_api_connect_timeout_ms: u64,
+    /// Whole-request timeout; a timed-out request counts as a failed attempt
+    /// and is retried with backoff like any other network error
+    pub central_api_request_timeout_ms: u64,
+    /// What to do with a 2xx response whose body couldn't be read
+    pub unreadable_success_body: UnreadableBodyAction,
+    /// Also send `X-Attempt: <n>` (1 for the first try) with each central
+    /// API request, next to the always-sent `X-Request-Id`
+    pub send_attempt_header: bool,
+    /// Days to keep confirmed/failed jobs before the retention sweep deletes
+    /// them (0 = keep forever)
+    pub job_retention_days: u32,
+    /// Broker DB size on disk above which the retention sweep runs right away
+    /// instead of waiting for its hourly turn (0 = only log the size)
+    pub db_size_sweep_threshold_bytes: u64,
+    /// Unfinished (queued or sending) jobs above which new bookings are
+    /// answered "throttled" instead of persisted (0 = no limit)
+    pub max_queued_jobs: u64,
+    /// Broker handoffs in flight at once: bookings waiting for the broker
+    /// task and received ops being persisted. Past it, inbound requests are
+    /// answered "busy" instead of piling up on sled and the central API.
+    pub max_concurrent_broker_ops: usize,
+    /// Shape required of client-supplied booking correlation_ids
+    pub correlation_id_format: CorrelationIdFormat,
+    /// Bookings sent with an empty correlation_id get one generated by the
+    /// gateway, returned in their `BookingAck` (otherwise they're rejected).
+    /// A fresh one per delivery: a re-sent request is not recognized as a duplicate.
+    pub generate_correlation_ids: bool,
+    /// Sender address of booking notifications
+    pub email_from: String,
+    /// Display name shown with `email_from` (empty = address only)
+    pub email_from_name: String,
+    /// Where replies to notifications go, if not to `email_from`
+    pub email_reply_to: Option<String>,
+    /// When set, a gateway only accepts bookings carrying a current token
+    /// signed by this peer (`mint-booking-token`); others get "unauthorized"
+    pub booking_token_issuer: Option<PeerId>,
+    /// Encrypts broker records at rest when set (from `db_encryption_key` or
+    /// `db_encryption_key_file`)
+    pub db_encryption_key: Option<DbEncryptionKey>,
+    // API configuration
+    pub api_cors_origins: Vec<String>,
+    /// Bearer token required by destructive API endpoints (DELETE); those
+    /// endpoints are refused while it's unset
+    pub api_token: Option<String>,
+    /// Requests per minute allowed on mutating endpoints (0 = unlimited)
+    pub api_rate_limit_per_min: u32,
+    pub api_rate_limit_scope: RateLimitScope,
+    // Logging configuration
+    pub log_format: LogFormat,
+    /// Default tracing filter (e.g. "info", "hybrid_connection_health=debug");
+    /// `RUST_LOG` takes precedence when set
+    pub log_level: String,
+    /// OTLP/HTTP traces endpoint (e.g. "http://localhost:4318/v1/traces");
+    /// spans are only exported when set
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Placeholder shown instead of a secret in [`RedactedConfig`]
+pub const REDACTED: &str = "***";
+
+/// Serializable view of the effective [`Config`] with secrets (identity key,
+/// `api_token`, DB encryption key) replaced by [`REDACTED`]. Unset secrets stay `null`.
+#[derive(Debug, Serialize)]
+pub struct RedactedConfig {
+    pub role: Role,
+    pub listen: Vec<String>,
+    pub dial: Option<String>,
+    pub peers: Vec<String>,
+    pub peer_id: String,
+    pub identity_keypair: &'static str,
+    pub require_persistent_identity: bool,
+    pub bootstrap_peers: Vec<String>,
+    pub enable_mdns: bool,
+    pub enable_kad: bool,
+    pub enable_relay: bool,
+    pub fallback_relay: Option<String>,
+    pub enable_upnp: bool,
+    pub discovery_timeout_secs: u64,
+    pub discovery_timeout_action: DiscoveryTimeoutAction,
+    pub health_check_interval_secs: u64,
+    pub ping_interval_secs: u64,
+    pub ping_timeout_secs: u64,
+    pub max_ping_failures: u32,
+    pub kad_query_timeout_secs: u64,
+    pub kad_maintenance_secs: u64,
+    pub kad_replication_factor: usize,
+    pub rr_request_timeout_secs: u64,
+    pub rr_max_retries: u32,
+    pub rr_failure_disconnect_threshold: u32,
+    pub agent_version: String,
+    pub event_log_capacity: usize,
+    pub snapshot_path: Option<String>,
+    pub allowed_peers: Vec<String>,
+    pub denied_peers: Vec<String>,
+    pub disconnect_unrelated_peers: bool,
+    pub network_id: Option<String>,
+    pub max_concurrent_dials: usize,
+    pub dial_timeout_secs: u64,
+    pub central_api_url: Option<String>,
+    pub central_api_health_path: Option<String>,
     pub db_path: String,
+    pub durability_mode: DurabilityMode,
     pub max_retry_attempts: u32,
+    pub retry_warn_threshold: u32,
     pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub backoff_jitter_ms: u64,
+    pub central_api_connect_timeout_ms: u64,
+    pub central_api_request_timeout_ms: u64,
+    pub unreadable_success_body: UnreadableBodyAction,
+    pub send_attempt_header: bool,
+    pub job_retention_days: u32,
+    pub db_size_sweep_threshold_bytes: u64,
+    pub max_queued_jobs: u64,
+    pub max_concurrent_broker_ops: usize,
+    pub correlation_id_format: CorrelationIdFormat,
+    pub generate_correlation_ids: bool,
+    pub email_from: String,
+    pub email_from_name: String,
+    pub email_reply_to: Option<String>,
+    pub booking_token_issuer: Option<String>,
+    pub db_encryption_key: Option<&'static str>,
+    pub api_cors_origins: Vec<String>,
+    pub api_token: Option<&'static str>,
+    pub api_rate_limit_per_min: u32,
+    pub api_rate_limit_scope: RateLimitScope,
+    pub log_format: LogFormat,
+    pub log_level: String,
+    pub otlp_endpoint: Option<String>,
+}
+
+/// `listen` in config.toml accepts either a single multiaddr string (legacy)
+/// or a list of multiaddrs, e.g. `["/ip4/0.0.0.0/tcp/4001", "/ip6/::/tcp/4001"]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ListenAddrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<ListenAddrs> for Vec<String> {
+    fn from(addrs: ListenAddrs) -> Self {
+        match addrs {
+            ListenAddrs::One(addr) => vec![addr],
+            ListenAddrs::Many(addrs) => addrs,
+        }
+    }
 }
 
 pub fn load_or_create_identity(path: &Path) -> identity::Keypair {
@@ -138,21 +555,77 @@ pub fn parse_args() -> (CliArgs, Config) {
     #[derive(Deserialize)]
     struct FileConfig {
         role: Option<Role>,
-        listen: Option<String>,
+        listen: Option<ListenAddrs>,
         dial: Option<String>,
         #[serde(default)]
         peers: Vec<String>,
+        require_persistent_identity: Option<bool>,
         #[serde(default)]
         bootstrap_peers: Vec<String>,
         enable_mdns: Option<bool>,
         enable_kad: Option<bool>,
         enable_relay: Option<bool>,
+        fallback_relay: Option<String>,
+        enable_upnp: Option<bool>,
         discovery_timeout_secs: Option<u64>,
+        discovery_timeout_action: Option<DiscoveryTimeoutAction>,
+        health_check_interval_secs: Option<u64>,
+        ping_interval_secs: Option<u64>,
+        ping_timeout_secs: Option<u64>,
+        max_ping_failures: Option<u32>,
+        kad_query_timeout_secs: Option<u64>,
+        kad_maintenance_secs: Option<u64>,
+        kad_replication_factor: Option<usize>,
+        rr_request_timeout_secs: Option<u64>,
+        rr_max_retries: Option<u32>,
+        rr_failure_disconnect_threshold: Option<u32>,
+        agent_version: Option<String>,
+        event_log_capacity: Option<usize>,
+        snapshot_path: Option<String>,
+        #[serde(default)]
+        allowed_peers: Vec<String>,
+        #[serde(default)]
+        denied_peers: Vec<String>,
+        disconnect_unrelated_peers: Option<bool>,
+        network_id: Option<String>,
+        max_concurrent_dials: Option<usize>,
+        dial_timeout_secs: Option<u64>,
         // Broker configuration
         central_api_url: Option<String>,
+        central_api_health_path: Option<String>,
         db_path: Option<String>,
+        durability_mode: Option<DurabilityMode>,
         max_retry_attempts: Option<u32>,
+        retry_warn_threshold: Option<u32>,
         initial_backoff_ms: Option<u64>,
+        max_backoff_ms: Option<u64>,
+        backoff_jitter_ms: Option<u64>,
+        central_api_connect_timeout_ms: Option<u64>,
+        central_api_request_timeout_ms: Option<u64>,
+        unreadable_success_body: Option<UnreadableBodyAction>,
+        send_attempt_header: Option<bool>,
+        job_retention_days: Option<u32>,
+        db_size_sweep_threshold_bytes: Option<u64>,
+        max_queued_jobs: Option<u64>,
+        max_concurrent_broker_ops: Option<usize>,
+        correlation_id_format: Option<CorrelationIdFormat>,
+        generate_correlation_ids: Option<bool>,
+        email_from: Option<String>,
+        email_from_name: Option<String>,
+        email_reply_to: Option<String>,
+        booking_token_issuer: Option<String>,
+        db_encryption_key: Option<String>,
+        db_encryption_key_file: Option<PathBuf>,
+        // API configuration
+        #[serde(default)]
+        api_cors_origins: Vec<String>,
+        api_token: Option<String>,
+        api_rate_limit_per_min: Option<u32>,
+        api_rate_limit_scope: Option<RateLimitScope>,
+        // Logging configuration
+        log_format: Option<LogFormat>,
+        log_level: Option<String>,
+        otlp_endpoint: Option<String>,
     }
 
     let file_config: Option<FileConfig> = if Path::new("config.toml").exists() {
@@ -165,35 +638,226 @@ pub fn parse_args() -> (CliArgs, Config) {
     // Determine Role, Listen, Dial based on args (Run subcommand or legacy top-level) or config file
     // Default values:
     let mut final_role = Role::Client;
-    let mut final_listen = "/ip4/0.0.0.0/tcp/0".to_string();
+    let mut final_listen = vec!["/ip4/0.0.0.0/tcp/0".to_string()];
     let mut final_dial = None;
     let mut final_peers = vec![];
+    let mut final_require_persistent_identity = false;
     let mut final_bootstrap_peers = vec![];
     let mut final_enable_mdns = true;
     let mut final_enable_kad = true;
     let mut final_enable_relay = false;
+    let mut final_fallback_relay = None;
+    let mut final_enable_upnp = false;
     let mut final_discovery_timeout = 60;
+    let mut final_discovery_timeout_action = DiscoveryTimeoutAction::Log;
+    let mut final_health_check_interval_secs = 10;
+    let mut final_ping_interval_secs = 15;
+    let mut final_ping_timeout_secs = 20;
+    let mut final_max_ping_failures = 3;
+    let mut final_kad_query_timeout_secs = 60;
+    let mut final_kad_maintenance_secs = 60;
+    let mut final_kad_replication_factor = libp2p::kad::K_VALUE.get();
+    let mut final_rr_request_timeout_secs = 30;
+    let mut final_rr_max_retries = 3;
+    let mut final_rr_failure_disconnect_threshold = 0;
+    let mut final_agent_version = format!("hch/{}", env!("CARGO_PKG_VERSION"));
+    let mut final_event_log_capacity = 100;
+    let mut final_snapshot_path = None;
+    let mut final_allowed_peers = vec![];
+    let mut final_denied_peers = vec![];
+    let mut final_disconnect_unrelated_peers = false;
+    let mut final_network_id = None;
+    let mut final_max_concurrent_dials = 8;
+    let mut final_dial_timeout_secs = 20;
     // Broker defaults
     let mut final_central_api_url = None;
+    let mut final_central_api_health_path = None;
     let mut final_db_path = "./data/broker.db".to_string();
+    let mut final_durability_mode = DurabilityMode::Strict;
     let mut final_max_retry_attempts = 10;
+    let mut final_retry_warn_threshold = 5;
     let mut final_initial_backoff_ms = 1000;
+    let mut final_max_backoff_ms = 300_000;
+    let mut final_backoff_jitter_ms = None;
+    let mut final_central_api_connect_timeout_ms = 10_000;
+    let mut final_central_api_request_timeout_ms = 30_000;
+    let mut final_unreadable_success_body = UnreadableBodyAction::Retry;
+    let mut final_send_attempt_header = false;
+    let mut final_job_retention_days = 30;
+    let mut final_db_size_sweep_threshold_bytes = 1024 * 1024 * 1024;
+    let mut final_max_queued_jobs = 10_000;
+    let mut final_max_concurrent_broker_ops = 64;
+    let mut final_correlation_id_format = CorrelationIdFormat::Uuid;
+    let mut final_generate_correlation_ids = false;
+    let mut final_email_from = "bookings@hybrid-connection-health.local".to_string();
+    let mut final_email_from_name = "Hybrid Connection Health".to_string();
+    let mut final_email_reply_to = None;
+    let mut final_booking_token_issuer = None;
+    let mut final_db_encryption_key = None;
+    // API defaults
+    let mut final_api_cors_origins = vec![];
+    let mut final_api_token = None;
+    let mut final_api_rate_limit_per_min = 60;
+    let mut final_api_rate_limit_scope = RateLimitScope::PerIp;
+    // Logging defaults
+    let mut final_log_format = LogFormat::Pretty;
+    let mut final_log_level = "info".to_string();
+    let mut final_otlp_endpoint = None;
 
     if let Some(cfg) = &file_config {
         if let Some(r) = &cfg.role { final_role = r.clone(); }
-        if let Some(l) = &cfg.listen { final_listen = l.clone(); }
+        if let Some(l) = &cfg.listen { final_listen = l.clone().into(); }
         final_dial = cfg.dial.clone();
         final_peers = cfg.peers.clone();
+        if let Some(require) = cfg.require_persistent_identity { final_require_persistent_identity = require; }
         final_bootstrap_peers = cfg.bootstrap_peers.clone();
         if let Some(mdns) = cfg.enable_mdns { final_enable_mdns = mdns; }
         if let Some(kad) = cfg.enable_kad { final_enable_kad = kad; }
         if let Some(relay) = cfg.enable_relay { final_enable_relay = relay; }
+        if let Some(relay) = &cfg.fallback_relay {
+            let addr: Multiaddr = relay
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid fallback_relay '{}': {:?}", relay, e));
+            if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                panic!("Invalid fallback_relay '{}': the address must end in /p2p/<relay peer id>", relay);
+            }
+            if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+                panic!("Invalid fallback_relay '{}': give the relay's own address, without /p2p-circuit", relay);
+            }
+            final_fallback_relay = Some(addr);
+        }
+        if let Some(upnp) = cfg.enable_upnp { final_enable_upnp = upnp; }
         if let Some(timeout) = cfg.discovery_timeout_secs { final_discovery_timeout = timeout; }
+        if let Some(action) = cfg.discovery_timeout_action { final_discovery_timeout_action = action; }
+        if let Some(interval) = cfg.health_check_interval_secs { final_health_check_interval_secs = interval; }
+        if let Some(interval) = cfg.ping_interval_secs { final_ping_interval_secs = interval; }
+        if let Some(timeout) = cfg.ping_timeout_secs { final_ping_timeout_secs = timeout; }
+        if let Some(max) = cfg.max_ping_failures { final_max_ping_failures = max; }
+        if let Some(timeout) = cfg.kad_query_timeout_secs { final_kad_query_timeout_secs = timeout; }
+        if let Some(secs) = cfg.kad_maintenance_secs { final_kad_maintenance_secs = secs; }
+        if let Some(factor) = cfg.kad_replication_factor { final_kad_replication_factor = factor; }
+        if let Some(timeout) = cfg.rr_request_timeout_secs { final_rr_request_timeout_secs = timeout; }
+        if let Some(retries) = cfg.rr_max_retries { final_rr_max_retries = retries; }
+        if let Some(threshold) = cfg.rr_failure_disconnect_threshold { final_rr_failure_disconnect_threshold = threshold; }
+        if let Some(version) = &cfg.agent_version { final_agent_version = version.clone(); }
+        if let Some(capacity) = cfg.event_log_capacity { final_event_log_capacity = capacity; }
+        if let Some(path) = &cfg.snapshot_path { final_snapshot_path = Some(path.clone()); }
+        final_allowed_peers = parse_peer_ids("allowed_peers", &cfg.allowed_peers);
+        final_denied_peers = parse_peer_ids("denied_peers", &cfg.denied_peers);
+        if let Some(disconnect) = cfg.disconnect_unrelated_peers { final_disconnect_unrelated_peers = disconnect; }
+        final_network_id = cfg.network_id.clone();
+        if let Some(max) = cfg.max_concurrent_dials { final_max_concurrent_dials = max; }
+        if let Some(timeout) = cfg.dial_timeout_secs { final_dial_timeout_secs = timeout; }
         // Broker config
         final_central_api_url = cfg.central_api_url.clone();
+        final_central_api_health_path = cfg.central_api_health_path.clone();
         if let Some(db_path) = &cfg.db_path { final_db_path = db_path.clone(); }
+        if let Some(mode) = cfg.durability_mode { final_durability_mode = mode; }
         if let Some(attempts) = cfg.max_retry_attempts { final_max_retry_attempts = attempts; }
+        if let Some(threshold) = cfg.retry_warn_threshold { final_retry_warn_threshold = threshold; }
         if let Some(backoff) = cfg.initial_backoff_ms { final_initial_backoff_ms = backoff; }
+        if let Some(backoff) = cfg.max_backoff_ms { final_max_backoff_ms = backoff; }
+        if let Some(jitter) = cfg.backoff_jitter_ms { final_backoff_jitter_ms = Some(jitter); }
+        if let Some(timeout) = cfg.central_api_connect_timeout_ms { final_central_api_connect_timeout_ms = timeout; }
+        if let Some(timeout) = cfg.central_api_request_timeout_ms { final_central_api_request_timeout_ms = timeout; }
+        if let Some(action) = cfg.unreadable_success_body { final_unreadable_success_body = action; }
+        if let Some(send) = cfg.send_attempt_header { final_send_attempt_header = send; }
+        if let Some(days) = cfg.job_retention_days { final_job_retention_days = days; }
+        if let Some(bytes) = cfg.db_size_sweep_threshold_bytes { final_db_size_sweep_threshold_bytes = bytes; }
+        if let Some(max) = cfg.max_queued_jobs { final_max_queued_jobs = max; }
+        if let Some(max) = cfg.max_concurrent_broker_ops { final_max_concurrent_broker_ops = max; }
+        if let Some(format) = cfg.correlation_id_format { final_correlation_id_format = format; }
+        if let Some(generate) = cfg.generate_correlation_ids { final_generate_correlation_ids = generate; }
+        if let Some(from) = &cfg.email_from { final_email_from = from.clone(); }
+        if let Some(name) = &cfg.email_from_name { final_email_from_name = name.clone(); }
+        if let Some(reply_to) = &cfg.email_reply_to { final_email_reply_to = Some(reply_to.clone()); }
+        if let Some(issuer) = &cfg.booking_token_issuer {
+            let issuer: PeerId = issuer
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid booking_token_issuer '{}': {:?}", issuer, e));
+            if crate::p2p::capability::issuer_public_key(&issuer).is_none() {
+                panic!("Invalid booking_token_issuer '{}': the PeerId must embed its public key (ed25519)", issuer);
+            }
+            final_booking_token_issuer = Some(issuer);
+        }
+        final_db_encryption_key = load_db_encryption_key(cfg.db_encryption_key.as_deref(), cfg.db_encryption_key_file.as_deref());
+        // API config
+        final_api_cors_origins = cfg.api_cors_origins.clone();
+        if let Some(token) = &cfg.api_token { final_api_token = Some(token.clone()); }
+        if let Some(limit) = cfg.api_rate_limit_per_min { final_api_rate_limit_per_min = limit; }
+        if let Some(scope) = cfg.api_rate_limit_scope { final_api_rate_limit_scope = scope; }
+        // Logging config
+        if let Some(format) = cfg.log_format { final_log_format = format; }
+        if let Some(level) = &cfg.log_level { final_log_level = level.clone(); }
+        final_otlp_endpoint = cfg.otlp_endpoint.clone();
+    }
+
+    // Environment overrides for logging (handy in containers)
+    if let Ok(format) = std::env::var("LOG_FORMAT") {
+        match LogFormat::from_str(&format, true) {
+            Ok(f) => final_log_format = f,
+            Err(_) => eprintln!("Ignoring invalid LOG_FORMAT '{}': expected pretty or json", format),
+        }
+    }
+
+    if !(1..=libp2p::kad::K_VALUE.get()).contains(&final_kad_replication_factor) {
+        panic!(
+            "Invalid kad_replication_factor {}: must be between 1 and {}",
+            final_kad_replication_factor,
+            libp2p::kad::K_VALUE
+        );
+    }
+    if final_central_api_connect_timeout_ms == 0 || final_central_api_request_timeout_ms == 0 {
+        panic!("Invalid central API timeouts: central_api_connect_timeout_ms and central_api_request_timeout_ms must be greater than 0");
+    }
+    if final_max_backoff_ms == 0 {
+        panic!("Invalid max_backoff_ms: must be greater than 0");
+    }
+    if final_kad_query_timeout_secs == 0 {
+        panic!("Invalid kad_query_timeout_secs: must be greater than 0");
+    }
+    if final_rr_request_timeout_secs == 0 {
+        panic!("Invalid rr_request_timeout_secs: must be greater than 0");
+    }
+    if final_health_check_interval_secs == 0 {
+        panic!("Invalid health_check_interval_secs: must be greater than 0");
+    }
+    if final_ping_interval_secs == 0 || final_ping_timeout_secs == 0 {
+        panic!("Invalid ping settings: ping_interval_secs and ping_timeout_secs must be greater than 0");
+    }
+    if final_network_id.as_deref().is_some_and(|id: &str| id.is_empty() || id.contains('/')) {
+        panic!("Invalid network_id: must be non-empty and must not contain '/'");
+    }
+    if final_max_concurrent_dials == 0 {
+        panic!("Invalid max_concurrent_dials: must be greater than 0");
+    }
+    if final_dial_timeout_secs == 0 {
+        panic!("Invalid dial_timeout_secs: must be greater than 0");
+    }
+    if final_max_concurrent_broker_ops == 0 {
+        panic!("Invalid max_concurrent_broker_ops: must be greater than 0");
+    }
+    if !crate::broker::handler::is_valid_email(&final_email_from) {
+        panic!("Invalid email_from '{}': expected a well-formed email address", final_email_from);
+    }
+    if let Some(reply_to) = final_email_reply_to.as_deref().filter(|a| !crate::broker::handler::is_valid_email(a)) {
+        panic!("Invalid email_reply_to '{}': expected a well-formed email address", reply_to);
+    }
+    // Goes into a From header: no line breaks, and no quotes or brackets that would end the display name
+    if final_email_from_name.chars().any(|c| c.is_control() || matches!(c, '"' | '\\' | '<' | '>')) {
+        panic!("Invalid email_from_name: must not contain control characters, quotes, backslashes or angle brackets");
+    }
+    if final_central_api_health_path.as_deref().is_some_and(|p: &str| !p.starts_with('/')) {
+        panic!("Invalid central_api_health_path: must start with '/'");
+    }
+
+    if final_api_token.as_deref().is_some_and(|t| t.trim().is_empty()) {
+        panic!("Invalid api_token: must not be empty");
+    }
+    for origin in &final_api_cors_origins {
+        if !is_valid_cors_origin(origin) {
+            panic!("Invalid api_cors_origins entry '{}': expected \"*\" or scheme://host[:port]", origin);
+        }
     }
 
     // Overrides from CLI
@@ -206,24 +870,38 @@ pub fn parse_args() -> (CliArgs, Config) {
                 final_role = r.clone();
             }
 
-            if let Some(l) = listen { final_listen = l.clone(); }
-            else if let Some(l) = &args.listen { final_listen = l.clone(); }
+            if !listen.is_empty() { final_listen = listen.clone(); }
+            else if !args.listen.is_empty() { final_listen = args.listen.clone(); }
 
             if let Some(d) = dial { final_dial = Some(d.clone()); }
             else if let Some(d) = &args.dial { final_dial = Some(d.clone()); }
         }
-        Some(Commands::PeerId) => {
-            // No config needed for PeerId mainly, but we return a valid config anyway
+        Some(Commands::PeerId) | Some(Commands::MintBookingToken { .. }) => {
+            // No config needed beyond the identity, but we return a valid config anyway
+        }
+        Some(Commands::BrokerExport { .. }) | Some(Commands::BrokerImport { .. }) => {
+            // Only db_path and the encryption key from config.toml are used
         }
         Some(Commands::TestSubmit { listen, dial, .. }) => {
             final_role = Role::Client; // Tester acts as client
-            final_listen = listen.clone();
+            final_listen = vec![listen.clone()];
+            final_dial = Some(dial.clone());
+        }
+        Some(Commands::TestBooking { dial, .. }) => {
+            final_role = Role::Client;
+            final_listen = vec!["/ip4/0.0.0.0/tcp/0".to_string()];
             final_dial = Some(dial.clone());
         }
+        Some(Commands::SubmitBooking { dial, .. }) => {
+            // Bootstrap peers and kad settings from config.toml still apply
+            final_role = Role::Client;
+            final_listen = vec!["/ip4/0.0.0.0/tcp/0".to_string()];
+            if let Some(d) = dial { final_dial = Some(d.clone()); }
+        }
         None => {
             // Fallback: Check top-level args
             if let Some(r) = &args.role { final_role = r.clone(); }
-            if let Some(l) = &args.listen { final_listen = l.clone(); }
+            if !args.listen.is_empty() { final_listen = args.listen.clone(); }
             if let Some(d) = &args.dial { final_dial = Some(d.clone()); }
         }
     }
@@ -231,8 +909,10 @@ pub fn parse_args() -> (CliArgs, Config) {
     // Identity handling
     let keypair = if let Some(path) = &args.identity_file {
         load_or_create_identity(path)
+    } else if final_require_persistent_identity {
+        panic!("require_persistent_identity is set but no --identity-file was given; refusing to run with an ephemeral identity");
     } else {
-        // If no file specified, generate ephemeral
+        // If no file specified, generate ephemeral (main warns about it)
         identity::Keypair::generate_ed25519()
     };
 
@@ -241,17 +921,266 @@ pub fn parse_args() -> (CliArgs, Config) {
         listen: final_listen,
         dial: final_dial,
         peers: final_peers,
+        require_persistent_identity: final_require_persistent_identity,
         identity_keypair: keypair,
         bootstrap_peers: final_bootstrap_peers,
         enable_mdns: final_enable_mdns,
         enable_kad: final_enable_kad,
         enable_relay: final_enable_relay,
+        fallback_relay: final_fallback_relay,
+        enable_upnp: final_enable_upnp,
         discovery_timeout_secs: final_discovery_timeout,
+        discovery_timeout_action: final_discovery_timeout_action,
+        health_check_interval_secs: final_health_check_interval_secs,
+        ping_interval_secs: final_ping_interval_secs,
+        ping_timeout_secs: final_ping_timeout_secs,
+        max_ping_failures: final_max_ping_failures,
+        kad_query_timeout_secs: final_kad_query_timeout_secs,
+        kad_maintenance_secs: final_kad_maintenance_secs,
+        kad_replication_factor: final_kad_replication_factor,
+        rr_request_timeout_secs: final_rr_request_timeout_secs,
+        rr_max_retries: final_rr_max_retries,
+        rr_failure_disconnect_threshold: final_rr_failure_disconnect_threshold,
+        agent_version: final_agent_version,
+        event_log_capacity: final_event_log_capacity,
+        snapshot_path: final_snapshot_path,
+        allowed_peers: final_allowed_peers,
+        denied_peers: final_denied_peers,
+        disconnect_unrelated_peers: final_disconnect_unrelated_peers,
+        network_id: final_network_id,
+        max_concurrent_dials: final_max_concurrent_dials,
+        dial_timeout_secs: final_dial_timeout_secs,
         central_api_url: final_central_api_url,
+        central_api_health_path: final_central_api_health_path,
         db_path: final_db_path,
+        durability_mode: final_durability_mode,
         max_retry_attempts: final_max_retry_attempts,
+        retry_warn_threshold: final_retry_warn_threshold,
         initial_backoff_ms: final_initial_backoff_ms,
+        max_backoff_ms: final_max_backoff_ms,
+        // Unset means full jitter over whatever the delay can grow to
+        backoff_jitter_ms: final_backoff_jitter_ms.unwrap_or(final_max_backoff_ms),
+        central_api_connect_timeout_ms: final_central_api_connect_timeout_ms,
+        central_api_request_timeout_ms: final_central_api_request_timeout_ms,
+        unreadable_success_body: final_unreadable_success_body,
+        send_attempt_header: final_send_attempt_header,
+        job_retention_days: final_job_retention_days,
+        db_size_sweep_threshold_bytes: final_db_size_sweep_threshold_bytes,
+        max_queued_jobs: final_max_queued_jobs,
+        max_concurrent_broker_ops: final_max_concurrent_broker_ops,
+        correlation_id_format: final_correlation_id_format,
+        generate_correlation_ids: final_generate_correlation_ids,
+        email_from: final_email_from,
+        email_from_name: final_email_from_name,
+        email_reply_to: final_email_reply_to,
+        booking_token_issuer: final_booking_token_issuer,
+        db_encryption_key: final_db_encryption_key,
+        api_cors_origins: final_api_cors_origins,
+        api_token: final_api_token,
+        api_rate_limit_per_min: final_api_rate_limit_per_min,
+        api_rate_limit_scope: final_api_rate_limit_scope,
+        log_format: final_log_format,
+        log_level: final_log_level,
+        otlp_endpoint: final_otlp_endpoint,
     };
 
     (args, config)
 }
+
+impl Config {
+    /// Loopback-only client config with short, deterministic defaults, shared
+    /// by the unit tests; tests override just the fields they exercise
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        Config {
+            role: Role::Client,
+            listen: vec!["/ip4/127.0.0.1/tcp/0".to_string()],
+            dial: None,
+            peers: vec![],
+            require_persistent_identity: false,
+            identity_keypair: libp2p::identity::Keypair::generate_ed25519(),
+            bootstrap_peers: vec![],
+            enable_mdns: false,
+            enable_kad: true,
+            enable_relay: false,
+            fallback_relay: None,
+            discovery_timeout_secs: 60,
+            discovery_timeout_action: DiscoveryTimeoutAction::Log,
+            health_check_interval_secs: 10,
+            ping_interval_secs: 15,
+            ping_timeout_secs: 20,
+            max_ping_failures: 3,
+            central_api_url: None,
+            central_api_health_path: None,
+            db_path: "./data/broker.db".to_string(),
+            durability_mode: DurabilityMode::Strict,
+            max_retry_attempts: 10,
+            retry_warn_threshold: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 300_000,
+            backoff_jitter_ms: 300_000,
+            api_cors_origins: vec![],
+            log_format: LogFormat::Pretty,
+            log_level: "info".to_string(),
+            otlp_endpoint: None,
+            allowed_peers: vec![],
+            denied_peers: vec![],
+            disconnect_unrelated_peers: false,
+            network_id: None,
+            max_concurrent_dials: 8,
+            dial_timeout_secs: 20,
+            kad_query_timeout_secs: 60,
+            kad_maintenance_secs: 60,
+            kad_replication_factor: 20,
+            agent_version: "hch/test".to_string(),
+            event_log_capacity: 100,
+            snapshot_path: None,
+            central_api_connect_timeout_ms: 10_000,
+            central_api_request_timeout_ms: 30_000,
+            unreadable_success_body: UnreadableBodyAction::Retry,
+            send_attempt_header: false,
+            job_retention_days: 30,
+            db_size_sweep_threshold_bytes: 0,
+            max_queued_jobs: 10_000,
+            max_concurrent_broker_ops: 64,
+            correlation_id_format: CorrelationIdFormat::Any,
+            generate_correlation_ids: false,
+            email_from: "bookings@example.com".to_string(),
+            email_from_name: "Bookings".to_string(),
+            email_reply_to: None,
+            booking_token_issuer: None,
+            db_encryption_key: None,
+            api_token: None,
+            api_rate_limit_per_min: 60,
+            api_rate_limit_scope: RateLimitScope::PerIp,
+            rr_request_timeout_secs: 30,
+            rr_max_retries: 3,
+            rr_failure_disconnect_threshold: 0,
+            enable_upnp: false,
+        }
+    }
+
+    /// Whether a peer may connect: not denied, and in the allowlist when one is set
+    pub fn is_peer_permitted(&self, peer_id: &PeerId) -> bool {
+        !self.denied_peers.contains(peer_id)
+            && (self.allowed_peers.is_empty() || self.allowed_peers.contains(peer_id))
+    }
+
+    /// The effective configuration with every secret redacted, safe to expose over the API
+    pub fn redacted(&self) -> RedactedConfig {
+        let peer_ids = |peers: &[PeerId]| peers.iter().map(|p| p.to_string()).collect();
+        RedactedConfig {
+            role: self.role.clone(),
+            listen: self.listen.clone(),
+            dial: self.dial.clone(),
+            peers: self.peers.clone(),
+            peer_id: self.identity_keypair.public().to_peer_id().to_string(),
+            identity_keypair: REDACTED,
+            require_persistent_identity: self.require_persistent_identity,
+            bootstrap_peers: self.bootstrap_peers.clone(),
+            enable_mdns: self.enable_mdns,
+            enable_kad: self.enable_kad,
+            enable_relay: self.enable_relay,
+            fallback_relay: self.fallback_relay.as_ref().map(|a| a.to_string()),
+            enable_upnp: self.enable_upnp,
+            discovery_timeout_secs: self.discovery_timeout_secs,
+            discovery_timeout_action: self.discovery_timeout_action,
+            health_check_interval_secs: self.health_check_interval_secs,
+            ping_interval_secs: self.ping_interval_secs,
+            ping_timeout_secs: self.ping_timeout_secs,
+            max_ping_failures: self.max_ping_failures,
+            kad_query_timeout_secs: self.kad_query_timeout_secs,
+            kad_maintenance_secs: self.kad_maintenance_secs,
+            kad_replication_factor: self.kad_replication_factor,
+            rr_request_timeout_secs: self.rr_request_timeout_secs,
+            rr_max_retries: self.rr_max_retries,
+            rr_failure_disconnect_threshold: self.rr_failure_disconnect_threshold,
+            agent_version: self.agent_version.clone(),
+            event_log_capacity: self.event_log_capacity,
+            snapshot_path: self.snapshot_path.clone(),
+            allowed_peers: peer_ids(&self.allowed_peers),
+            denied_peers: peer_ids(&self.denied_peers),
+            disconnect_unrelated_peers: self.disconnect_unrelated_peers,
+            network_id: self.network_id.clone(),
+            max_concurrent_dials: self.max_concurrent_dials,
+            dial_timeout_secs: self.dial_timeout_secs,
+            central_api_url: self.central_api_url.clone(),
+            central_api_health_path: self.central_api_health_path.clone(),
+            db_path: self.db_path.clone(),
+            durability_mode: self.durability_mode,
+            max_retry_attempts: self.max_retry_attempts,
+            retry_warn_threshold: self.retry_warn_threshold,
+            initial_backoff_ms: self.initial_backoff_ms,
+            max_backoff_ms: self.max_backoff_ms,
+            backoff_jitter_ms: self.backoff_jitter_ms,
+            central_api_connect_timeout_ms: self.central_api_connect_timeout_ms,
+            central_api_request_timeout_ms: self.central_api_request_timeout_ms,
+            unreadable_success_body: self.unreadable_success_body,
+            send_attempt_header: self.send_attempt_header,
+            job_retention_days: self.job_retention_days,
+            db_size_sweep_threshold_bytes: self.db_size_sweep_threshold_bytes,
+            max_queued_jobs: self.max_queued_jobs,
+            max_concurrent_broker_ops: self.max_concurrent_broker_ops,
+            correlation_id_format: self.correlation_id_format,
+            generate_correlation_ids: self.generate_correlation_ids,
+            email_from: self.email_from.clone(),
+            email_from_name: self.email_from_name.clone(),
+            email_reply_to: self.email_reply_to.clone(),
+            booking_token_issuer: self.booking_token_issuer.map(|p| p.to_string()),
+            db_encryption_key: self.db_encryption_key.as_ref().map(|_| REDACTED),
+            api_cors_origins: self.api_cors_origins.clone(),
+            api_token: self.api_token.as_ref().map(|_| REDACTED),
+            api_rate_limit_per_min: self.api_rate_limit_per_min,
+            api_rate_limit_scope: self.api_rate_limit_scope,
+            log_format: self.log_format,
+            log_level: self.log_level.clone(),
+            otlp_endpoint: self.otlp_endpoint.clone(),
+        }
+    }
+}
+
+/// Hex key from the config, or a key derived from the contents of a key file
+fn load_db_encryption_key(hex_key: Option<&str>, key_file: Option<&Path>) -> Option<DbEncryptionKey> {
+    match (hex_key, key_file) {
+        (Some(_), Some(_)) => panic!("Set only one of db_encryption_key and db_encryption_key_file"),
+        (Some(hex_key), None) => Some(
+            DbEncryptionKey::from_hex(hex_key)
+                .unwrap_or_else(|e| panic!("Invalid db_encryption_key: {}", e)),
+        ),
+        (None, Some(path)) => {
+            let material = fs::read(path)
+                .unwrap_or_else(|e| panic!("Failed to read db_encryption_key_file {}: {}", path.display(), e));
+            if material.is_empty() {
+                panic!("db_encryption_key_file {} is empty", path.display());
+            }
+            Some(DbEncryptionKey::derive(&material))
+        }
+        (None, None) => None,
+    }
+}
+
+fn parse_peer_ids(field: &str, values: &[String]) -> Vec<PeerId> {
+    values
+        .iter()
+        .map(|v| {
+            v.parse()
+                .unwrap_or_else(|e| panic!("Invalid {} entry '{}': {:?}", field, v, e))
+        })
+        .collect()
+}
+
+/// A CORS origin is either `*` or `scheme://host[:port]` with no path
+fn is_valid_cors_origin(origin: &str) -> bool {
+    if origin == "*" {
+        return true;
+    }
+    match origin.split_once("://") {
+        Some((scheme, host)) => {
+            matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains('/')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests;
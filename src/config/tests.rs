@@ -0,0 +1,36 @@
+use super::ListenAddrs;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct ListenOnly {
+    listen: ListenAddrs,
+}
+
+fn parse_listen(toml: &str) -> Vec<String> {
+    toml::from_str::<ListenOnly>(toml).unwrap().listen.into()
+}
+
+#[test]
+fn test_listen_accepts_a_single_address() {
+    // The legacy form, still found in older config.toml files
+    assert_eq!(parse_listen(r#"listen = "/ip4/0.0.0.0/tcp/4001""#), vec!["/ip4/0.0.0.0/tcp/4001"]);
+}
+
+#[test]
+fn test_listen_accepts_a_list_of_addresses() {
+    assert_eq!(
+        parse_listen(r#"listen = ["/ip4/0.0.0.0/tcp/4001", "/ip6/::/tcp/4001"]"#),
+        vec!["/ip4/0.0.0.0/tcp/4001", "/ip6/::/tcp/4001"],
+    );
+}
+
+#[test]
+fn test_cors_origins_are_bare_http_origins_or_any() {
+    for origin in ["*", "http://localhost:3000", "https://dashboard.example.com"] {
+        assert!(super::is_valid_cors_origin(origin), "{}", origin);
+    }
+    // No scheme, an unsupported scheme, a path or nothing after the scheme
+    for origin in ["localhost:3000", "ftp://example.com", "https://example.com/app", "https://"] {
+        assert!(!super::is_valid_cors_origin(origin), "{}", origin);
+    }
+}
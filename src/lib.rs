@@ -1,5 +1,6 @@
 pub mod api;
 pub mod broker;
 pub mod config;
+pub mod metrics;
 pub mod p2p;
 
@@ -2,117 +2,469 @@ mod config;
 mod p2p;
 mod api;
 mod broker;
+mod metrics;
 
 use anyhow::{Context, Result};
 use config::Commands;
-use p2p::swarm::{build_swarm, run_swarm, run_test_submission};
-use tracing::info;
+use p2p::protocol::BookingStatus;
+use p2p::swarm::{build_swarm, run_swarm, run_test_booking, run_test_submission, submit_booking};
+use prometheus_client::registry::Registry;
+use std::time::Duration;
+use tracing::{error, info, warn};
 use tokio::signal;
+use tokio::sync::watch;
+
+/// Upper bound for the swarm and broker workers to wind down after a shutdown
+/// signal. Covers a forwarder request in flight (30s HTTP timeout).
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix (e.g. `docker stop`)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => info!("Received Ctrl+C, shutting down..."),
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down..."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+        info!("Received Ctrl+C, shutting down...");
+    }
+}
+
+/// Flushes and stops the OTLP exporter when dropped, so spans still queued in
+/// the batch processor are sent before the process exits
+struct OtlpGuard(opentelemetry_sdk::trace::SdkTracerProvider);
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("Failed to shut down the OTLP exporter: {}", e);
+        }
+    }
+}
+
+/// Builds the OTLP/HTTP span exporter for `endpoint`
+fn init_otlp(config: &config::Config, endpoint: &str) -> Result<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .context("Failed to build the OTLP span exporter")?;
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name("hybrid-connection-health")
+        .with_attribute(opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .with_attribute(opentelemetry::KeyValue::new("node.role", config.role.to_string()))
+        .build();
+    // W3C `traceparent` so the Central API can join the forwarder's trace
+    opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+    Ok(opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build())
+}
+
+/// Install the global tracing subscriber. `RUST_LOG` wins over `log_level`.
+/// Logs go to stdout unless `to_stderr` (stdout is reserved for a result).
+/// With `otlp_endpoint` set, spans are also exported over OTLP; the returned
+/// guard must be kept alive until exit.
+fn init_logging(config: &config::Config, to_stderr: bool) -> Option<OtlpGuard> {
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::{EnvFilter, Layer};
+
+    let writer = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::try_new(&config.log_level).unwrap_or_else(|e| {
+            eprintln!("Invalid log_level '{}' ({}), falling back to info", config.log_level, e);
+            EnvFilter::new("info")
+        })
+    });
+
+    let fmt_layer = match config.log_format {
+        config::LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .boxed(),
+        config::LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer)
+            .with_target(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    // Without an endpoint (or if the exporter can't be built) only the plain subscriber is installed
+    let provider = config.otlp_endpoint.as_deref().and_then(|endpoint| {
+        init_otlp(config, endpoint)
+            .map_err(|e| eprintln!("OTLP trace export disabled: {:#}", e))
+            .ok()
+    });
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("hybrid-connection-health")));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+
+    provider.map(OtlpGuard)
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("setting default subscriber failed");
-
     // Parse CLI args
     let (cli_args, config) = config::parse_args();
 
+    // Initialize logging
+    let result_on_stdout = matches!(
+        cli_args.command,
+        Some(Commands::TestSubmit { json: true, .. }) | Some(Commands::SubmitBooking { .. })
+    );
+    let otlp_guard = init_logging(&config, result_on_stdout);
+    if let (Some(_), Some(endpoint)) = (&otlp_guard, &config.otlp_endpoint) {
+        info!("Exporting traces over OTLP to {}", endpoint);
+    }
+
     match cli_args.command {
         Some(Commands::PeerId) => {
             let peer_id = libp2p::PeerId::from(config.identity_keypair.public());
             println!("{}", peer_id);
+            if cli_args.identity_file.is_none() {
+                // stderr, so scripts reading the PeerId from stdout are unaffected
+                eprintln!("Note: no --identity-file given, this PeerId is ephemeral and won't be used by any later run");
+            }
+            return Ok(());
+        }
+        Some(Commands::MintBookingToken { peer, ttl_secs }) => {
+            // An ephemeral key would sign tokens no gateway can be configured to trust
+            if cli_args.identity_file.is_none() {
+                anyhow::bail!("mint-booking-token needs --identity-file (the issuer's identity)");
+            }
+            let token = p2p::capability::mint(&config.identity_keypair, &peer, std::time::Duration::from_secs(ttl_secs))?;
+            println!("{}", token);
             return Ok(());
         }
-        Some(Commands::TestSubmit { listen, dial, timeout_secs }) => {
+        Some(Commands::TestSubmit { listen, dial, timeout_secs, kind, entity, payload_file, json }) => {
             info!("Starting One-Shot Test: Submit Op -> Wait Ack");
-            // Build swarm with persistent identity (from config) but override listen addr
-            // We use the same config struct but maybe we should override listen in it?
-            // Actually build_swarm uses config.listen.
-            let mut test_config = config.clone();
-            test_config.listen = listen;
-            // dial is passed to run_test_submission, not used in build_swarm for initial dial here (though it could be)
+            let op_id = uuid::Uuid::new_v4().to_string();
+            let result = async {
+                let payload_json = match payload_file {
+                    Some(path) => {
+                        let payload = std::fs::read_to_string(&path)
+                            .with_context(|| format!("Failed to read payload file {}", path.display()))?;
+                        // Re-serialized so the Op carries compact JSON regardless of file formatting
+                        serde_json::from_str::<serde_json::Value>(&payload)
+                            .with_context(|| format!("Payload file {} is not valid JSON", path.display()))?
+                            .to_string()
+                    }
+                    None => "{}".to_string(),
+                };
+
+                // Build swarm with persistent identity (from config) but override listen addr
+                // We use the same config struct but maybe we should override listen in it?
+                // Actually build_swarm uses config.listen.
+                let mut test_config = config.clone();
+                test_config.listen = vec![listen];
+                // dial is passed to run_test_submission, not used in build_swarm for initial dial here (though it could be)
             
-            let swarm = build_swarm(&test_config).await?;
-            run_test_submission(swarm, dial, timeout_secs).await?;
+                let swarm = build_swarm(&test_config, &mut Registry::default()).await?;
+                let op = p2p::protocol::Op {
+                    op_id: op_id.clone(),
+                    actor_id: swarm.local_peer_id().to_string(),
+                    kind,
+                    entity,
+                    payload_json,
+                    created_at_ms: chrono::Utc::now().timestamp_millis(),
+                };
+                run_test_submission(swarm, dial, op, timeout_secs).await
+            }
+            .await;
+
+            if json {
+                // One line for scripts; a failure is still returned below, so the
+                // exit code mirrors `passed`
+                let report = serde_json::json!({
+                    "passed": result.is_ok(),
+                    "op_id": op_id,
+                    "rtt_ms": result.as_ref().ok().map(|rtt| rtt.as_millis() as u64),
+                    "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+                });
+                println!("{}", report);
+            }
+            let rtt = result?;
+            info!(rtt_ms = rtt.as_millis() as u64, "Test completed successfully.");
+            return Ok(());
+        }
+        Some(Commands::TestBooking { dial, date, start_time, end_time, name, email, timeout_secs }) => {
+            info!("Starting One-Shot Test: SubmitBooking -> Wait BookingAck");
+            let swarm = build_swarm(&config, &mut Registry::default()).await?;
+            let booking = p2p::protocol::BookingData { date, start_time, end_time, name };
+            let notify = p2p::protocol::NotifyData { email, locale: None, timezone: None };
+            run_test_booking(swarm, dial, booking, notify, config.fallback_relay.as_ref(), timeout_secs).await?;
             info!("Test completed successfully.");
             return Ok(());
         }
+        Some(Commands::SubmitBooking { dial, date, start_time, end_time, name, email, locale, timezone, token, timeout_secs }) => {
+            let swarm = build_swarm(&config, &mut Registry::default()).await?;
+            let booking = p2p::protocol::BookingData { date, start_time, end_time, name };
+            let notify = p2p::protocol::NotifyData { email, locale, timezone };
+            let (correlation_id, status) = submit_booking(swarm, dial, booking, notify, token, config.fallback_relay.as_ref(), timeout_secs).await?;
+            println!("{}", serde_json::json!({ "correlation_id": correlation_id, "status": status }));
+            // Anything else (busy, throttled, draining, failed, ...) means the booking wasn't taken
+            if !matches!(status, BookingStatus::Queued | BookingStatus::Confirmed) {
+                anyhow::bail!("Gateway did not accept the booking: {}", status);
+            }
+            return Ok(());
+        }
+        Some(Commands::BrokerExport { out }) => {
+            let storage = broker::storage::BrokerStorage::new(&config.db_path, config.db_encryption_key.as_ref(), config.durability_mode)
+                .context("Failed to open broker storage")?;
+            let file = std::fs::File::create(&out)
+                .with_context(|| format!("Failed to create export file {}", out.display()))?;
+            let stats = broker::backup::export_ndjson(&storage, std::io::BufWriter::new(file))?;
+            info!(jobs = stats.jobs, notifications = stats.notifications, ops = stats.ops, "Exported broker DB to {}", out.display());
+            return Ok(());
+        }
+        Some(Commands::BrokerImport { input }) => {
+            let storage = broker::storage::BrokerStorage::new(&config.db_path, config.db_encryption_key.as_ref(), config.durability_mode)
+                .context("Failed to open broker storage")?;
+            let file = std::fs::File::open(&input)
+                .with_context(|| format!("Failed to open import file {}", input.display()))?;
+            let stats = broker::backup::import_ndjson(&storage, std::io::BufReader::new(file))?;
+            info!(imported = stats.imported, skipped = stats.skipped, "Imported {} into broker DB", input.display());
+            return Ok(());
+        }
         _ => {
             // Run mode (Default or Explicit)
             info!("Starting P2P Node with Role: {}", config.role);
+            if cli_args.identity_file.is_none() {
+                warn!(
+                    "⚠️  No --identity-file given: running with an ephemeral identity. This node's PeerId ({}) \
+                     will change on every restart, so peers and bootstrap lists that know it will lose it.",
+                    config.identity_keypair.public().to_peer_id()
+                );
+            }
             
-            // Build Swarm
-            let swarm = build_swarm(&config).await?;
+            // Build Swarm (its transport registers bandwidth counters in the registry)
+            let mut registry = Registry::default();
+            let swarm = build_swarm(&config, &mut registry).await?;
+            let mut metrics = metrics::Metrics::new(registry);
             let local_peer_id = swarm.local_peer_id().to_string();
             let network_state = api::new_shared_network_state(&config, local_peer_id);
+            let broker_enabled = matches!(config.role, config::Role::Gateway) && config.central_api_url.is_some();
+            let dht_required = matches!(config.role, config::Role::Gateway) && config.enable_kad;
+            let readiness = std::sync::Arc::new(api::Readiness::new(broker_enabled, dht_required));
+
+            // Shutdown signal shared by the swarm loop and broker workers
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let mut worker_handles = Vec::new();
+
+            // Keep the /network history across restarts when snapshot_path is set
+            if let Some(path) = &config.snapshot_path {
+                worker_handles.push(tokio::spawn(api::persist_network_snapshot(
+                    network_state.clone(),
+                    path.into(),
+                    shutdown_rx.clone(),
+                )));
+            }
+            let mut broker_storage = None;
 
             // Setup broker components if Gateway role and central_api_url configured
-            let broker_handler = if matches!(config.role, config::Role::Gateway) && config.central_api_url.is_some() {
+            let broker_handler = if broker_enabled {
                 use broker::storage::BrokerStorage;
                 use broker::handler::BrokerHandler;
                 use broker::forwarder::ForwarderWorker;
                 use broker::notifier::NotifierWorker;
+                use broker::ops::OpWorker;
+                use broker::retention::{RetentionWorker, SizeMonitor};
+                use broker::supervisor::supervise;
                 use std::sync::Arc;
 
                 info!("Initializing broker components...");
                 
                 // Create storage
                 let storage = Arc::new(
-                    BrokerStorage::new(&config.db_path)
+                    BrokerStorage::new(&config.db_path, config.db_encryption_key.as_ref(), config.durability_mode)
                         .context("Failed to initialize broker storage")?
                 );
 
                 // Create broker handler
-                let handler = Arc::new(BrokerHandler::new(storage.clone()));
+                let handler = Arc::new(BrokerHandler::new(storage.clone(), config.max_queued_jobs)
+                    .with_max_concurrent_ops(config.max_concurrent_broker_ops));
 
-                // Spawn forwarder worker
-                let forwarder = ForwarderWorker::new(storage.clone(), config.clone())
-                    .context("Failed to create forwarder worker")?;
-                tokio::spawn(async move {
-                    if let Err(e) = forwarder.run().await {
-                        tracing::error!("Forwarder worker error: {:?}", e);
-                    }
-                });
+                // Spawn forwarder worker (restarted if it panics or errors out)
+                let forwarder = Arc::new(
+                    ForwarderWorker::new(storage.clone(), config.clone(), &metrics)
+                        .context("Failed to create forwarder worker")?,
+                );
+                worker_handles.push(tokio::spawn(supervise("forwarder", shutdown_rx.clone(), move |shutdown| {
+                    let forwarder = forwarder.clone();
+                    async move { forwarder.run(shutdown).await }
+                })));
                 info!("Forwarder worker spawned");
 
-                // Spawn notifier worker
-                let notifier = NotifierWorker::new(storage.clone());
-                tokio::spawn(async move {
-                    if let Err(e) = notifier.run().await {
-                        tracing::error!("Notifier worker error: {:?}", e);
-                    }
-                });
+                // Spawn notifier worker (restarted if it panics or errors out)
+                let notifier = Arc::new(NotifierWorker::new(storage.clone(), &config));
+                worker_handles.push(tokio::spawn(supervise("notifier", shutdown_rx.clone(), move |shutdown| {
+                    let notifier = notifier.clone();
+                    async move { notifier.run(shutdown).await }
+                })));
                 info!("Notifier worker spawned");
 
+                // Spawn op worker for ops received via OpSubmit (restarted if it panics or errors out)
+                let op_worker = Arc::new(OpWorker::new(storage.clone()));
+                worker_handles.push(tokio::spawn(supervise("ops", shutdown_rx.clone(), move |shutdown| {
+                    let op_worker = op_worker.clone();
+                    async move { op_worker.run(shutdown).await }
+                })));
+                info!("Op worker spawned");
+
+                // Spawn retention worker unless finished jobs are kept forever
+                if config.job_retention_days > 0 {
+                    let retention = Arc::new(RetentionWorker::new(storage.clone(), config.job_retention_days));
+                    worker_handles.push(tokio::spawn(supervise("retention", shutdown_rx.clone(), move |shutdown| {
+                        let retention = retention.clone();
+                        async move { retention.run(shutdown).await }
+                    })));
+                    info!("Retention worker spawned");
+                }
+
+                // Spawn DB size monitor (logs the size, sweeps early past the threshold)
+                let size_monitor = Arc::new(SizeMonitor::new(
+                    storage.clone(),
+                    config.db_size_sweep_threshold_bytes,
+                    config.job_retention_days,
+                ));
+                worker_handles.push(tokio::spawn(supervise("size-monitor", shutdown_rx.clone(), move |shutdown| {
+                    let size_monitor = size_monitor.clone();
+                    async move { size_monitor.run(shutdown).await }
+                })));
+                info!("DB size monitor spawned");
+
+                metrics.register_broker(storage.clone());
+                readiness.set_broker_ready(true);
+                broker_storage = Some(storage);
                 Some(handler)
             } else {
                 None
             };
 
+            let metrics = std::sync::Arc::new(metrics);
+
+            // Commands from the API into the swarm loop (e.g. DHT lookups)
+            let (swarm_commands, swarm_command_rx) = tokio::sync::mpsc::channel(32);
+
             // Iniciar API local en paralelo con el swarm
-            let api_state = network_state.clone();
+            let api_ctx = api::ApiContext {
+                config: std::sync::Arc::new(config.clone()),
+                network_state: network_state.clone(),
+                readiness: readiness.clone(),
+                metrics: metrics.clone(),
+                swarm_commands,
+                broker_storage: broker_storage.clone(),
+                broker_handler: broker_handler.clone(),
+            };
+            // SIGUSR1 puts the node in drain mode, like `POST /drain`
+            #[cfg(unix)]
+            let drain_task = {
+                let api_ctx = api_ctx.clone();
+                let mut sigusr1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())
+                    .context("Failed to install SIGUSR1 handler")?;
+                tokio::spawn(async move {
+                    while sigusr1.recv().await.is_some() {
+                        // start_drain counts the unfinished jobs in sled
+                        let ctx = api_ctx.clone();
+                        let Ok(report) = tokio::task::spawn_blocking(move || ctx.start_drain()).await else {
+                            continue;
+                        };
+                        info!("Received SIGUSR1, draining (unfinished jobs: {:?})", report.unfinished_jobs);
+                    }
+                })
+            };
             let api_task = tokio::spawn(async {
-                api::iniciar_api_local(api_state).await;
+                api::iniciar_api_local(api_ctx).await;
             });
 
-            // Run Swarm loop with graceful shutdown
-            tokio::select! {
-                res = run_swarm(swarm, config, network_state, broker_handler) => {
-                    if let Err(e) = res {
-                        tracing::error!("Swarm error: {:?}", e);
+            // Run Swarm loop until it exits on its own or a shutdown signal arrives
+            let mut swarm_task = tokio::spawn(run_swarm(
+                swarm,
+                config,
+                network_state,
+                readiness.clone(),
+                metrics,
+                broker_handler,
+                swarm_command_rx,
+                shutdown_rx,
+            ));
+            // Set when the swarm stopped on an error, so the exit code tells a
+            // supervisor to restart the node (e.g. discovery_timeout_action = "restart")
+            let mut swarm_error = None;
+            let swarm_finished = tokio::select! {
+                res = &mut swarm_task => {
+                    match res {
+                        Ok(Err(e)) => {
+                            error!("Swarm error: {:?}", e);
+                            swarm_error = Some(e);
+                        }
+                        Err(e) => error!("Swarm task failed: {:?}", e),
+                        Ok(Ok(())) => {}
                     }
+                    true
                 }
-                _ = signal::ctrl_c() => {
-                    info!("Received Ctrl+C, shutting down...");
+                _ = shutdown_signal() => false,
+            };
+
+            // Coordinated shutdown: stop the swarm (disconnecting peers) and let the
+            // broker workers finish the job they're on, then flush storage
+            readiness.set_broker_ready(false);
+            let _ = shutdown_tx.send(true);
+            let wind_down = async {
+                if !swarm_finished {
+                    let _ = swarm_task.await;
                 }
-            }
+                for handle in worker_handles {
+                    let _ = handle.await;
+                }
+            };
+            let timed_out = tokio::time::timeout(SHUTDOWN_TIMEOUT, wind_down).await.is_err();
 
             // Abort API task on shutdown
             api_task.abort();
+            #[cfg(unix)]
+            drain_task.abort();
+
+            if let Some(storage) = broker_storage {
+                match storage.flush() {
+                    Ok(()) => info!("Broker storage flushed"),
+                    Err(e) => error!("Failed to flush broker storage: {:?}", e),
+                }
+            }
+
+            // Returned rather than exiting, so the OTLP guard still exports
+            // buffered spans; whatever is left running is dropped with the runtime
+            if timed_out {
+                anyhow::bail!("Shutdown timed out after {:?}", SHUTDOWN_TIMEOUT);
+            }
+            if let Some(e) = swarm_error {
+                return Err(e.context("Swarm stopped"));
+            }
+            info!("Shutdown complete");
         }
     }
 
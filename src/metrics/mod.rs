@@ -0,0 +1,386 @@
+use crate::broker::storage::BrokerStorage;
+use crate::broker::types::{JobState, NotificationState};
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::MetricType;
+use prometheus_client::registry::{Registry, Unit};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use tracing::warn;
+
+pub type SharedMetrics = Arc<Metrics>;
+
+/// Sample name the libp2p bandwidth transport registers its byte counters under
+const BANDWIDTH_SAMPLE: &str = "libp2p_bandwidth_bytes_total";
+
+/// Histograms labeled by name/value pairs, all with the same buckets
+pub type HistogramFamily = Family<Vec<(String, String)>, Histogram, fn() -> Histogram>;
+
+/// Prometheus registry shared by the swarm transport and the local API (`GET /metrics`)
+pub struct Metrics {
+    registry: Registry,
+    /// Connections dropped because of `allowed_peers` / `denied_peers`
+    pub peers_rejected: Counter,
+    /// Outbound request/response exchanges that hit `rr_request_timeout_secs`
+    pub rr_timeouts: Counter,
+    /// Timed-out `OpSubmit`s re-sent to the peer
+    pub rr_retries: Counter,
+    /// Dials that hit `dial_timeout_secs` before the handshake finished
+    pub dial_timeouts: Counter,
+    /// Failed request/response exchanges by `direction`. Not labeled by peer:
+    /// every peer that ever connected would add a series for good; the
+    /// per-peer counts live in the network snapshot instead
+    rr_failures: Family<Vec<(String, String)>, Counter>,
+    /// Incoming messages that couldn't be decoded, by `kind`
+    malformed_messages: Family<Vec<(String, String)>, Counter>,
+    /// `SubmitBooking`s answered "busy" because the broker queue was full
+    /// (`max_concurrent_broker_ops` bookings waiting)
+    pub bookings_busy: Counter,
+    /// `OpSubmit`s answered "busy" because `max_concurrent_broker_ops` were in flight
+    pub ops_busy: Counter,
+    /// Attempts each booking job took to reach Confirmed or Failed
+    pub job_attempts: Histogram,
+    /// Jobs that reached `retry_warn_threshold` attempts
+    pub job_retry_warnings: Counter,
+    /// Confirmations whose notification already existed, so none was created
+    pub duplicate_notifications: Counter,
+    /// Duration of each central API booking request, by `outcome`
+    pub central_api_latency: HistogramFamily,
+}
+
+/// Bytes sent/received in one direction pair
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ByteCounts {
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+}
+
+/// Transport bandwidth totals, overall and per transport protocol stack (e.g. `/ip4/tcp`)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BandwidthStats {
+    #[serde(flatten)]
+    pub total: ByteCounts,
+    pub by_protocol: BTreeMap<String, ByteCounts>,
+}
+
+/// The samples of `GET /metrics` as JSON (`GET /metrics.json`), grouped by
+/// subsystem and keyed by sample name without the `hch_` prefix. Unlabeled
+/// samples are plain numbers; labeled ones a list of `{labels, value}`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsJson {
+    pub p2p: BTreeMap<String, serde_json::Value>,
+    pub broker: BTreeMap<String, serde_json::Value>,
+    pub notifier: BTreeMap<String, serde_json::Value>,
+}
+
+impl Metrics {
+    /// Takes the registry the transport was built with, so everything ends up in one scrape
+    pub fn new(mut registry: Registry) -> Self {
+        let peers_rejected = Counter::default();
+        let node = registry.sub_registry_with_prefix("hch");
+        node.register(
+            "peers_rejected",
+            "Connections closed because the peer is denied or not allowlisted",
+            peers_rejected.clone(),
+        );
+
+        let rr_timeouts = Counter::default();
+        node.register(
+            "rr_timeouts",
+            "Outbound request/response exchanges that timed out",
+            rr_timeouts.clone(),
+        );
+        let rr_retries = Counter::default();
+        node.register(
+            "rr_retries",
+            "Timed-out requests re-sent to the peer",
+            rr_retries.clone(),
+        );
+        let dial_timeouts = Counter::default();
+        node.register(
+            "dial_timeouts",
+            "Dial attempts that timed out before the connection handshake finished",
+            dial_timeouts.clone(),
+        );
+        let rr_failures = Family::default();
+        node.register(
+            "rr_failures",
+            "Failed request/response exchanges, by direction (inbound/outbound)",
+            rr_failures.clone(),
+        );
+        let malformed_messages = Family::default();
+        node.register(
+            "malformed_messages",
+            "Incoming requests/responses that couldn't be decoded, by kind (e.g. unknown_variant, truncated)",
+            malformed_messages.clone(),
+        );
+
+        let bookings_busy = Counter::default();
+        node.register(
+            "bookings_busy",
+            "Booking submissions refused because the broker queue was full",
+            bookings_busy.clone(),
+        );
+        let ops_busy = Counter::default();
+        node.register(
+            "broker_ops_busy",
+            "Received ops refused because max_concurrent_broker_ops were already being persisted",
+            ops_busy.clone(),
+        );
+
+        // 1, 2, 4, ... 128 attempts
+        let job_attempts = Histogram::new(exponential_buckets(1.0, 2.0, 8));
+        node.register(
+            "broker_job_attempts",
+            "Attempts a booking job took before it was confirmed or failed",
+            job_attempts.clone(),
+        );
+        let job_retry_warnings = Counter::default();
+        node.register(
+            "broker_job_retry_warnings",
+            "Booking jobs that reached retry_warn_threshold attempts",
+            job_retry_warnings.clone(),
+        );
+        let duplicate_notifications = Counter::default();
+        node.register(
+            "broker_notifications_duplicate",
+            "Confirmed jobs whose notification already existed (e.g. confirmed again after a requeue)",
+            duplicate_notifications.clone(),
+        );
+
+        // 5 ms, 10 ms, ... ~10 s; anything slower ends up in +Inf (see central_api_request_timeout_ms)
+        let central_api_latency: HistogramFamily =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.005, 2.0, 12)));
+        node.register_with_unit(
+            "broker_central_api_request_duration",
+            "Central API booking requests, from send to the full response body, by outcome (success, 4xx, 5xx, other, network_error)",
+            Unit::Seconds,
+            central_api_latency.clone(),
+        );
+
+        Self {
+            registry,
+            peers_rejected,
+            rr_timeouts,
+            rr_retries,
+            dial_timeouts,
+            rr_failures,
+            malformed_messages,
+            bookings_busy,
+            ops_busy,
+            job_attempts,
+            job_retry_warnings,
+            duplicate_notifications,
+            central_api_latency,
+        }
+    }
+
+    /// Adds broker job/notification counts and DB size, read from storage on each scrape
+    pub fn register_broker(&mut self, storage: Arc<BrokerStorage>) {
+        self.registry
+            .sub_registry_with_prefix("hch")
+            .register_collector(Box::new(BrokerCollector { storage }));
+    }
+
+    /// Counts a failed request/response exchange in `direction`
+    pub fn record_rr_failure(&self, direction: &'static str) {
+        self.rr_failures
+            .get_or_create(&vec![("direction".to_string(), direction.to_string())])
+            .inc();
+    }
+
+    /// Counts an incoming message that failed to decode as `kind`
+    pub fn record_malformed_message(&self, kind: &'static str) {
+        self.malformed_messages
+            .get_or_create(&vec![("kind".to_string(), kind.to_string())])
+            .inc();
+    }
+
+    /// Renders every registered metric in the OpenMetrics text format
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        // Writing into a String can't fail
+        let _ = encode(&mut out, &self.registry);
+        out
+    }
+
+    /// Reads the bandwidth counters back out of the registry.
+    ///
+    /// The transport keeps its label type private, so the encoded samples are the
+    /// only way to enumerate them.
+    pub fn bandwidth(&self) -> BandwidthStats {
+        parse_bandwidth(&self.encode())
+    }
+
+    /// Every registered metric as JSON. Built from the same encoding as
+    /// `encode`, so the two endpoints always report the same samples.
+    pub fn json(&self) -> MetricsJson {
+        group_samples(&self.encode())
+    }
+}
+
+/// Scrape-time view of the broker's storage counters
+struct BrokerCollector {
+    storage: Arc<BrokerStorage>,
+}
+
+impl fmt::Debug for BrokerCollector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BrokerCollector").finish_non_exhaustive()
+    }
+}
+
+impl Collector for BrokerCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), fmt::Error> {
+        // A storage error shouldn't fail the whole scrape; skip the affected metric
+        match self.storage.count_jobs_by_state() {
+            Ok(counts) => {
+                let mut family = encoder.encode_descriptor(
+                    "broker_jobs",
+                    "Booking jobs by state",
+                    None,
+                    MetricType::Gauge,
+                )?;
+                for state in JobState::ALL {
+                    let gauge = ConstGauge::new(counts.get(&state).copied().unwrap_or(0) as i64);
+                    gauge.encode(family.encode_family(&[("state", state.as_str())])?)?;
+                }
+            }
+            Err(e) => warn!("Failed to read job counts for metrics: {:?}", e),
+        }
+
+        match self.storage.count_notifications_by_state() {
+            Ok(counts) => {
+                let mut family = encoder.encode_descriptor(
+                    "broker_notifications",
+                    "Notifications by state",
+                    None,
+                    MetricType::Gauge,
+                )?;
+                for state in NotificationState::ALL {
+                    let gauge = ConstGauge::new(counts.get(&state).copied().unwrap_or(0) as i64);
+                    gauge.encode(family.encode_family(&[("state", state.as_str())])?)?;
+                }
+            }
+            Err(e) => warn!("Failed to read notification counts for metrics: {:?}", e),
+        }
+
+        match self.storage.storage_size_bytes() {
+            Ok(size) => {
+                let gauge = ConstGauge::new(size as i64);
+                let metric = encoder.encode_descriptor(
+                    "broker_db_size",
+                    "Size of the broker sled database on disk",
+                    Some(&Unit::Bytes),
+                    gauge.metric_type(),
+                )?;
+                gauge.encode(metric)?;
+            }
+            Err(e) => warn!("Failed to read DB size for metrics: {:?}", e),
+        }
+        Ok(())
+    }
+}
+
+/// One sample line of the text encoding
+struct Sample<'a> {
+    name: &'a str,
+    labels: Vec<(&'a str, &'a str)>,
+    value: &'a str,
+}
+
+impl Sample<'_> {
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+}
+
+/// Parses `name{key="value",...} value`; comment lines (`# HELP`, `# EOF`) are None
+fn parse_sample(line: &str) -> Option<Sample<'_>> {
+    if line.starts_with('#') {
+        return None;
+    }
+    let (head, value) = line.rsplit_once(' ')?;
+    let (name, labels) = match head.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_suffix('}')?),
+        None => (head, ""),
+    };
+    let labels = labels
+        .split("\",")
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key, value.trim_matches('"')))
+        .collect();
+    Some(Sample { name, labels, value: value.trim() })
+}
+
+impl MetricsJson {
+    /// The group a sample belongs to, by name
+    fn group_mut(&mut self, name: &str) -> &mut BTreeMap<String, serde_json::Value> {
+        if name.starts_with("hch_broker_notifications") {
+            &mut self.notifier
+        } else if name.starts_with("hch_broker_") || name.starts_with("hch_bookings_") {
+            &mut self.broker
+        } else {
+            &mut self.p2p
+        }
+    }
+}
+
+fn group_samples(encoded: &str) -> MetricsJson {
+    let mut json = MetricsJson::default();
+    for sample in encoded.lines().filter_map(parse_sample) {
+        let value = match sample.value.parse::<i64>() {
+            Ok(n) => serde_json::Value::from(n),
+            Err(_) => sample.value.parse::<f64>().map(serde_json::Value::from).unwrap_or_default(),
+        };
+        let group = json.group_mut(sample.name);
+        let key = sample.name.strip_prefix("hch_").unwrap_or(sample.name).to_string();
+        if sample.labels.is_empty() {
+            group.insert(key, value);
+            continue;
+        }
+        let labels: BTreeMap<&str, &str> = sample.labels.into_iter().collect();
+        let entry = group.entry(key).or_insert_with(|| serde_json::Value::Array(Vec::new()));
+        if let serde_json::Value::Array(series) = entry {
+            series.push(serde_json::json!({ "labels": labels, "value": value }));
+        }
+    }
+    json
+}
+
+fn parse_bandwidth(encoded: &str) -> BandwidthStats {
+    let mut stats = BandwidthStats::default();
+    for sample in encoded.lines().filter_map(parse_sample) {
+        if sample.name != BANDWIDTH_SAMPLE {
+            continue;
+        }
+        let Ok(bytes) = sample.value.parse::<u64>() else { continue };
+        let (Some(protocols), Some(direction)) = (sample.label("protocols"), sample.label("direction")) else {
+            continue;
+        };
+
+        let entry = stats.by_protocol.entry(protocols.to_string()).or_default();
+        match direction {
+            "Inbound" => {
+                entry.inbound_bytes += bytes;
+                stats.total.inbound_bytes += bytes;
+            }
+            "Outbound" => {
+                entry.outbound_bytes += bytes;
+                stats.total.outbound_bytes += bytes;
+            }
+            _ => {}
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,94 @@
+use super::*;
+use crate::config::DurabilityMode;
+
+#[test]
+fn test_bandwidth_parses_transport_counters() {
+    let encoded = "\
+# HELP libp2p_bandwidth_bytes Bandwidth usage by direction and transport protocols.
+# TYPE libp2p_bandwidth_bytes counter
+# UNIT libp2p_bandwidth_bytes bytes
+libp2p_bandwidth_bytes_total{protocols=\"/ip4/tcp\",direction=\"Inbound\"} 1200
+libp2p_bandwidth_bytes_total{protocols=\"/ip4/tcp\",direction=\"Outbound\"} 800
+libp2p_bandwidth_bytes_total{protocols=\"/ip6/tcp\",direction=\"Inbound\"} 50
+# EOF
+";
+    let stats = parse_bandwidth(encoded);
+
+    assert_eq!(stats.total.inbound_bytes, 1250);
+    assert_eq!(stats.total.outbound_bytes, 800);
+    assert_eq!(stats.by_protocol.len(), 2);
+    assert_eq!(stats.by_protocol["/ip4/tcp"].outbound_bytes, 800);
+    assert_eq!(stats.by_protocol["/ip6/tcp"].inbound_bytes, 50);
+    assert_eq!(stats.by_protocol["/ip6/tcp"].outbound_bytes, 0);
+}
+
+#[test]
+fn test_empty_registry_has_no_bandwidth() {
+    let metrics = Metrics::new(Registry::default());
+    let stats = metrics.bandwidth();
+
+    assert_eq!(stats.total.inbound_bytes, 0);
+    assert!(stats.by_protocol.is_empty());
+    assert!(metrics.encode().contains("# EOF"));
+}
+
+#[test]
+fn test_app_counters_are_encoded() {
+    let metrics = Metrics::new(Registry::default());
+    metrics.peers_rejected.inc();
+    metrics.rr_timeouts.inc();
+
+    let encoded = metrics.encode();
+    assert!(encoded.contains("hch_peers_rejected_total 1"));
+    assert!(encoded.contains("hch_rr_timeouts_total 1"));
+    assert!(encoded.contains("hch_rr_retries_total 0"));
+}
+
+#[test]
+fn test_rr_failures_are_labeled_by_direction_only() {
+    let metrics = Metrics::new(Registry::default());
+    metrics.record_rr_failure("outbound");
+    metrics.record_rr_failure("outbound");
+    metrics.record_rr_failure("inbound");
+
+    let encoded = metrics.encode();
+    assert!(encoded.contains("hch_rr_failures_total{direction=\"outbound\"} 2"));
+    assert!(encoded.contains("hch_rr_failures_total{direction=\"inbound\"} 1"));
+    assert!(!encoded.contains("peer_id"));
+}
+
+#[test]
+fn test_broker_collector_reports_counts() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("metrics.db");
+    let storage = Arc::new(BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+    let mut metrics = Metrics::new(Registry::default());
+    metrics.register_broker(storage);
+
+    let encoded = metrics.encode();
+    assert!(encoded.contains("hch_broker_jobs{state=\"queued\"} 0"));
+    assert!(encoded.contains("hch_broker_notifications{state=\"simulated_sent\"} 0"));
+    assert!(encoded.contains("hch_broker_db_size_bytes "));
+}
+
+#[test]
+fn test_json_groups_the_encoded_samples_by_subsystem() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("metrics.db");
+    let storage = Arc::new(BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+    let mut metrics = Metrics::new(Registry::default());
+    metrics.register_broker(storage);
+    metrics.peers_rejected.inc();
+    metrics.bookings_busy.inc();
+    metrics.record_rr_failure("outbound");
+
+    let json = serde_json::to_value(metrics.json()).unwrap();
+    assert_eq!(json["p2p"]["peers_rejected_total"], 1);
+    assert_eq!(json["p2p"]["rr_failures_total"][0]["labels"]["direction"], "outbound");
+    assert_eq!(json["p2p"]["rr_failures_total"][0]["value"], 1);
+    assert_eq!(json["broker"]["bookings_busy_total"], 1);
+    assert!(json["broker"]["broker_db_size_bytes"].is_u64());
+    assert_eq!(json["broker"]["broker_jobs"].as_array().unwrap().len(), 4);
+    assert_eq!(json["notifier"]["broker_notifications"][0]["labels"]["state"], "pending");
+    assert!(json["notifier"].get("broker_jobs").is_none());
+}
@@ -1,18 +1,24 @@
 use super::protocol::{OpCodec, Msg};
 use libp2p::{
     identify, mdns, kad, ping,
-    request_response,
-    swarm::NetworkBehaviour,
+    relay, request_response, upnp,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
 };
 
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "NodeBehaviourEvent")]
 pub struct NodeBehaviour {
     pub identify: identify::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
-    pub kad: kad::Behaviour<kad::store::MemoryStore>,
+    /// Absent when `enable_mdns = false`, so no mDNS queries are sent or answered
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    /// Absent when `enable_kad = false`: no routing table and no DHT answers
+    pub kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
     pub ping: ping::Behaviour,
     pub request_response: request_response::Behaviour<OpCodec>,
+    /// Absent unless `enable_upnp = true`; maps our listen ports on the LAN gateway
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+    /// Absent unless `fallback_relay` is set; carries our `/p2p-circuit` dials
+    pub relay_client: Toggle<relay::client::Behaviour>,
 }
 
 #[derive(Debug)]
@@ -22,6 +28,8 @@ pub enum NodeBehaviourEvent {
     Kad(kad::Event),
     Ping(ping::Event),
     RequestResponse(request_response::Event<Msg, Msg>),
+    Upnp(upnp::Event),
+    RelayClient(relay::client::Event),
 }
 
 // From trait implementations for event conversions
@@ -54,3 +62,15 @@ impl From<request_response::Event<Msg, Msg>> for NodeBehaviourEvent {
         NodeBehaviourEvent::RequestResponse(event)
     }
 }
+
+impl From<upnp::Event> for NodeBehaviourEvent {
+    fn from(event: upnp::Event) -> Self {
+        NodeBehaviourEvent::Upnp(event)
+    }
+}
+
+impl From<relay::client::Event> for NodeBehaviourEvent {
+    fn from(event: relay::client::Event) -> Self {
+        NodeBehaviourEvent::RelayClient(event)
+    }
+}
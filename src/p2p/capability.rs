@@ -0,0 +1,71 @@
+//! Short-lived capability tokens for `SubmitBooking`.
+//!
+//! A token is `<peer_id>.<expires_at_secs>.<hex signature>`: the issuer's
+//! identity key signs the peer allowed to submit and the expiry, so a gateway
+//! configured with `booking_token_issuer` accepts bookings only from peers
+//! holding a current token minted for them (`mint-booking-token`).
+
+use anyhow::{Context, Result};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Domain separation, so a signature made for something else can't pass as a token
+const SIGNING_CONTEXT: &[u8] = b"hybrid-connection-health/booking-token/1:";
+
+/// Multihash code of PeerIds that inline their public key (ed25519 keys)
+const IDENTITY_MULTIHASH: u64 = 0x00;
+
+fn signed_bytes(subject: &str, expires_at: u64) -> Vec<u8> {
+    [SIGNING_CONTEXT, format!("{}.{}", subject, expires_at).as_bytes()].concat()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The public key a PeerId embeds, if it embeds one (ed25519 identities do)
+pub fn issuer_public_key(issuer: &PeerId) -> Option<PublicKey> {
+    let multihash = issuer.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH {
+        return None;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+/// Mints a token letting `subject` submit bookings for `ttl`
+pub fn mint(issuer: &Keypair, subject: &PeerId, ttl: Duration) -> Result<String> {
+    let expires_at = now_secs()
+        .checked_add(ttl.as_secs())
+        .with_context(|| format!("Token lifetime of {} seconds is too long", ttl.as_secs()))?;
+    let subject = subject.to_string();
+    let signature = issuer
+        .sign(&signed_bytes(&subject, expires_at))
+        .context("Failed to sign booking token")?;
+    Ok(format!("{}.{}.{}", subject, expires_at, hex::encode(signature)))
+}
+
+/// Checks that `token` was signed by `issuer` for `peer` and hasn't expired
+pub fn verify(token: &str, issuer: &PublicKey, peer: &PeerId) -> Result<()> {
+    let mut parts = token.splitn(3, '.');
+    let (Some(subject), Some(expires_at), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("malformed token");
+    };
+    let expires_at: u64 = expires_at.parse().context("malformed expiry")?;
+    let signature = hex::decode(signature).context("malformed signature")?;
+
+    if !issuer.verify(&signed_bytes(subject, expires_at), &signature) {
+        anyhow::bail!("bad signature");
+    }
+    if subject != peer.to_string() {
+        anyhow::bail!("token was minted for {}", subject);
+    }
+    if expires_at <= now_secs() {
+        anyhow::bail!("token expired");
+    }
+    Ok(())
+}
+
@@ -0,0 +1,25 @@
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+/// DHT key gateways advertise themselves under as provider records
+pub const GATEWAY_SERVICE_KEY: &str = "/hybrid-connection-health/booking-gateway/1.0.0";
+
+/// Requests from other tasks (e.g. the local API) for the swarm event loop
+#[derive(Debug)]
+pub enum SwarmCommand {
+    /// Look up the providers of a DHT key; results land in the network snapshot
+    FindProviders(String),
+    /// Look up the peers closest to a DHT key; the result (or why the query
+    /// couldn't run or finish) is sent back on `reply`
+    FindClosest {
+        key: String,
+        reply: oneshot::Sender<Result<Vec<ClosestPeer>, String>>,
+    },
+}
+
+/// One peer of a `FindClosest` result
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClosestPeer {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
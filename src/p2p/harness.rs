@@ -0,0 +1,205 @@
+//! Test harness: nodes connected over an in-memory transport. `start_node`
+//! runs the real `run_swarm` loop; `connect_pair` adds a client that sends
+//! whatever requests the test hands it and returns the responses, so round
+//! trips are exercised without TCP, mDNS or sleeps.
+
+use super::behaviour::{NodeBehaviour, NodeBehaviourEvent};
+use super::command::SwarmCommand;
+use super::protocol::Msg;
+use super::swarm::{dial_bootstrap_peers, new_swarm, run_swarm};
+use crate::api::{new_shared_network_state, Readiness};
+use crate::broker::handler::BrokerHandler;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use libp2p::{
+    core::{muxing::StreamMuxerBox, transport::MemoryTransport, upgrade},
+    noise,
+    request_response::{self, OutboundRequestId},
+    swarm::SwarmEvent,
+    yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+use prometheus_client::registry::Registry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+type PendingReply = oneshot::Sender<Result<Msg, String>>;
+
+/// A swarm on `MemoryTransport` (noise + yamux like the TCP one) listening on
+/// a fresh `/memory/` address, which is returned once it's bound
+pub(crate) async fn memory_swarm(config: &Config) -> Result<(Swarm<NodeBehaviour>, Multiaddr)> {
+    memory_swarm_at(config, "/memory/0".parse()?).await
+}
+
+/// `memory_swarm` listening on `addr`, e.g. a `/memory/<port>` another node
+/// already tried to dial before anything listened there
+pub(crate) async fn memory_swarm_at(config: &Config, addr: Multiaddr) -> Result<(Swarm<NodeBehaviour>, Multiaddr)> {
+    let transport = MemoryTransport::default()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(&config.identity_keypair).context("Failed to create noise config")?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed();
+    let mut swarm = new_swarm(config, transport, None)?;
+    swarm.listen_on(addr)?;
+    loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            return Ok((swarm, address));
+        }
+    }
+}
+
+/// A node running `run_swarm`: its loop stops when this is dropped
+pub(crate) struct TestNode {
+    pub(crate) peer_id: PeerId,
+    pub(crate) addr: Multiaddr,
+    pub(crate) metrics: Arc<Metrics>,
+    _shutdown: watch::Sender<bool>,
+    commands: mpsc::Sender<SwarmCommand>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestNode {
+    /// Hands `command` to the node's event loop, as the local API would
+    pub(crate) async fn command(&self, command: SwarmCommand) {
+        self.commands.send(command).await.expect("node event loop stopped");
+    }
+
+    /// Waits for the event loop to stop on its own and returns its result
+    pub(crate) async fn exited(self) -> Result<()> {
+        let TestNode { _shutdown, task, .. } = self;
+        task.await?
+    }
+}
+
+/// Starts `run_swarm` for `config` (with `broker_handler` on gateways) on a
+/// fresh `/memory/` address, after dialing its `bootstrap_peers` as
+/// `build_swarm` does
+pub(crate) async fn start_node(config: Config, broker_handler: Option<Arc<BrokerHandler>>) -> Result<TestNode> {
+    start_node_at(config, "/memory/0".parse()?, broker_handler).await
+}
+
+/// `start_node` listening on `addr`
+pub(crate) async fn start_node_at(
+    config: Config,
+    addr: Multiaddr,
+    broker_handler: Option<Arc<BrokerHandler>>,
+) -> Result<TestNode> {
+    let (mut swarm, addr) = memory_swarm_at(&config, addr).await?;
+    dial_bootstrap_peers(&mut swarm, &config);
+    let peer_id = *swarm.local_peer_id();
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (commands_tx, commands_rx) = mpsc::channel(1);
+    let network_state = new_shared_network_state(&config, peer_id.to_string());
+    let readiness = Arc::new(Readiness::new(broker_handler.is_some(), false));
+    let metrics = Arc::new(Metrics::new(Registry::default()));
+    let task = tokio::spawn(run_swarm(
+        swarm,
+        config,
+        network_state,
+        readiness,
+        metrics.clone(),
+        broker_handler,
+        commands_rx,
+        shutdown_rx,
+    ));
+    Ok(TestNode { peer_id, addr, metrics, _shutdown: shutdown_tx, commands: commands_tx, task })
+}
+
+/// The client end, connected to the gateway
+pub(crate) struct TestClient {
+    requests: mpsc::Sender<(Msg, PendingReply)>,
+    task: JoinHandle<()>,
+}
+
+impl TestClient {
+    /// Sends `request` to the gateway and waits for its response
+    pub(crate) async fn request(&self, request: Msg) -> Result<Msg> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.requests
+            .send((request, reply_tx))
+            .await
+            .map_err(|_| anyhow::anyhow!("client event loop stopped"))?;
+        reply_rx
+            .await
+            .context("client event loop stopped")?
+            .map_err(|e| anyhow::anyhow!("request failed: {}", e))
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Starts a gateway (`start_node` with `broker_handler`) and a client, and
+/// returns once the client is connected to it. Only the role, identity and
+/// protocol settings of the configs are used; nothing listens on TCP.
+pub(crate) async fn connect_pair(
+    client_config: Config,
+    gateway_config: Config,
+    broker_handler: Option<Arc<BrokerHandler>>,
+) -> Result<(TestClient, TestNode)> {
+    let gateway = start_node(gateway_config, broker_handler).await?;
+    let gateway_peer_id = gateway.peer_id;
+
+    let (mut client_swarm, _) = memory_swarm(&client_config).await?;
+    client_swarm.dial(gateway.addr.clone())?;
+    loop {
+        match client_swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == gateway_peer_id => break,
+            SwarmEvent::OutgoingConnectionError { error, .. } => {
+                anyhow::bail!("client could not connect to the gateway: {}", error)
+            }
+            _ => {}
+        }
+    }
+
+    let (requests_tx, requests_rx) = mpsc::channel(8);
+    let task = tokio::spawn(drive_client(client_swarm, gateway_peer_id, requests_rx));
+
+    Ok((TestClient { requests: requests_tx, task }, gateway))
+}
+
+/// Client event loop: forwards each queued request to `gateway` and resolves
+/// it with the matching response (or the outbound failure)
+async fn drive_client(
+    mut swarm: Swarm<NodeBehaviour>,
+    gateway: PeerId,
+    mut requests: mpsc::Receiver<(Msg, PendingReply)>,
+) {
+    let mut pending: HashMap<OutboundRequestId, PendingReply> = HashMap::new();
+    loop {
+        tokio::select! {
+            Some((request, reply)) = requests.recv() => {
+                let request_id = swarm.behaviour_mut().request_response.send_request(&gateway, request);
+                pending.insert(request_id, reply);
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::Message {
+                    message: request_response::Message::Response { request_id, response },
+                    ..
+                })) => {
+                    if let Some(reply) = pending.remove(&request_id) {
+                        let _ = reply.send(Ok(response));
+                    }
+                }
+                SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
+                    request_id,
+                    error,
+                    ..
+                })) => {
+                    if let Some(reply) = pending.remove(&request_id) {
+                        let _ = reply.send(Err(error.to_string()));
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+}
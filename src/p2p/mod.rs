@@ -1,3 +1,10 @@
 pub mod protocol;
 pub mod behaviour;
 pub mod swarm;
+pub mod command;
+pub mod capability;
+
+#[cfg(test)]
+mod harness;
+#[cfg(test)]
+mod tests;
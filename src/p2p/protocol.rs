@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
 use async_trait::async_trait;
 use futures::{prelude::*, AsyncRead, AsyncWrite};
@@ -38,26 +39,205 @@ pub enum Msg {
     OpAck { op_id: String, ok: bool, msg: String },
     Heartbeat { role: String },
     SubmitBooking {
+        /// Idempotency key chosen by the client; empty asks a gateway with
+        /// `generate_correlation_ids` to pick one (the `BookingAck` carries it)
         correlation_id: String,
         booking: BookingData,
         notify: NotifyData,
+        /// Capability token (see `capability`), required by gateways with a `booking_token_issuer`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
     },
     BookingAck {
         correlation_id: String,
-        status: String,  // "queued"
+        status: String,  // a `BookingStatus`, as its lowercase name
     },
 }
 
-// --- Codec ---
+/// Outcome of a `SubmitBooking`. On the wire it's `BookingAck.status`, the
+/// lowercase variant name, so older peers keep matching on plain strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookingStatus {
+    /// Persisted; the broker will send it to central
+    Queued,
+    /// Already sent to central and confirmed
+    Confirmed,
+    /// Already sent to central and given up on
+    Failed,
+    /// Invalid request, e.g. a notification email that can never be delivered
+    Rejected,
+    /// Missing or bad capability token
+    Unauthorized,
+    /// Broker queue full; retry later
+    Busy,
+    /// Too many unfinished jobs; retry later
+    Throttled,
+    /// The gateway is draining before shutdown; submit elsewhere or later
+    Draining,
+    /// The gateway couldn't handle the booking
+    Error,
+}
+
+impl BookingStatus {
+    /// A `BookingAck.status` from the wire; `None` for a status this version
+    /// doesn't know
+    pub fn parse(status: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(status.to_string())).ok()
+    }
+
+    /// The `BookingAck` reporting this status for `correlation_id`
+    pub fn ack(self, correlation_id: String) -> Msg {
+        Msg::BookingAck {
+            correlation_id,
+            status: self.to_string(),
+        }
+    }
+}
+
+/// The wire name, taken from the serde representation so `rename_all` is the
+/// only place it's spelled out
+impl fmt::Display for BookingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => Err(fmt::Error),
+        }
+    }
+}
 
 // --- Codec ---
 
-#[derive(Debug, Clone)]
-pub struct OpProtocol;
+/// Versions of the request/response protocol. Both are offered; v2 is listed
+/// first so it's negotiated whenever the remote supports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpProtocol {
+    /// Bare JSON `Msg`
+    V1,
+    /// JSON envelope `{"v":2,"msg":...}`, room for fields without a flag day
+    V2,
+}
+
+impl OpProtocol {
+    /// In order of preference
+    pub const ALL: [OpProtocol; 2] = [OpProtocol::V2, OpProtocol::V1];
+
+    /// The protocol we'd negotiate with a peer advertising `protocols`
+    pub fn preferred<'a>(protocols: impl IntoIterator<Item = &'a str> + Clone) -> Option<OpProtocol> {
+        Self::ALL
+            .into_iter()
+            .find(|ours| protocols.clone().into_iter().any(|p| p == ours.as_ref()))
+    }
+}
 
 impl AsRef<str> for OpProtocol {
     fn as_ref(&self) -> &str {
-        "/node-agent/rr/1"
+        match self {
+            OpProtocol::V1 => "/node-agent/rr/1",
+            OpProtocol::V2 => "/node-agent/rr/2",
+        }
+    }
+}
+
+/// Why an incoming message couldn't be decoded; coarse enough to log without
+/// echoing what the peer sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedKind {
+    /// Not JSON at all
+    Syntax,
+    /// JSON that stops early, e.g. a stream cut short
+    Truncated,
+    /// A `Msg` variant we don't know: usually a peer running a newer version
+    UnknownVariant,
+    /// Well-formed JSON that doesn't fit `Msg` (missing field, wrong type)
+    Schema,
+    /// A `/node-agent/rr/2` envelope carrying another version
+    EnvelopeVersion,
+}
+
+impl MalformedKind {
+    /// Label used in logs and the `malformed_messages` metric
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MalformedKind::Syntax => "syntax",
+            MalformedKind::Truncated => "truncated",
+            MalformedKind::UnknownVariant => "unknown_variant",
+            MalformedKind::Schema => "schema",
+            MalformedKind::EnvelopeVersion => "envelope_version",
+        }
+    }
+}
+
+/// Decoding error carried inside the `io::Error` the codec returns, so the
+/// swarm can tell a malformed message apart from a network failure
+#[derive(Debug)]
+pub struct MalformedMsg {
+    pub kind: MalformedKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for MalformedMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed message ({}) at line {} column {}", self.kind.as_str(), self.line, self.column)
+    }
+}
+
+impl std::error::Error for MalformedMsg {}
+
+impl From<serde_json::Error> for MalformedMsg {
+    fn from(e: serde_json::Error) -> Self {
+        let kind = match e.classify() {
+            serde_json::error::Category::Eof => MalformedKind::Truncated,
+            serde_json::error::Category::Syntax | serde_json::error::Category::Io => MalformedKind::Syntax,
+            serde_json::error::Category::Data if e.to_string().starts_with("unknown variant") => {
+                MalformedKind::UnknownVariant
+            }
+            serde_json::error::Category::Data => MalformedKind::Schema,
+        };
+        MalformedMsg { kind, line: e.line(), column: e.column() }
+    }
+}
+
+impl From<MalformedMsg> for io::Error {
+    fn from(e: MalformedMsg) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The decoding error behind a request/response I/O failure, if that's what it was
+pub fn malformed_cause(error: &io::Error) -> Option<&MalformedMsg> {
+    error.get_ref()?.downcast_ref()
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnvelopeV2 {
+    v: u32,
+    msg: Msg,
+}
+
+/// Serializes `msg` in the wire format of `protocol`
+pub fn encode_msg(protocol: &OpProtocol, msg: Msg) -> io::Result<Vec<u8>> {
+    let data = match protocol {
+        OpProtocol::V1 => serde_json::to_vec(&msg),
+        OpProtocol::V2 => serde_json::to_vec(&EnvelopeV2 { v: 2, msg }),
+    };
+    data.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parses a message in the wire format of `protocol`. Failures are
+/// `InvalidData` errors wrapping a [`MalformedMsg`].
+pub fn decode_msg(protocol: &OpProtocol, data: &[u8]) -> io::Result<Msg> {
+    let malformed = |e: serde_json::Error| io::Error::from(MalformedMsg::from(e));
+    match protocol {
+        OpProtocol::V1 => serde_json::from_slice(data).map_err(malformed),
+        OpProtocol::V2 => {
+            let envelope: EnvelopeV2 = serde_json::from_slice(data).map_err(malformed)?;
+            if envelope.v != 2 {
+                return Err(MalformedMsg { kind: MalformedKind::EnvelopeVersion, line: 1, column: 0 }.into());
+            }
+            Ok(envelope.msg)
+        }
     }
 }
 
@@ -72,7 +252,7 @@ impl Codec for OpCodec {
 
     async fn read_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Request>
     where
@@ -85,15 +265,12 @@ impl Codec for OpCodec {
              return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Empty request"));
         }
 
-        let msg: Msg = serde_json::from_slice(&data)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        
-        Ok(msg)
+        decode_msg(protocol, &data)
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
@@ -106,40 +283,33 @@ impl Codec for OpCodec {
              return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Empty response"));
         }
 
-        let msg: Msg = serde_json::from_slice(&data)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        
-        Ok(msg)
+        decode_msg(protocol, &data)
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let data = serde_json::to_vec(&req)
-             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        
+        let data = encode_msg(protocol, req)?;
         io.write_all(&data).await?;
         Ok(())
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let data = serde_json::to_vec(&res)
-             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        
+        let data = encode_msg(protocol, res)?;
         io.write_all(&data).await?;
         Ok(())
     }
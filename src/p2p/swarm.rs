@@ -1,43 +1,338 @@
 use super::{
     behaviour::{NodeBehaviour, NodeBehaviourEvent},
-    protocol::{Op, OpCodec, OpProtocol, Msg},
+    capability,
+    protocol::{malformed_cause, BookingData, BookingStatus, Msg, NotifyData, Op, OpCodec, OpProtocol},
 };
-use crate::config::{Config, Role};
+use crate::config::{Config, DiscoveryTimeoutAction, Role};
 use anyhow::{Context, Result};
+use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use libp2p::{
-    core::upgrade,
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{timeout::TransportTimeoutError, Boxed},
+        upgrade,
+    },
+    metrics::BandwidthTransport,
     identify, kad, ping,
     mdns,
+    multiaddr::Protocol,
+    relay,
+    upnp,
     noise,
     request_response::{self, ProtocolSupport},
-    swarm::SwarmEvent,
+    swarm::{dial_opts::DialOpts, DialError, SwarmEvent},
     tcp,
     yamux,
     Multiaddr, PeerId, Swarm, Transport,
 };
-use std::collections::{HashMap, HashSet};
+use prometheus_client::registry::Registry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::num::NonZeroUsize;
 use std::time::{Duration, Instant};
-use tracing::{info, error, warn};
+use tracing::{debug, info, info_span, error, warn, Instrument};
 use uuid::Uuid;
 
+/// Distinct peers that must report the same observed address before we
+/// treat it as our external address
+pub(crate) const EXTERNAL_ADDR_CONFIRMATIONS: usize = 2;
+
+/// Our addresses as observed by currently connected peers (identify
+/// `observed_addr`); a peer's reports are forgotten when it disconnects
+pub(crate) struct ObservedAddrs {
+    observers: HashMap<Multiaddr, HashSet<PeerId>>,
+    threshold: usize,
+}
+
+impl ObservedAddrs {
+    pub(crate) fn new(threshold: usize) -> Self {
+        Self { observers: HashMap::new(), threshold }
+    }
+
+    /// Records that `peer` sees us at `addr`; true exactly when this report
+    /// makes the address reach the confirmation threshold
+    pub(crate) fn observe(&mut self, peer: PeerId, addr: Multiaddr) -> bool {
+        let observers = self.observers.entry(addr).or_default();
+        observers.insert(peer) && observers.len() == self.threshold
+    }
+
+    /// Drops `peer`'s reports, and the addresses nobody reports any more
+    pub(crate) fn disconnected(&mut self, peer: &PeerId) {
+        self.observers.retain(|_, observers| {
+            observers.remove(peer);
+            !observers.is_empty()
+        });
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.observers.len()
+    }
+}
+
+/// How long a connected peer has to show (via identify) that it speaks our
+/// request/response protocol before `disconnect_unrelated_peers` drops it
+pub(crate) const UNRELATED_PEER_GRACE: Duration = Duration::from_secs(60);
+
+/// Connected peers not (yet) known to speak our request/response protocol,
+/// with when they connected. Bootstrap and allowlisted peers are never tracked.
+pub(crate) struct UnrelatedPeers {
+    connected_at: HashMap<PeerId, Instant>,
+    exempt: HashSet<PeerId>,
+    grace: Duration,
+}
+
+impl UnrelatedPeers {
+    pub(crate) fn new(config: &Config, grace: Duration) -> Self {
+        let bootstrap = config.bootstrap_peers.iter().filter_map(|addr| {
+            addr.parse::<Multiaddr>().ok()?.iter().find_map(|p| match p {
+                libp2p::multiaddr::Protocol::P2p(peer_id) => Some(peer_id),
+                _ => None,
+            })
+        });
+        let exempt = config.allowed_peers.iter().copied().chain(bootstrap).collect();
+        Self { connected_at: HashMap::new(), exempt, grace }
+    }
+
+    /// Starts the grace period of a newly connected peer
+    pub(crate) fn connected(&mut self, peer: PeerId, now: Instant) {
+        if !self.exempt.contains(&peer) {
+            self.connected_at.entry(peer).or_insert(now);
+        }
+    }
+
+    /// Identify showed `peer` speaks our protocol: it's kept
+    pub(crate) fn speaks_our_protocol(&mut self, peer: &PeerId) {
+        self.connected_at.remove(peer);
+    }
+
+    pub(crate) fn disconnected(&mut self, peer: &PeerId) {
+        self.connected_at.remove(peer);
+    }
+
+    /// Peers whose grace period is over, no longer tracked once returned
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self
+            .connected_at
+            .iter()
+            .filter(|(_, at)| now.duration_since(**at) >= self.grace)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &expired {
+            self.connected_at.remove(peer);
+        }
+        expired
+    }
+}
+
+/// First and maximum wait before redialing a bootstrap peer that dropped
+pub(crate) const BOOTSTRAP_REDIAL_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const BOOTSTRAP_REDIAL_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Configured bootstrap peers (those with a `/p2p/` component) and the
+/// listen addresses each last reported via identify, so a bootstrap node
+/// whose IP changed is also redialed where it is now. The configured
+/// addresses are always kept: a node behind NAT or in a container reports
+/// addresses only reachable from its own host.
+pub(crate) struct BootstrapPeers {
+    peers: HashMap<PeerId, BootstrapPeer>,
+}
+
+struct BootstrapPeer {
+    configured: Vec<Multiaddr>,
+    /// Last identify `listen_addrs`, minus loopback and unspecified ones
+    reported: Vec<Multiaddr>,
+    /// When to redial; `None` while connected or while a redial is in flight
+    next_redial: Option<Instant>,
+    backoff: Duration,
+}
+
+impl BootstrapPeers {
+    pub(crate) fn new(config: &Config) -> Self {
+        let mut peers: HashMap<PeerId, BootstrapPeer> = HashMap::new();
+        for addr in config.bootstrap_peers.iter().filter_map(|a| a.parse::<Multiaddr>().ok()) {
+            let Some(peer_id) = addr.iter().find_map(|p| match p {
+                libp2p::multiaddr::Protocol::P2p(peer_id) => Some(peer_id),
+                _ => None,
+            }) else {
+                continue;
+            };
+            peers
+                .entry(peer_id)
+                .or_insert_with(|| BootstrapPeer {
+                    configured: Vec::new(),
+                    reported: Vec::new(),
+                    next_redial: None,
+                    backoff: BOOTSTRAP_REDIAL_INITIAL_BACKOFF,
+                })
+                .configured
+                .push(addr);
+        }
+        Self { peers }
+    }
+
+    /// Records the addresses `peer` just reported, dropping the ones no
+    /// other host could dial. Returns the previously reported ones when they
+    /// changed (only for bootstrap peers).
+    pub(crate) fn update_addrs(&mut self, peer: &PeerId, addrs: Vec<Multiaddr>) -> Option<Vec<Multiaddr>> {
+        let entry = self.peers.get_mut(peer)?;
+        let mut addrs: Vec<Multiaddr> = addrs.into_iter().filter(is_remotely_dialable).collect();
+        addrs.sort();
+        addrs.dedup();
+        if addrs.is_empty() || addrs == entry.reported {
+            return None;
+        }
+        Some(std::mem::replace(&mut entry.reported, addrs))
+    }
+
+    pub(crate) fn connected(&mut self, peer: &PeerId) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.next_redial = None;
+            entry.backoff = BOOTSTRAP_REDIAL_INITIAL_BACKOFF;
+        }
+    }
+
+    /// The last connection closed or a redial failed: try again after the
+    /// current backoff, which doubles for the attempt after that
+    pub(crate) fn disconnected(&mut self, peer: &PeerId, now: Instant) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.next_redial = Some(now + entry.backoff);
+            entry.backoff = (entry.backoff * 2).min(BOOTSTRAP_REDIAL_MAX_BACKOFF);
+        }
+    }
+
+    /// Peers due for a redial with the addresses to try (configured first,
+    /// then reported), each returned once per `disconnected`
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.peers
+            .iter_mut()
+            .filter(|(_, entry)| entry.next_redial.is_some_and(|at| at <= now))
+            .map(|(peer, entry)| {
+                entry.next_redial = None;
+                let mut addrs = entry.configured.clone();
+                for addr in &entry.reported {
+                    if !addrs.iter().any(|a| a.iter().filter(|p| !matches!(p, Protocol::P2p(_))).eq(addr.iter())) {
+                        addrs.push(addr.clone());
+                    }
+                }
+                (*peer, addrs)
+            })
+            .collect()
+    }
+}
+
+/// False for loopback and unspecified IP addresses, which only mean
+/// something on the host that reported them
+fn is_remotely_dialable(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => !ip.is_loopback() && !ip.is_unspecified(),
+        Some(Protocol::Ip6(ip)) => !ip.is_loopback() && !ip.is_unspecified(),
+        _ => true,
+    }
+}
+
+/// Outbox ops (`OpSubmit`) awaiting a response, kept so a timed-out send can
+/// be re-queued
+struct PendingOps {
+    requests: HashMap<request_response::OutboundRequestId, PendingOp>,
+    max_retries: u32,
+}
+
+struct PendingOp {
+    peer: PeerId,
+    msg: Msg,
+    retries: u32,
+}
+
+impl PendingOps {
+    fn new(max_retries: u32) -> Self {
+        Self { requests: HashMap::new(), max_retries }
+    }
+
+    fn send(&mut self, swarm: &mut Swarm<NodeBehaviour>, peer: PeerId, msg: Msg, retries: u32) {
+        let id = swarm.behaviour_mut().request_response.send_request(&peer, msg.clone());
+        self.requests.insert(id, PendingOp { peer, msg, retries });
+    }
+
+    /// Drops the op for a request that got a response or failed for good
+    fn complete(&mut self, id: &request_response::OutboundRequestId) {
+        self.requests.remove(id);
+    }
+
+    /// Re-sends a timed-out op while it has retries left
+    fn retry(&mut self, swarm: &mut Swarm<NodeBehaviour>, id: &request_response::OutboundRequestId) -> bool {
+        let Some(op) = self.requests.remove(id) else { return false };
+        if op.retries >= self.max_retries {
+            warn!("Giving up on request to {} after {} retries", op.peer, op.retries);
+            return false;
+        }
+        info!("🔁 Re-sending timed-out request to {} (retry {}/{})", op.peer, op.retries + 1, self.max_retries);
+        self.send(swarm, op.peer, op.msg, op.retries + 1);
+        true
+    }
+}
+
+/// Auto-dials waiting for a free slot; beyond this, new ones are dropped
+/// (discovery will report those peers again)
+const DEFERRED_DIAL_CAPACITY: usize = 64;
+
 /// Tracks dial attempts to prevent dial loops
-struct DialState {
+pub(crate) struct DialState {
     last_dial: HashMap<PeerId, Instant>,
     cooldown: Duration,
     bootstrap_attempted: bool,
+    /// Nodes that identified with another `network_id`; never auto-dialed
+    foreign_network: HashSet<PeerId>,
+    /// Auto-dials started and not yet connected or failed
+    in_flight: HashSet<PeerId>,
+    max_concurrent: usize,
+    /// Auto-dials waiting for an `in_flight` slot, oldest first
+    deferred: VecDeque<PeerId>,
 }
 
 impl DialState {
-    fn new() -> Self {
+    pub(crate) fn new(max_concurrent: usize) -> Self {
         Self {
             last_dial: HashMap::new(),
             cooldown: Duration::from_secs(30),
             bootstrap_attempted: false,
+            foreign_network: HashSet::new(),
+            in_flight: HashSet::new(),
+            max_concurrent,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn deferred(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// A dial to `peer_id` connected or failed, freeing its slot
+    pub(crate) fn dial_finished(&mut self, peer_id: &PeerId) -> bool {
+        self.in_flight.remove(peer_id)
+    }
+
+    /// Takes a slot for a relayed dial to `peer_id`: the one its failed
+    /// direct dial held, or a free one. False when every slot is taken.
+    pub(crate) fn take_relay_slot(&mut self, peer_id: &PeerId) -> bool {
+        if !self.in_flight.contains(peer_id) && self.in_flight.len() >= self.max_concurrent {
+            return false;
         }
+        self.in_flight.insert(*peer_id);
+        true
     }
     
     fn can_dial(&mut self, peer_id: &PeerId) -> bool {
+        if self.foreign_network.contains(peer_id) {
+            return false;
+        }
         if let Some(last) = self.last_dial.get(peer_id) {
             if last.elapsed() < self.cooldown {
                 return false;
@@ -48,30 +343,161 @@ impl DialState {
     }
 }
 
-pub async fn build_swarm(config: &Config) -> Result<Swarm<NodeBehaviour>> {
-    let id_keys = config.identity_keypair.clone();
-    let peer_id = PeerId::from(id_keys.public());
-    info!("🆔 Local PeerId: {}", peer_id);
+/// Request/response failures in a row per connected peer, for
+/// `rr_failure_disconnect_threshold` (a threshold of 0 never trips)
+pub(crate) struct RrFailureStreaks {
+    streaks: HashMap<PeerId, u32>,
+    threshold: u32,
+}
 
-    // NOTE: Relay support is not wired up yet in this repo. We still read this
-    // config so it's not silently ignored.
-    if config.enable_relay {
-        warn!("Relay is enabled in config, but relay transport/behaviour is not configured yet; ignoring enable_relay=true for now.");
+impl RrFailureStreaks {
+    pub(crate) fn new(threshold: u32) -> Self {
+        Self { streaks: HashMap::new(), threshold }
     }
 
-    let tcp_transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
-    
-    let transport = tcp_transport
-        .upgrade(upgrade::Version::V1)
-        .authenticate(noise::Config::new(&id_keys).context("Failed to create noise config")?)
-        .multiplex(yamux::Config::default())
-        .boxed();
+    /// Counts a failure; true once `peer` reached the threshold, which also
+    /// starts its streak over
+    pub(crate) fn failed(&mut self, peer: PeerId) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let streak = self.streaks.entry(peer).or_default();
+        *streak += 1;
+        if *streak < self.threshold {
+            return false;
+        }
+        self.streaks.remove(&peer);
+        true
+    }
+
+    /// A request or response got through: the streak is broken
+    pub(crate) fn succeeded(&mut self, peer: &PeerId) {
+        self.streaks.remove(peer);
+    }
+
+    pub(crate) fn disconnected(&mut self, peer: &PeerId) {
+        self.streaks.remove(peer);
+    }
+}
+
+/// Client side of `fallback_relay`: once a direct dial to a gateway or
+/// bootstrap peer fails, the peer is dialed once more through the relay's
+/// `/p2p-circuit` address. Other peers (mDNS, Kademlia routing) aren't: the
+/// relay would end up carrying every unreachable node we hear about.
+pub(crate) struct RelayFallback {
+    relay: Multiaddr,
+    relay_peer: PeerId,
+    /// Peers worth reaching through the relay
+    targets: HashSet<PeerId>,
+    /// Peers whose relayed dial is in flight, so its failure isn't relayed again
+    relaying: HashSet<PeerId>,
+}
+
+impl RelayFallback {
+    /// `relay` must end in `/p2p/<relay peer id>` (checked at config load)
+    pub(crate) fn new(relay: &Multiaddr) -> Option<Self> {
+        match relay.iter().last() {
+            Some(Protocol::P2p(relay_peer)) => Some(Self {
+                relay: relay.clone(),
+                relay_peer,
+                targets: HashSet::new(),
+                relaying: HashSet::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// `fallback_relay` for clients, targeting the bootstrap peers; gateways
+    /// are reached directly
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        let mut fallback = match config.role {
+            Role::Client => config.fallback_relay.as_ref().and_then(Self::new)?,
+            Role::Gateway => return None,
+        };
+        let bootstrap = config.bootstrap_peers.iter().filter_map(|addr| {
+            addr.parse::<Multiaddr>().ok()?.iter().find_map(|p| match p {
+                Protocol::P2p(peer_id) => Some(peer_id),
+                _ => None,
+            })
+        });
+        fallback.targets.extend(bootstrap);
+        Some(fallback)
+    }
+
+    /// Also relays failed dials to `peer` (e.g. a gateway found in the DHT)
+    pub(crate) fn add_target(&mut self, peer: PeerId) {
+        self.targets.insert(peer);
+    }
+
+    /// Where to dial `peer` next after a failed dial to it: through the relay
+    /// if the failed dial was a direct one to a target, `None` if it was
+    /// already relayed, to another peer, or to the relay itself
+    pub(crate) fn after_dial_failure(&mut self, peer: PeerId) -> Option<Multiaddr> {
+        if peer == self.relay_peer || !self.targets.contains(&peer) || self.relaying.remove(&peer) {
+            return None;
+        }
+        self.relaying.insert(peer);
+        Some(
+            self.relay
+                .clone()
+                .with(Protocol::P2pCircuit)
+                .with(Protocol::P2p(peer)),
+        )
+    }
+
+    /// The relayed dial to `peer` is over (connected, or could not start)
+    pub(crate) fn finished(&mut self, peer: &PeerId) {
+        self.relaying.remove(peer);
+    }
+}
+
+/// Whether `addr` goes through a relay circuit
+pub(crate) fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// Dials `peer` through the relay after a failed direct dial; false when the
+/// relay doesn't apply (no relay, unknown or non-target peer, already relayed)
+fn dial_via_relay(
+    swarm: &mut Swarm<NodeBehaviour>,
+    fallback: Option<&mut RelayFallback>,
+    peer: Option<PeerId>,
+    error: &DialError,
+) -> bool {
+    let (Some(fallback), Some(peer), DialError::Transport(_)) = (fallback, peer, error) else {
+        return false;
+    };
+    let Some(circuit) = fallback.after_dial_failure(peer) else {
+        return false;
+    };
+    info!("🛰️  Direct dial to {} failed, retrying through the fallback relay: {}", peer, circuit);
+    let opts = DialOpts::peer_id(peer).addresses(vec![circuit]).build();
+    match swarm.dial(opts) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to dial {} through the fallback relay: {}", peer, e);
+            fallback.finished(&peer);
+            false
+        }
+    }
+}
+
+/// Builds the behaviours from `config` and the swarm on top of `transport`,
+/// without listening on or dialing anything yet. `relay_client` pairs with
+/// the relay transport inside `transport`, if it has one.
+pub(crate) fn new_swarm(
+    config: &Config,
+    transport: Boxed<(PeerId, StreamMuxerBox)>,
+    relay_client: Option<relay::client::Behaviour>,
+) -> Result<Swarm<NodeBehaviour>> {
+    let id_keys = &config.identity_keypair;
+    let peer_id = PeerId::from(id_keys.public());
 
     // Identify behaviour
-    let identify = identify::Behaviour::new(identify::Config::new(
-        "/hybrid-connection-health/1.0.0".to_string(),
-        id_keys.public(),
-    ));
+    let identify = identify::Behaviour::new(
+        identify::Config::new(identify_protocol_version(config.network_id.as_deref()), id_keys.public())
+            .with_agent_version(config.agent_version.clone()),
+    );
 
     // mDNS for LAN discovery
     let mdns = if config.enable_mdns {
@@ -79,18 +505,21 @@ pub async fn build_swarm(config: &Config) -> Result<Swarm<NodeBehaviour>> {
             query_interval: Duration::from_secs(5),
             ..Default::default()
         };
-        mdns::tokio::Behaviour::new(mdns_config, peer_id)?
+        Some(mdns::tokio::Behaviour::new(mdns_config, peer_id)?)
     } else {
-        // Create disabled mDNS (will still be in the behaviour, just won't discover)
+        // No behaviour at all: nothing is bound to the mDNS socket
         warn!("mDNS disabled in configuration");
-        let mdns_config = mdns::Config::default();
-        mdns::tokio::Behaviour::new(mdns_config, peer_id)?
+        None
     };
 
     // Kademlia DHT
     let kad = if config.enable_kad {
         let mut kad_config = kad::Config::default();
-        kad_config.set_query_timeout(Duration::from_secs(60));
+        kad_config.set_query_timeout(Duration::from_secs(config.kad_query_timeout_secs));
+        // Validated to be within 1..=K_VALUE when the config is parsed
+        if let Some(factor) = NonZeroUsize::new(config.kad_replication_factor) {
+            kad_config.set_replication_factor(factor);
+        }
         let store = kad::store::MemoryStore::new(peer_id);
         let mut kad_behaviour = kad::Behaviour::with_config(peer_id, store, kad_config);
         
@@ -103,63 +532,103 @@ pub async fn build_swarm(config: &Config) -> Result<Swarm<NodeBehaviour>> {
             info!("📡 Kademlia mode: Client");
         }
         
-        kad_behaviour
+        Some(kad_behaviour)
     } else {
         warn!("Kademlia DHT disabled in configuration");
-        let store = kad::store::MemoryStore::new(peer_id);
-        kad::Behaviour::new(peer_id, store)
+        None
     };
 
     // Ping behaviour
-    let ping = ping::Behaviour::new(ping::Config::new());
+    let ping = ping::Behaviour::new(
+        ping::Config::new()
+            .with_interval(Duration::from_secs(config.ping_interval_secs))
+            .with_timeout(Duration::from_secs(config.ping_timeout_secs)),
+    );
+
+    // UPnP/NAT-PMP port mapping on the local router
+    let upnp = if config.enable_upnp {
+        info!("🔀 UPnP port mapping enabled");
+        Some(upnp::tokio::Behaviour::default())
+    } else {
+        None
+    };
 
     // RequestResponse
-    let protocols = std::iter::once((OpProtocol, ProtocolSupport::Full));
+    // Every version we speak, preferred first (see OpProtocol::ALL)
+    let protocols = OpProtocol::ALL.into_iter().map(|p| (p, ProtocolSupport::Full));
     let request_response = request_response::Behaviour::<OpCodec>::new(
         protocols,
-        request_response::Config::default(),
+        request_response::Config::default()
+            .with_request_timeout(Duration::from_secs(config.rr_request_timeout_secs)),
     );
 
     let behaviour = NodeBehaviour {
         identify,
-        mdns,
-        kad,
+        mdns: mdns.into(),
+        kad: kad.into(),
         ping,
         request_response,
+        upnp: upnp.into(),
+        relay_client: relay_client.into(),
     };
 
-    let mut swarm = Swarm::new(
+    Ok(Swarm::new(
         transport,
         behaviour,
         peer_id,
         libp2p::swarm::Config::with_tokio_executor()
             .with_idle_connection_timeout(Duration::from_secs(300)), // Keep connections alive for 5 minutes
-    );
+    ))
+}
 
-    swarm.listen_on(config.listen.parse()?)?;
+pub async fn build_swarm(config: &Config, registry: &mut Registry) -> Result<Swarm<NodeBehaviour>> {
+    let id_keys = config.identity_keypair.clone();
+    let peer_id = PeerId::from(id_keys.public());
+    info!("🆔 Local PeerId: {}", peer_id);
 
-    // Dial bootstrap peers for DHT
-    if config.enable_kad {
-        for bootstrap_addr in &config.bootstrap_peers {
-            match bootstrap_addr.parse::<Multiaddr>() {
-                Ok(addr) => {
-                    info!("🔗 Dialing bootstrap peer: {}", bootstrap_addr);
-                    if let Err(e) = swarm.dial(addr.clone()) {
-                        error!("Failed to dial bootstrap peer {}: {:?}", bootstrap_addr, e);
-                    }
-                    
-                    // Extract peer ID and add to Kademlia
-                    if let Some(libp2p::multiaddr::Protocol::P2p(peer_id_hash)) = 
-                        addr.iter().find(|p| matches!(p, libp2p::multiaddr::Protocol::P2p(_))) 
-                    {
-                        swarm.behaviour_mut().kad.add_address(&peer_id_hash, addr);
-                    }
-                }
-                Err(e) => error!("Invalid bootstrap multiaddr '{}': {:?}", bootstrap_addr, e),
-            }
-        }
+    // NOTE: Only the client side of relaying (`fallback_relay`) is wired up;
+    // acting as a relay isn't. We still read this config so it's not silently ignored.
+    if config.enable_relay {
+        warn!("enable_relay=true is ignored: this node can't act as a relay for others (only dialing through one with fallback_relay is supported)");
     }
 
+    let tcp_transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
+    // `/p2p-circuit` dials go to the relay client, everything else to TCP
+    let (relay_transport, relay_client) = relay::client::new(peer_id);
+    let relay_client = config.fallback_relay.is_some().then_some(relay_client);
+
+    let transport = relay_transport
+        .or_transport(tcp_transport)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(&id_keys).context("Failed to create noise config")?)
+        .multiplex(yamux::Config::default())
+        .timeout(Duration::from_secs(config.dial_timeout_secs))
+        // Surface the timeout as `TimedOut` so `is_dial_timeout` can spot it
+        .map_err(|e| match e {
+            TransportTimeoutError::Timeout => io::Error::new(io::ErrorKind::TimedOut, "dial timed out"),
+            TransportTimeoutError::TimerError(e) => e,
+            TransportTimeoutError::Other(e) => io::Error::other(e),
+        });
+    // Count bytes per direction and transport protocol stack
+    let transport = BandwidthTransport::new(transport, registry)
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed();
+
+    let mut swarm = new_swarm(config, transport, relay_client)?;
+
+    // Listen on every configured address (e.g. IPv4 + IPv6 for dual-stack)
+    for listen_addr in &config.listen {
+        let addr: Multiaddr = listen_addr
+            .parse()
+            .with_context(|| format!("Invalid listen multiaddr '{}'", listen_addr))?;
+        swarm
+            .listen_on(addr)
+            .with_context(|| format!("Failed to listen on '{}'", listen_addr))?;
+    }
+
+    // Dial bootstrap peers for DHT
+    dial_bootstrap_peers(&mut swarm, config);
+
     // Optional manual dial from CLI (legacy support)
     if let Some(dial_addr) = &config.dial {
         match dial_addr.parse::<Multiaddr>() {
@@ -189,51 +658,281 @@ pub async fn build_swarm(config: &Config) -> Result<Swarm<NodeBehaviour>> {
     Ok(swarm)
 }
 
-use crate::api::SharedNetworkState;
-use crate::broker::handler::BrokerHandler;
+use crate::api::{SharedNetworkState, SharedReadiness};
+use crate::metrics::SharedMetrics;
+use crate::broker::handler::{BookingRequest, BrokerHandler};
+use super::command::{ClosestPeer, SwarmCommand, GATEWAY_SERVICE_KEY};
 use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Protocol version advertised via identify (also reported by `/version`)
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "/hybrid-connection-health/1.0.0";
+
+/// Identify protocol version for `network_id`: the base version, with the
+/// network id appended as a last segment when one is configured
+pub(crate) fn identify_protocol_version(network_id: Option<&str>) -> String {
+    match network_id {
+        Some(id) => format!("{}/{}", IDENTIFY_PROTOCOL_VERSION, id),
+        None => IDENTIFY_PROTOCOL_VERSION.to_string(),
+    }
+}
+
+/// The network id a peer advertised in its identify protocol version
+pub(crate) fn network_id_of(protocol_version: &str) -> Option<&str> {
+    protocol_version.strip_prefix(IDENTIFY_PROTOCOL_VERSION)?.strip_prefix('/')
+}
+
+/// How long to wait for peers to close cleanly once shutdown is requested
+const DISCONNECT_GRACE: Duration = Duration::from_secs(5);
+
+/// Shared gate for the auto-dial paths (mDNS, Kademlia routing, DHT providers):
+/// never ourselves (two interfaces can report the same node), never a peer the
+/// allow/deny lists reject, and only when not connected and out of cooldown
+pub(crate) fn should_auto_dial(
+    swarm: &Swarm<NodeBehaviour>,
+    config: &Config,
+    dial_state: &mut DialState,
+    peer_id: &PeerId,
+) -> bool {
+    peer_id != swarm.local_peer_id()
+        && config.is_peer_permitted(peer_id)
+        && !swarm.is_connected(peer_id)
+        && dial_state.can_dial(peer_id)
+}
+
+/// Dials `peer_id` (found via `source`) if a dial slot is free, otherwise
+/// queues it until `drain_deferred_dials` has room. Callers check
+/// `should_auto_dial` first.
+pub(crate) fn auto_dial(swarm: &mut Swarm<NodeBehaviour>, dial_state: &mut DialState, peer_id: PeerId, source: &str) {
+    if dial_state.in_flight.len() >= dial_state.max_concurrent {
+        if dial_state.deferred.len() < DEFERRED_DIAL_CAPACITY && !dial_state.deferred.contains(&peer_id) {
+            debug!("⏳ Deferring dial to {} {}: {} dials in flight", source, peer_id, dial_state.in_flight.len());
+            dial_state.deferred.push_back(peer_id);
+        }
+        return;
+    }
+    info!("📞 Auto-dialing {}: {}", source, peer_id);
+    if swarm.dial(peer_id).is_ok() {
+        dial_state.in_flight.insert(peer_id);
+    }
+}
 
+/// Starts deferred dials while slots are free, skipping peers that got
+/// connected or became unwelcome while they waited
+pub(crate) fn drain_deferred_dials(swarm: &mut Swarm<NodeBehaviour>, config: &Config, dial_state: &mut DialState) {
+    while dial_state.in_flight.len() < dial_state.max_concurrent {
+        let Some(peer_id) = dial_state.deferred.pop_front() else { break };
+        if swarm.is_connected(&peer_id)
+            || !config.is_peer_permitted(&peer_id)
+            || dial_state.foreign_network.contains(&peer_id)
+        {
+            continue;
+        }
+        auto_dial(swarm, dial_state, peer_id, "deferred peer");
+    }
+}
+
+/// Whether a failed dial ran into `dial_timeout_secs`. The transport reports
+/// the timeout as an `io::ErrorKind::TimedOut` error, possibly wrapped again
+/// by the layers above it.
+pub(crate) fn is_dial_timeout(error: &DialError) -> bool {
+    let DialError::Transport(attempts) = error else {
+        return false;
+    };
+    attempts.iter().any(|(_, error)| {
+        let libp2p::TransportError::Other(error) = error else {
+            return false;
+        };
+        let mut error: &io::Error = error;
+        loop {
+            if error.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+            match error.get_ref().and_then(|inner| inner.downcast_ref::<io::Error>()) {
+                Some(inner) => error = inner,
+                None => return false,
+            }
+        }
+    })
+}
+
+/// Dials the configured bootstrap peers and adds them to Kademlia (no-op
+/// when Kademlia is disabled)
+pub(crate) fn dial_bootstrap_peers(swarm: &mut Swarm<NodeBehaviour>, config: &Config) {
+    if swarm.behaviour().kad.is_enabled() {
+        for bootstrap_addr in &config.bootstrap_peers {
+            match bootstrap_addr.parse::<Multiaddr>() {
+                Ok(addr) => {
+                    info!("🔗 Dialing bootstrap peer: {}", bootstrap_addr);
+                    if let Err(e) = swarm.dial(addr.clone()) {
+                        error!("Failed to dial bootstrap peer {}: {:?}", bootstrap_addr, e);
+                    }
+                    
+                    // Extract peer ID and add to Kademlia
+                    if let Some(libp2p::multiaddr::Protocol::P2p(peer_id_hash)) = 
+                        addr.iter().find(|p| matches!(p, libp2p::multiaddr::Protocol::P2p(_))) 
+                    {
+                        if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                            kad.add_address(&peer_id_hash, addr);
+                        }
+                    }
+                }
+                Err(e) => error!("Invalid bootstrap multiaddr '{}': {:?}", bootstrap_addr, e),
+            }
+        }
+    }
+}
+
+/// Advertises this node as a booking gateway in the DHT (re-published after each bootstrap)
+fn provide_gateway_service(swarm: &mut Swarm<NodeBehaviour>) {
+    if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+        if let Err(e) = kad.start_providing(kad::RecordKey::new(&GATEWAY_SERVICE_KEY)) {
+            warn!("Failed to advertise gateway provider record: {:?}", e);
+        }
+    }
+}
+
+/// Starts a DHT lookup for the providers of `key`
+fn find_providers(swarm: &mut Swarm<NodeBehaviour>, key: &str) {
+    match swarm.behaviour_mut().kad.as_mut() {
+        Some(kad) => {
+            info!("🔎 Looking up DHT providers for {}", key);
+            kad.get_providers(kad::RecordKey::new(&key));
+        }
+        None => warn!("Cannot look up providers for {}: Kademlia is disabled", key),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_swarm(
     mut swarm: Swarm<NodeBehaviour>,
     config: Config,
     network_state: SharedNetworkState,
+    readiness: SharedReadiness,
+    metrics: SharedMetrics,
     broker_handler: Option<Arc<BrokerHandler>>,
+    mut commands: mpsc::Receiver<SwarmCommand>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
-    let mut dial_state = DialState::new();
+    let mut dial_state = DialState::new(config.max_concurrent_dials);
+    let mut pending_ops = PendingOps::new(config.rr_max_retries);
+    let mut observed_addrs = ObservedAddrs::new(EXTERNAL_ADDR_CONFIRMATIONS);
+    // Received ops are persisted on their own tasks, acked once stored; each
+    // holds one of the handler's op slots, without one it is answered "busy"
+    let op_handler = broker_handler.clone();
+    let mut pending_op_acks = FuturesUnordered::new();
+    // Bookings are persisted by a separate task so a slow sled flush can't
+    // stall the event loop; a full queue is answered "busy" right away
+    let booking_queue = broker_handler.map(|handler| {
+        let (queue, requests) = mpsc::channel(config.max_concurrent_broker_ops);
+        tokio::spawn(handler.run_queue(requests));
+        queue
+    });
+    let mut pending_booking_acks = FuturesUnordered::new();
+    // Validated at config load, so the issuer always embeds its key
+    let booking_token_issuer = config.booking_token_issuer.as_ref().and_then(capability::issuer_public_key);
+    // On-demand closest-peer lookups (`SwarmCommand::FindClosest`) by query id
+    let mut pending_closest: HashMap<kad::QueryId, oneshot::Sender<Result<Vec<ClosestPeer>, String>>> = HashMap::new();
+    let mut bootstrap_peers = BootstrapPeers::new(&config);
+    let mut rr_failure_streaks = RrFailureStreaks::new(config.rr_failure_disconnect_threshold);
+    let mut unrelated_peers = config
+        .disconnect_unrelated_peers
+        .then(|| UnrelatedPeers::new(&config, UNRELATED_PEER_GRACE));
+    let mut relay_fallback = RelayFallback::from_config(&config);
+    let mut active_listen_addrs: HashSet<Multiaddr> = HashSet::new();
     let mut discovered_via_mdns: HashSet<PeerId> = HashSet::new();
     let mut discovered_via_kad: HashSet<PeerId> = HashSet::new();
     let start_time = Instant::now();
     let discovery_timeout = Duration::from_secs(config.discovery_timeout_secs);
+    // With `DiscoveryTimeoutAction::Rebootstrap`, when the next redial is due
+    let mut next_rebootstrap = start_time + discovery_timeout;
     
     // Health check interval
-    let mut health_check_interval = tokio::time::interval(Duration::from_secs(10));
+    let mut health_check_interval = tokio::time::interval(Duration::from_secs(config.health_check_interval_secs));
+    // (connected, mdns, kad) at the last health line, to keep idle nodes quiet
+    let mut last_health_counts = None;
     
-    // DHT maintenance interval (random walks)
-    let mut dht_maintenance_interval = tokio::time::interval(Duration::from_secs(60));
+    // DHT maintenance interval (random walks); none when kad_maintenance_secs = 0
+    let mut dht_maintenance_interval = (config.kad_maintenance_secs > 0)
+        .then(|| tokio::time::interval(Duration::from_secs(config.kad_maintenance_secs)));
 
     info!("🚀 Starting P2P swarm event loop...");
 
+    if matches!(config.role, Role::Gateway) {
+        provide_gateway_service(&mut swarm);
+    }
+
     loop {
         tokio::select! {
             event = swarm.select_next_some() => {
                 match event {
                     SwarmEvent::NewListenAddr { address, .. } => {
                         info!("🎧 Listening on {:?}", address);
+                        active_listen_addrs.insert(address);
+                        readiness.set_listening(true);
+                        network_state.write().await.set_listen_addrs(active_listen_addrs.iter().map(|a| a.to_string()));
+                    }
+                    SwarmEvent::ExpiredListenAddr { address, .. } => {
+                        info!("🔇 No longer listening on {:?}", address);
+                        active_listen_addrs.remove(&address);
+                        readiness.set_listening(!active_listen_addrs.is_empty());
+                        network_state.write().await.set_listen_addrs(active_listen_addrs.iter().map(|a| a.to_string()));
+                    }
+                    SwarmEvent::ListenerClosed { addresses, reason, .. } => {
+                        info!("🔇 Listener closed ({:?}), dropping {:?}", reason, addresses);
+                        for address in &addresses {
+                            active_listen_addrs.remove(address);
+                        }
+                        readiness.set_listening(!active_listen_addrs.is_empty());
+                        network_state.write().await.set_listen_addrs(active_listen_addrs.iter().map(|a| a.to_string()));
                     }
-                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    SwarmEvent::ListenerError { error, .. } => {
+                        warn!("🎧 Listener error: {}", error);
+                    }
+                    SwarmEvent::ExternalAddrConfirmed { address } => {
+                        info!("🌍 External address confirmed: {}", address);
+                        network_state.write().await.add_external_addr(address.to_string());
+                    }
+                    SwarmEvent::ExternalAddrExpired { address } => {
+                        info!("🌍 External address expired: {}", address);
+                        network_state.write().await.remove_external_addr(&address.to_string());
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
+                        if dial_state.dial_finished(&peer_id) {
+                            drain_deferred_dials(&mut swarm, &config, &mut dial_state);
+                        }
+                        if !config.is_peer_permitted(&peer_id) {
+                            warn!("⛔ Rejecting connection from {} ({}): peer is denied or not allowlisted", peer_id, endpoint.get_remote_address());
+                            metrics.peers_rejected.inc();
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                            continue;
+                        }
                         info!("✅ Connection established with {} ({})", peer_id, endpoint.get_remote_address());
 
                         // Update shared network snapshot
                         {
                             let mut snap = network_state.write().await;
-                            snap.set_connected(peer_id.to_string(), true);
+                            if endpoint.is_dialer() {
+                                snap.resolve_bootstrap_peer(endpoint.get_remote_address(), &peer_id.to_string());
+                            }
+                            snap.connection_established(peer_id.to_string(), num_established.get(), endpoint.is_dialer());
+                            if is_relayed(endpoint.get_remote_address()) {
+                                snap.track_relayed(peer_id.to_string(), 1);
+                            }
+                        }
+                        if let Some(fallback) = relay_fallback.as_mut() {
+                            fallback.finished(&peer_id);
                         }
+                        if let Some(unrelated) = unrelated_peers.as_mut() {
+                            unrelated.connected(peer_id, Instant::now());
+                        }
+                        bootstrap_peers.connected(&peer_id);
                         
                         // Add peer to Kademlia and trigger bootstrap when we have an active connection
                         // This ensures bootstrap works regardless of startup order
-                        if config.enable_kad {
+                        if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
                             // Add the peer's endpoint address to Kademlia routing table
-                            swarm.behaviour_mut().kad.add_address(&peer_id, endpoint.get_remote_address().clone());
+                            kad.add_address(&peer_id, endpoint.get_remote_address().clone());
                             
                             // Trigger Kademlia bootstrap if not attempted yet
                             // Wait a brief moment if we just started (to let identify exchange addresses)
@@ -243,7 +942,7 @@ pub async fn run_swarm(
                                 
                                 if should_bootstrap_now {
                                     info!("🌐 Bootstrapping Kademlia DHT after connection established...");
-                                    if let Err(e) = swarm.behaviour_mut().kad.bootstrap() {
+                                    if let Err(e) = kad.bootstrap() {
                                         warn!("Kademlia bootstrap failed (will retry later): {:?}", e);
                                     } else {
                                         dial_state.bootstrap_attempted = true;
@@ -263,16 +962,58 @@ pub async fn run_swarm(
                                  created_at_ms: 1234567890,
                              };
                              info!("📤 Sending OpSubmit to connected peer {}", peer_id);
-                             swarm.behaviour_mut().request_response.send_request(&peer_id, Msg::OpSubmit { op });
+                             pending_ops.send(&mut swarm, peer_id, Msg::OpSubmit { op }, 0);
                         }
                     }
-                    SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
-                        warn!("❌ Connection closed with {}: {:?}", peer_id, cause);
+                    SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                        debug!("Outgoing connection to {:?} failed: {}", peer_id, error);
+                        if is_dial_timeout(&error) {
+                            warn!("⏱️  Dial to {:?} timed out after {}s", peer_id, config.dial_timeout_secs);
+                            metrics.dial_timeouts.inc();
+                        }
+                        let tried: Vec<Multiaddr> = match &error {
+                            DialError::Transport(attempts) => attempts.iter().map(|(addr, _)| addr.clone()).collect(),
+                            _ => Vec::new(),
+                        };
+                        network_state.write().await.bootstrap_dial_failed(
+                            peer_id.map(|p| p.to_string()).as_deref(),
+                            &tried,
+                            &error.to_string(),
+                        );
+                        if let Some(peer_id) = peer_id {
+                            // A relayed dial keeps the failed dial's slot until it connects or fails
+                            let relayed = relay_fallback.is_some()
+                                && dial_state.take_relay_slot(&peer_id)
+                                && dial_via_relay(&mut swarm, relay_fallback.as_mut(), Some(peer_id), &error);
+                            if !relayed && dial_state.dial_finished(&peer_id) {
+                                drain_deferred_dials(&mut swarm, &config, &mut dial_state);
+                            }
+                            if !swarm.is_connected(&peer_id) {
+                                bootstrap_peers.disconnected(&peer_id, Instant::now());
+                            }
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, endpoint, cause, num_established, .. } => {
+                        if !config.is_peer_permitted(&peer_id) {
+                            continue;
+                        }
+                        warn!("❌ Connection closed with {}: {:?} ({} remaining)", peer_id, cause, num_established);
 
                         // Update shared network snapshot
                         {
                             let mut snap = network_state.write().await;
-                            snap.set_connected(peer_id.to_string(), false);
+                            if is_relayed(endpoint.get_remote_address()) {
+                                snap.track_relayed(peer_id.to_string(), -1);
+                            }
+                            snap.connection_closed(peer_id.to_string(), num_established, endpoint.is_dialer());
+                        }
+                        if num_established == 0 {
+                            if let Some(unrelated) = unrelated_peers.as_mut() {
+                                unrelated.disconnected(&peer_id);
+                            }
+                            bootstrap_peers.disconnected(&peer_id, Instant::now());
+                            rr_failure_streaks.disconnected(&peer_id);
+                            observed_addrs.disconnected(&peer_id);
                         }
                     }
                     
@@ -280,24 +1021,60 @@ pub async fn run_swarm(
                     SwarmEvent::Behaviour(NodeBehaviourEvent::Identify(event)) => {
                         match *event {
                             identify::Event::Received { peer_id, info, .. } => {
-                                info!("🔍 Identified peer {}: {} protocols, observed_addr={:?}", 
-                                      peer_id, info.protocols.len(), info.observed_addr);
+                                info!("🔍 Identified peer {} ({}): {} protocols, observed_addr={:?}",
+                                      peer_id, info.agent_version, info.protocols.len(), info.observed_addr);
+                                let rr_protocol = OpProtocol::preferred(info.protocols.iter().map(|p| p.as_ref()));
+                                // Only nodes of this app carry a network id; other DHT peers are left alone
+                                let peer_network = network_id_of(&info.protocol_version);
+                                if rr_protocol.is_some() && peer_network != config.network_id.as_deref() {
+                                    warn!("🚧 Dropping {}: network_id {:?} doesn't match ours ({:?})",
+                                          peer_id, peer_network, config.network_id);
+                                    dial_state.foreign_network.insert(peer_id);
+                                    if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                                        kad.remove_peer(&peer_id);
+                                    }
+                                    let _ = swarm.disconnect_peer_id(peer_id);
+                                    continue;
+                                }
+                                dial_state.foreign_network.remove(&peer_id);
+                                if let (Some(unrelated), Some(_)) = (unrelated_peers.as_mut(), rr_protocol) {
+                                    unrelated.speaks_our_protocol(&peer_id);
+                                }
+                                let mut snap = network_state.write().await;
+                                snap.set_agent_version(peer_id.to_string(), info.agent_version.clone());
+                                snap.set_rr_protocol(peer_id.to_string(), rr_protocol.map(|p| p.as_ref().to_string()));
+                                drop(snap);
+
+                                if let Some(previous) = bootstrap_peers.update_addrs(&peer_id, info.listen_addrs.clone()) {
+                                    info!("🔀 Bootstrap peer {} now listens on {:?} (was {:?}); redials will try them after the configured address",
+                                          peer_id, info.listen_addrs, previous);
+                                }
+
+                                if observed_addrs.observe(peer_id, info.observed_addr.clone()) {
+                                    info!("🌍 External address {} confirmed by {} peers", info.observed_addr, EXTERNAL_ADDR_CONFIRMATIONS);
+                                    // Reaches the snapshot through SwarmEvent::ExternalAddrConfirmed
+                                    swarm.add_external_address(info.observed_addr.clone());
+                                }
                                 
                                 // Add peer's listen addresses to Kademlia and swarm
                                 for addr in info.listen_addrs {
-                                    swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+                                    if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                                        kad.add_address(&peer_id, addr.clone());
+                                    }
                                     swarm.add_peer_address(peer_id, addr);
                                 }
                                 
                                 // Trigger Kademlia bootstrap after first successful identify
                                 // This is a fallback in case ConnectionEstablished didn't trigger it
                                 // We no longer require the 5-second delay since we have better timing in ConnectionEstablished
-                                if config.enable_kad && !dial_state.bootstrap_attempted {
-                                    info!("🌐 Bootstrapping Kademlia DHT after identify...");
-                                    if let Err(e) = swarm.behaviour_mut().kad.bootstrap() {
-                                        error!("Kademlia bootstrap failed: {:?}", e);
-                                    } else {
-                                        dial_state.bootstrap_attempted = true;
+                                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                                    if !dial_state.bootstrap_attempted {
+                                        info!("🌐 Bootstrapping Kademlia DHT after identify...");
+                                        if let Err(e) = kad.bootstrap() {
+                                            error!("Kademlia bootstrap failed: {:?}", e);
+                                        } else {
+                                            dial_state.bootstrap_attempted = true;
+                                        }
                                     }
                                 }
                             }
@@ -312,6 +1089,10 @@ pub async fn run_swarm(
                     // mDNS events
                     SwarmEvent::Behaviour(NodeBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                         for (peer_id, multiaddr) in list {
+                            if dial_state.foreign_network.contains(&peer_id) {
+                                debug!("📡 mDNS ignoring {} from another network", peer_id);
+                                continue;
+                            }
                             info!("📡 mDNS Discovered: {} at {}", peer_id, multiaddr);
                             discovered_via_mdns.insert(peer_id);
 
@@ -321,37 +1102,90 @@ pub async fn run_swarm(
                             }
                             
                             swarm.add_peer_address(peer_id, multiaddr.clone());
-                            if config.enable_kad {
-                                swarm.behaviour_mut().kad.add_address(&peer_id, multiaddr);
+                            if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                                kad.add_address(&peer_id, multiaddr);
                             }
                             
                             // Symmetric auto-dial (no role restriction)
-                            if !swarm.is_connected(&peer_id) && dial_state.can_dial(&peer_id) {
-                                info!("📞 Auto-dialing mDNS peer: {}", peer_id);
-                                let _ = swarm.dial(peer_id);
+                            if should_auto_dial(&swarm, &config, &mut dial_state, &peer_id) {
+                                auto_dial(&mut swarm, &mut dial_state, peer_id, "mDNS peer");
                             }
                         }
                     }
                     SwarmEvent::Behaviour(NodeBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                        let mut snap = network_state.write().await;
                         for (peer_id, _multiaddr) in list {
                             info!("⏱️  mDNS Expired: {}", peer_id);
+                            snap.on_mdns_expired(&peer_id.to_string());
                         }
                     }
                     
                     // Kademlia events
-                    SwarmEvent::Behaviour(NodeBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { result, .. })) => {
+                    SwarmEvent::Behaviour(NodeBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { id, result, step, .. })) => {
                         match result {
-                            kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { peer, .. })) => {
+                            kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { peer, num_remaining })) => {
                                 info!("✅ Kademlia bootstrap success with peer: {}", peer);
+                                if readiness.dht_pending() {
+                                    info!("🧭 First Kademlia bootstrap succeeded; reporting DHT ready");
+                                    readiness.set_dht_ready(true);
+                                }
+                                // Once the round finishes the routing table is populated:
+                                // publish our provider record / look for gateways
+                                if num_remaining == 0 {
+                                    match config.role {
+                                        Role::Gateway => provide_gateway_service(&mut swarm),
+                                        Role::Client => find_providers(&mut swarm, GATEWAY_SERVICE_KEY),
+                                    }
+                                }
                             }
                             kad::QueryResult::Bootstrap(Err(e)) => {
                                 error!("❌ Kademlia bootstrap error: {:?}", e);
                             }
+                            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers })) => {
+                                let key = String::from_utf8_lossy(key.as_ref()).into_owned();
+                                info!("🧭 Found {} provider(s) for {}", providers.len(), key);
+                                if let (Some(fallback), true) = (relay_fallback.as_mut(), key == GATEWAY_SERVICE_KEY) {
+                                    for provider in &providers {
+                                        fallback.add_target(*provider);
+                                    }
+                                }
+                                network_state.write().await
+                                    .add_providers(key, providers.iter().map(|p| p.to_string()));
+
+                                // Providers are the gateways we want to talk to
+                                for provider in providers {
+                                    if should_auto_dial(&swarm, &config, &mut dial_state, &provider) {
+                                        auto_dial(&mut swarm, &mut dial_state, provider, "DHT provider");
+                                    }
+                                }
+                            }
+                            kad::QueryResult::GetProviders(Err(e)) => {
+                                warn!("Provider lookup failed: {:?}", e);
+                            }
+                            kad::QueryResult::StartProviding(Err(e)) => {
+                                warn!("Publishing provider record failed: {:?}", e);
+                            }
                             kad::QueryResult::GetClosestPeers(Ok(ok)) => {
                                 info!("🔍 Found {} closest peers via Kademlia", ok.peers.len());
                                 for peer_info in &ok.peers {
                                     discovered_via_kad.insert(peer_info.peer_id);
                                 }
+                                if step.last {
+                                    if let Some(reply) = pending_closest.remove(&id) {
+                                        let peers = ok.peers.into_iter().map(|peer_info| ClosestPeer {
+                                            peer_id: peer_info.peer_id.to_string(),
+                                            addrs: peer_info.addrs.iter().map(|a| a.to_string()).collect(),
+                                        });
+                                        let _ = reply.send(Ok(peers.collect()));
+                                    }
+                                }
+                            }
+                            kad::QueryResult::GetClosestPeers(Err(e)) => {
+                                // Also the fate of most maintenance walks on a small network
+                                if let Some(reply) = pending_closest.remove(&id) {
+                                    warn!("Closest peers lookup failed: {:?}", e);
+                                    let _ = reply.send(Err(e.to_string()));
+                                }
                             }
                             _ => {}
                         }
@@ -366,9 +1200,8 @@ pub async fn run_swarm(
                         }
                         
                         // Auto-dial if not connected (symmetric)
-                        if !swarm.is_connected(&peer) && dial_state.can_dial(&peer) {
-                            info!("📞 Auto-dialing peer from Kademlia routing table: {}", peer);
-                            let _ = swarm.dial(peer);
+                        if should_auto_dial(&swarm, &config, &mut dial_state, &peer) {
+                            auto_dial(&mut swarm, &mut dial_state, peer, "peer from Kademlia routing table");
                         }
                     }
                     
@@ -376,29 +1209,86 @@ pub async fn run_swarm(
                     SwarmEvent::Behaviour(NodeBehaviourEvent::Ping(ping::Event { peer, result, .. })) => {
                         match result {
                             Ok(rtt) => {
-                                {
+                                let avg_rtt_ms = {
                                     let mut snap = network_state.write().await;
-                                    snap.set_rtt_ms(peer.to_string(), rtt.as_millis() as u64);
-                                }
-                                // Don't log every ping to reduce noise
-                                if rtt.as_millis() > 500 {
-                                    warn!("🏓 High latency ping from {}: {:?}", peer, rtt);
+                                    snap.set_rtt_ms(peer.to_string(), rtt.as_millis() as u64)
+                                };
+                                // Don't log every ping to reduce noise; warn on the moving
+                                // average so a single spike doesn't trigger it
+                                if avg_rtt_ms > 500.0 {
+                                    warn!("🏓 High latency ping from {}: {:?} (avg {:.0} ms)", peer, rtt, avg_rtt_ms);
                                 }
                             }
+                            // A peer without the ping protocol isn't dead, it just can't tell us
+                            Err(ping::Failure::Unsupported) => debug!("Peer {} doesn't support ping", peer),
                             Err(e) => {
                                 warn!("Ping failure with {}: {:?}", peer, e);
+                                let failures = {
+                                    let mut snap = network_state.write().await;
+                                    if matches!(e, ping::Failure::Timeout) {
+                                        snap.on_ping_timeout(peer.to_string());
+                                    }
+                                    snap.record_ping_failure(peer.to_string())
+                                };
+                                if config.max_ping_failures > 0 && failures >= config.max_ping_failures {
+                                    warn!("🏓 Disconnecting {} after {} failed pings in a row", peer, failures);
+                                    let _ = swarm.disconnect_peer_id(peer);
+                                }
                             }
                         }
                     }
                     
+                    // UPnP events
+                    SwarmEvent::Behaviour(NodeBehaviourEvent::RelayClient(relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. })) => {
+                        info!("🛰️  Circuit established through relay {}", relay_peer_id);
+                    }
+                    SwarmEvent::Behaviour(NodeBehaviourEvent::Upnp(event)) => {
+                        match event {
+                            upnp::Event::NewExternalAddr(addr) => {
+                                info!("🔀 UPnP mapped external address {}", addr);
+                                network_state.write().await.set_upnp_mapping(addr.to_string(), true);
+                            }
+                            upnp::Event::ExpiredExternalAddr(addr) => {
+                                warn!("🔀 UPnP mapping for {} expired", addr);
+                                network_state.write().await.set_upnp_mapping(addr.to_string(), false);
+                            }
+                            upnp::Event::GatewayNotFound => {
+                                warn!("🔀 UPnP: no gateway found on the local network");
+                            }
+                            upnp::Event::NonRoutableGateway => {
+                                warn!("🔀 UPnP: gateway is not exposed to the public network, mappings won't help");
+                            }
+                        }
+                    }
+
                     // RequestResponse events
                     SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::Message { peer, message, .. })) => {
+                       rr_failure_streaks.succeeded(&peer);
                        match message {
                            request_response::Message::Request { request, channel, .. } => {
                                match request {
                                    Msg::OpSubmit { op } => {
                                        info!("📥 Received OpSubmit from {}: {:?}", peer, op);
-                                       
+
+                                       // With a broker, ack only once the op is persisted
+                                       if let Some(handler) = op_handler.clone() {
+                                           let Some(slot) = handler.try_reserve_op() else {
+                                               warn!("Too many ops in flight, answering busy to {}: op_id={}", peer, op.op_id);
+                                               metrics.ops_busy.inc();
+                                               let busy_ack = Msg::OpAck { op_id: op.op_id, ok: false, msg: "busy".into() };
+                                               let _ = swarm.behaviour_mut().request_response.send_response(channel, busy_ack);
+                                               continue;
+                                           };
+                                           let op_id = op.op_id.clone();
+                                           let stored = tokio::spawn(async move { handler.handle_op_submit(op, peer.to_string()).await });
+                                           pending_op_acks.push(async move {
+                                               let stored = stored.await;
+                                               drop(slot);
+                                               (peer, channel, op_id, stored)
+                                           });
+                                           continue;
+                                       }
+
                                        let ack = Msg::OpAck { 
                                            op_id: op.op_id, 
                                            ok: true, 
@@ -408,49 +1298,74 @@ pub async fn run_swarm(
                                        info!("📤 Sending OpAck to {}", peer);
                                        let _ = swarm.behaviour_mut().request_response.send_response(channel, ack);
                                    },
-                                   Msg::SubmitBooking { correlation_id, booking, notify } => {
+                                   Msg::SubmitBooking { correlation_id, booking, notify, token } => {
                                        // Only process if Gateway role and broker handler available
                                        if matches!(config.role, Role::Gateway) {
-                                           if let Some(ref handler) = broker_handler {
+                                           if let Some(ref queue) = booking_queue {
                                                info!("📥 Received SubmitBooking from {}: correlation_id={}", peer, correlation_id);
-                                               
-                                               // Handle booking submission
-                                               match handler.handle_submit_booking(correlation_id.clone(), booking, notify).await {
-                                                   Ok(ack) => {
-                                                       info!("📤 Sending BookingAck to {}: correlation_id={}", peer, correlation_id);
-                                                       let _ = swarm.behaviour_mut().request_response.send_response(channel, ack);
-                                                   },
-                                                   Err(e) => {
-                                                       error!("Failed to handle booking submission: {:?}", e);
-                                                       // Send error ACK
-                                                       let error_ack = Msg::BookingAck {
-                                                           correlation_id,
-                                                           status: "error".to_string(),
-                                                       };
+
+                                               if let Some(issuer) = booking_token_issuer.as_ref() {
+                                                   let checked = token
+                                                       .as_deref()
+                                                       .context("no token")
+                                                       .and_then(|token| capability::verify(token, issuer, &peer));
+                                                   if let Err(e) = checked {
+                                                       warn!("Rejecting booking {} from {}: {}", correlation_id, peer, e);
+                                                       let unauthorized_ack = BookingStatus::Unauthorized.ack(correlation_id);
+                                                       let _ = swarm.behaviour_mut().request_response.send_response(channel, unauthorized_ack);
+                                                       continue;
+                                                   }
+                                               }
+
+                                               // A new id per delivery, so a re-sent request becomes another job
+                                               // (see generate_correlation_ids in config.toml.example)
+                                               let correlation_id = if correlation_id.is_empty() && config.generate_correlation_ids {
+                                                   let generated = Uuid::new_v4().to_string();
+                                                   info!("Assigned correlation_id={} to booking from {}", generated, peer);
+                                                   generated
+                                               } else {
+                                                   correlation_id
+                                               };
+                                               if !config.correlation_id_format.accepts(&correlation_id) {
+                                                   warn!("Rejecting booking from {}: malformed correlation_id {:?}", peer, correlation_id);
+                                                   let rejected_ack = BookingStatus::Rejected.ack(correlation_id);
+                                                   let _ = swarm.behaviour_mut().request_response.send_response(channel, rejected_ack);
+                                                   continue;
+                                               }
+
+                                               // Handled by the broker task; the ack is sent once it replies
+                                               let (reply, ack) = oneshot::channel();
+                                               let request = BookingRequest { correlation_id, booking, notify, reply };
+                                               match queue.try_send(request) {
+                                                   Ok(()) => pending_booking_acks.push(async move { (peer, channel, ack.await) }),
+                                                   Err(mpsc::error::TrySendError::Full(request)) => {
+                                                       warn!("Broker queue full, answering busy to {}: correlation_id={}", peer, request.correlation_id);
+                                                       metrics.bookings_busy.inc();
+                                                       let busy_ack = BookingStatus::Busy.ack(request.correlation_id);
+                                                       let _ = swarm.behaviour_mut().request_response.send_response(channel, busy_ack);
+                                                   }
+                                                   Err(mpsc::error::TrySendError::Closed(request)) => {
+                                                       error!("Broker queue closed, cannot handle booking {}", request.correlation_id);
+                                                       let error_ack = BookingStatus::Error.ack(request.correlation_id);
                                                        let _ = swarm.behaviour_mut().request_response.send_response(channel, error_ack);
                                                    }
                                                }
                                            } else {
                                                warn!("Received SubmitBooking but broker handler not available");
-                                               let error_ack = Msg::BookingAck {
-                                                   correlation_id,
-                                                   status: "error".to_string(),
-                                               };
+                                               let error_ack = BookingStatus::Error.ack(correlation_id);
                                                let _ = swarm.behaviour_mut().request_response.send_response(channel, error_ack);
                                            }
                                        } else {
                                            warn!("Received SubmitBooking but node is not a Gateway");
-                                           let error_ack = Msg::BookingAck {
-                                               correlation_id,
-                                               status: "error".to_string(),
-                                           };
+                                           let error_ack = BookingStatus::Error.ack(correlation_id);
                                            let _ = swarm.behaviour_mut().request_response.send_response(channel, error_ack);
                                        }
                                    },
                                    _ => info!("Received other request from {}", peer),
                                }
                            }
-                           request_response::Message::Response { response, .. } => {
+                           request_response::Message::Response { request_id, response } => {
+                                pending_ops.complete(&request_id);
                                 match response {
                                     Msg::OpAck { op_id, ok, msg } => {
                                         info!("📬 Received OpAck from {}: op_id={} ok={} msg={}", peer, op_id, ok, msg);
@@ -466,11 +1381,49 @@ pub async fn run_swarm(
                     SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::ResponseSent { .. })) => {
                         // Response sent confirmation
                     }
-                    SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure { peer, error, .. })) => {
-                        error!("Outbound failure for peer {:?}: {:?}", peer, error);
+                    SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure { peer, request_id, error, .. })) => {
+                        let malformed = match &error {
+                            request_response::OutboundFailure::Io(e) => malformed_cause(e),
+                            _ => None,
+                        };
+                        if let Some(malformed) = malformed {
+                            warn!("Malformed response from {}: {}", peer, malformed);
+                            metrics.record_malformed_message(malformed.kind.as_str());
+                        } else {
+                            error!("Outbound failure for peer {:?}: {:?}", peer, error);
+                        }
+                        metrics.record_rr_failure("outbound");
+                        network_state.write().await.record_outbound_failure(peer.to_string());
+                        if rr_failure_streaks.failed(peer) {
+                            warn!("Disconnecting {} after {} request/response failures in a row", peer, config.rr_failure_disconnect_threshold);
+                            let _ = swarm.disconnect_peer_id(peer);
+                        }
+                        if matches!(error, request_response::OutboundFailure::Timeout) {
+                            metrics.rr_timeouts.inc();
+                            if pending_ops.retry(&mut swarm, &request_id) {
+                                metrics.rr_retries.inc();
+                            }
+                        } else {
+                            pending_ops.complete(&request_id);
+                        }
                     }
                     SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::InboundFailure { peer, error, .. })) => {
-                         error!("Inbound failure for peer {:?}: {:?}", peer, error);
+                        let malformed = match &error {
+                            request_response::InboundFailure::Io(e) => malformed_cause(e),
+                            _ => None,
+                        };
+                        if let Some(malformed) = malformed {
+                            warn!("Malformed request from {}: {}", peer, malformed);
+                            metrics.record_malformed_message(malformed.kind.as_str());
+                        } else {
+                            error!("Inbound failure for peer {:?}: {:?}", peer, error);
+                        }
+                        metrics.record_rr_failure("inbound");
+                        network_state.write().await.record_inbound_failure(peer.to_string());
+                        if rr_failure_streaks.failed(peer) {
+                            warn!("Disconnecting {} after {} request/response failures in a row", peer, config.rr_failure_disconnect_threshold);
+                            let _ = swarm.disconnect_peer_id(peer);
+                        }
                     }
                     // End of primary event handlers
                     _ => {}
@@ -481,9 +1434,50 @@ pub async fn run_swarm(
                 let connected = swarm.connected_peers().count();
                 let uptime = start_time.elapsed();
                 
-                info!("💚 Discovery health: connected={}, mdns_discovered={}, kad_discovered={}, uptime={:?}",
-                      connected, discovered_via_mdns.len(), discovered_via_kad.len(), uptime);
+                let counts = (connected, discovered_via_mdns.len(), discovered_via_kad.len());
+                if last_health_counts.replace(counts) != Some(counts) {
+                    info!("💚 Discovery health: connected={}, mdns_discovered={}, kad_discovered={}, uptime={:?}",
+                          counts.0, counts.1, counts.2, uptime);
+                } else {
+                    debug!("💚 Discovery health unchanged: connected={}, mdns_discovered={}, kad_discovered={}, uptime={:?}",
+                           counts.0, counts.1, counts.2, uptime);
+                }
+
+                network_state.write().await.set_bandwidth(metrics.bandwidth());
+
+                // Bootstrap peers that dropped, at the addresses they last reported
+                for (peer_id, addrs) in bootstrap_peers.due(Instant::now()) {
+                    if swarm.is_connected(&peer_id)
+                        || !config.is_peer_permitted(&peer_id)
+                        || dial_state.foreign_network.contains(&peer_id)
+                    {
+                        continue;
+                    }
+                    info!("🔁 Redialing bootstrap peer {} at {:?}", peer_id, addrs);
+                    let opts = DialOpts::peer_id(peer_id).addresses(addrs).build();
+                    match swarm.dial(opts) {
+                        Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+                        Err(e) => {
+                            warn!("Failed to redial bootstrap peer {}: {}", peer_id, e);
+                            bootstrap_peers.disconnected(&peer_id, Instant::now());
+                        }
+                    }
+                }
+
+                if let Some(unrelated) = unrelated_peers.as_mut() {
+                    for peer_id in unrelated.expired(Instant::now()) {
+                        info!("✂️  Disconnecting {}: no shared protocol after {:?}", peer_id, UNRELATED_PEER_GRACE);
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                    }
+                }
                 
+                // Standalone / mDNS-only gateways never bootstrap: stop waiting
+                if uptime > discovery_timeout && readiness.dht_pending() {
+                    warn!("⚠️  No successful Kademlia bootstrap after {:?} (attempted: {}); reporting ready without the DHT",
+                          discovery_timeout, dial_state.bootstrap_attempted);
+                    readiness.set_dht_ready(true);
+                }
+
                 // Warning if no peers discovered
                 if uptime > discovery_timeout && connected == 0 {
                     error!("⚠️  No peers discovered after {:?}. Check bootstrap_peers config and network connectivity.", discovery_timeout);
@@ -491,41 +1485,372 @@ pub async fn run_swarm(
                     if config.bootstrap_peers.is_empty() && !config.enable_mdns {
                         error!("💡 Hint: Both mDNS and bootstrap_peers are disabled/empty. Enable at least one discovery method.");
                     }
+
+                    match config.discovery_timeout_action {
+                        DiscoveryTimeoutAction::Log => {}
+                        DiscoveryTimeoutAction::Restart => {
+                            anyhow::bail!("No peers discovered after {:?}; stopping so the node is restarted", discovery_timeout);
+                        }
+                        DiscoveryTimeoutAction::Rebootstrap if Instant::now() >= next_rebootstrap => {
+                            if config.bootstrap_peers.is_empty() || !swarm.behaviour().kad.is_enabled() {
+                                warn!("🔁 discovery_timeout_action is rebootstrap, but there are no bootstrap peers to dial (or Kademlia is disabled)");
+                            } else {
+                                info!("🔁 Re-attempting bootstrap dials after {:?} without peers", discovery_timeout);
+                                dial_bootstrap_peers(&mut swarm, &config);
+                                // Bootstrap Kademlia again once one of them connects
+                                dial_state.bootstrap_attempted = false;
+                            }
+                            next_rebootstrap = Instant::now() + discovery_timeout;
+                        }
+                        DiscoveryTimeoutAction::Rebootstrap => {}
+                    }
                 }
             }
             
-            _ = dht_maintenance_interval.tick() => {
+            _ = async { dht_maintenance_interval.as_mut().expect("checked by the guard").tick().await },
+                if dht_maintenance_interval.is_some() => {
+                // Nothing to walk with an empty routing table; the next
+                // connection triggers a bootstrap anyway
+                if swarm.network_info().num_peers() == 0 {
+                    debug!("Skipping DHT maintenance: no connected peers");
+                    continue;
+                }
                 // Periodic random DHT walk to keep routing table fresh
-                if config.enable_kad && dial_state.bootstrap_attempted {
-                    let random_peer = PeerId::random();
-                    swarm.behaviour_mut().kad.get_closest_peers(random_peer);
+                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                    if dial_state.bootstrap_attempted {
+                        kad.get_closest_peers(PeerId::random());
+                        if matches!(config.role, Role::Client) {
+                            find_providers(&mut swarm, GATEWAY_SERVICE_KEY);
+                        }
+                    }
+                }
+            }
+
+            Some((peer, channel, op_id, stored)) = pending_op_acks.next() => {
+                let ack = match stored {
+                    Ok(Ok(true)) => Msg::OpAck { op_id, ok: true, msg: "Persisted".into() },
+                    Ok(Ok(false)) => Msg::OpAck { op_id, ok: false, msg: "draining".into() },
+                    Ok(Err(e)) => {
+                        error!("Failed to persist op {} from {}: {:?}", op_id, peer, e);
+                        Msg::OpAck { op_id, ok: false, msg: "error".into() }
+                    }
+                    Err(e) => {
+                        error!("Op task for {} from {} failed: {:?}", op_id, peer, e);
+                        Msg::OpAck { op_id, ok: false, msg: "error".into() }
+                    }
+                };
+                info!("📤 Sending OpAck to {}: {:?}", peer, ack);
+                let _ = swarm.behaviour_mut().request_response.send_response(channel, ack);
+            }
+
+            Some((peer, channel, ack)) = pending_booking_acks.next() => {
+                match ack {
+                    Ok(ack) => {
+                        info!("📤 Sending BookingAck to {}: {:?}", peer, ack);
+                        let _ = swarm.behaviour_mut().request_response.send_response(channel, ack);
+                    }
+                    // The broker task went away without answering; dropping the
+                    // channel lets the peer see the request fail
+                    Err(_) => error!("Broker task dropped a booking from {} unanswered", peer),
+                }
+            }
+
+            Some(command) = commands.recv() => {
+                match command {
+                    SwarmCommand::FindProviders(key) => find_providers(&mut swarm, &key),
+                    SwarmCommand::FindClosest { key, reply } => match swarm.behaviour_mut().kad.as_mut() {
+                        Some(kad) => {
+                            info!("🔎 Looking up the closest peers to {}", key);
+                            let id = kad.get_closest_peers(key.into_bytes());
+                            pending_closest.insert(id, reply);
+                        }
+                        None => {
+                            let _ = reply.send(Err("Kademlia is disabled".to_string()));
+                        }
+                    },
                 }
             }
+
+            _ = shutdown.changed() => {
+                info!("🛑 Shutdown requested, no longer accepting new work");
+                readiness.set_listening(false);
+                break;
+            }
         }
     }
+
+    disconnect_all(&mut swarm).await;
+    Ok(())
+}
+
+/// Disconnects every peer and keeps driving the swarm until the connections
+/// are closed (or the grace period elapses), so in-flight streams end cleanly.
+async fn disconnect_all(swarm: &mut Swarm<NodeBehaviour>) {
+    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    info!("👋 Disconnecting {} peer(s)...", peers.len());
+    for peer in peers {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+
+    let drained = tokio::time::timeout(DISCONNECT_GRACE, async {
+        while swarm.connected_peers().next().is_some() {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Some connections did not close within {:?}", DISCONNECT_GRACE);
+    }
+}
+
+/// Dial attempts `run_test_submission` makes before relying on mDNS alone
+const TEST_DIAL_MAX_ATTEMPTS: u32 = 5;
+const TEST_DIAL_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const TEST_DIAL_MAX_BACKOFF: Duration = Duration::from_secs(4);
+
+/// Dials the test target. With a known PeerId we dial the peer so the swarm
+/// tries every address it knows, including ones found via mDNS.
+fn dial_test_target(swarm: &mut Swarm<NodeBehaviour>, addr: &Multiaddr, target_peer: Option<PeerId>) -> Result<(), DialError> {
+    match target_peer {
+        Some(peer_id) => swarm.dial(
+            DialOpts::peer_id(peer_id)
+                .addresses(vec![addr.clone()])
+                .extend_addresses_through_behaviour()
+                .build(),
+        ),
+        None => swarm.dial(addr.clone()),
+    }
+}
+
+/// Sends `op` to the peer at `dial_addr` and waits for an `OpAck` with the same `op_id`
+/// Returns the round-trip time from sending the Op to receiving its ACK
+pub async fn run_test_submission(swarm: Swarm<NodeBehaviour>, dial_addr: String, op: Op, timeout_secs: u64) -> Result<Duration> {
+    let expected_op_id = op.op_id.clone();
+    info!("Test: Submitting Op kind={} entity={}", op.kind, op.entity);
+    run_test_request(swarm, dial_addr, Msg::OpSubmit { op }, timeout_secs, None, |peer, response| match response {
+        Msg::OpAck { op_id, ok, msg } => {
+            info!("Test: Received ACK from {}: op_id={} ok={} msg={}", peer, op_id, ok, msg);
+            if op_id == expected_op_id && ok {
+                info!("Test PASSED: Valid ACK received.");
+                Ok(())
+            } else {
+                anyhow::bail!("Test FAILED: Invalid ACK (id mismatch or ok=false)")
+            }
+        }
+        other => anyhow::bail!("Test FAILED: expected OpAck, got {:?}", other),
+    })
+    .await
 }
 
-pub async fn run_test_submission(mut swarm: Swarm<NodeBehaviour>, dial_addr: String, timeout_secs: u64) -> Result<()> {
-    // 1. Dial the target
+/// Sends a `SubmitBooking` to the gateway at `dial_addr` and waits for a
+/// `BookingAck` with status `queued` for the same correlation_id.
+/// With `fallback_relay`, a failed direct dial is retried through the relay.
+pub async fn run_test_booking(
+    swarm: Swarm<NodeBehaviour>,
+    dial_addr: String,
+    booking: BookingData,
+    notify: NotifyData,
+    fallback_relay: Option<&Multiaddr>,
+    timeout_secs: u64,
+) -> Result<()> {
+    let correlation_id = Uuid::new_v4().to_string();
+    info!("Test: Submitting booking correlation_id={}", correlation_id);
+    let request = Msg::SubmitBooking { correlation_id: correlation_id.clone(), booking, notify, token: None };
+    let relay_fallback = fallback_relay.and_then(RelayFallback::new);
+    run_test_request(swarm, dial_addr, request, timeout_secs, relay_fallback, |peer, response| match response {
+        Msg::BookingAck { correlation_id: acked_id, status } => {
+            info!("Test: Received BookingAck from {}: correlation_id={} status={}", peer, acked_id, status);
+            if acked_id == correlation_id && BookingStatus::parse(&status) == Some(BookingStatus::Queued) {
+                info!("Test PASSED: Booking queued by the gateway.");
+                Ok(())
+            } else {
+                anyhow::bail!("Test FAILED: Invalid BookingAck (id mismatch or status={})", status)
+            }
+        }
+        other => anyhow::bail!("Test FAILED: expected BookingAck, got {:?}", other),
+    })
+    .instrument(info_span!("booking", correlation_id = %correlation_id))
+    .await
+    .map(|_rtt| ())
+}
+
+/// Sends a `SubmitBooking` to a gateway and returns the correlation_id and the
+/// status of its `BookingAck` (an error if the status is unknown to us). Without `dial_addr` the gateway is found through
+/// the DHT first; `timeout_secs` bounds the lookup and the request separately.
+/// `token` is only needed by gateways that require a capability token; with
+/// `fallback_relay`, a failed direct dial is retried through the relay.
+pub async fn submit_booking(
+    mut swarm: Swarm<NodeBehaviour>,
+    dial_addr: Option<String>,
+    booking: BookingData,
+    notify: NotifyData,
+    token: Option<String>,
+    fallback_relay: Option<&Multiaddr>,
+    timeout_secs: u64,
+) -> Result<(String, BookingStatus)> {
+    let dial_addr = match dial_addr {
+        Some(addr) => addr,
+        None => {
+            let gateway = tokio::time::timeout(Duration::from_secs(timeout_secs), discover_gateway(&mut swarm))
+                .await
+                .map_err(|_| anyhow::anyhow!("No gateway found in the DHT within {} seconds", timeout_secs))??;
+            format!("/p2p/{}", gateway)
+        }
+    };
+
+    let correlation_id = Uuid::new_v4().to_string();
+    info!("Submitting booking correlation_id={}", correlation_id);
+    let request = Msg::SubmitBooking { correlation_id: correlation_id.clone(), booking, notify, token };
+    let mut ack_status = None;
+    let relay_fallback = fallback_relay.and_then(RelayFallback::new);
+    run_test_request(swarm, dial_addr, request, timeout_secs, relay_fallback, |peer, response| match response {
+        Msg::BookingAck { correlation_id: acked_id, status } if acked_id == correlation_id => {
+            info!("Received BookingAck from {}: status={}", peer, status);
+            ack_status = Some(BookingStatus::parse(&status).with_context(|| format!("Unknown BookingAck status: {}", status))?);
+            Ok(())
+        }
+        Msg::BookingAck { correlation_id: acked_id, .. } => {
+            anyhow::bail!("BookingAck for another booking: {}", acked_id)
+        }
+        other => anyhow::bail!("Expected BookingAck, got {:?}", other),
+    })
+    .instrument(info_span!("booking", correlation_id = %correlation_id))
+    .await?;
+
+    let status = ack_status.context("BookingAck was not recorded")?;
+    Ok((correlation_id, status))
+}
+
+/// Bootstraps Kademlia from the peers `build_swarm` dialed, looks up
+/// `GATEWAY_SERVICE_KEY` providers and returns the first one we connect to
+async fn discover_gateway(swarm: &mut Swarm<NodeBehaviour>) -> Result<PeerId> {
+    if !swarm.behaviour().kad.is_enabled() {
+        anyhow::bail!("No --dial given and Kademlia is disabled: set enable_kad and bootstrap_peers in config.toml");
+    }
+    info!("🔎 Looking for a booking gateway in the DHT...");
+    let mut bootstrapping = false;
+    let mut candidates: HashSet<PeerId> = HashSet::new();
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                if candidates.contains(&peer_id) {
+                    info!("✅ Connected to gateway {}", peer_id);
+                    return Ok(peer_id);
+                }
+                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                    kad.add_address(&peer_id, endpoint.get_remote_address().clone());
+                    if !bootstrapping && kad.bootstrap().is_ok() {
+                        bootstrapping = true;
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(NodeBehaviourEvent::Identify(event)) => {
+                if let identify::Event::Received { peer_id, info, .. } = *event {
+                    if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                        for addr in info.listen_addrs {
+                            kad.add_address(&peer_id, addr);
+                        }
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(NodeBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { result, .. })) => match result {
+                kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { num_remaining: 0, .. })) => {
+                    find_providers(swarm, GATEWAY_SERVICE_KEY);
+                }
+                kad::QueryResult::Bootstrap(Err(e)) => {
+                    warn!("Kademlia bootstrap error: {:?}", e);
+                }
+                kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+                    for provider in providers {
+                        if provider == *swarm.local_peer_id() {
+                            continue;
+                        }
+                        if swarm.is_connected(&provider) {
+                            return Ok(provider);
+                        }
+                        if candidates.insert(provider) {
+                            info!("📞 Dialing gateway {}", provider);
+                            let _ = swarm.dial(provider);
+                        }
+                    }
+                }
+                kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }))
+                    if candidates.is_empty() =>
+                {
+                    anyhow::bail!("No gateway is advertised in the DHT");
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// One-shot request/response against the peer at `dial_addr`: sends `request`
+/// once connected and hands the first response to `verify`. Each failed
+/// direct dial is followed by one through `relay_fallback`, if given.
+async fn run_test_request<F>(
+    mut swarm: Swarm<NodeBehaviour>,
+    dial_addr: String,
+    request: Msg,
+    timeout_secs: u64,
+    mut relay_fallback: Option<RelayFallback>,
+    verify: F,
+) -> Result<Duration>
+where
+    F: FnOnce(PeerId, Msg) -> Result<()>,
+{
+    // 1. Dial the target (retried with backoff: in CI it may not be listening yet)
     let addr: Multiaddr = dial_addr.parse()?;
-    info!("Test: Dialing {}...", addr);
-    swarm.dial(addr.clone())?;
-    
     let target_peer = match addr.iter().find(|p| matches!(p, libp2p::multiaddr::Protocol::P2p(_))) {
         Some(libp2p::multiaddr::Protocol::P2p(peer_id)) => Some(peer_id),
         _ => None,
     };
+    let mut dial_attempts = 0;
+    let mut dial_backoff = TEST_DIAL_INITIAL_BACKOFF;
+    let mut next_dial_at = Some(Instant::now());
+    let mut connected = false;
+    if let (Some(fallback), Some(peer)) = (relay_fallback.as_mut(), target_peer) {
+        fallback.add_target(peer);
+    }
 
-    let mut op_sent = false;
-    let expected_op_id = Uuid::new_v4().to_string();
+    let mut request_sent_at = None;
+    let mut request = Some(request);
     let timeout = Duration::from_secs(timeout_secs);
     let start_time = Instant::now();
 
+    // Already connected (e.g. the gateway was found through the DHT): send right away
+    if let Some(peer_id) = target_peer.filter(|peer| swarm.is_connected(peer)) {
+        connected = true;
+        next_dial_at = None;
+        if let Some(request) = request.take() {
+            info!("Test: Sending request to {}", peer_id);
+            swarm.behaviour_mut().request_response.send_request(&peer_id, request);
+            request_sent_at = Some(Instant::now());
+        }
+    }
+
     loop {
         if start_time.elapsed() > timeout {
             anyhow::bail!("Test timed out after {} seconds", timeout_secs);
         }
 
+        if next_dial_at.is_some_and(|at| Instant::now() >= at) {
+            dial_attempts += 1;
+            info!("Test: Dialing {} (attempt {}/{})...", addr, dial_attempts, TEST_DIAL_MAX_ATTEMPTS);
+            next_dial_at = None;
+            match dial_test_target(&mut swarm, &addr, target_peer) {
+                Ok(()) => {}
+                // The dial from build_swarm (config.dial) is still in flight; wait for its outcome
+                Err(DialError::DialPeerConditionFalse(_)) => {}
+                Err(e) => {
+                    warn!("Test: Dial attempt {} failed: {:?}", dial_attempts, e);
+                    next_dial_at = schedule_test_redial(&swarm, dial_attempts, &mut dial_backoff)?;
+                }
+            }
+        }
+
         let event = tokio::select! {
              e = swarm.select_next_some() => e,
              _ = tokio::time::sleep(Duration::from_millis(100)) => continue,
@@ -542,19 +1867,13 @@ pub async fn run_test_submission(mut swarm: Swarm<NodeBehaviour>, dial_addr: Str
                          continue;
                      }
                 }
+                connected = true;
+                next_dial_at = None;
                 
-                if !op_sent {
-                     let op = Op {
-                         op_id: expected_op_id.clone(),
-                         actor_id: swarm.local_peer_id().to_string(),
-                         kind: "TestOp".into(),
-                         entity: "test".into(),
-                         payload_json: "{}".into(),
-                         created_at_ms: 123456,
-                     };
-                     info!("Test: Sending OpSubmit to {}", peer_id);
-                     swarm.behaviour_mut().request_response.send_request(&peer_id, Msg::OpSubmit { op });
-                     op_sent = true;
+                if let Some(request) = request.take() {
+                     info!("Test: Sending request to {}", peer_id);
+                     swarm.behaviour_mut().request_response.send_request(&peer_id, request);
+                     request_sent_at = Some(Instant::now());
                 }
             }
              SwarmEvent::Behaviour(NodeBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
@@ -567,23 +1886,27 @@ pub async fn run_test_submission(mut swarm: Swarm<NodeBehaviour>, dial_addr: Str
                         }
                     }
                 }
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                let ours = target_peer.is_none() || peer_id == target_peer;
+                if ours && !connected && next_dial_at.is_none() {
+                    warn!("Test: Dial attempt {} failed: {}", dial_attempts, error);
+                    if !dial_via_relay(&mut swarm, relay_fallback.as_mut(), target_peer, &error) {
+                        next_dial_at = schedule_test_redial(&swarm, dial_attempts, &mut dial_backoff)?;
+                    }
+                }
             }
              SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::Message { 
                 peer, 
-                message: request_response::Message::Response { response: Msg::OpAck { op_id, ok, msg }, .. }, 
+                message: request_response::Message::Response { response, .. }, 
                 .. 
              })) => {
-                info!("Test: Received ACK from {}: op_id={} ok={} msg={}", peer, op_id, ok, msg);
-                if op_id == expected_op_id && ok {
-                    info!("Test PASSED: Valid ACK received.");
-                    return Ok(());
-                } else {
-                    anyhow::bail!("Test FAILED: Invalid ACK (id mismatch or ok=false)");
-                }
+                let rtt = request_sent_at.map(|at| at.elapsed()).unwrap_or_default();
+                return verify(peer, response).map(|()| rtt);
              }
              SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure { error, .. })) => {
                  error!("Test: Outbound failure: {:?}", error);
-                 if op_sent {
+                 if request_sent_at.is_some() {
                       anyhow::bail!("Test FAILED: Outbound failure after send");
                  }
              }
@@ -591,3 +1914,23 @@ pub async fn run_test_submission(mut swarm: Swarm<NodeBehaviour>, dial_addr: Str
         }
     }
 }
+
+/// Picks when to redial after a failed attempt. Once the attempts are used up
+/// we keep waiting only if mDNS may still find the target, otherwise give up.
+fn schedule_test_redial(
+    swarm: &Swarm<NodeBehaviour>,
+    dial_attempts: u32,
+    backoff: &mut Duration,
+) -> Result<Option<Instant>> {
+    if dial_attempts < TEST_DIAL_MAX_ATTEMPTS {
+        let at = Instant::now() + *backoff;
+        *backoff = (*backoff * 2).min(TEST_DIAL_MAX_BACKOFF);
+        return Ok(Some(at));
+    }
+    if swarm.behaviour().mdns.is_enabled() {
+        warn!("Test: Giving up on explicit dials after {} attempts; waiting for mDNS to find the target", dial_attempts);
+        Ok(None)
+    } else {
+        anyhow::bail!("Test FAILED: could not connect after {} dial attempts", dial_attempts)
+    }
+}
@@ -0,0 +1,929 @@
+use super::protocol::{BookingData, NotifyData, Op};
+use super::swarm::build_swarm;
+use crate::broker::{handler::BrokerHandler, storage::BrokerStorage};
+use crate::config::{Config, CorrelationIdFormat, DurabilityMode, Role};
+use prometheus_client::registry::Registry;
+use std::sync::Arc;
+
+// Helper to create a loopback-only config for building test swarms
+fn create_test_config() -> Config {
+    Config::for_tests()
+}
+
+// Helper to create a gateway config for the in-memory harness (no DHT or mDNS to interfere)
+fn create_gateway_config() -> Config {
+    let mut config = create_test_config();
+    config.role = Role::Gateway;
+    config.enable_kad = false;
+    config
+}
+
+// Helper to create a gateway's broker storage and handler. Keep the TempDir
+// alive for as long as the storage is used.
+fn gateway_storage_and_handler() -> (tempfile::TempDir, Arc<BrokerStorage>, Arc<BrokerHandler>) {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let db_path = temp_dir.path().join("broker.db");
+    let storage = Arc::new(BrokerStorage::new(db_path.to_str().unwrap(), None, DurabilityMode::Strict).unwrap());
+    let handler = BrokerHandler::new(storage.clone(), 0)
+        .with_max_concurrent_ops(create_gateway_config().max_concurrent_broker_ops);
+    (temp_dir, storage, Arc::new(handler))
+}
+
+// Helper to create test booking data
+fn sample_booking() -> (BookingData, NotifyData) {
+    let booking = BookingData {
+        date: "2026-01-15".to_string(),
+        start_time: "10:00".to_string(),
+        end_time: "11:00".to_string(),
+        name: "Test User".to_string(),
+    };
+    let notify = NotifyData { email: "test@example.com".to_string(), locale: None, timezone: None };
+    (booking, notify)
+}
+
+// Helper to create an op with the given id
+fn sample_op(op_id: &str) -> Op {
+    Op {
+        op_id: op_id.to_string(),
+        actor_id: "client".to_string(),
+        kind: "UpsertNote".to_string(),
+        entity: "note:1".to_string(),
+        payload_json: "{}".to_string(),
+        created_at_ms: 0,
+    }
+}
+
+// Log output captured by `capture_logs`
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+
+    // Polls until a log line contains `needle`, for at most `within`
+    async fn wait_for(&self, needle: &str, within: std::time::Duration) {
+        let found = tokio::time::timeout(within, async {
+            while !self.text().contains(needle) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(found.is_ok(), "no log line with {:?} in:\n{}", needle, self.text());
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Helper to capture DEBUG and above on this thread. The test runtime is
+// single-threaded, so nodes spawned by the test log here too.
+fn capture_logs() -> (CapturedLogs, tracing::subscriber::DefaultGuard) {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
+
+#[tokio::test]
+async fn test_op_submit_round_trip_over_memory_transport() {
+    use super::harness::connect_pair;
+    use super::protocol::Msg;
+
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let (client, _gateway) = connect_pair(client_config, create_gateway_config(), None).await.unwrap();
+
+    let ack = client.request(Msg::OpSubmit { op: sample_op("op-1") }).await.unwrap();
+    assert!(matches!(ack, Msg::OpAck { op_id, ok: true, .. } if op_id == "op-1"));
+}
+
+#[tokio::test]
+async fn test_op_submit_is_persisted_before_its_ack() {
+    use super::harness::connect_pair;
+    use super::protocol::Msg;
+    use crate::broker::types::InboundOpState;
+    
+
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let client_peer_id = client_config.identity_keypair.public().to_peer_id();
+    let (client, _gateway) = connect_pair(client_config, create_gateway_config(), Some(handler)).await.unwrap();
+
+    let ack = client.request(Msg::OpSubmit { op: sample_op("op-1") }).await.unwrap();
+
+    assert!(matches!(ack, Msg::OpAck { op_id, ok: true, .. } if op_id == "op-1"));
+    let stored = storage.get_inbound_op("op-1").unwrap().unwrap();
+    assert_eq!(stored.state, InboundOpState::Received);
+    assert_eq!(stored.from_peer, client_peer_id.to_string());
+}
+
+#[tokio::test]
+async fn test_op_submit_is_answered_busy_without_a_free_slot() {
+    use super::harness::connect_pair;
+    use super::protocol::Msg;
+    
+    
+
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    let gateway_config = create_gateway_config();
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let (client, gateway) = connect_pair(client_config, gateway_config.clone(), Some(handler.clone())).await.unwrap();
+
+    // Every slot is taken, as if that many ops were still being persisted
+    let slots: Vec<_> = (0..gateway_config.max_concurrent_broker_ops)
+        .map(|_| handler.try_reserve_op().unwrap())
+        .collect();
+    let ack = client.request(Msg::OpSubmit { op: sample_op("op-1") }).await.unwrap();
+    assert!(matches!(ack, Msg::OpAck { op_id, ok: false, msg } if op_id == "op-1" && msg == "busy"));
+    assert!(storage.get_inbound_op("op-1").unwrap().is_none());
+    assert_eq!(gateway.metrics.ops_busy.get(), 1);
+
+    drop(slots);
+    let ack = client.request(Msg::OpSubmit { op: sample_op("op-1") }).await.unwrap();
+    assert!(matches!(ack, Msg::OpAck { op_id, ok: true, .. } if op_id == "op-1"));
+    assert!(storage.get_inbound_op("op-1").unwrap().is_some());
+    assert_eq!(gateway.metrics.ops_busy.get(), 1);
+}
+
+#[tokio::test]
+async fn test_submit_booking_round_trip_over_memory_transport() {
+    use super::harness::connect_pair;
+    use super::protocol::Msg;
+    use crate::broker::types::JobState;
+    
+
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let (client, _gateway) = connect_pair(client_config, create_gateway_config(), Some(handler)).await.unwrap();
+
+    let (booking, notify) = sample_booking();
+    let request = Msg::SubmitBooking { correlation_id: "booking-1".to_string(), booking, notify, token: None };
+    let ack = client.request(request).await.unwrap();
+
+    assert!(matches!(ack, Msg::BookingAck { correlation_id, status } if correlation_id == "booking-1" && status == "queued"));
+    // The ACK is only sent once the job is persisted on the gateway
+    assert_eq!(storage.get_booking_job("booking-1").unwrap().unwrap().state, JobState::Queued);
+}
+
+#[tokio::test]
+async fn test_submit_booking_finds_the_gateway_in_the_dht() {
+    use super::harness::{memory_swarm, start_node};
+    use super::protocol::BookingStatus;
+    use super::swarm::{dial_bootstrap_peers, submit_booking};
+    use crate::broker::types::JobState;
+    
+
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    // A gateway with Kademlia advertises itself under GATEWAY_SERVICE_KEY
+    let mut gateway_config = create_gateway_config();
+    gateway_config.enable_kad = true;
+    let gateway = start_node(gateway_config, Some(handler)).await.unwrap();
+
+    // The client only knows it as a bootstrap peer, not as the gateway to dial
+    let mut client_config = create_test_config();
+    client_config.bootstrap_peers = vec![format!("{}/p2p/{}", gateway.addr, gateway.peer_id)];
+    let (mut swarm, _) = memory_swarm(&client_config).await.unwrap();
+    dial_bootstrap_peers(&mut swarm, &client_config);
+
+    let (booking, notify) = sample_booking();
+    let (correlation_id, status) = submit_booking(swarm, None, booking, notify, None, None, 10).await.unwrap();
+
+    assert_eq!(status, BookingStatus::Queued);
+    assert_eq!(storage.get_booking_job(&correlation_id).unwrap().unwrap().state, JobState::Queued);
+}
+
+#[tokio::test]
+async fn test_test_submit_redials_a_gateway_that_starts_late() {
+    use super::harness::{memory_swarm, start_node_at};
+    
+    use super::swarm::run_test_submission;
+    
+    
+    use std::time::Duration;
+
+    let (logs, _guard) = capture_logs();
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    let gateway_config = create_gateway_config();
+    let gateway_addr: libp2p::Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+    let dial = format!("{}/p2p/{}", gateway_addr, gateway_config.identity_keypair.public().to_peer_id());
+
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let (swarm, _) = memory_swarm(&client_config).await.unwrap();
+    let submission = tokio::spawn(run_test_submission(swarm, dial, sample_op("op-late"), 10));
+
+    // Nothing listens yet, so the first dial fails; a later one finds the gateway
+    logs.wait_for("Test: Dial attempt 1 failed", Duration::from_secs(5)).await;
+    let _gateway = start_node_at(gateway_config, gateway_addr, Some(handler))
+        .await
+        .unwrap();
+
+    submission.await.unwrap().unwrap();
+    assert!(storage.get_inbound_op("op-late").unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_test_booking_passes_only_when_the_booking_is_queued() {
+    use super::harness::{memory_swarm, start_node};
+    
+    use super::swarm::run_test_booking;
+    
+    
+
+    let (_temp_dir, _, handler) = gateway_storage_and_handler();
+    let gateway = start_node(create_gateway_config(), Some(handler.clone())).await.unwrap();
+    let dial = format!("{}/p2p/{}", gateway.addr, gateway.peer_id);
+
+    let (booking, notify) = sample_booking();
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+
+    let (swarm, _) = memory_swarm(&client_config).await.unwrap();
+    run_test_booking(swarm, dial.clone(), booking.clone(), notify.clone(), None, 10).await.unwrap();
+    assert_eq!(handler.unfinished_jobs().unwrap(), 1);
+
+    // Any other status fails the test, e.g. a gateway that is draining
+    handler.start_draining();
+    let (swarm, _) = memory_swarm(&client_config).await.unwrap();
+    let err = run_test_booking(swarm, dial, booking, notify, None, 10).await.unwrap_err();
+    assert!(err.to_string().contains("draining"), "{:?}", err);
+    assert_eq!(handler.unfinished_jobs().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_gateway_with_token_issuer_rejects_bookings_without_a_valid_token() {
+    use super::capability::mint;
+    use super::harness::connect_pair;
+    use super::protocol::Msg;
+    
+    use libp2p::identity::Keypair;
+    
+    use std::time::Duration;
+
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    let issuer = Keypair::generate_ed25519();
+    let mut gateway_config = create_gateway_config();
+    gateway_config.booking_token_issuer = Some(issuer.public().to_peer_id());
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let client_peer_id = client_config.identity_keypair.public().to_peer_id();
+    let (client, _gateway) = connect_pair(client_config, gateway_config, Some(handler)).await.unwrap();
+
+    let submit = |correlation_id: &str, token: Option<String>| {
+        let (booking, notify) = sample_booking();
+        Msg::SubmitBooking { correlation_id: correlation_id.to_string(), booking, notify, token }
+    };
+    let status = |ack: Msg| match ack {
+        Msg::BookingAck { status, .. } => status,
+        other => panic!("expected BookingAck, got {:?}", other),
+    };
+
+    assert_eq!(status(client.request(submit("no-token", None)).await.unwrap()), "unauthorized");
+    let someone_elses = mint(&issuer, &libp2p::PeerId::random(), Duration::from_secs(60)).unwrap();
+    assert_eq!(status(client.request(submit("wrong-peer", Some(someone_elses))).await.unwrap()), "unauthorized");
+    assert!(storage.get_booking_job("no-token").unwrap().is_none());
+
+    let token = mint(&issuer, &client_peer_id, Duration::from_secs(60)).unwrap();
+    assert_eq!(status(client.request(submit("with-token", Some(token))).await.unwrap()), "queued");
+}
+
+#[tokio::test]
+async fn test_gateway_rejects_malformed_correlation_ids_and_generates_missing_ones() {
+    use super::harness::connect_pair;
+    use super::protocol::Msg;
+    use crate::broker::types::JobState;
+    
+
+    let (_temp_dir, storage, handler) = gateway_storage_and_handler();
+    let mut gateway_config = create_gateway_config();
+    gateway_config.correlation_id_format = CorrelationIdFormat::Uuid;
+    gateway_config.generate_correlation_ids = true;
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let (client, _gateway) = connect_pair(client_config, gateway_config, Some(handler)).await.unwrap();
+
+    let submit = |correlation_id: &str| {
+        let (booking, notify) = sample_booking();
+        Msg::SubmitBooking { correlation_id: correlation_id.to_string(), booking, notify, token: None }
+    };
+    let ack = |msg: Msg| match msg {
+        Msg::BookingAck { correlation_id, status } => (correlation_id, status),
+        other => panic!("expected BookingAck, got {:?}", other),
+    };
+
+    let (id, status) = ack(client.request(submit("booking-1")).await.unwrap());
+    assert_eq!((id.as_str(), status.as_str()), ("booking-1", "rejected"));
+    assert!(storage.get_booking_job("booking-1").unwrap().is_none());
+
+    let uuid = uuid::Uuid::new_v4().to_string();
+    let (id, status) = ack(client.request(submit(&uuid)).await.unwrap());
+    assert_eq!((id, status.as_str()), (uuid, "queued"));
+
+    // An empty id is replaced by one the gateway picks, and acked under it
+    let (id, status) = ack(client.request(submit("")).await.unwrap());
+    assert_eq!(status, "queued");
+    assert!(CorrelationIdFormat::Uuid.accepts(&id));
+    assert_eq!(storage.get_booking_job(&id).unwrap().unwrap().state, JobState::Queued);
+}
+
+#[tokio::test]
+async fn test_find_closest_replies_with_the_query_result() {
+    use super::command::SwarmCommand;
+    use super::harness::connect_pair;
+    use tokio::sync::oneshot;
+
+    let mut client_config = create_test_config();
+    client_config.enable_kad = false;
+    let mut gateway_config = create_gateway_config();
+    gateway_config.enable_kad = true;
+    let (_client, gateway) = connect_pair(client_config, gateway_config, None).await.unwrap();
+    let (reply, result) = oneshot::channel();
+    gateway.command(SwarmCommand::FindClosest { key: "some-key".to_string(), reply }).await;
+    // The only peer the gateway knows doesn't speak Kademlia
+    assert_eq!(result.await.unwrap(), Ok(vec![]));
+
+    let (_client, gateway) = connect_pair(create_test_config(), create_gateway_config(), None).await.unwrap();
+    let (reply, result) = oneshot::channel();
+    gateway.command(SwarmCommand::FindClosest { key: "some-key".to_string(), reply }).await;
+    assert_eq!(result.await.unwrap(), Err("Kademlia is disabled".to_string()));
+}
+
+#[tokio::test]
+async fn test_timed_out_op_is_resent_then_given_up() {
+    use super::behaviour::NodeBehaviourEvent;
+    use super::harness::{memory_swarm, start_node};
+    use super::protocol::Msg;
+    use futures::StreamExt;
+    use libp2p::{request_response, swarm::SwarmEvent};
+    use std::time::Duration;
+
+    // A gateway that takes every request and never answers
+    let (mut silent, silent_addr) = memory_swarm(&create_gateway_config()).await.unwrap();
+    let mut config = create_test_config();
+    config.bootstrap_peers = vec![format!("{}/p2p/{}", silent_addr, silent.local_peer_id())];
+    config.rr_request_timeout_secs = 1;
+    config.rr_max_retries = 2;
+    let client = start_node(config, None).await.unwrap();
+
+    let mut op_ids = Vec::new();
+    let mut held_channels = Vec::new();
+    let collect = async {
+        loop {
+            if let SwarmEvent::Behaviour(NodeBehaviourEvent::RequestResponse(request_response::Event::Message {
+                message: request_response::Message::Request { request: Msg::OpSubmit { op }, channel, .. },
+                ..
+            })) = silent.select_next_some().await
+            {
+                op_ids.push(op.op_id);
+                held_channels.push(channel);
+            }
+        }
+    };
+    // The first send and two re-sends time out
+    let timed_out_thrice = async {
+        while client.metrics.rr_timeouts.get() < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let waited = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::select! {
+            _ = collect => {}
+            _ = timed_out_thrice => {}
+        }
+    })
+    .await;
+
+    assert!(waited.is_ok(), "only {} timeouts", client.metrics.rr_timeouts.get());
+    // The third timeout gave up instead of sending again
+    assert_eq!(op_ids.len(), 3, "{:?}", op_ids);
+    assert!(op_ids.iter().all(|id| *id == op_ids[0]));
+    assert_eq!(client.metrics.rr_timeouts.get(), 3);
+    assert_eq!(client.metrics.rr_retries.get(), 2);
+}
+
+#[tokio::test]
+async fn test_discovery_timeout_restart_stops_the_node() {
+    use super::harness::start_node;
+    use crate::config::DiscoveryTimeoutAction;
+    use std::time::Duration;
+
+    let mut config = create_test_config();
+    config.discovery_timeout_secs = 1;
+    config.health_check_interval_secs = 1;
+    config.discovery_timeout_action = DiscoveryTimeoutAction::Restart;
+    let node = start_node(config, None).await.unwrap();
+
+    let err = tokio::time::timeout(Duration::from_secs(5), node.exited()).await.unwrap().unwrap_err();
+    assert!(err.to_string().contains("No peers discovered"), "{:?}", err);
+}
+
+#[tokio::test]
+async fn test_discovery_timeout_rebootstrap_dials_bootstrap_peers_again() {
+    use super::harness::{memory_swarm_at, start_node};
+    use crate::config::DiscoveryTimeoutAction;
+    use futures::StreamExt;
+    use libp2p::swarm::SwarmEvent;
+    use libp2p::Multiaddr;
+    use std::time::Duration;
+
+    // Nothing listens there yet, so the dial made at startup fails. Without a
+    // `/p2p/` part the bootstrap tracker can't redial it; only a rebootstrap can.
+    let bootstrap_addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+    let mut config = create_test_config();
+    config.bootstrap_peers = vec![bootstrap_addr.to_string()];
+    config.discovery_timeout_secs = 1;
+    config.health_check_interval_secs = 1;
+    config.discovery_timeout_action = DiscoveryTimeoutAction::Rebootstrap;
+    let node = start_node(config, None).await.unwrap();
+
+    let (mut bootstrap, _) = memory_swarm_at(&create_gateway_config(), bootstrap_addr).await.unwrap();
+    let redialed = async {
+        loop {
+            if let SwarmEvent::ConnectionEstablished { peer_id, .. } = bootstrap.select_next_some().await {
+                if peer_id == node.peer_id {
+                    return;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), redialed).await.expect("bootstrap peer was not dialed again");
+}
+
+#[tokio::test]
+async fn test_unchanged_health_ticks_are_logged_at_debug() {
+    use super::harness::start_node;
+    use std::time::Duration;
+
+    let (logs, _guard) = capture_logs();
+    let lines = |level: &str, message: &str| {
+        logs.text().lines().filter(|line| line.contains(level) && line.contains(message)).count()
+    };
+
+    let mut config = create_test_config();
+    config.health_check_interval_secs = 1;
+    let node = start_node(config, None).await.unwrap();
+    // Wait for the third tick; none of them sees anything connected or discovered
+    let ticks = tokio::time::timeout(Duration::from_secs(10), async {
+        while lines("DEBUG ", "Discovery health unchanged:") < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    drop(node);
+
+    assert!(ticks.is_ok(), "{}", logs.text());
+    assert_eq!(lines(" INFO ", "Discovery health:"), 1);
+}
+
+#[tokio::test]
+async fn test_mdns_disabled_builds_no_behaviour() {
+    let config = create_test_config();
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+
+    // A disabled toggle owns no mDNS socket, so nothing is queried or answered
+    assert!(!swarm.behaviour().mdns.is_enabled());
+}
+
+#[tokio::test]
+async fn test_dial_to_a_stalled_handshake_times_out() {
+    use super::swarm::is_dial_timeout;
+    use futures::StreamExt;
+    use libp2p::swarm::SwarmEvent;
+
+    // Accepts TCP connections but never answers the noise handshake
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            held.push(socket);
+        }
+    });
+
+    let mut config = create_test_config();
+    config.enable_kad = false;
+    config.dial_timeout_secs = 1;
+    let mut swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    swarm.dial(format!("/ip4/127.0.0.1/tcp/{}", port).parse::<libp2p::Multiaddr>().unwrap()).unwrap();
+
+    let error = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        loop {
+            if let SwarmEvent::OutgoingConnectionError { error, .. } = swarm.select_next_some().await {
+                return error;
+            }
+        }
+    })
+    .await
+    .expect("dial was not given up on");
+    assert!(is_dial_timeout(&error), "{}", error);
+}
+
+#[tokio::test]
+async fn test_listens_on_every_configured_address() {
+    use super::swarm::run_swarm;
+    use crate::api::{new_shared_network_state, Readiness};
+    use crate::metrics::Metrics;
+    use std::time::Duration;
+
+    let mut config = create_test_config();
+    config.listen = vec!["/ip4/127.0.0.1/tcp/0".to_string(), "/ip6/::/tcp/0".to_string()];
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    let network_state = new_shared_network_state(&config, swarm.local_peer_id().to_string());
+    let (_shutdown, shutdown_rx) = tokio::sync::watch::channel(false);
+    let (_commands, commands_rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(run_swarm(
+        swarm,
+        config,
+        network_state.clone(),
+        Arc::new(Readiness::new(false, false)),
+        Arc::new(Metrics::new(Registry::default())),
+        None,
+        commands_rx,
+        shutdown_rx,
+    ));
+
+    // Both families show up in the snapshot once they are bound
+    let bound = async {
+        loop {
+            let listen_addrs = network_state.read().await.listen_addrs.clone();
+            if listen_addrs.iter().any(|a| a.starts_with("/ip4/127.0.0.1/tcp/"))
+                && listen_addrs.iter().any(|a| a.starts_with("/ip6/"))
+            {
+                return listen_addrs;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    let listen_addrs = tokio::time::timeout(Duration::from_secs(5), bound).await.expect("not listening on both addresses");
+    assert!(listen_addrs.iter().all(|a| !a.ends_with("/tcp/0")), "{:?}", listen_addrs);
+}
+
+#[tokio::test]
+async fn test_mdns_enabled_builds_behaviour() {
+    let mut config = create_test_config();
+    config.enable_mdns = true;
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+
+    assert!(swarm.behaviour().mdns.is_enabled());
+}
+
+#[tokio::test]
+async fn test_kad_disabled_builds_no_behaviour() {
+    let mut config = create_test_config();
+    config.enable_kad = false;
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+
+    assert!(!swarm.behaviour().kad.is_enabled());
+}
+
+#[tokio::test]
+async fn test_upnp_toggle_follows_config() {
+    let mut config = create_test_config();
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    assert!(!swarm.behaviour().upnp.is_enabled());
+
+    config.enable_upnp = true;
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    assert!(swarm.behaviour().upnp.is_enabled());
+}
+
+#[test]
+fn test_observed_addr_confirmed_once_by_distinct_peers() {
+    use super::swarm::ObservedAddrs;
+    use libp2p::{Multiaddr, PeerId};
+
+    let addr: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+    let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+    let mut observed = ObservedAddrs::new(2);
+
+    assert!(!observed.observe(a, addr.clone()));
+    // The same peer reporting again doesn't add confidence
+    assert!(!observed.observe(a, addr.clone()));
+    assert!(observed.observe(b, addr.clone()));
+    // Already confirmed: later reports don't re-announce it
+    assert!(!observed.observe(c, addr.clone()));
+
+    // Disconnected peers' reports are dropped, and so is an address nobody reports
+    let other: Multiaddr = "/ip4/198.51.100.1/tcp/4001".parse().unwrap();
+    assert!(!observed.observe(a, other));
+    observed.disconnected(&a);
+    assert_eq!(observed.len(), 1);
+    observed.disconnected(&b);
+    observed.disconnected(&c);
+    assert_eq!(observed.len(), 0);
+    // Reported by enough connected peers again, it is confirmed again
+    assert!(!observed.observe(a, addr.clone()));
+    assert!(observed.observe(b, addr));
+}
+
+#[test]
+fn test_unrelated_peers_expire_unless_exempt_or_speaking_our_protocol() {
+    use super::swarm::UnrelatedPeers;
+    use libp2p::PeerId;
+    use std::time::{Duration, Instant};
+
+    let (bootstrap, allowed) = (PeerId::random(), PeerId::random());
+    let (ours, stranger, gone) = (PeerId::random(), PeerId::random(), PeerId::random());
+    let mut config = create_test_config();
+    config.bootstrap_peers = vec![format!("/ip4/203.0.113.1/tcp/4001/p2p/{}", bootstrap)];
+    config.allowed_peers = vec![allowed];
+    let mut unrelated = UnrelatedPeers::new(&config, Duration::from_secs(60));
+
+    let start = Instant::now();
+    for peer in [bootstrap, allowed, ours, stranger, gone] {
+        unrelated.connected(peer, start);
+    }
+    unrelated.speaks_our_protocol(&ours);
+    unrelated.disconnected(&gone);
+
+    assert!(unrelated.expired(start + Duration::from_secs(59)).is_empty());
+    assert_eq!(unrelated.expired(start + Duration::from_secs(60)), vec![stranger]);
+    // Returned peers aren't reported twice
+    assert!(unrelated.expired(start + Duration::from_secs(120)).is_empty());
+}
+
+#[tokio::test]
+async fn test_auto_dials_stay_within_the_concurrency_cap() {
+    use super::swarm::{auto_dial, drain_deferred_dials, DialState};
+
+    let mut config = create_test_config();
+    config.enable_mdns = false;
+    config.enable_kad = false;
+    let mut swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    let mut dial_state = DialState::new(2);
+
+    // The swarm is never polled, so every started dial stays in flight
+    let peers: Vec<libp2p::PeerId> = (0..10).map(|_| libp2p::PeerId::random()).collect();
+    for peer in &peers {
+        swarm.add_peer_address(*peer, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        auto_dial(&mut swarm, &mut dial_state, *peer, "test peer");
+    }
+    assert_eq!(dial_state.in_flight(), 2);
+    assert_eq!(dial_state.deferred(), 8);
+
+    // A finished dial frees exactly one slot for the next deferred peer
+    assert!(dial_state.dial_finished(&peers[0]));
+    drain_deferred_dials(&mut swarm, &config, &mut dial_state);
+    assert_eq!(dial_state.in_flight(), 2);
+    assert_eq!(dial_state.deferred(), 7);
+}
+
+#[tokio::test]
+async fn test_auto_dial_skips_ourselves_denied_peers_and_recent_dials() {
+    use super::swarm::{should_auto_dial, DialState};
+
+    let denied = libp2p::PeerId::random();
+    let mut config = create_test_config();
+    config.enable_kad = false;
+    config.denied_peers = vec![denied];
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    let mut dial_state = DialState::new(2);
+
+    // Our own PeerId can come back from mDNS on a second interface
+    let local = *swarm.local_peer_id();
+    assert!(!should_auto_dial(&swarm, &config, &mut dial_state, &local));
+    assert!(!should_auto_dial(&swarm, &config, &mut dial_state, &denied));
+
+    let stranger = libp2p::PeerId::random();
+    assert!(should_auto_dial(&swarm, &config, &mut dial_state, &stranger));
+    // Still cooling down from that dial
+    assert!(!should_auto_dial(&swarm, &config, &mut dial_state, &stranger));
+}
+
+#[tokio::test]
+async fn test_fallback_relay_enables_the_relay_client() {
+    let mut config = create_test_config();
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    assert!(!swarm.behaviour().relay_client.is_enabled());
+
+    let relay = libp2p::PeerId::random();
+    config.fallback_relay = Some(format!("/ip4/203.0.113.5/tcp/4001/p2p/{}", relay).parse().unwrap());
+    let swarm = build_swarm(&config, &mut Registry::default()).await.unwrap();
+    assert!(swarm.behaviour().relay_client.is_enabled());
+}
+
+#[test]
+fn test_failed_direct_dial_falls_back_to_the_relay_once() {
+    use super::swarm::{is_relayed, RelayFallback};
+
+    let relay = libp2p::PeerId::random();
+    let gateway = libp2p::PeerId::random();
+    let bootstrap = libp2p::PeerId::random();
+    let mut config = create_test_config();
+    config.fallback_relay = Some(format!("/ip4/203.0.113.5/tcp/4001/p2p/{}", relay).parse().unwrap());
+    config.bootstrap_peers = vec![format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", bootstrap)];
+    let mut fallback = RelayFallback::from_config(&config).unwrap();
+
+    // Only gateways and bootstrap peers are worth a relayed dial
+    assert_eq!(fallback.after_dial_failure(gateway), None);
+    assert!(fallback.after_dial_failure(bootstrap).is_some());
+    fallback.add_target(gateway);
+
+    // Direct dial failed: go through the relay's circuit
+    let circuit = fallback.after_dial_failure(gateway).unwrap();
+    assert_eq!(circuit.to_string(), format!("/ip4/203.0.113.5/tcp/4001/p2p/{}/p2p-circuit/p2p/{}", relay, gateway));
+    assert!(is_relayed(&circuit));
+    // The relayed dial failed too: back to the caller's own retry policy
+    assert_eq!(fallback.after_dial_failure(gateway), None);
+    // ...whose next direct failure is relayed again
+    assert!(fallback.after_dial_failure(gateway).is_some());
+    // Failing to reach the relay itself is not retried through it
+    assert_eq!(fallback.after_dial_failure(relay), None);
+
+    // Gateways never relay their dials
+    config.role = Role::Gateway;
+    assert!(RelayFallback::from_config(&config).is_none());
+}
+
+#[test]
+fn test_relayed_dial_takes_over_the_failed_dial_slot() {
+    use super::swarm::DialState;
+
+    let (a, b) = (libp2p::PeerId::random(), libp2p::PeerId::random());
+    let mut dial_state = DialState::new(1);
+
+    // No slot held and none free: no relayed dial
+    assert!(dial_state.take_relay_slot(&a));
+    assert!(!dial_state.take_relay_slot(&b));
+    // A peer keeps the slot it already holds
+    assert!(dial_state.take_relay_slot(&a));
+    assert_eq!(dial_state.in_flight(), 1);
+
+    assert!(dial_state.dial_finished(&a));
+    assert!(dial_state.take_relay_slot(&b));
+}
+
+#[test]
+fn test_bootstrap_peer_redialed_at_its_latest_address_with_backoff() {
+    use super::swarm::{BootstrapPeers, BOOTSTRAP_REDIAL_INITIAL_BACKOFF};
+    use std::time::Instant;
+
+    let peer = libp2p::PeerId::random();
+    let mut config = create_test_config();
+    config.bootstrap_peers = vec![
+        format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer),
+        "/ip4/10.0.0.2/tcp/4001".to_string(), // no peer id: can't be redialed by id
+    ];
+    let mut bootstrap = BootstrapPeers::new(&config);
+
+    let configured: libp2p::Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer).parse().unwrap();
+    let moved: libp2p::Multiaddr = "/ip4/10.0.0.9/tcp/4001".parse().unwrap();
+    assert!(bootstrap.update_addrs(&peer, vec![moved.clone()]).is_some());
+    assert!(bootstrap.update_addrs(&peer, vec![moved.clone()]).is_none());
+    assert!(bootstrap.update_addrs(&libp2p::PeerId::random(), vec![moved.clone()]).is_none());
+    // Loopback and unspecified addresses only mean something on the peer's own host
+    let local = vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap(), "/ip6/::/tcp/4001".parse().unwrap(), moved.clone()];
+    assert!(bootstrap.update_addrs(&peer, local).is_none());
+    // Reporting the configured address again doesn't duplicate it
+    assert!(bootstrap.update_addrs(&peer, vec!["/ip4/10.0.0.1/tcp/4001".parse().unwrap(), moved.clone()]).is_some());
+
+    let now = Instant::now();
+    bootstrap.disconnected(&peer, now);
+    assert!(bootstrap.due(now).is_empty());
+    let due = bootstrap.due(now + BOOTSTRAP_REDIAL_INITIAL_BACKOFF);
+    // The configured address is kept and tried first
+    assert_eq!(due, vec![(peer, vec![configured, moved])]);
+    assert!(bootstrap.due(now + BOOTSTRAP_REDIAL_INITIAL_BACKOFF).is_empty());
+
+    // The next failure waits twice as long; a connection resets that
+    bootstrap.disconnected(&peer, now);
+    assert!(bootstrap.due(now + BOOTSTRAP_REDIAL_INITIAL_BACKOFF).is_empty());
+    assert_eq!(bootstrap.due(now + BOOTSTRAP_REDIAL_INITIAL_BACKOFF * 2).len(), 1);
+    bootstrap.connected(&peer);
+    bootstrap.disconnected(&peer, now);
+    assert_eq!(bootstrap.due(now + BOOTSTRAP_REDIAL_INITIAL_BACKOFF).len(), 1);
+}
+
+#[test]
+fn test_booking_token_accepted_only_for_its_peer_issuer_and_lifetime() {
+    use super::capability::{issuer_public_key, mint, verify};
+    use libp2p::identity::Keypair;
+    use libp2p::PeerId;
+    use std::time::Duration;
+
+    let issuer = Keypair::generate_ed25519();
+    let issuer_key = issuer_public_key(&issuer.public().to_peer_id()).unwrap();
+    let peer = PeerId::random();
+    let token = mint(&issuer, &peer, Duration::from_secs(60)).unwrap();
+
+    assert!(verify(&token, &issuer_key, &peer).is_ok());
+    assert!(verify(&token, &issuer_key, &PeerId::random()).is_err());
+    assert!(verify(&token, &Keypair::generate_ed25519().public(), &peer).is_err());
+    let expired = mint(&issuer, &peer, Duration::ZERO).unwrap();
+    assert!(verify(&expired, &issuer_key, &peer).is_err());
+
+    // Stretching the expiry breaks the signature
+    let mut parts: Vec<String> = token.split('.').map(str::to_string).collect();
+    parts[1] = (parts[1].parse::<u64>().unwrap() + 3600).to_string();
+    assert!(verify(&parts.join("."), &issuer_key, &peer).is_err());
+    assert!(verify("garbage", &issuer_key, &peer).is_err());
+
+    // An expiry past u64::MAX seconds is refused rather than wrapped around
+    assert!(mint(&issuer, &peer, Duration::from_secs(u64::MAX)).is_err());
+}
+
+#[test]
+fn test_network_id_roundtrips_through_identify_protocol_version() {
+    use super::swarm::{identify_protocol_version, network_id_of, IDENTIFY_PROTOCOL_VERSION};
+
+    assert_eq!(identify_protocol_version(None), IDENTIFY_PROTOCOL_VERSION);
+    assert_eq!(network_id_of(&identify_protocol_version(None)), None);
+    assert_eq!(network_id_of(&identify_protocol_version(Some("staging"))), Some("staging"));
+    // Other apps' identify versions never carry one of our network ids
+    assert_eq!(network_id_of("/ipfs/0.1.0"), None);
+}
+
+#[test]
+fn test_rr_protocol_versions_roundtrip_and_prefer_v2() {
+    use super::protocol::{decode_msg, encode_msg, Msg, OpProtocol};
+
+    let msg = Msg::BookingAck { correlation_id: "c1".to_string(), status: "queued".to_string() };
+    for protocol in OpProtocol::ALL {
+        let data = encode_msg(&protocol, msg.clone()).unwrap();
+        assert!(matches!(
+            decode_msg(&protocol, &data).unwrap(),
+            Msg::BookingAck { correlation_id, .. } if correlation_id == "c1"
+        ));
+    }
+    // Each version only reads its own framing
+    let v1 = encode_msg(&OpProtocol::V1, msg.clone()).unwrap();
+    assert!(decode_msg(&OpProtocol::V2, &v1).is_err());
+    let v2 = encode_msg(&OpProtocol::V2, msg).unwrap();
+    assert!(decode_msg(&OpProtocol::V1, &v2).is_err());
+
+    assert_eq!(OpProtocol::preferred(["/ipfs/id/1.0.0", "/node-agent/rr/1", "/node-agent/rr/2"]), Some(OpProtocol::V2));
+    assert_eq!(OpProtocol::preferred(["/node-agent/rr/1"]), Some(OpProtocol::V1));
+    assert_eq!(OpProtocol::preferred(["/ipfs/ping/1.0.0"]), None);
+}
+
+#[test]
+fn test_booking_status_keeps_its_wire_strings() {
+    use super::protocol::{BookingStatus, Msg};
+
+    assert_eq!(serde_json::to_string(&BookingStatus::Throttled).unwrap(), r#""throttled""#);
+    assert_eq!(serde_json::from_str::<BookingStatus>(r#""unauthorized""#).unwrap(), BookingStatus::Unauthorized);
+    assert_eq!(BookingStatus::Throttled.to_string(), "throttled");
+    assert_eq!(BookingStatus::parse("draining"), Some(BookingStatus::Draining));
+    assert_eq!(BookingStatus::parse("someday"), None);
+    assert!(matches!(
+        BookingStatus::Queued.ack("c1".to_string()),
+        Msg::BookingAck { correlation_id, status } if correlation_id == "c1" && status == "queued"
+    ));
+}
+
+#[test]
+fn test_malformed_messages_are_classified() {
+    use super::protocol::{decode_msg, malformed_cause, MalformedKind, OpProtocol};
+
+    let kind = |protocol: OpProtocol, data: &str| {
+        let error = decode_msg(&protocol, data.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        malformed_cause(&error).expect("decode errors carry a MalformedMsg").kind
+    };
+
+    assert_eq!(kind(OpProtocol::V1, "not json"), MalformedKind::Syntax);
+    assert_eq!(kind(OpProtocol::V1, r#"{"BookingAck":{"correlation_id":"c1","#), MalformedKind::Truncated);
+    assert_eq!(kind(OpProtocol::V1, r#"{"CancelBooking":{"correlation_id":"c1"}}"#), MalformedKind::UnknownVariant);
+    assert_eq!(kind(OpProtocol::V1, r#"{"BookingAck":{"correlation_id":"c1"}}"#), MalformedKind::Schema);
+    assert_eq!(kind(OpProtocol::V2, r#"{"v":3,"msg":{"Heartbeat":{"role":"client"}}}"#), MalformedKind::EnvelopeVersion);
+    // Only the position is reported, never what the peer sent
+    let error = decode_msg(&OpProtocol::V1, br#"{"Secret":{"email":"a@b.c"}}"#).unwrap_err();
+    assert!(!malformed_cause(&error).unwrap().to_string().contains("Secret"));
+}
+
+#[test]
+fn test_rr_failure_streak_trips_at_threshold_and_resets_on_success() {
+    use super::swarm::RrFailureStreaks;
+    use libp2p::PeerId;
+
+    let peer = PeerId::random();
+    let mut streaks = RrFailureStreaks::new(3);
+    assert!(!streaks.failed(peer));
+    assert!(!streaks.failed(peer));
+    // A message getting through breaks the streak
+    streaks.succeeded(&peer);
+    assert!(!streaks.failed(peer));
+    assert!(!streaks.failed(peer));
+    assert!(streaks.failed(peer));
+    // Tripping starts the count over
+    assert!(!streaks.failed(peer));
+
+    let mut disabled = RrFailureStreaks::new(0);
+    assert!((0..10).all(|_| !disabled.failed(peer)));
+}
@@ -4,9 +4,6 @@
 
 #[cfg(test)]
 mod integration_tests {
-    use std::time::Duration;
-    use tokio::time::sleep;
-
     // Note: Full integration tests would require:
     // 1. Starting a mock HTTP server (e.g., using wiremock or a simple HTTP server)
     // 2. Starting two P2P nodes (client + gateway)
@@ -40,11 +37,6 @@ mod integration_tests {
     async fn test_forwarder_with_mock_http() {
         // Basic test to verify forwarder can make HTTP requests
         // This is a simplified version - full test would use wiremock
-        
-        use hybrid_connection_health::broker::forwarder::ForwarderWorker;
-        use hybrid_connection_health::broker::storage::BrokerStorage;
-        use hybrid_connection_health::config::{Config, Role};
-        use std::sync::Arc;
 
         // This test would require setting up a mock HTTP server
         // For now, we'll skip it
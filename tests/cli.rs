@@ -0,0 +1,58 @@
+// Tests that run the built binary, for behaviour that lives in main.rs
+// (logging setup and one-shot command output)
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Runs `test-submit --json` against a port nothing listens on, from `dir`
+/// (where a config.toml may be waiting)
+fn failing_test_submit(dir: &Path, envs: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hybrid-connection-health"))
+        .current_dir(dir)
+        .args(["test-submit", "--listen", "/ip4/127.0.0.1/tcp/0", "--dial", "/ip4/127.0.0.1/tcp/1", "--timeout-secs", "1", "--json"])
+        .env_remove("RUST_LOG")
+        .env_remove("RUST_BACKTRACE")
+        .env_remove("LOG_FORMAT")
+        .envs(envs.iter().copied())
+        .output()
+        .expect("failed to run the binary")
+}
+
+fn config_dir(config_toml: &str) -> tempfile::TempDir {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(dir.path().join("config.toml"), config_toml).unwrap();
+    dir
+}
+
+#[test]
+fn test_submit_json_prints_one_result_line_and_fails() {
+    let dir = config_dir("enable_mdns = false\n");
+    let output = failing_test_submit(dir.path(), &[]);
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // Logs went to stderr: stdout holds the result alone
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(report["passed"], false);
+    assert!(report["op_id"].as_str().is_some_and(|id| !id.is_empty()));
+    assert!(report["rtt_ms"].is_null());
+    assert!(report["error"].as_str().unwrap().contains("timed out"), "{}", report);
+}
+
+#[test]
+fn test_json_log_format_and_log_level() {
+    let dir = config_dir("enable_mdns = false\nlog_level = \"warn\"\n");
+    let output = failing_test_submit(dir.path(), &[("LOG_FORMAT", "json")]);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let logs: Vec<serde_json::Value> = stderr
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // The failed dial attempts are warnings; the "Dialing ..." lines are info and filtered out
+    assert!(!logs.is_empty(), "{}", stderr);
+    assert!(logs.iter().all(|log| log["level"] == "WARN" || log["level"] == "ERROR"), "{}", stderr);
+    assert!(logs.iter().all(|log| log["target"].is_string()), "{}", stderr);
+}